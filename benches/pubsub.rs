@@ -0,0 +1,401 @@
+//! Benchmarks of [`State`], using image-sized values.
+//!
+//! The benchmarks use the public API of the library part of the crate, so they don't need the rest
+//! of the server. Run them on stable using:
+//!
+//! ```shell
+//! cargo bench --bench pubsub
+//! ```
+//!
+//! Benchmarks can be selected by passing (part of) their name, e.g.
+//! `cargo bench --bench pubsub -- mutate_state`.
+//!
+//! ## Comparing runs
+//!
+//! Criterion compares each run to the previous one, and reports if a change is significant. To
+//! compare against a specific state, save a named baseline before applying a change, on the same
+//! machine:
+//!
+//! ```shell
+//! cargo bench --bench pubsub -- --save-baseline before
+//! # apply the change
+//! cargo bench --bench pubsub -- --baseline before
+//! ```
+//!
+//! ## Contention
+//!
+//! The `contention` benchmarks compare a single [`State`] to a [`ShardedState`], with several
+//! writers mutating them concurrently. Shards can only pay off if the writers actually run in
+//! parallel, so run them on a machine with at least as many cores as there are writers. On fewer
+//! cores, they only show the overhead of sharding, mostly of merging the events of the shards
+//! for each subscriber.
+//!
+//! ## Serializing
+//!
+//! The `serialize` benchmarks compare many websocket-like clients serializing each event on their
+//! own, to clients of a derived state of [`Encoded`] values, which serializes each value once.
+
+use bommer::pubsub::{Encoded, ShardedState, State, Subscription};
+use bommer_api::data::{Image, ImageBuilder, ImageRef, PodRef, SbomState, SBOM};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::runtime::Runtime;
+
+/// Number of pods of each image
+const PODS: usize = 8;
+
+fn image(n: usize) -> (ImageRef, Image) {
+    let image = (0..PODS)
+        .map(|i| PodRef {
+            namespace: format!("namespace-{}", n % 16),
+            name: format!("pod-{n}-{i}"),
+        })
+        .collect::<ImageBuilder>()
+        .sbom(SbomState::Found(vec![SBOM::new(format!(
+            r#"{{"components":[],"n":{n}}}"#
+        ))]))
+        .build();
+
+    (
+        ImageRef(format!("quay.io/example/image-{n}@sha256:{n:064x}")),
+        image,
+    )
+}
+
+/// One of the first 1000 images, with a value which is different for each `i`.
+fn mutation(i: usize) -> (ImageRef, Image) {
+    let (key, mut value) = image(i % 1_000);
+    value.annotations.insert("i".into(), i.to_string());
+    (key, value)
+}
+
+fn state(keys: usize) -> HashMap<ImageRef, Image> {
+    (0..keys).map(image).collect()
+}
+
+fn runtime() -> Runtime {
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()
+        .expect("must create runtime")
+}
+
+/// Keep draining a subscription, taking `delay` for each event.
+async fn drain(state: &State<ImageRef, Image>, delay: Option<Duration>) {
+    drain_subscription(state.subscribe(16).await, delay);
+}
+
+fn drain_subscription(mut sub: Subscription<ImageRef, Image>, delay: Option<Duration>) {
+    tokio::spawn(async move {
+        while sub.recv().await.is_some() {
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    });
+}
+
+fn mutate_state(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("mutate_state");
+
+    for subscribers in [0, 1, 10, 100] {
+        let state = State::from(state(1_000));
+        runtime.block_on(async {
+            for _ in 0..subscribers {
+                drain(&state, None).await;
+            }
+        });
+
+        let mut i = 0;
+        group.bench_function(BenchmarkId::new("subscribers", subscribers), |b| {
+            b.to_async(&runtime).iter(|| {
+                i += 1;
+                let (key, value) = mutation(i);
+                let state = state.clone();
+                async move { state.mutate_state(key, |_| Some(value)).await }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+fn set_state(c: &mut Criterion) {
+    let runtime = runtime();
+    let state = State::default();
+    let values = self::state(10_000);
+    runtime.block_on(drain(&state, None));
+
+    let mut group = c.benchmark_group("set_state");
+    group.sample_size(10);
+    group.bench_function("10k_keys", |b| {
+        b.to_async(&runtime).iter_batched(
+            || values.clone(),
+            |values| {
+                let state = state.clone();
+                async move { state.set_state(values).await }
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+
+    let mut ndjson = Vec::new();
+    for (key, value) in &values {
+        serde_json::to_writer(&mut ndjson, &(key, value)).expect("must serialize");
+        ndjson.push(b'\n');
+    }
+
+    let mut group = c.benchmark_group("load_ndjson");
+    group.sample_size(10);
+    group.bench_function("10k_keys", |b| {
+        b.to_async(&runtime).iter(|| async {
+            state
+                .load_ndjson(ndjson.as_slice())
+                .await
+                .expect("must load");
+        })
+    });
+    group.finish();
+}
+
+fn subscribe(c: &mut Criterion) {
+    let runtime = runtime();
+    let state = State::from(state(1_000));
+
+    // keep writing in the background
+    let writer = {
+        let state = state.clone();
+        runtime.spawn(async move {
+            for i in 0.. {
+                let (key, value) = mutation(i);
+                state.mutate_state(key, |_| Some(value)).await;
+            }
+        })
+    };
+
+    c.bench_function("subscribe/write_contention", |b| {
+        b.to_async(&runtime)
+            .iter(|| async { drop(state.subscribe(16).await) })
+    });
+
+    writer.abort();
+}
+
+fn fan_out(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("fan_out");
+
+    // mostly fast subscribers, with a few slow ones holding up the broadcast
+    for slow in [0, 1, 5] {
+        let state = State::from(state(1_000));
+        runtime.block_on(async {
+            for _ in 0..(20 - slow) {
+                drain(&state, None).await;
+            }
+            for _ in 0..slow {
+                drain(&state, Some(Duration::from_micros(200))).await;
+            }
+        });
+
+        let mut i = 0;
+        group.bench_function(format!("20_subscribers_{slow}_slow"), |b| {
+            b.to_async(&runtime).iter(|| {
+                i += 1;
+                let (key, value) = mutation(i);
+                let state = state.clone();
+                async move { state.mutate_state(key, |_| Some(value)).await }
+            })
+        });
+    }
+
+    group.finish();
+}
+
+/// Writers mutating the state concurrently, each of them using its own keys.
+const WRITERS: usize = 8;
+
+/// Split `iterations` calls of `f` across `WRITERS` tasks, returning the time it took.
+async fn concurrent<F, Fut>(iterations: u64, f: F) -> Duration
+where
+    F: Fn(usize, usize) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = ()> + Send,
+{
+    let per_writer = (iterations as usize).div_ceil(WRITERS);
+
+    let start = Instant::now();
+    let writers = (0..WRITERS)
+        .map(|writer| {
+            let f = f.clone();
+            tokio::spawn(async move {
+                for i in 0..per_writer {
+                    f(writer, i).await;
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for writer in writers {
+        writer.await.expect("writer must not panic");
+    }
+
+    // rounding up might have added a few calls
+    start
+        .elapsed()
+        .mul_f64(iterations as f64 / (per_writer * WRITERS) as f64)
+}
+
+fn contention(c: &mut Criterion) {
+    // each writer owns a range of keys, so that writes only contend for the lock(s)
+    let mutation = |writer: usize, i: usize| {
+        let (key, mut value) = image(writer * 1_000 + i % 1_000);
+        value.annotations.insert("i".into(), i.to_string());
+        (key, value)
+    };
+
+    let runtime = runtime();
+    let mut group = c.benchmark_group("contention");
+
+    let state = State::from(state(WRITERS * 1_000));
+    runtime.block_on(async {
+        for _ in 0..10 {
+            drain(&state, None).await;
+        }
+    });
+    group.bench_function(
+        format!("{WRITERS}_writers_10_subscribers_single_lock"),
+        |b| {
+            b.to_async(&runtime).iter_custom(|iterations| {
+                let state = state.clone();
+                concurrent(iterations, move |writer, i| {
+                    let state = state.clone();
+                    let (key, value) = mutation(writer, i);
+                    async move { state.mutate_state(key, |_| Some(value)).await }
+                })
+            })
+        },
+    );
+
+    for shards in [8, 32] {
+        let state = ShardedState::new(shards);
+        runtime.block_on(async {
+            state.set_state(self::state(WRITERS * 1_000)).await;
+            for _ in 0..10 {
+                drain_subscription(state.subscribe(16).await, None);
+            }
+        });
+        group.bench_function(
+            format!("{WRITERS}_writers_10_subscribers_{shards}_shards"),
+            |b| {
+                b.to_async(&runtime).iter_custom(|iterations| {
+                    let state = state.clone();
+                    concurrent(iterations, move |writer, i| {
+                        let state = state.clone();
+                        let (key, value) = mutation(writer, i);
+                        async move { state.mutate_state(key, |_| Some(value)).await }
+                    })
+                })
+            },
+        );
+    }
+
+    group.finish();
+}
+
+/// Number of clients of the `serialize` benchmarks
+const CLIENTS: usize = 200;
+
+/// Subscribe `CLIENTS` times, serializing each event like a websocket client would, and counting
+/// the events serialized.
+async fn clients<V>(state: &State<ImageRef, V>) -> Arc<AtomicUsize>
+where
+    V: Clone + std::fmt::Debug + PartialEq + serde::Serialize + Send + Sync + 'static,
+{
+    let serialized = Arc::new(AtomicUsize::new(0));
+    for _ in 0..CLIENTS {
+        let mut sub = state.subscribe(64).await;
+        let serialized = serialized.clone();
+        tokio::spawn(async move {
+            while let Some(evt) = sub.recv().await {
+                let json = serde_json::to_string(&evt).expect("must serialize");
+                std::hint::black_box(json);
+                serialized.fetch_add(1, Ordering::Relaxed);
+            }
+        });
+    }
+
+    // skip the initial state
+    while serialized.load(Ordering::Relaxed) < CLIENTS {
+        tokio::task::yield_now().await;
+    }
+    serialized
+}
+
+/// Mutate `state` `iterations` times, returning the time until all clients serialized each event.
+async fn serialized_by_clients(
+    iterations: u64,
+    state: &State<ImageRef, Image>,
+    serialized: &AtomicUsize,
+) -> Duration {
+    let expected = serialized.load(Ordering::Relaxed) + iterations as usize * CLIENTS;
+
+    let start = Instant::now();
+    for i in 0..iterations as usize {
+        let (key, value) = mutation(i);
+        state.mutate_state(key, |_| Some(value)).await;
+    }
+    while serialized.load(Ordering::Relaxed) < expected {
+        // clients which got evicted for falling behind would never catch up
+        assert!(
+            start.elapsed() < Duration::from_secs(60),
+            "clients must keep up"
+        );
+        tokio::task::yield_now().await;
+    }
+    start.elapsed()
+}
+
+fn serialize(c: &mut Criterion) {
+    let runtime = runtime();
+    let mut group = c.benchmark_group("serialize");
+    group.sample_size(10);
+
+    let state = State::from(state(1_000));
+    let serialized = runtime.block_on(clients(&state));
+    group.bench_function(format!("{CLIENTS}_clients_each"), |b| {
+        b.to_async(&runtime)
+            .iter_custom(|iterations| serialized_by_clients(iterations, &state, &serialized))
+    });
+
+    let state = State::from(self::state(1_000));
+    let serialized = runtime.block_on(async {
+        let encoded = state
+            .map(
+                |key, value| (key.clone(), Encoded::new(value).expect("must serialize")),
+                |value, _| value,
+            )
+            .await;
+        clients(&encoded).await
+    });
+    group.bench_function(format!("{CLIENTS}_clients_encoded_once"), |b| {
+        b.to_async(&runtime)
+            .iter_custom(|iterations| serialized_by_clients(iterations, &state, &serialized))
+    });
+
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    mutate_state,
+    set_state,
+    subscribe,
+    fan_out,
+    contention,
+    serialize
+);
+criterion_main!(benches);
@@ -1,13 +1,282 @@
-use std::collections::{HashMap, HashSet};
+use crate::sbom::{SbomDocument, SbomFormat, Severity, SpdxDocument, Vulnerability};
+use serde::{Deserialize, Deserializer};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque};
 use std::fmt::{Debug, Display, Formatter};
 use std::hash::Hash;
 use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 #[serde(rename_all = "camelCase")]
 pub struct Image {
     pub pods: HashSet<PodRef>,
     pub sbom: SbomState,
+    /// free-form notes, attached by users
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub annotations: BTreeMap<String, String>,
+    /// the most recent numbers of pods, sampled whenever the pods changed
+    #[serde(default, skip_serializing_if = "PodHistory::is_empty")]
+    pub pod_history: PodHistory,
+    /// what is expected of the SBOM of the image, according to the policy
+    #[serde(default, skip_serializing_if = "Classification::is_required")]
+    pub classification: Classification,
+    /// the SBOMs of each architecture, if the image is a manifest list (multi-arch image) which
+    /// got resolved, in which case `sbom` is their combined state
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub architectures: BTreeMap<String, ArchitectureSbom>,
+}
+
+/// The SBOM of a single architecture of a multi-arch image
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ArchitectureSbom {
+    /// the image of the architecture, pinned by its own digest
+    pub image: ImageRef,
+    pub sbom: SbomState,
+}
+
+impl Image {
+    /// Build an image, e.g. while iterating over the containers of pods.
+    pub fn builder() -> ImageBuilder {
+        ImageBuilder::default()
+    }
+
+    /// The vulnerabilities of the image, by severity.
+    ///
+    /// Several SBOMs of an image describe the same image, so for each severity the largest count
+    /// of any of them is taken, instead of adding them up.
+    pub fn severities(&self) -> Severities {
+        self.sbom
+            .sboms()
+            .iter()
+            .fold(Severities::default(), |acc, sbom| acc.max(&sbom.severities))
+    }
+
+    /// The state of each architecture, e.g. `found for amd64, missing for arm64`, `None` unless
+    /// the image is a resolved manifest list.
+    pub fn architecture_summary(&self) -> Option<String> {
+        if self.architectures.is_empty() {
+            return None;
+        }
+
+        let mut by_state = BTreeMap::<_, Vec<_>>::new();
+        for (architecture, sbom) in &self.architectures {
+            by_state
+                .entry(sbom.sbom.label())
+                .or_default()
+                .push(architecture.as_str());
+        }
+
+        Some(
+            by_state
+                .into_iter()
+                .map(|(state, architectures)| {
+                    format!("{state} for {}", architectures.join(" and "))
+                })
+                .collect::<Vec<_>>()
+                .join(", "),
+        )
+    }
+
+    /// Whether some, but not all, architectures of a multi-arch image have an SBOM.
+    pub fn is_partially_found(&self) -> bool {
+        let found = self
+            .architectures
+            .values()
+            .filter(|architecture| matches!(architecture.sbom, SbomState::Found(_)))
+            .count();
+        found > 0 && found < self.architectures.len()
+    }
+
+    /// Replace the pods, recording their number in the history if they changed.
+    pub fn set_pods(&mut self, pods: HashSet<PodRef>) {
+        if self.pods != pods {
+            self.pod_history.record(pods.len());
+            self.pods = pods;
+        }
+    }
+}
+
+/// The number of vulnerabilities, by severity
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct Severities(pub BTreeMap<Severity, usize>);
+
+impl Severities {
+    pub fn get(&self, severity: Severity) -> usize {
+        self.0.get(&severity).copied().unwrap_or_default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.values().all(|count| *count == 0)
+    }
+
+    /// The larger count of each severity
+    pub fn max(&self, other: &Self) -> Self {
+        let mut result = self.clone();
+        for (severity, count) in &other.0 {
+            let entry = result.0.entry(*severity).or_default();
+            *entry = (*entry).max(*count);
+        }
+        result
+    }
+}
+
+impl FromIterator<Severity> for Severities {
+    fn from_iter<T: IntoIterator<Item = Severity>>(iter: T) -> Self {
+        let mut result = Self::default();
+        for severity in iter {
+            *result.0.entry(severity).or_default() += 1;
+        }
+        result
+    }
+}
+
+/// The number of samples a [`PodHistory`] keeps
+pub const POD_HISTORY_LEN: usize = 20;
+
+/// The most recent numbers of pods of an image, oldest first, keeping [`POD_HISTORY_LEN`] samples.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(transparent)]
+pub struct PodHistory(VecDeque<usize>);
+
+impl PodHistory {
+    /// Add a sample, dropping the oldest one if the history is full.
+    pub fn record(&mut self, pods: usize) {
+        if self.0.len() >= POD_HISTORY_LEN {
+            self.0.pop_front();
+        }
+        self.0.push_back(pods);
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// The samples, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = usize> + '_ {
+        self.0.iter().copied()
+    }
+
+    /// The largest sample, zero if there are none
+    pub fn max(&self) -> usize {
+        self.iter().max().unwrap_or_default()
+    }
+}
+
+/// What is expected of the SBOM of an image, ordered from the least to the most strict
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "camelCase")]
+pub enum Classification {
+    /// the image doesn't need an SBOM, e.g. a third-party image
+    Exempt,
+    /// the image should have an SBOM, but not having one is only a warning
+    Recommended,
+    /// the image must have an SBOM
+    #[default]
+    Required,
+}
+
+impl Classification {
+    /// A short, stable name of the classification, e.g. for use as a label
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Exempt => "exempt",
+            Self::Recommended => "recommended",
+            Self::Required => "required",
+        }
+    }
+
+    pub fn is_required(&self) -> bool {
+        *self == Self::Required
+    }
+
+    pub fn is_exempt(&self) -> bool {
+        *self == Self::Exempt
+    }
+}
+
+impl Display for Classification {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.label())
+    }
+}
+
+/// Builds an [`Image`], starting out without pods, and with its SBOM scheduled for lookup.
+///
+/// Pods can also be collected into a builder: `pods.into_iter().collect::<ImageBuilder>()`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ImageBuilder {
+    pods: HashSet<PodRef>,
+    sbom: SbomState,
+}
+
+impl Default for ImageBuilder {
+    fn default() -> Self {
+        Self {
+            pods: Default::default(),
+            sbom: SbomState::Scheduled,
+        }
+    }
+}
+
+impl ImageBuilder {
+    /// Add a pod using the image, adding the same pod again has no effect.
+    pub fn pod(&mut self, pod: PodRef) -> &mut Self {
+        self.pods.insert(pod);
+        self
+    }
+
+    pub fn sbom(&mut self, state: SbomState) -> &mut Self {
+        self.sbom = state;
+        self
+    }
+
+    /// Build the image, starting its history with the current number of pods.
+    pub fn build(&self) -> Image {
+        let mut pod_history = PodHistory::default();
+        pod_history.record(self.pods.len());
+        Image {
+            pods: self.pods.clone(),
+            sbom: self.sbom.clone(),
+            annotations: Default::default(),
+            pod_history,
+            classification: Default::default(),
+            architectures: Default::default(),
+        }
+    }
+}
+
+impl FromIterator<PodRef> for ImageBuilder {
+    fn from_iter<T: IntoIterator<Item = PodRef>>(iter: T) -> Self {
+        let mut builder = Self::default();
+        builder.extend(iter);
+        builder
+    }
+}
+
+impl Extend<PodRef> for ImageBuilder {
+    fn extend<T: IntoIterator<Item = PodRef>>(&mut self, iter: T) {
+        self.pods.extend(iter);
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
@@ -16,12 +285,229 @@ pub enum SbomState {
     Scheduled,
     Err(String),
     Missing,
-    Found(SBOM),
+    /// no source of SBOMs is configured for the registry of the image, so it wasn't looked up
+    Unsupported,
+    /// the SBOMs of the image, e.g. in different formats, at least one
+    #[serde(deserialize_with = "one_or_many")]
+    Found(Vec<SBOM>),
+}
+
+/// Also accept a single SBOM, as stored before images could have several of them.
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<SBOM>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(SBOM),
+        Many(Vec<SBOM>),
+    }
+
+    match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(sbom) => Ok(vec![sbom]),
+        OneOrMany::Many(sboms) if sboms.is_empty() => {
+            Err(serde::de::Error::invalid_length(0, &"at least one SBOM"))
+        }
+        OneOrMany::Many(sboms) => Ok(sboms),
+    }
+}
+
+impl SbomState {
+    /// A short, stable name of the state, e.g. for use as a label
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Scheduled => "scheduled",
+            Self::Err(_) => "error",
+            Self::Missing => "missing",
+            Self::Unsupported => "unsupported",
+            Self::Found(_) => "found",
+        }
+    }
+
+    /// Combine the states of the architectures of a multi-arch image.
+    ///
+    /// The image only has an SBOM if all of its architectures have one, so the least resolved
+    /// state wins: a failed lookup, then a pending one, then a missing SBOM. The SBOMs of all
+    /// architectures are kept. Without any states, the SBOM is missing.
+    pub fn combine<'a>(states: impl IntoIterator<Item = &'a SbomState>) -> SbomState {
+        fn rank(state: &SbomState) -> u8 {
+            match state {
+                SbomState::Err(_) => 0,
+                SbomState::Scheduled => 1,
+                SbomState::Missing => 2,
+                SbomState::Unsupported => 3,
+                SbomState::Found(_) => 4,
+            }
+        }
+
+        let mut combined: Option<SbomState> = None;
+        for state in states {
+            combined = Some(match (combined, state) {
+                (None, state) => state.clone(),
+                (Some(SbomState::Found(mut sboms)), SbomState::Found(more)) => {
+                    sboms.extend(more.iter().cloned());
+                    SbomState::Found(sboms)
+                }
+                (Some(current), state) if rank(state) < rank(&current) => state.clone(),
+                (Some(current), _) => current,
+            });
+        }
+
+        combined.unwrap_or(SbomState::Missing)
+    }
+
+    /// The SBOMs found, empty unless the state is [`SbomState::Found`]
+    pub fn sboms(&self) -> &[SBOM] {
+        match self {
+            Self::Found(sboms) => sboms,
+            _ => &[],
+        }
+    }
 }
 
+/// The transitions of the SBOM state machine
+pub struct SbomStateTransition;
+
+impl SbomStateTransition {
+    /// Check if moving from one state to another is expected.
+    ///
+    /// New images start out as scheduled, and get resolved by looking up their SBOM. Missing and
+    /// failed lookups get re-scheduled. Replacing a state with one of the same kind (e.g. an
+    /// updated SBOM) is fine too.
+    pub fn validate(from: &SbomState, to: &SbomState) -> Result<(), InvalidTransition> {
+        use SbomState::*;
+
+        match (from, to) {
+            (Scheduled, _)
+            | (Err(_), Scheduled | Err(_))
+            | (Missing, Scheduled | Missing)
+            | (Unsupported, Scheduled | Unsupported)
+            | (Found(_), Found(_)) => Ok(()),
+            _ => Result::Err(InvalidTransition {
+                from: from.label(),
+                to: to.label(),
+            }),
+        }
+    }
+}
+
+/// A transition the SBOM state machine doesn't allow
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct InvalidTransition {
+    pub from: &'static str,
+    pub to: &'static str,
+}
+
+impl Display for InvalidTransition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "invalid SBOM state transition: {} → {}",
+            self.from, self.to
+        )
+    }
+}
+
+impl std::error::Error for InvalidTransition {}
+
 #[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub struct SBOM {
-    pub data: String,
+    /// shared between clones, and between images with the same SBOM if interned by the backend
+    pub data: Arc<str>,
+    /// format of the data, detected when creating the SBOM
+    #[serde(default)]
+    pub format: SbomFormat,
+    /// digest of the image the SBOM describes, extracted from the data
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub subject_digest: Option<String>,
+    /// number of components, counted when creating the SBOM, unknown if the data is invalid
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component_count: Option<usize>,
+    /// vulnerabilities reported by the data, by severity, counted when creating the SBOM
+    #[serde(default, skip_serializing_if = "Severities::is_empty")]
+    pub severities: Severities,
+}
+
+impl SBOM {
+    /// Create a new SBOM from its (CycloneDX or SPDX JSON) data.
+    ///
+    /// Values derived from the data are extracted here, so that they don't need to be computed
+    /// again when they are used. As the data can't change afterwards, they stay valid.
+    pub fn new(data: String) -> Self {
+        let format = SbomFormat::detect(&data);
+        let (subject_digest, component_count, severities) = match format {
+            SbomFormat::CycloneDx => {
+                let document = serde_json::from_str::<SbomDocument>(&data).ok();
+                (
+                    document
+                        .as_ref()
+                        .and_then(|document| document.subject_digest()),
+                    document.as_ref().map(|document| document.components.len()),
+                    document
+                        .iter()
+                        .flat_map(|document| &document.vulnerabilities)
+                        .map(Vulnerability::severity)
+                        .collect(),
+                )
+            }
+            SbomFormat::Spdx => (
+                None,
+                serde_json::from_str::<SpdxDocument>(&data)
+                    .ok()
+                    .map(|document| document.packages.len()),
+                Severities::default(),
+            ),
+        };
+        Self {
+            data: data.into(),
+            format,
+            subject_digest,
+            component_count,
+            severities,
+        }
+    }
+
+    /// The digest the SBOM describes, if it differs from the digest of `image`.
+    ///
+    /// If either of the digests is unknown, there is no mismatch.
+    pub fn digest_mismatch(&self, image: &ImageRef) -> Option<&str> {
+        let expected = image.parts().digest?;
+        self.subject_digest
+            .as_deref()
+            .filter(|actual| *actual != expected)
+    }
+
+    /// The SHA-256 digest of the data, addressing SBOMs by their content.
+    pub fn content_digest(&self) -> String {
+        crate::sha256_digest(self.data.as_bytes())
+    }
+
+    /// Parse the SBOM data as a (CycloneDX JSON) document, SPDX documents aren't supported.
+    pub fn parse(&self) -> Result<SbomDocument, serde_json::Error> {
+        serde_json::from_str(&self.data)
+    }
+}
+
+/// The limit of tracked images, and how close the backend is to it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageLimitStatus {
+    /// maximum number of tracked images, zero if unlimited
+    pub max_images: usize,
+    /// number of tracked images
+    pub images: usize,
+    /// new images which weren't tracked, as the limit was reached
+    pub refused: u64,
+    /// images which were dropped, to make room for new ones
+    pub evicted: u64,
+}
+
+impl ImageLimitStatus {
+    /// The maximum number of images is tracked, so new images are refused or evict others.
+    pub fn at_capacity(&self) -> bool {
+        self.max_images > 0 && self.images >= self.max_images
+    }
 }
 
 /// A reference to a pod
@@ -40,17 +526,367 @@ pub struct ImageState {
     pub pods: HashSet<PodRef>,
 }
 
-#[derive(
-    Clone, Debug, Eq, PartialEq, Hash, Ord, PartialOrd, serde::Deserialize, serde::Serialize,
-)]
+/// How an image changed, compared to a baseline
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum ImageChange {
+    /// the image is new since the baseline
+    Added,
+    /// the image is gone since the baseline
+    Removed,
+    /// the image was there before, but its pods changed
+    Modified,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ImageDiff {
+    pub change: ImageChange,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub added_pods: BTreeSet<PodRef>,
+    #[serde(default, skip_serializing_if = "BTreeSet::is_empty")]
+    pub removed_pods: BTreeSet<PodRef>,
+}
+
+/// The images and pods added or removed since a baseline, unchanged images are not listed
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct BaselineDiff(pub BTreeMap<ImageRef, ImageDiff>);
+
+impl BaselineDiff {
+    pub fn new(baseline: &HashMap<ImageRef, Image>, current: &HashMap<ImageRef, Image>) -> Self {
+        let mut diff = BTreeMap::new();
+        let none = HashSet::new();
+
+        for (image, state) in current {
+            let (change, before) = match baseline.get(image) {
+                Some(before) => (ImageChange::Modified, &before.pods),
+                None => (ImageChange::Added, &none),
+            };
+            let added_pods = state
+                .pods
+                .difference(before)
+                .cloned()
+                .collect::<BTreeSet<_>>();
+            let removed_pods = before
+                .difference(&state.pods)
+                .cloned()
+                .collect::<BTreeSet<_>>();
+
+            if change == ImageChange::Modified && added_pods.is_empty() && removed_pods.is_empty() {
+                continue;
+            }
+
+            diff.insert(
+                image.clone(),
+                ImageDiff {
+                    change,
+                    added_pods,
+                    removed_pods,
+                },
+            );
+        }
+
+        for (image, state) in baseline {
+            if !current.contains_key(image) {
+                diff.insert(
+                    image.clone(),
+                    ImageDiff {
+                        change: ImageChange::Removed,
+                        added_pods: Default::default(),
+                        removed_pods: state.pods.iter().cloned().collect(),
+                    },
+                );
+            }
+        }
+
+        Self(diff)
+    }
+
+    pub fn get(&self, image: &ImageRef) -> Option<&ImageDiff> {
+        self.0.get(image)
+    }
+
+    /// Images which are gone since the baseline
+    pub fn removed(&self) -> impl Iterator<Item = (&ImageRef, &ImageDiff)> {
+        self.0
+            .iter()
+            .filter(|(_, diff)| diff.change == ImageChange::Removed)
+    }
+}
+
+/// The pods added to and removed from an image
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct PodSetDiff {
+    pub added: BTreeSet<PodRef>,
+    pub removed: BTreeSet<PodRef>,
+}
+
+impl PodSetDiff {
+    pub fn new(before: &HashSet<PodRef>, after: &HashSet<PodRef>) -> Self {
+        Self {
+            added: after.difference(before).cloned().collect(),
+            removed: before.difference(after).cloned().collect(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// The numbers of a workload, for clients which don't need its images.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadSummary {
+    pub image_count: usize,
+    /// distinct pods, counting pods running several images once
+    pub pod_count: usize,
+    pub sbom_found: usize,
+    pub sbom_missing: usize,
+    /// no source of SBOMs is configured for the registry of the image
+    #[serde(default)]
+    pub sbom_unsupported: usize,
+    pub sbom_error: usize,
+    pub sbom_scheduled: usize,
+}
+
+impl WorkloadSummary {
+    pub fn new<'a>(images: impl IntoIterator<Item = &'a Image>) -> Self {
+        let mut summary = Self::default();
+        let mut pods = HashSet::new();
+
+        for image in images {
+            summary.image_count += 1;
+            pods.extend(&image.pods);
+            match image.sbom {
+                SbomState::Found(_) => summary.sbom_found += 1,
+                SbomState::Missing => summary.sbom_missing += 1,
+                SbomState::Unsupported => summary.sbom_unsupported += 1,
+                SbomState::Err(_) => summary.sbom_error += 1,
+                SbomState::Scheduled => summary.sbom_scheduled += 1,
+            }
+        }
+
+        summary.pod_count = pods.len();
+        summary
+    }
+}
+
+/// The changes between two states of the workload, each list ordered by image.
+///
+/// Other than the [`BaselineDiff`], this also covers changes of the SBOM state, e.g. for logging
+/// what changed.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadDiff {
+    pub added: Vec<ImageRef>,
+    pub removed: Vec<ImageRef>,
+    /// images present before and after, whose pods changed
+    pub pod_changes: Vec<(ImageRef, PodSetDiff)>,
+    /// images present before and after, whose SBOM state changed, from the state before to after
+    pub sbom_transitions: Vec<(ImageRef, SbomState, SbomState)>,
+}
+
+impl WorkloadDiff {
+    pub fn new(before: &HashMap<ImageRef, Image>, after: &HashMap<ImageRef, Image>) -> Self {
+        let mut diff = Self::default();
+
+        for (image, state) in after {
+            let Some(previous) = before.get(image) else {
+                diff.added.push(image.clone());
+                continue;
+            };
+
+            let pods = PodSetDiff::new(&previous.pods, &state.pods);
+            if !pods.is_empty() {
+                diff.pod_changes.push((image.clone(), pods));
+            }
+            if previous.sbom != state.sbom {
+                diff.sbom_transitions.push((
+                    image.clone(),
+                    previous.sbom.clone(),
+                    state.sbom.clone(),
+                ));
+            }
+        }
+
+        diff.removed = before
+            .keys()
+            .filter(|image| !after.contains_key(image))
+            .cloned()
+            .collect();
+
+        diff.added.sort_unstable();
+        diff.removed.sort_unstable();
+        diff.pod_changes.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+        diff.sbom_transitions
+            .sort_unstable_by(|(a, _, _), (b, _, _)| a.cmp(b));
+
+        diff
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty()
+            && self.removed.is_empty()
+            && self.pod_changes.is_empty()
+            && self.sbom_transitions.is_empty()
+    }
+}
+
+/// A one-line summary, e.g. `2 added, 1 removed, 3 with changed pods, 0 SBOM transitions`
+impl Display for WorkloadDiff {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} added, {} removed, {} with changed pods, {} SBOM transitions",
+            self.added.len(),
+            self.removed.len(),
+            self.pod_changes.len(),
+            self.sbom_transitions.len()
+        )
+    }
+}
+
+/// A reference to an image, like `registry/repository:tag@digest`.
+///
+/// References are ordered by registry, repository, tag, and digest. Tags which are semantic
+/// versions (optionally prefixed with `v`) are compared as such, and sort before all other tags.
+#[derive(Clone, Debug, Eq, PartialEq, Hash, serde::Deserialize, serde::Serialize)]
 pub struct ImageRef(pub String);
 
+/// The parts of an [`ImageRef`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageRefParts<'a> {
+    /// the registry, `None` if it is implied (e.g. `nginx:latest`)
+    pub registry: Option<&'a str>,
+    pub repository: &'a str,
+    pub tag: Option<&'a str>,
+    pub digest: Option<&'a str>,
+}
+
+impl ImageRef {
+    /// Split the reference into its parts.
+    pub fn parts(&self) -> ImageRefParts<'_> {
+        // some runtimes only report the digest
+        if self.0.starts_with("sha256:") {
+            return ImageRefParts {
+                registry: None,
+                repository: "",
+                tag: None,
+                digest: Some(&self.0),
+            };
+        }
+
+        let (name, digest) = match self.0.split_once('@') {
+            Some((name, digest)) => (name, Some(digest)),
+            None => (self.0.as_str(), None),
+        };
+
+        // a colon might also be part of the registry's port
+        let (name, tag) = match name.rsplit_once(':') {
+            Some((name, tag)) if !tag.contains('/') => (name, Some(tag)),
+            _ => (name, None),
+        };
+
+        let (registry, repository) = match name.split_once('/') {
+            Some((registry, repository))
+                if registry.contains('.') || registry.contains(':') || registry == "localhost" =>
+            {
+                (Some(registry), repository)
+            }
+            _ => (None, name),
+        };
+
+        ImageRefParts {
+            registry,
+            repository,
+            tag,
+            digest,
+        }
+    }
+}
+
+impl Ord for ImageRef {
+    fn cmp(&self, other: &Self) -> Ordering {
+        let (a, b) = (self.parts(), other.parts());
+        a.registry
+            .cmp(&b.registry)
+            .then_with(|| a.repository.cmp(b.repository))
+            .then_with(|| cmp_tag(a.tag, b.tag))
+            .then_with(|| a.digest.cmp(&b.digest))
+            // stay consistent with `Eq`
+            .then_with(|| self.0.cmp(&other.0))
+    }
+}
+
+impl PartialOrd for ImageRef {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+fn cmp_tag(a: Option<&str>, b: Option<&str>) -> Ordering {
+    fn version(tag: &str) -> Option<semver::Version> {
+        semver::Version::parse(tag.strip_prefix('v').unwrap_or(tag)).ok()
+    }
+
+    match (a, b) {
+        (Some(a), Some(b)) => match (version(a), version(b)) {
+            (Some(va), Some(vb)) => va.cmp(&vb),
+            (Some(_), None) => Ordering::Less,
+            (None, Some(_)) => Ordering::Greater,
+            (None, None) => a.cmp(b),
+        },
+        (a, b) => a.cmp(&b),
+    }
+}
+
 impl Display for ImageRef {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         Display::fmt(&self.0, f)
     }
 }
 
+/// Parse a reference, e.g. as reported for a container.
+///
+/// References which can't name an image are rejected: empty ones, and ones containing whitespace
+/// or control characters. Other than that, the reference is taken as it is, see
+/// [`ImageRef::parts`].
+impl FromStr for ImageRef {
+    type Err = InvalidImageRef;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() {
+            return Err(InvalidImageRef::Empty);
+        }
+        if s.chars().any(|c| c.is_whitespace() || c.is_control()) {
+            return Err(InvalidImageRef::InvalidCharacter);
+        }
+        Ok(Self(s.to_string()))
+    }
+}
+
+/// The reason a string isn't a valid [`ImageRef`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum InvalidImageRef {
+    Empty,
+    InvalidCharacter,
+}
+
+impl Display for InvalidImageRef {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Empty => f.write_str("empty image reference"),
+            Self::InvalidCharacter => {
+                f.write_str("image reference contains whitespace or control characters")
+            }
+        }
+    }
+}
+
+impl std::error::Error for InvalidImageRef {}
+
 impl Deref for ImageRef {
     type Target = String;
 
@@ -70,4 +906,254 @@ where
     Modified(K, V),
     Removed(K),
     Restart(HashMap<K, V>),
+    /// A part of the initial state, delivered instead of a single [`Event::Restart`] to
+    /// subscribers which requested it. The state is complete once `is_last` is `true`.
+    #[serde(rename_all = "camelCase")]
+    RestartChunk {
+        entries: HashMap<K, V>,
+        is_last: bool,
+    },
+}
+
+impl<K, V> Event<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug,
+{
+    /// A short, stable name of the kind of event, e.g. for use as a label
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Added(..) => "added",
+            Self::Modified(..) => "modified",
+            Self::Removed(_) => "removed",
+            Self::Restart(_) => "restart",
+            Self::RestartChunk { .. } => "restartChunk",
+        }
+    }
+
+    /// The number of entries the event carries, which is one for single changes
+    pub fn entries(&self) -> usize {
+        match self {
+            Self::Added(..) | Self::Modified(..) | Self::Removed(_) => 1,
+            Self::Restart(entries) | Self::RestartChunk { entries, .. } => entries.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef";
+    const OTHER: &str = "sha256:fedcba9876543210";
+
+    fn sbom(subject: serde_json::Value) -> SBOM {
+        SBOM::new(serde_json::json!({"metadata": {"component": subject}}).to_string())
+    }
+
+    #[test]
+    fn subject_digest() {
+        let by_version = sbom(serde_json::json!({"name": "nginx", "version": DIGEST}));
+        assert_eq!(by_version.subject_digest.as_deref(), Some(DIGEST));
+
+        let by_purl = sbom(serde_json::json!({
+            "name": "nginx",
+            "version": "1.25",
+            "purl": "pkg:oci/nginx@sha256%3A0123456789abcdef?arch=amd64",
+        }));
+        assert_eq!(by_purl.subject_digest.as_deref(), Some(DIGEST));
+
+        let unknown = sbom(serde_json::json!({"name": "nginx", "version": "1.25"}));
+        assert_eq!(unknown.subject_digest, None);
+        assert_eq!(SBOM::new("not json".to_string()).subject_digest, None);
+    }
+
+    #[test]
+    fn digest_mismatch() {
+        let sbom = sbom(serde_json::json!({"name": "nginx", "version": DIGEST}));
+
+        let same = ImageRef(format!("docker.io/library/nginx@{DIGEST}"));
+        assert_eq!(sbom.digest_mismatch(&same), None);
+
+        let other = ImageRef(format!("docker.io/library/nginx@{OTHER}"));
+        assert_eq!(sbom.digest_mismatch(&other), Some(DIGEST));
+
+        // nothing to compare with
+        let tagged = ImageRef("docker.io/library/nginx:1.25".to_string());
+        assert_eq!(sbom.digest_mismatch(&tagged), None);
+        let unknown = SBOM::new("{}".to_string());
+        assert_eq!(unknown.digest_mismatch(&other), None);
+    }
+
+    fn pods(names: &[&str]) -> Image {
+        names
+            .iter()
+            .map(|name| PodRef {
+                namespace: "default".to_string(),
+                name: name.to_string(),
+            })
+            .collect::<ImageBuilder>()
+            .build()
+    }
+
+    fn pod_refs(names: &[&str]) -> BTreeSet<PodRef> {
+        pods(names).pods.into_iter().collect()
+    }
+
+    #[test]
+    fn baseline_diff() {
+        let image = |name: &str| ImageRef(name.to_string());
+        let baseline = HashMap::from([
+            (image("a"), pods(&["p1", "p2"])),
+            (image("b"), pods(&["p1"])),
+            (image("c"), pods(&["p1"])),
+        ]);
+        let current = HashMap::from([
+            (image("a"), pods(&["p1", "p3"])),
+            (image("b"), pods(&["p1"])),
+            (image("d"), pods(&["p1"])),
+        ]);
+
+        let diff = BaselineDiff::new(&baseline, &current);
+
+        assert_eq!(
+            diff.get(&image("a")),
+            Some(&ImageDiff {
+                change: ImageChange::Modified,
+                added_pods: pod_refs(&["p3"]),
+                removed_pods: pod_refs(&["p2"]),
+            })
+        );
+        assert_eq!(diff.get(&image("b")), None);
+        assert_eq!(
+            diff.get(&image("d")),
+            Some(&ImageDiff {
+                change: ImageChange::Added,
+                added_pods: pod_refs(&["p1"]),
+                removed_pods: Default::default(),
+            })
+        );
+        let removed = diff.removed().collect::<Vec<_>>();
+        assert_eq!(
+            removed,
+            vec![(
+                &image("c"),
+                &ImageDiff {
+                    change: ImageChange::Removed,
+                    added_pods: Default::default(),
+                    removed_pods: pod_refs(&["p1"]),
+                }
+            )]
+        );
+
+        assert_eq!(
+            BaselineDiff::new(&current, &current),
+            BaselineDiff::default()
+        );
+    }
+
+    #[test]
+    fn component_count() {
+        let sbom = SBOM::new(
+            serde_json::json!({"components": [
+                {"name": "openssl", "purl": "pkg:rpm/openssl@3.0.7"},
+                {"name": "zlib", "purl": "pkg:rpm/zlib@1.2.13"},
+            ]})
+            .to_string(),
+        );
+        assert_eq!(sbom.component_count, Some(2));
+
+        assert_eq!(SBOM::new("{}".to_string()).component_count, Some(0));
+        assert_eq!(SBOM::new("not json".to_string()).component_count, None);
+
+        // counted once, and carried along when serialized
+        let json = serde_json::to_string(&sbom).unwrap();
+        let sbom: SBOM = serde_json::from_str(&json).unwrap();
+        assert_eq!(sbom.component_count, Some(2));
+    }
+
+    #[test]
+    fn found_one_or_many() {
+        let one = SBOM::new("{}".to_string());
+        let many = vec![one.clone(), SBOM::new("[]".to_string())];
+
+        let found = SbomState::Found(many.clone());
+        let json = serde_json::to_value(&found).unwrap();
+        assert_eq!(serde_json::from_value::<SbomState>(json).unwrap(), found);
+
+        // stored before images could have several SBOMs
+        let single = serde_json::json!({ "found": one });
+        assert_eq!(
+            serde_json::from_value::<SbomState>(single).unwrap(),
+            SbomState::Found(vec![one])
+        );
+
+        let empty = serde_json::to_value(SbomState::Found(vec![])).unwrap();
+        assert!(serde_json::from_value::<SbomState>(empty).is_err());
+    }
+
+    #[test]
+    fn pod_history() {
+        let mut image = pods(&["a"]);
+        assert_eq!(image.pod_history.iter().collect::<Vec<_>>(), vec![1]);
+
+        // only changes are recorded
+        image.set_pods(pods(&["a", "b"]).pods);
+        image.set_pods(pods(&["a", "b"]).pods);
+        image.set_pods(pods(&["c"]).pods);
+        assert_eq!(image.pod_history.iter().collect::<Vec<_>>(), vec![1, 2, 1]);
+        assert_eq!(image.pod_history.max(), 2);
+
+        // keeping the most recent samples
+        for n in 0..POD_HISTORY_LEN + 5 {
+            image.pod_history.record(n);
+        }
+        assert_eq!(image.pod_history.len(), POD_HISTORY_LEN);
+        assert_eq!(
+            image.pod_history.iter().collect::<Vec<_>>(),
+            (5..POD_HISTORY_LEN + 5).collect::<Vec<_>>()
+        );
+        assert_eq!(PodHistory::default().max(), 0);
+    }
+
+    #[test]
+    fn workload_summary() {
+        let pod = |name: &str| PodRef {
+            namespace: "default".to_string(),
+            name: name.to_string(),
+        };
+        let image = |pods: &[&str], sbom: SbomState| {
+            let mut image = ImageBuilder::default();
+            for name in pods {
+                image.pod(pod(name));
+            }
+            image.sbom(sbom).build()
+        };
+
+        let images = [
+            // a pod running several images is counted once
+            image(
+                &["a", "b"],
+                SbomState::Found(vec![sbom(serde_json::json!({}))]),
+            ),
+            image(&["a"], SbomState::Missing),
+            image(&["c"], SbomState::Err("timeout".to_string())),
+            image(&["c"], SbomState::Err("denied".to_string())),
+            image(&["d"], SbomState::Scheduled),
+            image(&[], SbomState::Unsupported),
+        ];
+        assert_eq!(
+            WorkloadSummary::new(&images),
+            WorkloadSummary {
+                image_count: 6,
+                pod_count: 4,
+                sbom_found: 1,
+                sbom_missing: 1,
+                sbom_unsupported: 1,
+                sbom_error: 2,
+                sbom_scheduled: 1,
+            }
+        );
+        assert_eq!(WorkloadSummary::new([]), WorkloadSummary::default());
+    }
 }
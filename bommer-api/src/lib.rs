@@ -1 +1,10 @@
+use sha2::{Digest, Sha256};
+
 pub mod data;
+pub mod risk;
+pub mod sbom;
+
+/// The SHA-256 digest of `data`, in the OCI format (`sha256:<hex>`)
+fn sha256_digest(data: &[u8]) -> String {
+    format!("sha256:{:x}", Sha256::digest(data))
+}
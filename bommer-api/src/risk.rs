@@ -0,0 +1,122 @@
+//! A single score for the risk the workload is exposed to, e.g. for a dashboard.
+//!
+//! Each vulnerability of an image adds the weight of its severity to the score of the image. The
+//! more pods run an image, the more exposed it is, so the score of an image is multiplied by the
+//! number of its pods raised to the power of [`RiskWeights::pod_exponent`]. The score of the
+//! workload is the sum of the scores of its images.
+//!
+//! With the default weights, a critical vulnerability counts as much as two high ones, and an
+//! image running in four pods scores twice as much as one running in a single pod.
+
+use crate::data::Image;
+use crate::sbom::Severity;
+
+/// The weights of the risk score, see the module documentation
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default, rename_all = "camelCase")]
+pub struct RiskWeights {
+    pub critical: f64,
+    pub high: f64,
+    pub medium: f64,
+    pub low: f64,
+    /// `1` weights images by their number of pods, `0` ignores the number of pods
+    pub pod_exponent: f64,
+}
+
+impl Default for RiskWeights {
+    fn default() -> Self {
+        Self {
+            critical: 10.0,
+            high: 5.0,
+            medium: 2.0,
+            low: 0.5,
+            pod_exponent: 0.5,
+        }
+    }
+}
+
+impl RiskWeights {
+    /// The weight of a single vulnerability, unrated ones and informational findings don't count
+    pub fn severity(&self, severity: Severity) -> f64 {
+        match severity {
+            Severity::Critical => self.critical,
+            Severity::High => self.high,
+            Severity::Medium => self.medium,
+            Severity::Low => self.low,
+            Severity::Info | Severity::None | Severity::Unknown => 0.0,
+        }
+    }
+
+    /// The score of an image, zero if it has no pods
+    pub fn image(&self, image: &Image) -> f64 {
+        if image.pods.is_empty() {
+            return 0.0;
+        }
+
+        let vulnerabilities = image
+            .severities()
+            .0
+            .iter()
+            .map(|(severity, count)| self.severity(*severity) * *count as f64)
+            .sum::<f64>();
+
+        vulnerabilities * (image.pods.len() as f64).powf(self.pod_exponent)
+    }
+
+    /// The score of a workload
+    pub fn workload<'a>(&self, images: impl IntoIterator<Item = &'a Image>) -> f64 {
+        images.into_iter().map(|image| self.image(image)).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::{ImageBuilder, PodRef, SbomState, SBOM};
+
+    /// An image running in `pods` pods, with vulnerabilities of the given severities
+    fn image(pods: usize, severities: &[&str]) -> Image {
+        let vulnerabilities = severities
+            .iter()
+            .map(|severity| serde_json::json!({"ratings": [{"severity": severity}]}))
+            .collect::<Vec<_>>();
+        let sbom = SBOM::new(
+            serde_json::json!({"components": [], "vulnerabilities": vulnerabilities}).to_string(),
+        );
+
+        (0..pods)
+            .map(|n| PodRef {
+                namespace: "default".to_string(),
+                name: format!("pod-{n}"),
+            })
+            .collect::<ImageBuilder>()
+            .sbom(SbomState::Found(vec![sbom]))
+            .build()
+    }
+
+    #[test]
+    fn risk_score() {
+        let weights = RiskWeights::default();
+
+        // a critical vulnerability counts as much as two high ones
+        let critical = image(1, &["critical"]);
+        assert_eq!(weights.image(&critical), 10.0);
+        assert_eq!(weights.image(&image(1, &["high", "high"])), 10.0);
+        // unrated vulnerabilities and informational findings don't count
+        assert_eq!(weights.image(&image(1, &["info", "none", "bogus"])), 0.0);
+
+        // four pods score twice as much as one
+        let spread = image(4, &["critical", "medium", "low"]);
+        assert_eq!(weights.image(&spread), 2.0 * 12.5);
+        assert_eq!(weights.image(&image(0, &["critical"])), 0.0);
+
+        assert_eq!(weights.workload([&critical, &spread]), 35.0);
+        assert_eq!(weights.workload([]), 0.0);
+
+        let by_image = RiskWeights {
+            pod_exponent: 0.0,
+            ..Default::default()
+        };
+        assert_eq!(by_image.image(&spread), 12.5);
+    }
+}
@@ -0,0 +1,337 @@
+//! A minimal model of (CycloneDX) SBOM documents, only covering the parts we need.
+//!
+//! SPDX documents are recognized, but only their packages get counted.
+
+use oci_spec::image::{
+    DescriptorBuilder, ImageManifest, ImageManifestBuilder, MediaType, SCHEMA_VERSION,
+};
+use oci_spec::OciSpecError;
+use serde::de::IgnoredAny;
+use std::collections::HashSet;
+use std::fmt::{Display, Formatter};
+
+/// The format of an SBOM document
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomFormat {
+    /// CycloneDX (JSON), also assumed for documents in an unknown format
+    #[default]
+    CycloneDx,
+    /// SPDX (JSON)
+    Spdx,
+}
+
+impl SbomFormat {
+    /// Detect the format of a (JSON) document, by the presence of the SPDX version.
+    pub fn detect(data: &str) -> Self {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Probe {
+            spdx_version: Option<IgnoredAny>,
+        }
+
+        match serde_json::from_str::<Probe>(data) {
+            Ok(Probe {
+                spdx_version: Some(_),
+            }) => Self::Spdx,
+            _ => Self::CycloneDx,
+        }
+    }
+}
+
+impl Display for SbomFormat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::CycloneDx => f.write_str("CycloneDX"),
+            Self::Spdx => f.write_str("SPDX"),
+        }
+    }
+}
+
+/// The parts of an SPDX document we need
+#[derive(Clone, Debug, Default, serde::Deserialize)]
+pub struct SpdxDocument {
+    #[serde(default)]
+    pub packages: Vec<IgnoredAny>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SbomDocument {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub metadata: Option<Metadata>,
+    #[serde(default)]
+    pub components: Vec<Component>,
+    /// known vulnerabilities of the components, if the document reports them
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub vulnerabilities: Vec<Vulnerability>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Metadata {
+    /// The component the document describes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub component: Option<Component>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Component {
+    pub name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub purl: Option<String>,
+}
+
+/// The severity of a vulnerability, ordered from the most to the least severe
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash, serde::Serialize, serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Critical,
+    High,
+    Medium,
+    Low,
+    Info,
+    None,
+    /// not rated, or rated using a severity we don't know
+    #[serde(other)]
+    Unknown,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Vulnerability {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub ratings: Vec<Rating>,
+}
+
+impl Vulnerability {
+    /// The highest severity the vulnerability is rated with
+    pub fn severity(&self) -> Severity {
+        self.ratings
+            .iter()
+            .filter_map(|rating| rating.severity)
+            .min()
+            .unwrap_or(Severity::Unknown)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Rating {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub severity: Option<Severity>,
+}
+
+impl SbomDocument {
+    /// The digest of the image the document describes, if it is known.
+    ///
+    /// This is taken from the version of the subject component, or from its (OCI) purl.
+    pub fn subject_digest(&self) -> Option<String> {
+        let subject = self.metadata.as_ref()?.component.as_ref()?;
+
+        if let Some(version) = subject.version.as_deref().filter(|v| is_digest(v)) {
+            return Some(version.to_string());
+        }
+
+        // pkg:oci/name@sha256%3A...?qualifiers#subpath
+        let purl = subject.purl.as_deref()?;
+        let (_, version) = purl.rsplit_once('@')?;
+        let version = version
+            .split(['?', '#'])
+            .next()?
+            .replace("%3A", ":")
+            .replace("%3a", ":");
+        is_digest(&version).then_some(version)
+    }
+
+    fn purls(&self) -> HashSet<&str> {
+        self.components
+            .iter()
+            .filter_map(|component| component.purl.as_deref())
+            .collect()
+    }
+
+    /// Components of this document, which are also present in `other`, matched by their purl.
+    ///
+    /// Components without a purl are never considered to be common.
+    pub fn intersect<'a>(&'a self, other: &'a SbomDocument) -> Vec<&'a Component> {
+        let purls = other.purls();
+        self.components
+            .iter()
+            .filter(|component| {
+                component
+                    .purl
+                    .as_deref()
+                    .is_some_and(|purl| purls.contains(purl))
+            })
+            .collect()
+    }
+
+    /// Merge two documents, keeping the metadata of `self`, and dropping components of `other` which are already present.
+    ///
+    /// Components are matched by their purl, or if they don't have one, by being equal. The same
+    /// goes for vulnerabilities, which are matched by their ID.
+    pub fn union(&self, other: &SbomDocument) -> SbomDocument {
+        let purls = self.purls();
+        let mut components = self.components.clone();
+
+        for component in &other.components {
+            let present = match &component.purl {
+                Some(purl) => purls.contains(purl.as_str()),
+                None => self.components.contains(component),
+            };
+            if !present {
+                components.push(component.clone());
+            }
+        }
+
+        let mut vulnerabilities = self.vulnerabilities.clone();
+        for vulnerability in &other.vulnerabilities {
+            let present = match &vulnerability.id {
+                Some(id) => self
+                    .vulnerabilities
+                    .iter()
+                    .any(|v| v.id.as_ref() == Some(id)),
+                None => self.vulnerabilities.contains(vulnerability),
+            };
+            if !present {
+                vulnerabilities.push(vulnerability.clone());
+            }
+        }
+
+        SbomDocument {
+            metadata: self.metadata.clone(),
+            components,
+            vulnerabilities,
+        }
+    }
+}
+
+fn is_digest(value: &str) -> bool {
+    value.starts_with("sha256:")
+}
+
+/// Media type of CycloneDX JSON documents
+pub const CYCLONEDX_MEDIA_TYPE: &str = "application/vnd.cyclonedx+json";
+
+/// The content of the empty config of artifacts, which only carry layers
+const OCI_EMPTY_DATA: &[u8] = b"{}";
+
+/// An OCI (1.1) image manifest, used for storing an SBOM as an artifact in a registry
+pub type OciManifest = ImageManifest;
+
+impl SbomDocument {
+    /// The document, as stored in the layer of an OCI artifact.
+    pub fn to_oci_blob(&self) -> Result<Vec<u8>, serde_json::Error> {
+        serde_json::to_vec(self)
+    }
+
+    /// An OCI artifact manifest, carrying the document (see [`Self::to_oci_blob`]) as its single
+    /// layer, referring to the image it describes.
+    ///
+    /// The subject is only set if the digest of the image is known, see
+    /// [`Self::subject_digest`]. It is only known by its digest, so the size of the image
+    /// manifest needs to be provided as `subject_size`.
+    pub fn to_oci_artifact_manifest(&self, subject_size: i64) -> Result<OciManifest, OciSpecError> {
+        let blob = self.to_oci_blob()?;
+
+        let config = DescriptorBuilder::default()
+            .media_type(MediaType::EmptyJSON)
+            .digest(crate::sha256_digest(OCI_EMPTY_DATA))
+            .size(OCI_EMPTY_DATA.len() as i64)
+            .build()?;
+        let layer = DescriptorBuilder::default()
+            .media_type(CYCLONEDX_MEDIA_TYPE)
+            .digest(crate::sha256_digest(&blob))
+            .size(blob.len() as i64)
+            .build()?;
+
+        let mut manifest = ImageManifestBuilder::default()
+            .schema_version(SCHEMA_VERSION)
+            .media_type(MediaType::ImageManifest)
+            .artifact_type(CYCLONEDX_MEDIA_TYPE)
+            .config(config)
+            .layers(vec![layer])
+            .build()?;
+
+        if let Some(digest) = self.subject_digest() {
+            manifest.set_subject(Some(
+                DescriptorBuilder::default()
+                    .media_type(MediaType::ImageManifest)
+                    .digest(digest)
+                    .size(subject_size)
+                    .build()?,
+            ));
+        }
+
+        Ok(manifest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DIGEST: &str = "sha256:0123456789abcdef";
+
+    #[test]
+    fn sha256_digest() {
+        assert_eq!(
+            crate::sha256_digest(b"abc"),
+            "sha256:ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn oci_artifact_manifest() {
+        let document: SbomDocument = serde_json::from_value(serde_json::json!({
+            "metadata": {"component": {"name": "nginx", "version": DIGEST}},
+            "components": [{"name": "zlib", "purl": "pkg:rpm/zlib@1.2.13"}],
+        }))
+        .unwrap();
+        let blob = document.to_oci_blob().unwrap();
+
+        let manifest = document.to_oci_artifact_manifest(1234).unwrap();
+        assert_eq!(manifest.schema_version(), 2);
+        assert_eq!(manifest.media_type(), &Some(MediaType::ImageManifest));
+        assert_eq!(
+            manifest.artifact_type(),
+            &Some(MediaType::from(CYCLONEDX_MEDIA_TYPE))
+        );
+        assert_eq!(manifest.config().media_type(), &MediaType::EmptyJSON);
+        assert_eq!(manifest.config().size(), 2);
+
+        let [layer] = manifest.layers().as_slice() else {
+            panic!("expected a single layer");
+        };
+        assert_eq!(layer.digest(), &crate::sha256_digest(&blob));
+        assert_eq!(layer.size(), blob.len() as i64);
+
+        let subject = manifest.subject().as_ref().expect("subject must be set");
+        assert_eq!(subject.digest(), DIGEST);
+        assert_eq!(subject.size(), 1234);
+        assert_eq!(subject.media_type(), &MediaType::ImageManifest);
+
+        let json = serde_json::to_value(&manifest).unwrap();
+        assert_eq!(json["artifactType"], CYCLONEDX_MEDIA_TYPE);
+        assert_eq!(json["subject"]["size"], 1234);
+    }
+
+    #[test]
+    fn oci_artifact_manifest_without_subject() {
+        let manifest = SbomDocument::default()
+            .to_oci_artifact_manifest(1234)
+            .unwrap();
+        assert_eq!(manifest.subject(), &None);
+        assert_eq!(manifest.layers().len(), 1);
+    }
+}
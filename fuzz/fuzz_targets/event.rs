@@ -0,0 +1,29 @@
+//! Events are received from the (network exposed) workload stream.
+//!
+//! Run using `cargo +nightly fuzz run event`, starting from the seeds in `corpus/event`.
+
+#![no_main]
+
+use bommer_api::data::{Event, Image, ImageRef};
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(evt) = serde_json::from_slice::<Event<ImageRef, Image>>(data) else {
+        return;
+    };
+
+    let images = match &evt {
+        Event::Added(_, image) | Event::Modified(_, image) => vec![image],
+        Event::Removed(_) => vec![],
+        Event::Restart(entries) | Event::RestartChunk { entries, .. } => entries.values().collect(),
+    };
+    for image in images {
+        let _ = image.severities();
+        for sbom in image.sbom.sboms() {
+            let _ = sbom.parse();
+        }
+    }
+
+    // what we decoded, we must be able to encode again
+    serde_json::to_vec(&evt).expect("decoded event must serialize");
+});
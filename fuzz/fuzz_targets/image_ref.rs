@@ -0,0 +1,34 @@
+//! Image references come from pod specs, which may contain anything a user typed.
+//!
+//! Run using `cargo +nightly fuzz run image_ref`, starting from the seeds in `corpus/image_ref`.
+
+#![no_main]
+
+use bommer_api::data::ImageRef;
+use libfuzzer_sys::fuzz_target;
+use std::cmp::Ordering;
+
+fuzz_target!(|data: (&str, &str)| {
+    let (a, b) = data;
+    let (Ok(a), Ok(b)) = (a.parse::<ImageRef>(), b.parse::<ImageRef>()) else {
+        return;
+    };
+
+    // the parts must be slices of the reference
+    let parts = a.parts();
+    for part in [
+        parts.registry,
+        Some(parts.repository),
+        parts.tag,
+        parts.digest,
+    ]
+    .into_iter()
+    .flatten()
+    {
+        assert!(a.contains(part));
+    }
+
+    // the ordering must be consistent, as references are used as keys of ordered maps
+    assert_eq!(a.cmp(&b), b.cmp(&a).reverse());
+    assert_eq!(a.cmp(&b) == Ordering::Equal, a == b);
+});
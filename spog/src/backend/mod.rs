@@ -1,6 +1,8 @@
 mod pkg;
+mod stream;
 
 pub use pkg::*;
+pub use stream::*;
 
 use url::{ParseError, Url};
 
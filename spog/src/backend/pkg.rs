@@ -1,5 +1,7 @@
 use super::{Backend, Error};
-use bommer_api::data::{Image, ImageRef};
+use bommer_api::data::{Image, ImageLimitStatus, ImageRef, WorkloadDiff, WorkloadSummary};
+use bommer_api::risk::RiskWeights;
+use reqwest::StatusCode;
 use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
 
@@ -11,6 +13,30 @@ pub struct WorkloadService {
 #[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
 pub struct Workload(pub HashMap<ImageRef, Image>);
 
+impl Workload {
+    /// The changes from `before` to `after`
+    #[allow(unused)]
+    pub fn diff(before: &Workload, after: &Workload) -> WorkloadDiff {
+        WorkloadDiff::new(before, after)
+    }
+
+    /// The risk score of the workload, using the default weights, see [`bommer_api::risk`].
+    #[allow(unused)]
+    pub fn risk_score(&self) -> f64 {
+        self.risk_score_with(&RiskWeights::default())
+    }
+
+    pub fn risk_score_with(&self, weights: &RiskWeights) -> f64 {
+        weights.workload(self.values())
+    }
+
+    /// The numbers of the workload, see [`WorkloadSummary`].
+    #[allow(unused)]
+    pub fn summarize(&self) -> WorkloadSummary {
+        WorkloadSummary::new(self.values())
+    }
+}
+
 impl Deref for Workload {
     type Target = HashMap<ImageRef, Image>;
 
@@ -44,4 +70,94 @@ impl WorkloadService {
             .json()
             .await?)
     }
+
+    pub async fn set_annotation(
+        &self,
+        image: &ImageRef,
+        key: &str,
+        value: String,
+    ) -> Result<(), Error> {
+        self.client
+            .put(self.backend.join("/api/v1/annotation")?)
+            .query(&[("image", image.as_str()), ("key", key)])
+            .body(value)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Get the baseline, `None` if none was set.
+    pub async fn baseline(&self) -> Result<Option<Workload>, Error> {
+        let response = self
+            .client
+            .get(self.backend.join("/api/v1/baseline")?)
+            .send()
+            .await?;
+
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+
+        Ok(Some(response.error_for_status()?.json().await?))
+    }
+
+    /// Take the current workload as the new baseline.
+    pub async fn set_baseline(&self) -> Result<(), Error> {
+        self.client
+            .put(self.backend.join("/api/v1/baseline")?)
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
+
+    /// Retry the failed SBOM lookups of the images, returns the number of images scheduled.
+    pub async fn retry(&self, images: &[ImageRef]) -> Result<usize, Error> {
+        #[derive(serde::Deserialize)]
+        struct Response {
+            scheduled: usize,
+        }
+
+        let response: Response = self
+            .client
+            .post(self.backend.join("/api/v1/retry")?)
+            .json(images)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(response.scheduled)
+    }
+
+    /// The limit of tracked images, from the backend's info.
+    pub async fn image_limit(&self) -> Result<ImageLimitStatus, Error> {
+        #[derive(serde::Deserialize)]
+        #[serde(rename_all = "camelCase")]
+        struct Info {
+            #[serde(default)]
+            image_limit: ImageLimitStatus,
+        }
+
+        let info: Info = self
+            .client
+            .get(self.backend.join("/api/v1/info")?)
+            .send()
+            .await?
+            .error_for_status()?
+            .json()
+            .await?;
+        Ok(info.image_limit)
+    }
+
+    pub async fn remove_annotation(&self, image: &ImageRef, key: &str) -> Result<(), Error> {
+        self.client
+            .delete(self.backend.join("/api/v1/annotation")?)
+            .query(&[("image", image.as_str()), ("key", key)])
+            .send()
+            .await?
+            .error_for_status()?;
+        Ok(())
+    }
 }
@@ -0,0 +1,262 @@
+//! The workload, as streamed from the backend.
+
+use super::Workload;
+use bommer_api::data::{Event, Image, ImageRef};
+use gloo_timers::callback::Timeout;
+use std::collections::HashMap;
+use std::rc::Rc;
+use std::time::Duration;
+use yew::prelude::*;
+use yew_hooks::{use_websocket_with_options, UseWebSocketOptions, UseWebSocketReadyState};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BackoffOptions {
+    /// delay before the first reconnect attempt
+    pub initial: Duration,
+    /// upper limit of the delay
+    pub max: Duration,
+    /// factor the delay grows by with each attempt
+    pub factor: f64,
+}
+
+impl Default for BackoffOptions {
+    fn default() -> Self {
+        Self {
+            initial: Duration::from_millis(500),
+            max: Duration::from_secs(30),
+            factor: 2.0,
+        }
+    }
+}
+
+/// Exponential backoff with jitter, so that clients don't reconnect all at the same time.
+#[derive(Clone, Debug)]
+pub struct Backoff {
+    options: BackoffOptions,
+    attempt: u32,
+}
+
+impl Backoff {
+    pub fn new(options: BackoffOptions) -> Self {
+        Self {
+            options,
+            attempt: 0,
+        }
+    }
+
+    /// Start over, once connected again.
+    pub fn reset(&mut self) {
+        self.attempt = 0;
+    }
+
+    /// The delay before the next attempt.
+    ///
+    /// `jitter` is expected to be a random number in `[0, 1)`, picking a delay between half and
+    /// the full exponential delay.
+    pub fn next_delay(&mut self, jitter: f64) -> Duration {
+        let BackoffOptions {
+            initial,
+            max,
+            factor,
+        } = self.options;
+
+        let delay =
+            (initial.as_secs_f64() * factor.powi(self.attempt as i32)).min(max.as_secs_f64());
+        self.attempt = self.attempt.saturating_add(1);
+
+        Duration::from_secs_f64(delay * (0.5 + jitter.clamp(0.0, 1.0) / 2.0))
+    }
+}
+
+/// The workload, kept up to date by applying the events of the stream.
+///
+/// When the connection drops, the last known workload is kept, until the initial state of the
+/// new connection replaces it.
+#[derive(Clone, Debug, Default)]
+pub struct WorkloadStream {
+    pub workload: Rc<Workload>,
+    /// chunks of the initial state, until we received the last one
+    pending: HashMap<ImageRef, Image>,
+}
+
+impl WorkloadStream {
+    pub fn apply(&mut self, evt: Event<ImageRef, Image>) {
+        match evt {
+            Event::Added(image, state) | Event::Modified(image, state) => {
+                Rc::make_mut(&mut self.workload).insert(image, state);
+            }
+            Event::Removed(image) => {
+                Rc::make_mut(&mut self.workload).remove(&image);
+            }
+            Event::Restart(state) => {
+                self.pending.clear();
+                self.workload = Rc::new(Workload(state));
+            }
+            Event::RestartChunk { entries, is_last } => {
+                self.pending.extend(entries);
+                if is_last {
+                    self.workload = Rc::new(Workload(std::mem::take(&mut self.pending)));
+                }
+            }
+        }
+    }
+
+    /// The connection dropped, a new one starts over with its initial state.
+    pub fn disconnected(&mut self) {
+        self.pending.clear();
+    }
+}
+
+pub struct UseWorkloadStream {
+    pub workload: Rc<Workload>,
+    pub connected: bool,
+}
+
+/// Stream the workload from `url`, reconnecting with a backoff when the connection drops.
+#[hook]
+pub fn use_workload_stream(url: String, options: BackoffOptions) -> UseWorkloadStream {
+    let ws = use_websocket_with_options(
+        url,
+        UseWebSocketOptions {
+            // we handle reconnecting ourselves
+            reconnect_limit: Some(0),
+            ..Default::default()
+        },
+    );
+
+    let stream = use_mut_ref(WorkloadStream::default);
+    let backoff = use_mut_ref(|| Backoff::new(options));
+    let update = use_force_update();
+
+    {
+        let stream = stream.clone();
+        use_effect_with_deps(
+            move |message| {
+                if let Some(message) = &**message {
+                    if let Ok(evt) = serde_json::from_str::<Event<ImageRef, Image>>(message) {
+                        stream.borrow_mut().apply(evt);
+                        update.force_update();
+                    }
+                }
+                || ()
+            },
+            ws.message.clone(),
+        );
+    }
+
+    {
+        let ws = ws.clone();
+        let stream = stream.clone();
+        use_effect_with_deps(
+            move |state| {
+                let timeout = match state {
+                    UseWebSocketReadyState::Open => {
+                        backoff.borrow_mut().reset();
+                        None
+                    }
+                    UseWebSocketReadyState::Closed => {
+                        stream.borrow_mut().disconnected();
+                        let delay = backoff.borrow_mut().next_delay(js_sys::Math::random());
+                        log::info!("Workload stream disconnected, reconnecting in {delay:?}");
+                        Some(Timeout::new(delay.as_millis() as u32, move || ws.open()))
+                    }
+                    _ => None,
+                };
+                // cancel a pending reconnect when unmounted
+                move || drop(timeout)
+            },
+            (*ws.ready_state).clone(),
+        );
+    }
+
+    let workload = stream.borrow().workload.clone();
+    UseWorkloadStream {
+        workload,
+        connected: *ws.ready_state == UseWebSocketReadyState::Open,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::ImageBuilder;
+
+    #[test]
+    fn backoff_grows_to_max() {
+        let mut backoff = Backoff::new(BackoffOptions {
+            initial: Duration::from_secs(1),
+            max: Duration::from_secs(5),
+            factor: 2.0,
+        });
+
+        let delays = (0..5)
+            .map(|_| backoff.next_delay(0.999_999).as_secs_f64().round())
+            .collect::<Vec<_>>();
+        assert_eq!(delays, vec![1.0, 2.0, 4.0, 5.0, 5.0]);
+
+        backoff.reset();
+        assert_eq!(backoff.next_delay(0.999_999).as_secs_f64().round(), 1.0);
+    }
+
+    #[test]
+    fn backoff_jitter() {
+        let options = BackoffOptions {
+            initial: Duration::from_secs(4),
+            ..Default::default()
+        };
+
+        assert_eq!(
+            Backoff::new(options).next_delay(0.0),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            Backoff::new(options).next_delay(0.5),
+            Duration::from_secs(3)
+        );
+        // out of range values are clamped
+        assert_eq!(
+            Backoff::new(options).next_delay(-1.0),
+            Duration::from_secs(2)
+        );
+        assert_eq!(
+            Backoff::new(options).next_delay(7.0),
+            Duration::from_secs(4)
+        );
+    }
+
+    #[test]
+    fn chunked_restart() {
+        let image = |name: &str| ImageRef(name.to_string());
+        let empty = || ImageBuilder::default().build();
+        let mut stream = WorkloadStream::default();
+        stream.apply(Event::Restart(HashMap::from([(image("old"), empty())])));
+
+        stream.apply(Event::RestartChunk {
+            entries: HashMap::from([(image("a"), empty())]),
+            is_last: false,
+        });
+        // the last known workload is kept, until the restart is complete
+        assert!(stream.workload.contains_key(&image("old")));
+
+        stream.apply(Event::RestartChunk {
+            entries: HashMap::from([(image("b"), empty())]),
+            is_last: true,
+        });
+        let mut images = stream.workload.keys().cloned().collect::<Vec<_>>();
+        images.sort();
+        assert_eq!(images, vec![image("a"), image("b")]);
+
+        // chunks of a dropped connection are discarded
+        stream.apply(Event::RestartChunk {
+            entries: HashMap::from([(image("c"), empty())]),
+            is_last: false,
+        });
+        stream.disconnected();
+        stream.apply(Event::RestartChunk {
+            entries: HashMap::from([(image("d"), empty())]),
+            is_last: true,
+        });
+        let images = stream.workload.keys().cloned().collect::<Vec<_>>();
+        assert_eq!(images, vec![image("d")]);
+    }
+}
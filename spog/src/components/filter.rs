@@ -0,0 +1,371 @@
+use crate::backend::Workload;
+use bommer_api::data::{Image, ImageRef, SbomState};
+use itertools::Itertools;
+use patternfly_yew::prelude::*;
+use std::rc::Rc;
+use yew::prelude::*;
+
+/// The registry images without an explicit one are pulled from
+const DEFAULT_REGISTRY: &str = "docker.io";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, strum::Display, strum::EnumIter)]
+pub enum SbomStatusFilter {
+    #[strum(to_string = "Retrieving")]
+    Scheduled,
+    Found,
+    Missing,
+    #[strum(to_string = "No scanner")]
+    Unsupported,
+    Failed,
+}
+
+impl SbomStatusFilter {
+    pub fn matches(&self, sbom: &SbomState) -> bool {
+        matches!(
+            (self, sbom),
+            (Self::Scheduled, SbomState::Scheduled)
+                | (Self::Found, SbomState::Found(_))
+                | (Self::Missing, SbomState::Missing)
+                | (Self::Unsupported, SbomState::Unsupported)
+                | (Self::Failed, SbomState::Err(_))
+        )
+    }
+}
+
+/// The criteria the workload table gets filtered by, all of them need to match.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct WorkloadFilter {
+    /// part of the image reference, ignoring case
+    pub text: Option<String>,
+    pub sbom_status: Option<SbomStatusFilter>,
+    pub registry: Option<String>,
+}
+
+pub enum FilterAction {
+    /// set the search text, an empty text clears it
+    SetText(String),
+    SetSbomStatus(Option<SbomStatusFilter>),
+    SetRegistry(Option<String>),
+}
+
+impl Reducible for WorkloadFilter {
+    type Action = FilterAction;
+
+    fn reduce(self: Rc<Self>, action: Self::Action) -> Rc<Self> {
+        let mut filter = (*self).clone();
+        filter.apply(action);
+        Rc::new(filter)
+    }
+}
+
+impl WorkloadFilter {
+    pub fn apply(&mut self, action: FilterAction) {
+        match action {
+            FilterAction::SetText(text) => {
+                self.text = Some(text).filter(|text| !text.trim().is_empty())
+            }
+            FilterAction::SetSbomStatus(sbom_status) => self.sbom_status = sbom_status,
+            FilterAction::SetRegistry(registry) => self.registry = registry,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.text.is_none() && self.sbom_status.is_none() && self.registry.is_none()
+    }
+
+    pub fn matches(&self, image: &ImageRef, state: &Image) -> bool {
+        self.text.as_ref().map_or(true, |text| {
+            image.0.to_lowercase().contains(&text.trim().to_lowercase())
+        }) && self
+            .sbom_status
+            .map_or(true, |status| status.matches(&state.sbom))
+            && self
+                .registry
+                .as_ref()
+                .map_or(true, |registry| registry == self::registry(image))
+    }
+}
+
+/// The registry of an image, falling back to the default one
+pub fn registry(image: &ImageRef) -> &str {
+    image.parts().registry.unwrap_or(DEFAULT_REGISTRY)
+}
+
+/// An active filter, shown as a chip
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FilterChip {
+    Namespace(String),
+    Image(ImageRef),
+    Text(String),
+    SbomStatus(SbomStatusFilter),
+    Registry(String),
+}
+
+impl FilterChip {
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Namespace(_) => "Namespace",
+            Self::Image(_) => "Image",
+            Self::Text(_) => "Search",
+            Self::SbomStatus(_) => "SBOM status",
+            Self::Registry(_) => "Registry",
+        }
+    }
+
+    pub fn text(&self) -> String {
+        match self {
+            Self::Namespace(text) | Self::Text(text) | Self::Registry(text) => text.clone(),
+            Self::Image(image) => image.0.clone(),
+            Self::SbomStatus(status) => status.to_string(),
+        }
+    }
+
+    /// Remove the filter from the criteria, `None` for the filters kept elsewhere.
+    pub fn remove(&self) -> Option<FilterAction> {
+        match self {
+            Self::Namespace(_) | Self::Image(_) => None,
+            Self::Text(_) => Some(FilterAction::SetText(String::new())),
+            Self::SbomStatus(_) => Some(FilterAction::SetSbomStatus(None)),
+            Self::Registry(_) => Some(FilterAction::SetRegistry(None)),
+        }
+    }
+}
+
+/// The active filters, the selected image and the namespace first, as they narrow down the most.
+pub fn active_filters(
+    filter: &WorkloadFilter,
+    selected: Option<&ImageRef>,
+    namespace: &str,
+) -> Vec<FilterChip> {
+    let mut chips = Vec::new();
+    if !namespace.is_empty() {
+        chips.push(FilterChip::Namespace(namespace.to_string()));
+    }
+    chips.extend(selected.cloned().map(FilterChip::Image));
+    chips.extend(filter.text.clone().map(FilterChip::Text));
+    chips.extend(filter.sbom_status.map(FilterChip::SbomStatus));
+    chips.extend(filter.registry.clone().map(FilterChip::Registry));
+    chips
+}
+
+#[derive(Clone, PartialEq, Properties)]
+pub struct WorkloadFilterBarProperties {
+    pub workload: Rc<Workload>,
+    pub filter: WorkloadFilter,
+    pub onchange: Callback<FilterAction>,
+    /// the image selected elsewhere, e.g. from the top images
+    #[prop_or_default]
+    pub selected: Option<ImageRef>,
+    #[prop_or_default]
+    pub onclearselected: Callback<()>,
+    /// the namespace the workload is limited to, empty for all of them
+    #[prop_or_default]
+    pub namespace: String,
+    #[prop_or_default]
+    pub onclearnamespace: Callback<()>,
+}
+
+/// The search bar, along with the filter selection and the active filters, as chips.
+#[function_component(WorkloadFilterBar)]
+pub fn workload_filter_bar(props: &WorkloadFilterBarProperties) -> Html {
+    let registries = use_memo(
+        |workload| {
+            workload
+                .keys()
+                .map(|image| registry(image).to_string())
+                .unique()
+                .sorted_unstable()
+                .collect::<Vec<_>>()
+        },
+        props.workload.clone(),
+    );
+
+    let errors = use_memo(
+        |workload| {
+            workload
+                .values()
+                .filter(|image| SbomStatusFilter::Failed.matches(&image.sbom))
+                .count()
+        },
+        props.workload.clone(),
+    );
+
+    let ontext = props.onchange.reform(FilterAction::SetText);
+
+    // toggle showing only the failed images
+    let errors_only = props.filter.sbom_status == Some(SbomStatusFilter::Failed);
+    let onerrors = props.onchange.reform(move |_| {
+        FilterAction::SetSbomStatus(match errors_only {
+            true => None,
+            false => Some(SbomStatusFilter::Failed),
+        })
+    });
+
+    html!(
+        <>
+            <Toolbar>
+                <ToolbarItem>
+                    <TextInput
+                        value={props.filter.text.clone().unwrap_or_default()}
+                        onchange={ontext}
+                        placeholder="Search images…"
+                        icon={TextInputIcon::Search}
+                    />
+                </ToolbarItem>
+                <ToolbarItem>
+                    <Dropdown
+                        text={props.filter.sbom_status.map(|status| status.to_string()).unwrap_or_else(|| "SBOM status".to_string())}
+                    >
+                        { for <SbomStatusFilter as strum::IntoEnumIterator>::iter().map(|status| {
+                            let onclick = props.onchange.reform(move |_| FilterAction::SetSbomStatus(Some(status)));
+                            html_nested!(<DropdownItem {onclick}>{ status.to_string() }</DropdownItem>)
+                        })}
+                    </Dropdown>
+                </ToolbarItem>
+                <ToolbarItem>
+                    <Button
+                        label={format!("Errors ({})", *errors)}
+                        variant={match errors_only {
+                            true => ButtonVariant::Primary,
+                            false => ButtonVariant::Secondary,
+                        }}
+                        disabled={*errors == 0 && !errors_only}
+                        onclick={onerrors}
+                    />
+                </ToolbarItem>
+                <ToolbarItem>
+                    <Dropdown
+                        text={props.filter.registry.clone().unwrap_or_else(|| "Registry".to_string())}
+                    >
+                        { for registries.iter().map(|registry| {
+                            let onclick = {
+                                let registry = registry.clone();
+                                props.onchange.reform(move |_| FilterAction::SetRegistry(Some(registry.clone())))
+                            };
+                            html_nested!(<DropdownItem {onclick}>{ registry }</DropdownItem>)
+                        })}
+                    </Dropdown>
+                </ToolbarItem>
+            </Toolbar>
+            <FilterChips
+                filter={props.filter.clone()}
+                onchange={props.onchange.clone()}
+                selected={props.selected.clone()}
+                onclearselected={props.onclearselected.clone()}
+                namespace={props.namespace.clone()}
+                onclearnamespace={props.onclearnamespace.clone()}
+            />
+        </>
+    )
+}
+
+#[derive(Clone, PartialEq, Properties)]
+pub struct FilterChipsProperties {
+    pub filter: WorkloadFilter,
+    pub onchange: Callback<FilterAction>,
+    #[prop_or_default]
+    pub selected: Option<ImageRef>,
+    #[prop_or_default]
+    pub onclearselected: Callback<()>,
+    #[prop_or_default]
+    pub namespace: String,
+    #[prop_or_default]
+    pub onclearnamespace: Callback<()>,
+}
+
+/// The active filters, each of them can be removed by closing its chip.
+///
+/// Besides the criteria of the filter bar, this covers the selected image and the namespace, which
+/// are kept elsewhere. Clearing all filters clears them too.
+#[function_component(FilterChips)]
+pub fn filter_chips(props: &FilterChipsProperties) -> Html {
+    let chips = active_filters(&props.filter, props.selected.as_ref(), &props.namespace);
+    if chips.is_empty() {
+        return html!();
+    }
+
+    let onremove = {
+        let onchange = props.onchange.clone();
+        let onclearselected = props.onclearselected.clone();
+        let onclearnamespace = props.onclearnamespace.clone();
+        Callback::from(move |chip: FilterChip| match (chip.remove(), chip) {
+            (Some(action), _) => onchange.emit(action),
+            (None, FilterChip::Namespace(_)) => onclearnamespace.emit(()),
+            (None, _) => onclearselected.emit(()),
+        })
+    };
+
+    let onclear = {
+        let onremove = onremove.clone();
+        let chips = chips.clone();
+        Callback::from(move |_| chips.iter().cloned().for_each(|chip| onremove.emit(chip)))
+    };
+
+    html!(
+        <div class="bommer-c-filter-chips">
+            { for chips.into_iter().map(|chip| {
+                let onclose = onremove.reform({
+                    let chip = chip.clone();
+                    move |_| chip.clone()
+                });
+                html!(
+                    <ChipGroup label={chip.label()}>
+                        <Chip text={chip.text()} {onclose} />
+                    </ChipGroup>
+                )
+            }) }
+            <Button label="Clear all filters" variant={ButtonVariant::Link} onclick={onclear} />
+        </div>
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_chips() {
+        let mut filter = WorkloadFilter::default();
+        assert_eq!(active_filters(&filter, None, ""), vec![]);
+
+        filter.apply(FilterAction::SetText("nginx".to_string()));
+        filter.apply(FilterAction::SetSbomStatus(Some(SbomStatusFilter::Failed)));
+        filter.apply(FilterAction::SetRegistry(Some("quay.io".to_string())));
+        let selected = ImageRef("quay.io/nginx".to_string());
+        let chips = active_filters(&filter, Some(&selected), "default");
+        assert_eq!(
+            chips
+                .iter()
+                .map(|chip| (chip.label(), chip.text()))
+                .collect::<Vec<_>>(),
+            [
+                ("Namespace", "default".to_string()),
+                ("Image", "quay.io/nginx".to_string()),
+                ("Search", "nginx".to_string()),
+                ("SBOM status", "Failed".to_string()),
+                ("Registry", "quay.io".to_string()),
+            ]
+        );
+
+        // clearing all filters removes each of them, the namespace and image are kept elsewhere
+        let mut kept_elsewhere = Vec::new();
+        for chip in chips {
+            match chip.remove() {
+                Some(action) => filter.apply(action),
+                None => kept_elsewhere.push(chip),
+            }
+        }
+        assert!(filter.is_empty());
+        assert_eq!(
+            kept_elsewhere,
+            [
+                FilterChip::Namespace("default".to_string()),
+                FilterChip::Image(selected)
+            ]
+        );
+
+        // a blank search doesn't count
+        filter.apply(FilterAction::SetText("  ".to_string()));
+        assert_eq!(active_filters(&filter, None, ""), vec![]);
+    }
+}
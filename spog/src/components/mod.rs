@@ -1,6 +1,10 @@
 //! Re-usable component
 
 pub mod backend;
+pub mod filter;
+pub mod risk;
+pub mod status;
+pub mod top;
 pub mod workload;
 
 use patternfly_yew::prelude::*;
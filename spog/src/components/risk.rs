@@ -0,0 +1,54 @@
+use crate::backend::Workload;
+use bommer_api::data::Severities;
+use bommer_api::risk::RiskWeights;
+use bommer_api::sbom::Severity;
+use patternfly_yew::prelude::*;
+use std::rc::Rc;
+use yew::prelude::*;
+
+/// The severities listed next to the score
+const SEVERITIES: [(Severity, &str); 4] = [
+    (Severity::Critical, "critical"),
+    (Severity::High, "high"),
+    (Severity::Medium, "medium"),
+    (Severity::Low, "low"),
+];
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct RiskScoreProperties {
+    pub workload: Rc<Workload>,
+    #[prop_or_default]
+    pub weights: RiskWeights,
+}
+
+/// Show the risk score of the workload, along with the vulnerabilities it is based on.
+#[function_component(RiskScore)]
+pub fn risk_score(props: &RiskScoreProperties) -> Html {
+    let summary = use_memo(
+        |(workload, weights)| {
+            let severities = workload.values().map(|image| image.severities()).fold(
+                Severities::default(),
+                |mut acc, severities| {
+                    for (severity, count) in severities.0 {
+                        *acc.0.entry(severity).or_default() += count;
+                    }
+                    acc
+                },
+            );
+            (workload.risk_score_with(weights), severities)
+        },
+        (props.workload.clone(), props.weights.clone()),
+    );
+    let (score, severities) = &*summary;
+
+    html!(
+        <div class="bommer-c-risk-score">
+            <Title level={Level::H3}>{ "Risk score" }</Title>
+            <div class="bommer-c-risk-score__value">{ format!("{score:.0}") }</div>
+            <div>
+                { SEVERITIES.iter().map(|(severity, label)| format!("{} {label}", severities.get(*severity))).collect::<Vec<_>>().join(", ") }
+                { " vulnerabilities, weighted by the pods running them" }
+            </div>
+        </div>
+    )
+}
@@ -0,0 +1,44 @@
+use crate::backend::Workload;
+use bommer_api::data::SbomState;
+use patternfly_yew::prelude::*;
+use std::rc::Rc;
+use yew::prelude::*;
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct WorkloadStatusBarProperties {
+    pub workload: Rc<Workload>,
+}
+
+/// Summarize the state of the workload: all images, the ones with an SBOM, and the ones whose
+/// lookup failed or which don't have one.
+///
+/// Images still being looked up, and images exempt from having an SBOM, only count towards the
+/// total.
+#[function_component(WorkloadStatusBar)]
+pub fn workload_status_bar(props: &WorkloadStatusBarProperties) -> Html {
+    let counts = use_memo(
+        |workload| {
+            workload.values().fold(
+                (0usize, 0usize, 0usize),
+                |(total, healthy, degraded), image| match image.sbom {
+                    _ if image.classification.is_exempt() => (total + 1, healthy, degraded),
+                    SbomState::Found(_) => (total + 1, healthy + 1, degraded),
+                    SbomState::Err(_) | SbomState::Missing | SbomState::Unsupported => {
+                        (total + 1, healthy, degraded + 1)
+                    }
+                    SbomState::Scheduled => (total + 1, healthy, degraded),
+                },
+            )
+        },
+        props.workload.clone(),
+    );
+    let (total, healthy, degraded) = *counts;
+
+    html!(
+        <div class="bommer-c-status-bar">
+            <Label color={Color::Blue} label={format!("{total} images")} />
+            <Label color={Color::Green} label={format!("{healthy} with SBOM")} />
+            <Label color={Color::Red} label={format!("{degraded} without SBOM")} />
+        </div>
+    )
+}
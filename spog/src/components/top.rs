@@ -0,0 +1,112 @@
+use crate::backend::Workload;
+use bommer_api::data::ImageRef;
+use itertools::Itertools;
+use patternfly_yew::prelude::*;
+use std::rc::Rc;
+use yew::prelude::*;
+
+/// Get the `n` images with the most pods, ties are ordered by the image reference.
+pub fn top_images(workload: &Workload, n: usize) -> Vec<(ImageRef, usize)> {
+    workload
+        .iter()
+        .map(|(image, state)| (image.clone(), state.pods.len()))
+        .sorted_unstable_by(|(a, a_count), (b, b_count)| {
+            b_count.cmp(a_count).then_with(|| a.cmp(b))
+        })
+        .take(n)
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct TopImagesProperties {
+    pub workload: Rc<Workload>,
+
+    #[prop_or(5)]
+    pub limit: usize,
+
+    /// the currently selected image
+    #[prop_or_default]
+    pub selected: Option<ImageRef>,
+
+    /// called when an image gets clicked
+    #[prop_or_default]
+    pub onselect: Callback<ImageRef>,
+}
+
+/// Show the top images by pod count, as horizontal bars.
+#[function_component(TopImages)]
+pub fn top_images_widget(props: &TopImagesProperties) -> Html {
+    let top = use_memo(
+        |(workload, limit)| top_images(workload, *limit),
+        (props.workload.clone(), props.limit),
+    );
+
+    let max = top
+        .first()
+        .map(|(_, count)| *count)
+        .unwrap_or_default()
+        .max(1);
+
+    html!(
+        <div class="bommer-c-top-images">
+            <Title level={Level::H3}>{ "Top images by pod count" }</Title>
+            { for top.iter().map(|(image, count)| {
+                let onclick = {
+                    let image = image.clone();
+                    props.onselect.reform(move |_: MouseEvent| image.clone())
+                };
+                let mut class = classes!("bommer-c-top-images__item");
+                if props.selected.as_ref() == Some(image) {
+                    class.push("pf-m-selected");
+                }
+                html!(
+                    <div {class} {onclick} title={image.to_string()}>
+                        <div class="bommer-c-top-images__label">{ image.to_string() }</div>
+                        <div class="bommer-c-top-images__bar" style={format!("width: {}%;", count * 100 / max)}>
+                            { count }
+                        </div>
+                    </div>
+                )
+            })}
+        </div>
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::{ImageBuilder, PodRef};
+
+    fn image(name: &str, pods: usize) -> (ImageRef, bommer_api::data::Image) {
+        let image = (0..pods)
+            .map(|n| PodRef {
+                namespace: "default".to_string(),
+                name: format!("{name}-{n}"),
+            })
+            .collect::<ImageBuilder>()
+            .build();
+        (ImageRef(name.to_string()), image)
+    }
+
+    #[test]
+    fn most_pods_first() {
+        let workload = Workload(
+            [image("a", 1), image("b", 3), image("c", 2), image("d", 3)]
+                .into_iter()
+                .collect(),
+        );
+
+        let top = top_images(&workload, 3);
+        assert_eq!(
+            top,
+            vec![
+                (ImageRef("b".to_string()), 3),
+                (ImageRef("d".to_string()), 3),
+                (ImageRef("c".to_string()), 2),
+            ]
+        );
+
+        assert_eq!(top_images(&workload, 10).len(), 4);
+        assert!(top_images(&Workload::default(), 5).is_empty());
+    }
+}
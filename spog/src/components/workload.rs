@@ -1,82 +1,734 @@
-use bommer_api::data::{Image, ImageRef, SbomState};
+use crate::backend::WorkloadService;
+use crate::components::filter::WorkloadFilter;
+use crate::hooks::{use_backend, use_media_query};
+use bommer_api::data::{
+    BaselineDiff, Classification, Image, ImageChange, ImageDiff, ImageRef, PodHistory, SbomState,
+    POD_HISTORY_LEN, SBOM,
+};
 use itertools::Itertools;
 use patternfly_yew::prelude::*;
+use std::collections::HashSet;
 use std::rc::Rc;
 use yew::prelude::*;
 
+/// The annotation key used for the user provided note
+const NOTE: &str = "note";
+
+/// Viewports narrower than a tablet, hiding the SBOM column
+const TABLET: &str = "(max-width: 768px)";
+/// Viewports narrower than a phone in landscape, also hiding the pods column
+const MOBILE: &str = "(max-width: 576px)";
+
 #[derive(Clone, Debug, PartialEq, Properties)]
 pub struct WorkloadTableProperties {
     pub workload: Rc<crate::backend::Workload>,
+    /// only show this image
+    #[prop_or_default]
+    pub filter: Option<ImageRef>,
+    /// only show images matching these criteria
+    #[prop_or_default]
+    pub criteria: WorkloadFilter,
+    /// highlight changes compared to a baseline
+    #[prop_or_default]
+    pub diff: Option<Rc<BaselineDiff>>,
+    /// hide less important columns on narrow screens, stacking the cells of the rows
+    #[prop_or(true)]
+    pub responsive_columns: bool,
+}
+
+/// The columns of the [`WorkloadTable`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Column {
+    Image,
+    Pods,
+    Sbom,
+    Note,
 }
 
-#[derive(PartialEq)]
+impl Column {
+    /// The columns to show, least important ones hidden first, with their label and width
+    fn visible(hide_pods: bool, hide_sbom: bool) -> Vec<(Column, &'static str, ColumnWidth)> {
+        [
+            (Column::Image, "Image", ColumnWidth::Percent(60)),
+            (Column::Pods, "Pods", ColumnWidth::Percent(10)),
+            (Column::Sbom, "SBOM", ColumnWidth::Percent(10)),
+            (Column::Note, "Note", ColumnWidth::Percent(15)),
+        ]
+        .into_iter()
+        .filter(|(column, _, _)| match column {
+            Column::Pods => !hide_pods,
+            Column::Sbom => !hide_sbom,
+            Column::Image | Column::Note => true,
+        })
+        .collect()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
 pub struct WorkloadEntry {
     id: ImageRef,
     state: Image,
+    diff: Option<ImageDiff>,
+    /// the columns shown, by their index
+    columns: Rc<[Column]>,
 }
 
 impl TableEntryRenderer for WorkloadEntry {
     fn render_cell(&self, context: &CellContext) -> Cell {
-        match context.column {
-            0 => html!(self.id.to_string()).into(),
-            1 => html!(self.state.pods.len()).into(),
-            2 => match &self.state.sbom {
-                SbomState::Scheduled => html!("Retrieving…").into(),
-                SbomState::Missing => html!("Missing").into(),
-                SbomState::Err(err) => Cell::new(html!(
-                    <Tooltip text={err.to_string()}>
-                        { format!("Failed ({err})") }
-                    </Tooltip>
-                ))
-                .text_modifier(TextModifier::Truncate),
-                SbomState::Found(_) => html!("Found").into(),
+        match self.columns.get(context.column) {
+            Some(Column::Image) => render_image(&self.id, self.diff.as_ref()).into(),
+            Some(Column::Pods) => render_pods(&self.state, self.diff.as_ref()).into(),
+            Some(Column::Sbom) => match &self.state.sbom {
+                SbomState::Err(_) => Cell::new(render_sbom(&self.id, &self.state))
+                    .text_modifier(TextModifier::Truncate),
+                _ => render_sbom(&self.id, &self.state).into(),
             },
-            _ => Default::default(),
+            Some(Column::Note) => {
+                Cell::new(render_note(&self.state)).text_modifier(TextModifier::Truncate)
+            }
+            None => Default::default(),
         }
         .into()
     }
 
     fn render_details(&self) -> Vec<Span> {
         vec![Span::max(html!(
-            <ul>
-                { for self.state.pods.iter().sorted_unstable().map(| pod|{
-                    html!(<li> { &pod.namespace }  { " / " } { &pod.name} </li> )
-                })}
-            </ul>
+            <>
+                <ul>
+                    { for self.state.pods.iter().sorted_unstable().map(| pod|{
+                        let added = self.diff.as_ref().is_some_and(|diff| diff.added_pods.contains(pod));
+                        html!(
+                            <li>
+                                { &pod.namespace }  { " / " } { &pod.name}
+                                if added {
+                                    { " " } <Label color={Color::Green} label="New" />
+                                }
+                            </li>
+                        )
+                    })}
+                    // pods which are gone since the baseline
+                    { for self.diff.iter().flat_map(|diff| &diff.removed_pods).map(|pod| {
+                        html!(<li><del> { &pod.namespace }  { " / " } { &pod.name} </del></li> )
+                    })}
+                </ul>
+                if !self.state.architectures.is_empty() {
+                    <ul>
+                        { for self.state.architectures.iter().map(|(architecture, arch)| html!(
+                            <li>
+                                { architecture } { ": " }
+                                { render_sbom_state(&arch.image, &arch.sbom) }
+                                { " (" } { arch.image.to_string() } { ")" }
+                            </li>
+                        ))}
+                    </ul>
+                }
+                <NoteEditor
+                    image={self.id.clone()}
+                    note={self.state.annotations.get(NOTE).cloned()}
+                />
+            </>
         ))]
     }
 }
 
+fn render_image(image: &ImageRef, diff: Option<&ImageDiff>) -> Html {
+    match diff.map(|diff| diff.change) {
+        Some(ImageChange::Added) => html!(
+            <>
+                { image.to_string() } { " " }
+                <Label color={Color::Green} label="New" />
+            </>
+        ),
+        _ => html!(image.to_string()),
+    }
+}
+
+/// The number of pods, along with the number of pods added and removed since the baseline
+fn render_pods(state: &Image, diff: Option<&ImageDiff>) -> Html {
+    let count = match diff {
+        Some(diff) if diff.change == ImageChange::Modified => html!(
+            <>
+                { state.pods.len() }
+                <span class="bommer-c-pod-diff">
+                    { format!(" (+{}/−{})", diff.added_pods.len(), diff.removed_pods.len()) }
+                </span>
+            </>
+        ),
+        _ => html!(state.pods.len()),
+    };
+
+    html!(<>{ count } { render_sparkline(&state.pod_history) }</>)
+}
+
+const SPARKLINE_WIDTH: usize = 40;
+const SPARKLINE_HEIGHT: usize = 12;
+
+/// The points of the sparkline, `None` if there is no trend to show yet
+fn sparkline_points(history: &PodHistory) -> Option<String> {
+    if history.len() < 2 {
+        return None;
+    }
+
+    let max = history.max().max(1) as f64;
+    let step = SPARKLINE_WIDTH as f64 / (POD_HISTORY_LEN - 1) as f64;
+    // align the most recent sample to the right edge
+    let offset = (POD_HISTORY_LEN - history.len()) as f64 * step;
+    Some(
+        history
+            .iter()
+            .enumerate()
+            .map(|(i, pods)| {
+                let x = offset + i as f64 * step;
+                let y = SPARKLINE_HEIGHT as f64 * (1.0 - pods as f64 / max);
+                format!("{x:.1},{y:.1}")
+            })
+            .join(" "),
+    )
+}
+
+/// A tiny line chart of the recent numbers of pods, nothing if there is no trend to show yet
+fn render_sparkline(history: &PodHistory) -> Html {
+    let Some(points) = sparkline_points(history) else {
+        return html!();
+    };
+
+    html!(
+        <svg
+            class="bommer-c-sparkline"
+            width={SPARKLINE_WIDTH.to_string()}
+            height={SPARKLINE_HEIGHT.to_string()}
+            viewBox={format!("0 0 {SPARKLINE_WIDTH} {SPARKLINE_HEIGHT}")}
+        >
+            <title>{ format!("Pods: {}", history.iter().join(" → ")) }</title>
+            <polyline {points} />
+        </svg>
+    )
+}
+
+/// The state of the SBOM, pointing out images which the policy exempts from having one
+fn render_sbom(image: &ImageRef, state: &Image) -> Html {
+    let sbom = match state.architecture_summary() {
+        // the combined state of a multi-arch image hides the architectures which have an SBOM
+        Some(summary) if state.is_partially_found() => html!(
+            <Tooltip text={summary.clone()}>
+                { format!("Partial ({summary})") }
+            </Tooltip>
+        ),
+        _ => render_sbom_state(image, &state.sbom),
+    };
+    match (&state.sbom, state.classification) {
+        (SbomState::Found(_), _) | (_, Classification::Required) => sbom,
+        (_, classification) => html!(
+            <>
+                { sbom }
+                { " " }
+                <Tooltip text={format!("The image policy classifies this image as {classification}")}>
+                    <Label color={Color::Grey} label={classification.label()} />
+                </Tooltip>
+            </>
+        ),
+    }
+}
+
+fn render_sbom_state(image: &ImageRef, sbom: &SbomState) -> Html {
+    match sbom {
+        SbomState::Scheduled => html!("Retrieving…"),
+        SbomState::Missing => html!("Missing"),
+        SbomState::Unsupported => html!(
+            <Tooltip text="No SBOM source is configured for the registry of this image">
+                { "No scanner configured" }
+            </Tooltip>
+        ),
+        SbomState::Err(err) => html!(
+            <Tooltip text={err.to_string()}>
+                { format!("Failed ({err})") }
+            </Tooltip>
+        ),
+        SbomState::Found(sboms) => html!(
+            <SbomSelector image={image.clone()} sboms={sboms.clone()} />
+        ),
+    }
+}
+
+fn render_found(image: &ImageRef, sbom: &SBOM) -> Html {
+    match sbom.digest_mismatch(image) {
+        Some(digest) => html!(
+            <>
+                { "Found " }
+                <Tooltip text={format!("The SBOM describes a different image: {digest}")}>
+                    <Label color={Color::Orange} label="Digest mismatch" />
+                </Tooltip>
+            </>
+        ),
+        None => match sbom.component_count {
+            Some(count) => html!(format!("Found ({count} components)")),
+            None => html!("Found"),
+        },
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct SbomSelectorProperties {
+    pub image: ImageRef,
+    pub sboms: Vec<SBOM>,
+}
+
+/// The SBOMs found for an image, showing one of them at a time if there are several.
+#[function_component(SbomSelector)]
+pub fn sbom_selector(props: &SbomSelectorProperties) -> Html {
+    let selected = use_state_eq(|| 0usize);
+
+    let Some(sbom) = props.sboms.get(*selected).or(props.sboms.first()) else {
+        return html!("Found");
+    };
+
+    if props.sboms.len() < 2 {
+        return render_found(&props.image, sbom);
+    }
+
+    let label =
+        |n: usize, sbom: &SBOM| format!("{} ({}/{})", sbom.format, n + 1, props.sboms.len());
+
+    html!(
+        <>
+            <Dropdown text={label(*selected, sbom)}>
+                { for props.sboms.iter().enumerate().map(|(n, sbom)| {
+                    let onclick = {
+                        let selected = selected.clone();
+                        Callback::from(move |_| selected.set(n))
+                    };
+                    html_nested!(<DropdownItem {onclick}>{ label(n, sbom) }</DropdownItem>)
+                })}
+            </Dropdown>
+            { " " }
+            { render_found(&props.image, sbom) }
+        </>
+    )
+}
+
+fn render_note(state: &Image) -> Html {
+    html!(state.annotations.get(NOTE).cloned().unwrap_or_default())
+}
+
+/// The entries to show, ordered by image
+pub fn entries<'a>(
+    workload: &'a crate::backend::Workload,
+    filter: &'a Option<ImageRef>,
+    criteria: &'a WorkloadFilter,
+) -> impl Iterator<Item = (&'a ImageRef, &'a Image)> {
+    workload
+        .0
+        .iter()
+        .filter(|(k, _)| filter.as_ref().map_or(true, |filter| filter == *k))
+        .filter(|(k, v)| criteria.matches(k, v))
+        .sorted_unstable_by(|(a, _), (b, _)| a.cmp(b))
+}
+
+/// The images shown whose SBOM lookup failed, which can be retried
+pub fn failed_entries(
+    workload: &crate::backend::Workload,
+    filter: &Option<ImageRef>,
+    criteria: &WorkloadFilter,
+) -> Vec<ImageRef> {
+    entries(workload, filter, criteria)
+        .filter(|(_, image)| matches!(image.sbom, SbomState::Err(_)))
+        .map(|(image, _)| image.clone())
+        .collect()
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct NoteEditorProperties {
+    pub image: ImageRef,
+    #[prop_or_default]
+    pub note: Option<String>,
+}
+
+/// Edit the note of an image, an empty note removes it.
+#[function_component(NoteEditor)]
+pub fn note_editor(props: &NoteEditorProperties) -> Html {
+    let backend = use_backend();
+    let value = use_state_eq(|| props.note.clone().unwrap_or_default());
+
+    let onchange = {
+        let value = value.clone();
+        Callback::from(move |text: String| value.set(text))
+    };
+
+    let onsave = {
+        let image = props.image.clone();
+        let value = value.clone();
+        Callback::from(move |_| {
+            let service = WorkloadService::new((*backend).clone());
+            let image = image.clone();
+            let value = (*value).clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                let result = match value.trim().is_empty() {
+                    true => service.remove_annotation(&image, NOTE).await,
+                    false => service.set_annotation(&image, NOTE, value).await,
+                };
+                if let Err(err) = result {
+                    log::warn!("Failed to store note: {err}");
+                }
+            });
+        })
+    };
+
+    html!(
+        <Flex>
+            <FlexItem>
+                <TextInput value={(*value).clone()} {onchange} placeholder="Add a note…" />
+            </FlexItem>
+            <FlexItem>
+                <Button label="Save" variant={ButtonVariant::Secondary} onclick={onsave} />
+            </FlexItem>
+        </Flex>
+    )
+}
+
 #[function_component(WorkloadTable)]
 pub fn workload_table(props: &WorkloadTableProperties) -> Html {
+    let tablet = use_media_query(TABLET);
+    let mobile = use_media_query(MOBILE);
+
+    let responsive = props.responsive_columns;
+    let columns = Column::visible(responsive && mobile, responsive && tablet);
+
     let header = html_nested!(
         <TableHeader>
-            <TableColumn label="Image" width={ColumnWidth::Percent(80)} />
-            <TableColumn label="Pods" width={ColumnWidth::Percent(5)}   />
-            <TableColumn label="SBOM" width={ColumnWidth::Percent(10)}  />
+            { for columns.iter().map(|(_, label, width)| html_nested!(
+                <TableColumn label={*label} width={*width} />
+            ))}
         </TableHeader>
     );
+    let columns = columns
+        .into_iter()
+        .map(|(column, _, _)| column)
+        .collect::<Rc<[_]>>();
+
+    // the model keeps track of which rows are expanded, by index, so a new model would collapse
+    // all rows, and make the page jump
+    let previous = use_mut_ref(TableState::default);
 
     let entries = use_memo(
-        |workload| {
-            let mut entries = SharedTableModel::with_capacity(workload.0.len());
-            for (k, v) in workload.0.iter().sorted_unstable_by_key(|(k, _)| *k) {
-                entries.push(WorkloadEntry {
+        |(workload, filter, criteria, diff, columns)| {
+            let entries = self::entries(workload, filter, criteria)
+                .map(|(k, v)| WorkloadEntry {
                     id: k.clone(),
                     state: v.clone(),
+                    diff: diff.as_ref().and_then(|diff| diff.get(k)).cloned(),
+                    columns: columns.clone(),
                 })
+                .collect::<Vec<_>>();
+            previous.borrow_mut().update(entries)
+        },
+        (
+            props.workload.clone(),
+            props.filter.clone(),
+            props.criteria.clone(),
+            props.diff.clone(),
+            columns,
+        ),
+    );
+
+    match responsive {
+        true => html!(
+            <Table<SharedTableModel<WorkloadEntry>>
+                {header}
+                grid={TableGridMode::Medium}
+                entries={(*entries).clone()}
+                mode={TableMode::CompactExpandable}
+            />
+        ),
+        false => html!(
+            <Table<SharedTableModel<WorkloadEntry>>
+                {header}
+                entries={(*entries).clone()}
+                mode={TableMode::CompactExpandable}
+            />
+        ),
+    }
+}
+
+/// The entries last shown by a [`WorkloadTable`], along with their model.
+#[derive(Default)]
+struct TableState {
+    entries: Vec<WorkloadEntry>,
+    model: SharedTableModel<WorkloadEntry>,
+}
+
+impl TableState {
+    /// The model for `entries`, keeping rows expanded which were expanded before.
+    ///
+    /// If none of the entries changed, the current model is kept as it is.
+    fn update(&mut self, entries: Vec<WorkloadEntry>) -> SharedTableModel<WorkloadEntry> {
+        if entries == self.entries {
+            return self.model.clone();
+        }
+
+        let expanded = self
+            .entries
+            .iter()
+            .enumerate()
+            .filter(|(n, _)| self.model.is_expanded(*n))
+            .map(|(_, entry)| entry.id.clone())
+            .collect::<HashSet<_>>();
+
+        let mut model = SharedTableModel::with_capacity(entries.len());
+        for (n, entry) in entries.iter().enumerate() {
+            model.push(entry.clone());
+            if expanded.contains(&entry.id) {
+                model.set_expanded(n, true);
             }
-            entries
+        }
+
+        self.entries = entries;
+        self.model = model.clone();
+        model
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct VirtualWorkloadTableProperties {
+    pub workload: Rc<crate::backend::Workload>,
+    /// only show this image
+    #[prop_or_default]
+    pub filter: Option<ImageRef>,
+    /// only show images matching these criteria
+    #[prop_or_default]
+    pub criteria: WorkloadFilter,
+    /// highlight changes compared to a baseline
+    #[prop_or_default]
+    pub diff: Option<Rc<BaselineDiff>>,
+    /// height of a single row, in pixels
+    #[prop_or(36)]
+    pub row_height: u32,
+    /// height of the scrollable area, in pixels
+    #[prop_or(600)]
+    pub height: u32,
+    /// number of rows to render outside the visible area, in each direction
+    #[prop_or(10)]
+    pub overscan: usize,
+}
+
+/// The scroll position of the virtual table
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct ScrollState {
+    top: u32,
+}
+
+/// The range of rows to render, for a scroll position
+fn visible_rows(
+    scroll: ScrollState,
+    total: usize,
+    row_height: u32,
+    height: u32,
+    overscan: usize,
+) -> std::ops::Range<usize> {
+    let row_height = row_height.max(1);
+    let first = (scroll.top / row_height) as usize;
+    let visible = (height / row_height) as usize + 1;
+
+    let start = first.saturating_sub(overscan).min(total);
+    let end = (first + visible + overscan).min(total);
+
+    start..end
+}
+
+/// A workload table which only renders the rows currently visible.
+///
+/// Other than the [`WorkloadTable`], this table uses fixed-height rows and doesn't support
+/// expanding rows, but keeps up with many thousands of images.
+#[function_component(VirtualWorkloadTable)]
+pub fn virtual_workload_table(props: &VirtualWorkloadTableProperties) -> Html {
+    let scroll = use_state_eq(ScrollState::default);
+
+    let entries = use_memo(
+        |(workload, filter, criteria)| {
+            self::entries(workload, filter, criteria)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<Vec<_>>()
         },
-        props.workload.clone(),
+        (
+            props.workload.clone(),
+            props.filter.clone(),
+            props.criteria.clone(),
+        ),
+    );
+
+    let onscroll = {
+        let scroll = scroll.clone();
+        Callback::from(move |evt: Event| {
+            let element: web_sys::Element = evt.target_unchecked_into();
+            scroll.set(ScrollState {
+                top: element.scroll_top().max(0) as u32,
+            });
+        })
+    };
+
+    let range = visible_rows(
+        *scroll,
+        entries.len(),
+        props.row_height,
+        props.height,
+        props.overscan,
     );
+    let padding_top = range.start as u32 * props.row_height;
+    let padding_bottom = (entries.len() - range.end) as u32 * props.row_height;
+    let row_style = format!("height: {}px;", props.row_height);
+
+    html!(
+        <div
+            class="bommer-c-virtual-table"
+            style={format!("height: {}px;", props.height)}
+            {onscroll}
+        >
+            <table class="pf-c-table pf-m-compact pf-m-grid-md" role="grid">
+                <thead>
+                    <tr>
+                        <th width="60%">{ "Image" }</th>
+                        <th width="10%">{ "Pods" }</th>
+                        <th width="10%">{ "SBOM" }</th>
+                        <th width="15%">{ "Note" }</th>
+                    </tr>
+                </thead>
+                <tbody>
+                    if padding_top > 0 {
+                        <tr style={format!("height: {padding_top}px;")} />
+                    }
+                    { for entries[range].iter().map(|(id, state)| {
+                        let diff = props.diff.as_ref().and_then(|diff| diff.get(id));
+                        html!(
+                            <tr key={id.to_string()} style={row_style.clone()}>
+                                <td class="pf-m-truncate">{ render_image(id, diff) }</td>
+                                <td>{ render_pods(state, diff) }</td>
+                                <td class="pf-m-truncate">{ render_sbom(id, state) }</td>
+                                <td class="pf-m-truncate">{ render_note(state) }</td>
+                            </tr>
+                        )
+                    })}
+                    if padding_bottom > 0 {
+                        <tr style={format!("height: {padding_bottom}px;")} />
+                    }
+                </tbody>
+            </table>
+        </div>
+    )
+}
+
+#[derive(Clone, Debug, PartialEq, Properties)]
+pub struct RemovedImagesProperties {
+    pub diff: Rc<BaselineDiff>,
+}
+
+/// The images which are gone since the baseline.
+#[function_component(RemovedImages)]
+pub fn removed_images(props: &RemovedImagesProperties) -> Html {
+    if props.diff.removed().next().is_none() {
+        return html!();
+    }
 
     html!(
-        <Table<SharedTableModel<WorkloadEntry>>
-            {header}
-            grid={TableGridMode::Medium}
-            entries={(*entries).clone()}
-            mode={TableMode::CompactExpandable}
-        />
+        <>
+            <Title level={Level::H3}>{ "Removed since baseline" }</Title>
+            <ul>
+                { for props.diff.removed().map(|(image, diff)| html!(
+                    <li>
+                        <del>{ image.to_string() }</del>
+                        { format!(" ({} pods)", diff.removed_pods.len()) }
+                    </li>
+                ))}
+            </ul>
+        </>
     )
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::Workload;
+    use crate::components::filter::SbomStatusFilter;
+    use bommer_api::data::ImageBuilder;
+
+    fn image(name: &str, sbom: SbomState) -> (ImageRef, Image) {
+        (
+            ImageRef(name.to_string()),
+            ImageBuilder::default().sbom(sbom).build(),
+        )
+    }
+
+    fn refs(names: &[&str]) -> Vec<ImageRef> {
+        names
+            .iter()
+            .map(|name| ImageRef(name.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn retry_failed_entries_shown() {
+        let workload = Workload(
+            [
+                image("quay.io/a", SbomState::Err("timeout".to_string())),
+                image("docker.io/b", SbomState::Err("denied".to_string())),
+                image("quay.io/c", SbomState::Missing),
+                image("quay.io/d", SbomState::Scheduled),
+            ]
+            .into_iter()
+            .collect(),
+        );
+
+        let criteria = WorkloadFilter::default();
+        assert_eq!(
+            failed_entries(&workload, &None, &criteria),
+            refs(&["docker.io/b", "quay.io/a"])
+        );
+
+        // the errors chip shows the same images
+        let errors_only = WorkloadFilter {
+            sbom_status: Some(SbomStatusFilter::Failed),
+            ..Default::default()
+        };
+        assert_eq!(
+            entries(&workload, &None, &errors_only)
+                .map(|(image, _)| image.clone())
+                .collect::<Vec<_>>(),
+            refs(&["docker.io/b", "quay.io/a"])
+        );
+
+        // only the failed ones of those shown
+        let criteria = WorkloadFilter {
+            registry: Some("quay.io".to_string()),
+            ..errors_only
+        };
+        assert_eq!(
+            failed_entries(&workload, &None, &criteria),
+            refs(&["quay.io/a"])
+        );
+        let selected = Some(ImageRef("quay.io/c".to_string()));
+        assert!(failed_entries(&workload, &selected, &WorkloadFilter::default()).is_empty());
+    }
+
+    #[test]
+    fn sparkline() {
+        let mut history = PodHistory::default();
+        history.record(2);
+        assert_eq!(sparkline_points(&history), None);
+
+        // the most recent samples at the right edge, the largest one at the top
+        history.record(4);
+        history.record(0);
+        let step = SPARKLINE_WIDTH as f64 / (POD_HISTORY_LEN - 1) as f64;
+        let x = |n: usize| format!("{:.1}", SPARKLINE_WIDTH as f64 - n as f64 * step);
+        assert_eq!(
+            sparkline_points(&history).unwrap(),
+            format!("{},6.0 {},0.0 {},12.0", x(2), x(1), x(0))
+        );
+
+        for _ in 0..POD_HISTORY_LEN {
+            history.record(0);
+        }
+        let points = sparkline_points(&history).unwrap();
+        assert_eq!(points.split(' ').count(), POD_HISTORY_LEN);
+        assert!(points.starts_with("0.0,12.0 "));
+        assert!(points.ends_with(" 40.0,12.0"));
+    }
+}
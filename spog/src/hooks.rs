@@ -1,5 +1,8 @@
 use crate::backend::Backend;
 use std::rc::Rc;
+use wasm_bindgen::prelude::*;
+use wasm_bindgen::JsCast;
+use web_sys::MediaQueryList;
 use yew::prelude::*;
 
 /// Get the backend instance. **Panics** if called from a component not nested somewhere under
@@ -9,3 +12,46 @@ pub fn use_backend() -> Rc<Backend> {
     use_context::<Rc<Backend>>()
         .expect("Must be called from a component wrapped in a 'Backend' component")
 }
+
+/// Whether a media query matches, e.g. `(max-width: 768px)`, following changes of the viewport.
+#[hook]
+pub fn use_media_query(query: &str) -> bool {
+    let matches = use_state_eq(|| media_query(query).is_some_and(|list| list.matches()));
+
+    {
+        let matches = matches.clone();
+        use_effect_with_deps(
+            move |query: &String| {
+                let list = media_query(query);
+                let listener = list.as_ref().map(|list| {
+                    matches.set(list.matches());
+                    let callback = {
+                        let list = list.clone();
+                        Closure::<dyn Fn()>::new(move || matches.set(list.matches()))
+                    };
+                    let _ = list.add_event_listener_with_callback(
+                        "change",
+                        callback.as_ref().unchecked_ref(),
+                    );
+                    callback
+                });
+
+                move || {
+                    if let (Some(list), Some(callback)) = (list, listener) {
+                        let _ = list.remove_event_listener_with_callback(
+                            "change",
+                            callback.as_ref().unchecked_ref(),
+                        );
+                    }
+                }
+            },
+            query.to_string(),
+        );
+    }
+
+    *matches
+}
+
+fn media_query(query: &str) -> Option<MediaQueryList> {
+    web_sys::window()?.match_media(query).ok().flatten()
+}
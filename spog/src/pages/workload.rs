@@ -1,11 +1,22 @@
-use crate::backend::{self, IntoWs, WorkloadService};
-use crate::components::{remote_content, workload::WorkloadTable};
+use crate::backend::{self, use_workload_stream, BackoffOptions, IntoWs, WorkloadService};
+use crate::components::{
+    filter::{FilterAction, WorkloadFilter, WorkloadFilterBar},
+    remote_content,
+    risk::RiskScore,
+    status::WorkloadStatusBar,
+    top::TopImages,
+    workload::{failed_entries, RemovedImages, VirtualWorkloadTable, WorkloadTable},
+};
 use crate::hooks::use_backend;
-use bommer_api::data::{Event, Image, ImageRef};
+use crate::pages::AppRoute;
+use bommer_api::data::{BaselineDiff, ImageLimitStatus, ImageRef};
 use patternfly_yew::prelude::*;
 use std::rc::Rc;
 use yew::prelude::*;
-use yew_hooks::use_websocket;
+use yew_nested_router::prelude::*;
+
+/// Above this number of images, switch to the virtual table
+const VIRTUAL_TABLE_THRESHOLD: usize = 1000;
 
 #[derive(Clone, Debug, PartialEq, Eq, Properties)]
 pub struct WorkloadProperties {
@@ -17,7 +28,7 @@ pub struct WorkloadProperties {
 pub fn workload(props: &WorkloadProperties) -> Html {
     let backend = use_backend();
 
-    let ws = use_websocket(
+    let stream = use_workload_stream(
         backend
             .join(match props.namespace.is_empty() {
                 true => "/api/v1/workload_stream".to_string(),
@@ -26,38 +37,136 @@ pub fn workload(props: &WorkloadProperties) -> Html {
             .unwrap()
             .into_ws()
             .to_string(),
+        BackoffOptions::default(),
     );
 
-    let workload = use_state(|| Rc::new(backend::Workload::default()));
+    let workload = stream.workload.clone();
+    let selected = use_state_eq(|| None::<ImageRef>);
+    let criteria = use_reducer_eq(WorkloadFilter::default);
+
+    let onfilter = {
+        let criteria = criteria.dispatcher();
+        Callback::from(move |action: FilterAction| criteria.dispatch(action))
+    };
+
+    // comparing to the baseline, bumping the generation re-fetches it
+    let compare = use_state_eq(|| false);
+    let baseline = use_state(|| None::<Rc<backend::Workload>>);
+    let generation = use_state_eq(|| 0usize);
 
     {
-        let workload = workload.clone();
+        let baseline = baseline.clone();
+        let backend = backend.clone();
         use_effect_with_deps(
-            move |message| {
-                if let Some(message) = &**message {
-                    if let Ok(evt) = serde_json::from_str::<Event<ImageRef, Image>>(&message) {
-                        match evt {
-                            Event::Added(image, state) | Event::Modified(image, state) => {
-                                let mut s = (**workload).clone();
-                                s.insert(image, state);
-                                workload.set(Rc::new(s));
-                            }
-                            Event::Removed(image) => {
-                                let mut s = (**workload).clone();
-                                s.remove(&image);
-                                workload.set(Rc::new(s));
-                            }
-                            Event::Restart(state) => {
-                                workload.set(Rc::new(backend::Workload(state)));
-                            }
+            move |(compare, _)| {
+                if *compare {
+                    let service = WorkloadService::new((*backend).clone());
+                    wasm_bindgen_futures::spawn_local(async move {
+                        match service.baseline().await {
+                            Ok(result) => baseline.set(result.map(Rc::new)),
+                            Err(err) => log::warn!("Failed to fetch baseline: {err}"),
                         }
-                    }
+                    });
+                }
+                || ()
+            },
+            (*compare, *generation),
+        );
+    }
+
+    let ontoggle = {
+        let compare = compare.clone();
+        Callback::from(move |state: bool| compare.set(state))
+    };
+
+    let onsetbaseline = {
+        let backend = backend.clone();
+        let generation = generation.clone();
+        Callback::from(move |_| {
+            let service = WorkloadService::new((*backend).clone());
+            let generation = generation.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match service.set_baseline().await {
+                    Ok(()) => generation.set(*generation + 1),
+                    Err(err) => log::warn!("Failed to set baseline: {err}"),
                 }
+            });
+        })
+    };
+
+    let diff = use_memo(
+        |(workload, baseline, compare)| match (compare, baseline) {
+            (true, Some(baseline)) => Some(Rc::new(BaselineDiff::new(baseline, workload))),
+            _ => None,
+        },
+        (workload.clone(), (*baseline).clone(), *compare),
+    );
 
+    // the backend reaches its limit by adding images, so check again whenever their number changed
+    let limit = use_state_eq(ImageLimitStatus::default);
+    {
+        let limit = limit.clone();
+        let backend = backend.clone();
+        use_effect_with_deps(
+            move |_| {
+                let service = WorkloadService::new((*backend).clone());
+                wasm_bindgen_futures::spawn_local(async move {
+                    match service.image_limit().await {
+                        Ok(result) => limit.set(result),
+                        Err(err) => log::warn!("Failed to fetch the image limit: {err}"),
+                    }
+                });
                 || ()
             },
-            ws.message,
-        )
+            workload.0.len(),
+        );
+    }
+
+    // the failed images currently shown, which "Retry all shown" schedules again
+    let failed = use_memo(
+        |(workload, selected, criteria)| failed_entries(workload, selected, criteria),
+        (workload.clone(), (*selected).clone(), (*criteria).clone()),
+    );
+
+    let onretry = {
+        let backend = backend.clone();
+        let failed = failed.clone();
+        Callback::from(move |_| {
+            let service = WorkloadService::new((*backend).clone());
+            let failed = failed.clone();
+            wasm_bindgen_futures::spawn_local(async move {
+                match service.retry(&failed).await {
+                    Ok(scheduled) => log::info!("Scheduled {scheduled} images again"),
+                    Err(err) => log::warn!("Failed to retry images: {err}"),
+                }
+            });
+        })
+    };
+
+    let onclearselected = {
+        let selected = selected.clone();
+        Callback::from(move |()| selected.set(None))
+    };
+
+    // the namespace is part of the route, so leave it for the workload of all namespaces
+    let router = use_router::<AppRoute>();
+    let onclearnamespace = Callback::from(move |()| {
+        if let Some(router) = &router {
+            router.push(AppRoute::ByNamespace {
+                namespace: Default::default(),
+            });
+        }
+    });
+
+    // clicking the selected image again clears the filter
+    let onselect = {
+        let selected = selected.clone();
+        Callback::from(move |image: ImageRef| {
+            selected.set(match *selected == Some(image.clone()) {
+                true => None,
+                false => Some(image),
+            })
+        })
     };
 
     html!(
@@ -68,14 +177,82 @@ pub fn workload(props: &WorkloadProperties) -> Html {
                 fill=false
             >
                 <Content>
-                    <Title level={Level::H1}>{"Discovered Workload"}</Title>
+                    <Title level={Level::H1}>
+                        {"Discovered Workload"}
+                        if !stream.connected {
+                            { " " } <Label color={Color::Orange} label="Reconnecting…" />
+                        }
+                    </Title>
                 </Content>
             </PageSection>
 
             <PageSection variant={PageSectionVariant::Default} fill=true>
-                <WorkloadTable workload={(*workload).clone()} />
+                if limit.at_capacity() {
+                    { capacity_banner(&*limit) }
+                }
+                <RiskScore workload={workload.clone()} />
+                <TopImages
+                    workload={workload.clone()}
+                    selected={(*selected).clone()}
+                    {onselect}
+                />
+                <Toolbar>
+                    <ToolbarItem>
+                        <Switch checked={*compare} label="Compare to baseline" on_change={ontoggle} />
+                    </ToolbarItem>
+                    <ToolbarItem>
+                        <Button label="Set baseline" variant={ButtonVariant::Secondary} onclick={onsetbaseline} />
+                    </ToolbarItem>
+                    <ToolbarItem>
+                        <Button
+                            label={format!("Retry all shown ({})", failed.len())}
+                            variant={ButtonVariant::Secondary}
+                            disabled={failed.is_empty()}
+                            onclick={onretry}
+                        />
+                    </ToolbarItem>
+                </Toolbar>
+                <WorkloadFilterBar
+                    workload={workload.clone()}
+                    filter={(*criteria).clone()}
+                    onchange={onfilter}
+                    selected={(*selected).clone()}
+                    {onclearselected}
+                    namespace={props.namespace.clone()}
+                    {onclearnamespace}
+                />
+                if *compare && baseline.is_none() {
+                    <p>{ "No baseline set yet" }</p>
+                }
+                <WorkloadStatusBar workload={workload.clone()} />
+                if workload.0.len() > VIRTUAL_TABLE_THRESHOLD {
+                    <VirtualWorkloadTable workload={workload.clone()} filter={(*selected).clone()} criteria={(*criteria).clone()} diff={(*diff).clone()} />
+                } else {
+                    <WorkloadTable workload={workload.clone()} filter={(*selected).clone()} criteria={(*criteria).clone()} diff={(*diff).clone()} />
+                }
+                if let Some(diff) = &*diff {
+                    <RemovedImages diff={diff.clone()} />
+                }
             </PageSection>
 
         </>
     )
 }
+
+/// Warn that the backend tracks the maximum number of images, so the workload is incomplete.
+fn capacity_banner(limit: &ImageLimitStatus) -> Html {
+    html!(
+        <div class="pf-c-alert pf-m-inline pf-m-warning pf-u-mb-md" aria-label="Image limit reached">
+            <div class="pf-c-alert__icon">{ Icon::ExclamationTriangle }</div>
+            <p class="pf-c-alert__title">
+                { format!("The backend tracks its maximum of {} images", limit.max_images) }
+            </p>
+            <div class="pf-c-alert__description">
+                { format!(
+                    "The workload is incomplete: {} new images were refused, and {} were evicted to make room for others.",
+                    limit.refused, limit.evicted
+                ) }
+            </div>
+        </div>
+    )
+}
@@ -1,3 +1,4 @@
+use chrono::{DateTime, Duration, Utc};
 use yew::prelude::*;
 
 pub trait RenderOptional: Sized {
@@ -26,3 +27,59 @@ where
         }
     }
 }
+
+/// Render the age of `timestamp`, relative to `now`, in a human friendly way.
+///
+/// Wall-clock timestamps may come from a different machine (or change with NTP adjustments), so
+/// a timestamp in the future is treated as "just now" instead of producing a negative age.
+#[allow(unused)]
+pub fn humanize_age(timestamp: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let age = (now - timestamp).max(Duration::zero());
+
+    match age {
+        age if age < Duration::minutes(1) => "just now".to_string(),
+        age if age < Duration::hours(1) => plural(age.num_minutes(), "minute"),
+        age if age < Duration::days(1) => plural(age.num_hours(), "hour"),
+        age => plural(age.num_days(), "day"),
+    }
+}
+
+fn plural(n: i64, unit: &str) -> String {
+    match n {
+        1 => format!("1 {unit} ago"),
+        n => format!("{n} {unit}s ago"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    #[test]
+    fn humanize() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        assert_eq!(humanize_age(now, now), "just now");
+        assert_eq!(humanize_age(now - Duration::seconds(59), now), "just now");
+        assert_eq!(
+            humanize_age(now - Duration::minutes(1), now),
+            "1 minute ago"
+        );
+        assert_eq!(
+            humanize_age(now - Duration::minutes(5), now),
+            "5 minutes ago"
+        );
+        assert_eq!(humanize_age(now - Duration::hours(2), now), "2 hours ago");
+        assert_eq!(humanize_age(now - Duration::days(1), now), "1 day ago");
+        assert_eq!(humanize_age(now - Duration::days(30), now), "30 days ago");
+    }
+
+    #[test]
+    fn future_is_just_now() {
+        let now = Utc.timestamp_opt(1_700_000_000, 0).unwrap();
+
+        assert_eq!(humanize_age(now + Duration::seconds(1), now), "just now");
+        assert_eq!(humanize_age(now + Duration::days(3), now), "just now");
+    }
+}
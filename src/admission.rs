@@ -0,0 +1,474 @@
+//! Admission policy, deciding on new pods based on what we know about the SBOMs of their images.
+
+use crate::bombastic::SbomSourceRouter;
+use crate::policy::{ImagePolicy, Subject};
+use crate::reload::Setting;
+use crate::workload::WorkloadState;
+use bommer_api::data::{ImageRef, SbomState};
+use k8s_openapi::api::core::v1::{Namespace, Pod};
+use kube::core::admission::{AdmissionRequest, AdmissionResponse, AdmissionReview};
+use kube::core::DynamicObject;
+use kube::{Api, Client};
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use std::time::Duration;
+use tracing::{info, warn};
+
+/// How to deal with images which don't have an SBOM
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum Mode {
+    /// Allow the pod, but return a warning
+    #[default]
+    Audit,
+    /// Reject the pod
+    Enforce,
+}
+
+/// How to respond in enforce mode, if an image can't be evaluated
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum FailurePolicy {
+    /// Allow the pod, but return a warning (fail open)
+    #[default]
+    Ignore,
+    /// Reject the pod (fail closed)
+    Fail,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Policy {
+    pub mode: Mode,
+    /// namespaces with this label set to `true` are exempt, disabled if empty
+    pub exempt_label: String,
+    /// look up images which are not known yet, with this timeout
+    pub lookup_timeout: Option<Duration>,
+    pub failure_policy: FailurePolicy,
+}
+
+/// The result of checking a single image
+enum Outcome {
+    Found,
+    /// there is no SBOM for the image
+    Missing(String),
+    /// we don't know if there is an SBOM for the image
+    Unknown(String),
+}
+
+/// Answers admission reviews for pods.
+#[derive(Clone)]
+pub struct Admission {
+    /// the policy may change when reloading the configuration
+    policy: Setting<Policy>,
+    /// classifies images as required, recommended, or exempt from having an SBOM
+    image_policy: Arc<ImagePolicy>,
+    map: WorkloadState,
+    source: SbomSourceRouter,
+    namespaces: Api<Namespace>,
+}
+
+impl Admission {
+    pub fn new(
+        policy: Setting<Policy>,
+        image_policy: Arc<ImagePolicy>,
+        map: WorkloadState,
+        source: SbomSourceRouter,
+        client: Client,
+    ) -> Self {
+        Self {
+            policy,
+            image_policy,
+            map,
+            source,
+            namespaces: Api::all(client),
+        }
+    }
+
+    pub async fn review(&self, review: AdmissionReview<Pod>) -> AdmissionReview<DynamicObject> {
+        let request: AdmissionRequest<Pod> = match review.try_into() {
+            Ok(request) => request,
+            Err(err) => {
+                warn!("Invalid admission review: {err}");
+                return AdmissionResponse::invalid(err.to_string()).into_review();
+            }
+        };
+
+        self.decide(&request).await.into_review()
+    }
+
+    async fn decide(&self, request: &AdmissionRequest<Pod>) -> AdmissionResponse {
+        let response = AdmissionResponse::from(request);
+        let policy = self.policy.get();
+
+        let Some(pod) = &request.object else {
+            return response;
+        };
+
+        let mut warnings = Vec::new();
+        let mut denials = Vec::new();
+
+        match self.is_exempt(&policy, request.namespace.as_deref()).await {
+            Ok(true) => return response,
+            Ok(false) => {}
+            Err(err) => failed(
+                &policy,
+                format!("Unable to check if the namespace is exempt: {err}"),
+                &mut warnings,
+                &mut denials,
+            ),
+        }
+
+        for image in images(pod) {
+            let image = ImageRef(image.to_string());
+            let classification = self.image_policy.classify(&Subject {
+                image: &image,
+                namespace: request.namespace.as_deref(),
+                labels: pod.metadata.labels.as_ref(),
+            });
+            if classification.is_exempt() {
+                continue;
+            }
+
+            match self.check(&policy, &image).await {
+                Outcome::Found => {}
+                // recommended images never get a pod rejected
+                Outcome::Missing(reason) | Outcome::Unknown(reason)
+                    if !classification.is_required() =>
+                {
+                    warnings.push(reason)
+                }
+                Outcome::Missing(reason) => match policy.mode {
+                    Mode::Audit => warnings.push(reason),
+                    Mode::Enforce => denials.push(reason),
+                },
+                Outcome::Unknown(reason) => failed(&policy, reason, &mut warnings, &mut denials),
+            }
+        }
+
+        let mut response = match denials.is_empty() {
+            true => response,
+            false => {
+                info!(
+                    namespace = request.namespace,
+                    name = request.name,
+                    "Rejecting pod: {denials:?}"
+                );
+                response.deny(denials.join("; "))
+            }
+        };
+
+        if !warnings.is_empty() {
+            response.warnings = Some(warnings);
+        }
+
+        response
+    }
+
+    async fn is_exempt(
+        &self,
+        policy: &Policy,
+        namespace: Option<&str>,
+    ) -> Result<bool, kube::Error> {
+        let Some(namespace) = namespace.filter(|_| !policy.exempt_label.is_empty()) else {
+            return Ok(false);
+        };
+
+        let namespace = self.namespaces.get(namespace).await?;
+
+        Ok(namespace
+            .metadata
+            .labels
+            .as_ref()
+            .and_then(|labels| labels.get(&policy.exempt_label))
+            .is_some_and(|value| value == "true"))
+    }
+
+    async fn check(&self, policy: &Policy, image: &ImageRef) -> Outcome {
+        let Some(digest) = image.parts().digest else {
+            return Outcome::Unknown(format!(
+                "{image}: not pinned by digest, unable to verify its SBOM"
+            ));
+        };
+
+        // running pods are reported with their full image ID, so we can only match the digest
+        let cached = self
+            .map
+            .lock_state_for_read()
+            .await
+            .iter()
+            .find(|(known, _)| known.parts().digest == Some(digest))
+            .map(|(_, state)| match &state.sbom {
+                SbomState::Found(_) => Outcome::Found,
+                SbomState::Missing => Outcome::Missing(format!("{image}: no SBOM available")),
+                SbomState::Unsupported => Outcome::Missing(unsupported(image)),
+                SbomState::Err(err) => {
+                    Outcome::Unknown(format!("{image}: failed to look up SBOM: {err}"))
+                }
+                SbomState::Scheduled => {
+                    Outcome::Unknown(format!("{image}: SBOM lookup still pending"))
+                }
+            });
+
+        match (cached, policy.lookup_timeout) {
+            (Some(outcome @ (Outcome::Found | Outcome::Missing(_))), _) => outcome,
+            (cached, None) => cached.unwrap_or_else(|| {
+                Outcome::Unknown(format!("{image}: not known yet, unable to verify its SBOM"))
+            }),
+            (_, Some(timeout)) => match tokio::time::timeout(timeout, self.lookup(image)).await {
+                Ok(Ok(true)) => Outcome::Found,
+                Ok(Ok(false)) if self.source.source(image).is_none() => {
+                    Outcome::Missing(unsupported(image))
+                }
+                Ok(Ok(false)) => Outcome::Missing(format!("{image}: no SBOM available")),
+                Ok(Err(err)) => Outcome::Unknown(format!("{image}: failed to look up SBOM: {err}")),
+                Err(_) => Outcome::Unknown(format!("{image}: timed out looking up SBOM")),
+            },
+        }
+    }
+
+    /// Look up the SBOM of an image, returning if there is one.
+    ///
+    /// Images of registries without a source of SBOMs don't have one.
+    async fn lookup(&self, image: &ImageRef) -> anyhow::Result<bool> {
+        match self.source.source(image) {
+            Some(source) => Ok(source.lookup(image).await?.is_some()),
+            None => Ok(false),
+        }
+    }
+}
+
+fn unsupported(image: &ImageRef) -> String {
+    format!("{image}: no SBOM source configured for its registry")
+}
+
+/// Record a failure to evaluate the policy, according to the failure policy.
+fn failed(policy: &Policy, reason: String, warnings: &mut Vec<String>, denials: &mut Vec<String>) {
+    match (policy.mode, policy.failure_policy) {
+        (Mode::Enforce, FailurePolicy::Fail) => denials.push(reason),
+        _ => warnings.push(reason),
+    }
+}
+
+/// All distinct images of a pod, including init and ephemeral containers
+fn images(pod: &Pod) -> BTreeSet<&str> {
+    let Some(spec) = &pod.spec else {
+        return Default::default();
+    };
+
+    spec.containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+        .filter_map(|container| container.image.as_deref())
+        .chain(
+            spec.ephemeral_containers
+                .iter()
+                .flatten()
+                .filter_map(|container| container.image.as_deref()),
+        )
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bombastic::SbomSourceRouter;
+    use crate::server::webhook::validate;
+    use crate::testutil::FakeSource;
+    use actix_web::{test, web, App};
+    use bommer_api::data::{ImageBuilder, SBOM};
+    use std::collections::HashMap;
+
+    const FOUND: &str = "quay.io/app/found@sha256:1111";
+    const MISSING: &str = "quay.io/app/missing@sha256:2222";
+    const REMOTE: &str = "quay.io/app/remote@sha256:3333";
+    const STALLED: &str = "quay.io/app/stalled@sha256:4444";
+    const UNPINNED: &str = "quay.io/app/unpinned:latest";
+
+    fn policy(mode: Mode, failure_policy: FailurePolicy) -> Policy {
+        Policy {
+            mode,
+            exempt_label: String::new(),
+            lookup_timeout: Some(Duration::from_millis(50)),
+            failure_policy,
+        }
+    }
+
+    async fn admission(policy: Policy) -> Admission {
+        let map = WorkloadState::default();
+        let known = |sbom| {
+            let mut image = ImageBuilder::default().build();
+            image.sbom = sbom;
+            image
+        };
+        map.set_state(HashMap::from([
+            (
+                ImageRef(FOUND.to_string()),
+                known(SbomState::Found(vec![SBOM::new("{}".to_string())])),
+            ),
+            (ImageRef(MISSING.to_string()), known(SbomState::Missing)),
+        ]))
+        .await;
+
+        let source = FakeSource(HashMap::from([(
+            ImageRef(REMOTE.to_string()),
+            Some(SBOM::new("{}".to_string())),
+        )]));
+
+        // never contacted, as no namespace is exempt
+        let client =
+            Client::try_from(kube::Config::new("http://127.0.0.1:1".parse().unwrap())).unwrap();
+
+        Admission::new(
+            Setting::fixed(policy),
+            Default::default(),
+            map,
+            SbomSourceRouter::new(source),
+            client,
+        )
+    }
+
+    /// Post a review of a pod running `images`, returning the response
+    async fn review(policy: Policy, images: &[&str]) -> serde_json::Value {
+        let app = test::init_service(
+            App::new()
+                .app_data(web::Data::new(admission(policy).await))
+                .service(validate),
+        )
+        .await;
+
+        let containers = images
+            .iter()
+            .enumerate()
+            .map(|(n, image)| serde_json::json!({"name": format!("c{n}"), "image": image}))
+            .collect::<Vec<_>>();
+        let review = serde_json::json!({
+            "apiVersion": "admission.k8s.io/v1",
+            "kind": "AdmissionReview",
+            "request": {
+                "uid": "705ab4f5-6393-11e8-b7cc-42010a800002",
+                "kind": {"group": "", "version": "v1", "kind": "Pod"},
+                "resource": {"group": "", "version": "v1", "resource": "pods"},
+                "operation": "CREATE",
+                "userInfo": {},
+                "namespace": "default",
+                "name": "web",
+                "object": {
+                    "metadata": {"namespace": "default", "name": "web"},
+                    "spec": {"containers": containers},
+                },
+                "dryRun": false,
+            },
+        });
+
+        let request = test::TestRequest::post()
+            .uri("/validate")
+            .set_json(review)
+            .to_request();
+        let response: serde_json::Value = test::call_and_read_body_json(&app, request).await;
+        assert_eq!(
+            response["response"]["uid"],
+            "705ab4f5-6393-11e8-b7cc-42010a800002"
+        );
+        response["response"].clone()
+    }
+
+    fn warnings(response: &serde_json::Value) -> Vec<&str> {
+        response["warnings"]
+            .as_array()
+            .into_iter()
+            .flatten()
+            .filter_map(|warning| warning.as_str())
+            .collect()
+    }
+
+    #[actix_web::test]
+    async fn allows_images_with_sbom() {
+        let response = review(policy(Mode::Enforce, FailurePolicy::Fail), &[FOUND, REMOTE]).await;
+        assert_eq!(response["allowed"], true);
+        assert!(warnings(&response).is_empty());
+    }
+
+    #[actix_web::test]
+    async fn audit_warns() {
+        let response = review(policy(Mode::Audit, FailurePolicy::Fail), &[FOUND, MISSING]).await;
+        assert_eq!(response["allowed"], true);
+        assert_eq!(
+            warnings(&response),
+            vec![format!("{MISSING}: no SBOM available")]
+        );
+    }
+
+    #[actix_web::test]
+    async fn enforce_rejects() {
+        let response = review(
+            policy(Mode::Enforce, FailurePolicy::Ignore),
+            &[FOUND, MISSING],
+        )
+        .await;
+        assert_eq!(response["allowed"], false);
+        assert_eq!(
+            response["status"]["message"],
+            format!("{MISSING}: no SBOM available")
+        );
+    }
+
+    #[actix_web::test]
+    async fn fail_open() {
+        let response = review(policy(Mode::Enforce, FailurePolicy::Ignore), &[UNPINNED]).await;
+        assert_eq!(response["allowed"], true);
+        assert_eq!(
+            warnings(&response),
+            vec![format!(
+                "{UNPINNED}: not pinned by digest, unable to verify its SBOM"
+            )]
+        );
+    }
+
+    #[actix_web::test]
+    async fn fail_closed() {
+        let response = review(policy(Mode::Enforce, FailurePolicy::Fail), &[UNPINNED]).await;
+        assert_eq!(response["allowed"], false);
+        assert_eq!(
+            response["status"]["message"],
+            format!("{UNPINNED}: not pinned by digest, unable to verify its SBOM")
+        );
+    }
+
+    #[actix_web::test]
+    async fn lookup_timeout() {
+        let reason = format!("{STALLED}: timed out looking up SBOM");
+
+        let response = review(policy(Mode::Enforce, FailurePolicy::Ignore), &[STALLED]).await;
+        assert_eq!(response["allowed"], true);
+        assert_eq!(warnings(&response), vec![reason.as_str()]);
+
+        let response = review(policy(Mode::Enforce, FailurePolicy::Fail), &[STALLED]).await;
+        assert_eq!(response["allowed"], false);
+        assert_eq!(response["status"]["message"], reason);
+
+        // only enforcing rejects pods
+        let response = review(policy(Mode::Audit, FailurePolicy::Fail), &[STALLED]).await;
+        assert_eq!(response["allowed"], true);
+        assert_eq!(warnings(&response), vec![reason.as_str()]);
+    }
+}
@@ -0,0 +1,463 @@
+//! Alerts on the SBOM coverage of the workload, e.g. a namespace dropping below a threshold.
+//!
+//! Rules are evaluated periodically against the current workload. Once a rule fires, it only
+//! resolves again after its value crossed the `clear` threshold, so that a value flapping around
+//! the threshold doesn't cause a notification on every evaluation.
+
+use crate::workload::WorkloadState;
+use anyhow::bail;
+use bommer_api::data::{Image, ImageRef, SbomState};
+use reqwest::header;
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{Display, Formatter};
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+use url::Url;
+
+/// The message sent by default, see [`AlertConfig::message`]
+const DEFAULT_MESSAGE: &str =
+    "[{status}] {rule}: {metric} of {scope} is {value} (threshold {threshold})";
+
+/// Timeout for sending a single notification
+const SEND_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct AlertConfig {
+    /// endpoint notifications get posted to, e.g. a Slack incoming webhook, only logged if not set
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<Url>,
+    /// interval for evaluating the rules, in seconds
+    pub interval_secs: u64,
+    /// template of the notification text, unless the rule has one of its own
+    pub message: String,
+    pub rules: Vec<AlertRule>,
+}
+
+impl Default for AlertConfig {
+    fn default() -> Self {
+        Self {
+            url: None,
+            interval_secs: 30,
+            message: DEFAULT_MESSAGE.to_string(),
+            rules: Vec::new(),
+        }
+    }
+}
+
+impl AlertConfig {
+    /// Check the rules, which are only evaluated later on.
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !self.rules.is_empty() && self.interval_secs == 0 {
+            bail!("The interval for evaluating alert rules must not be zero");
+        }
+        for rule in &self.rules {
+            rule.validate()?;
+        }
+        Ok(())
+    }
+
+    pub fn interval(&self) -> Duration {
+        Duration::from_secs(self.interval_secs)
+    }
+}
+
+/// A threshold on a metric of the workload, or of a single namespace
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AlertRule {
+    pub name: String,
+    /// namespace the rule applies to, all of them if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub metric: AlertMetric,
+    /// fire once the value drops below this threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub below: Option<f64>,
+    /// fire once the value rises above this threshold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub above: Option<f64>,
+    /// resolve only once the value is back at this threshold, the firing one if not set
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub clear: Option<f64>,
+    /// time images entering the error state are counted for, in seconds, for `new-errors`
+    #[serde(default = "default_window_secs")]
+    pub window_secs: u64,
+    /// template of the notification text
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+fn default_window_secs() -> u64 {
+    600
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum AlertMetric {
+    /// percentage of the images with an SBOM, ignoring images still being looked up
+    Coverage,
+    /// number of images whose lookup failed
+    Errors,
+    /// number of images whose lookup started failing within the window of the rule
+    NewErrors,
+}
+
+impl Display for AlertMetric {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Coverage => "SBOM coverage",
+            Self::Errors => "failed lookups",
+            Self::NewErrors => "new failed lookups",
+        })
+    }
+}
+
+impl AlertRule {
+    fn validate(&self) -> anyhow::Result<()> {
+        let name = &self.name;
+        match (self.below, self.above, self.clear) {
+            (Some(_), Some(_), _) | (None, None, _) => {
+                bail!("Alert rule {name} needs exactly one of `below` and `above`")
+            }
+            (Some(below), None, Some(clear)) if clear < below => {
+                bail!("Alert rule {name} would clear below its threshold ({clear} < {below})")
+            }
+            (None, Some(above), Some(clear)) if clear > above => {
+                bail!("Alert rule {name} would clear above its threshold ({clear} > {above})")
+            }
+            _ => {}
+        }
+        if self.metric == AlertMetric::NewErrors && self.window_secs == 0 {
+            bail!("Alert rule {name} needs a window");
+        }
+        Ok(())
+    }
+
+    fn threshold(&self) -> f64 {
+        self.below.or(self.above).unwrap_or_default()
+    }
+
+    fn fires(&self, value: f64) -> bool {
+        match (self.below, self.above) {
+            (Some(below), _) => value < below,
+            (_, Some(above)) => value > above,
+            _ => false,
+        }
+    }
+
+    fn clears(&self, value: f64) -> bool {
+        match (self.below, self.above) {
+            (Some(below), _) => value >= self.clear.unwrap_or(below),
+            (_, Some(above)) => value <= self.clear.unwrap_or(above),
+            _ => true,
+        }
+    }
+
+    fn applies(&self, image: &Image) -> bool {
+        match &self.namespace {
+            Some(namespace) => image.pods.iter().any(|pod| &pod.namespace == namespace),
+            None => true,
+        }
+    }
+
+    fn scope(&self) -> String {
+        match &self.namespace {
+            Some(namespace) => format!("namespace {namespace}"),
+            None => "all namespaces".to_string(),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.window_secs)
+    }
+}
+
+/// The state of an alert rule
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AlertStatus {
+    pub rule: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    pub firing: bool,
+    /// the value at the last evaluation, `None` if there was nothing to evaluate, e.g. no images
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<f64>,
+    /// seconds since the epoch the rule started firing, or resolved
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<u64>,
+    pub notifications: u64,
+    pub failed_notifications: u64,
+}
+
+/// Evaluates the alert rules, and keeps track of their state.
+#[derive(Clone, Debug)]
+pub struct Alerting {
+    config: Arc<AlertConfig>,
+    status: Arc<parking_lot::Mutex<Vec<AlertStatus>>>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Transition {
+    Firing,
+    Resolved,
+}
+
+impl Display for Transition {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Self::Firing => "FIRING",
+            Self::Resolved => "RESOLVED",
+        })
+    }
+}
+
+/// The notification posted to the endpoint, `text` being understood by Slack
+#[derive(Clone, Debug, serde::Serialize)]
+struct Notification<'a> {
+    text: String,
+    alert: NotificationAlert<'a>,
+}
+
+#[derive(Clone, Debug, serde::Serialize)]
+struct NotificationAlert<'a> {
+    rule: &'a str,
+    status: Transition,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    namespace: Option<&'a str>,
+    metric: AlertMetric,
+    value: f64,
+    threshold: f64,
+}
+
+/// Images which started failing, to evaluate [`AlertMetric::NewErrors`]
+#[derive(Default)]
+struct ErrorTracker {
+    /// whether the last completed lookup of an image failed, `None` until the first evaluation
+    failed: Option<HashMap<ImageRef, bool>>,
+    /// the images which started failing, oldest first
+    entered: VecDeque<(Instant, Image)>,
+}
+
+impl ErrorTracker {
+    fn update(&mut self, state: &HashMap<ImageRef, Image>, now: Instant, keep: Duration) {
+        // images failing at startup didn't just start failing
+        let seeding = self.failed.is_none();
+        let failed = self.failed.get_or_insert_with(Default::default);
+        failed.retain(|image, _| state.contains_key(image));
+
+        for (image_ref, image) in state {
+            // re-scans don't count as a new failure
+            let is_err = match image.sbom {
+                SbomState::Scheduled => continue,
+                SbomState::Err(_) => true,
+                _ => false,
+            };
+            let was_err = failed.insert(image_ref.clone(), is_err);
+            if is_err && was_err != Some(true) && !seeding {
+                self.entered.push_back((now, image.clone()));
+            }
+        }
+
+        while let Some((at, _)) = self.entered.front() {
+            if now.duration_since(*at) <= keep {
+                break;
+            }
+            self.entered.pop_front();
+        }
+    }
+
+    fn count(&self, rule: &AlertRule, now: Instant) -> usize {
+        self.entered
+            .iter()
+            .filter(|(at, image)| now.duration_since(*at) <= rule.window() && rule.applies(image))
+            .count()
+    }
+}
+
+impl Alerting {
+    pub fn new(config: AlertConfig) -> Self {
+        let status = config
+            .rules
+            .iter()
+            .map(|rule| AlertStatus {
+                rule: rule.name.clone(),
+                namespace: rule.namespace.clone(),
+                firing: false,
+                value: None,
+                since: None,
+                notifications: 0,
+                failed_notifications: 0,
+            })
+            .collect();
+
+        Self {
+            config: Arc::new(config),
+            status: Arc::new(parking_lot::Mutex::new(status)),
+        }
+    }
+
+    /// The state of each rule, in the order of the configuration
+    pub fn status(&self) -> Vec<AlertStatus> {
+        self.status.lock().clone()
+    }
+
+    /// Evaluate the rules periodically, once the initial sync completed.
+    pub async fn run(self, map: WorkloadState) -> anyhow::Result<()> {
+        map.startup().wait().await;
+        info!("Evaluating {} alert rules", self.config.rules.len());
+
+        let client = reqwest::Client::builder().timeout(SEND_TIMEOUT).build()?;
+        let keep = self
+            .config
+            .rules
+            .iter()
+            .map(AlertRule::window)
+            .max()
+            .unwrap_or_default();
+        let mut errors = ErrorTracker::default();
+        let mut interval = tokio::time::interval(self.config.interval());
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+        loop {
+            interval.tick().await;
+
+            let now = Instant::now();
+            let state = map.get_state().await;
+            errors.update(&state, now, keep);
+
+            for (n, rule) in self.config.rules.iter().enumerate() {
+                let value = match rule.metric {
+                    AlertMetric::Coverage => coverage(rule, &state),
+                    AlertMetric::Errors => Some(
+                        state
+                            .values()
+                            .filter(|image| matches!(image.sbom, SbomState::Err(_)))
+                            .filter(|image| rule.applies(image))
+                            .count() as f64,
+                    ),
+                    AlertMetric::NewErrors => Some(errors.count(rule, now) as f64),
+                };
+
+                if let Some(transition) = self.evaluate(n, rule, value) {
+                    let value = value.unwrap_or_default();
+                    let sent = self.notify(&client, rule, transition, value).await;
+                    let mut status = self.status.lock();
+                    match sent {
+                        true => status[n].notifications += 1,
+                        false => status[n].failed_notifications += 1,
+                    }
+                }
+            }
+        }
+    }
+
+    /// Update the state of a rule with a new value, returning if it started firing or resolved.
+    fn evaluate(&self, n: usize, rule: &AlertRule, value: Option<f64>) -> Option<Transition> {
+        let mut status = self.status.lock();
+        let status = &mut status[n];
+        status.value = value;
+
+        // nothing to evaluate keeps the rule as it is
+        let value = value?;
+        let transition = match status.firing {
+            false if rule.fires(value) => Transition::Firing,
+            true if rule.clears(value) => Transition::Resolved,
+            _ => return None,
+        };
+
+        status.firing = transition == Transition::Firing;
+        status.since = Some(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        );
+        Some(transition)
+    }
+
+    /// Send a notification, returning if it was delivered.
+    async fn notify(
+        &self,
+        client: &reqwest::Client,
+        rule: &AlertRule,
+        status: Transition,
+        value: f64,
+    ) -> bool {
+        let template = rule.message.as_deref().unwrap_or(&self.config.message);
+        let text = render(template, rule, status, value);
+        match status {
+            Transition::Firing => warn!("Alert: {text}"),
+            Transition::Resolved => info!("Alert: {text}"),
+        }
+
+        let Some(url) = &self.config.url else {
+            return true;
+        };
+        let notification = Notification {
+            text,
+            alert: NotificationAlert {
+                rule: &rule.name,
+                status,
+                namespace: rule.namespace.as_deref(),
+                metric: rule.metric,
+                value,
+                threshold: rule.threshold(),
+            },
+        };
+        let body = match serde_json::to_vec(&notification) {
+            Ok(body) => body,
+            Err(err) => {
+                warn!(
+                    "Failed to serialize notification of alert {}: {err}",
+                    rule.name
+                );
+                return false;
+            }
+        };
+        let result = client
+            .post(url.clone())
+            .header(header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status());
+        if let Err(err) = &result {
+            warn!("Failed to send notification of alert {}: {err}", rule.name);
+        }
+        result.is_ok()
+    }
+}
+
+/// The percentage of the images with an SBOM, `None` if there are no looked up images
+///
+/// Images exempt from having an SBOM don't count.
+fn coverage(rule: &AlertRule, state: &HashMap<ImageRef, Image>) -> Option<f64> {
+    let (found, resolved) = state
+        .values()
+        .filter(|image| rule.applies(image) && !image.classification.is_exempt())
+        .fold((0usize, 0usize), |(found, resolved), image| {
+            match image.sbom {
+                SbomState::Scheduled => (found, resolved),
+                SbomState::Found(_) => (found + 1, resolved + 1),
+                _ => (found, resolved + 1),
+            }
+        });
+
+    (resolved > 0).then(|| 100.0 * found as f64 / resolved as f64)
+}
+
+/// Fill in the placeholders of a message template.
+fn render(template: &str, rule: &AlertRule, status: Transition, value: f64) -> String {
+    template
+        .replace("{status}", &status.to_string())
+        .replace("{rule}", &rule.name)
+        .replace("{metric}", &rule.metric.to_string())
+        .replace("{scope}", &rule.scope())
+        .replace("{namespace}", rule.namespace.as_deref().unwrap_or_default())
+        .replace("{value}", &((value * 10.0).round() / 10.0).to_string())
+        .replace("{threshold}", &rule.threshold().to_string())
+}
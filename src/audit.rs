@@ -0,0 +1,325 @@
+//! Audit records of security-relevant changes of the workload.
+
+use crate::pubsub::HookHandle;
+use crate::workload::WorkloadState;
+use bommer_api::data::{Event, Image, ImageRef, SbomState};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::future::Future;
+use std::io::Write;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::warn;
+
+/// The actor of changes made outside the scope of [`as_actor`]
+pub const SYSTEM_ACTOR: &str = "system";
+
+tokio::task_local! {
+    static ACTOR: &'static str;
+}
+
+/// Run `f`, attributing the changes it makes to the workload to `actor`.
+pub async fn as_actor<F: Future>(actor: &'static str, f: F) -> F::Output {
+    ACTOR.scope(actor, f).await
+}
+
+fn current_actor() -> &'static str {
+    ACTOR.try_with(|actor| *actor).unwrap_or(SYSTEM_ACTOR)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum AuditAction {
+    ImageAdded,
+    ImageRemoved,
+    SbomFound,
+    SbomFailed,
+}
+
+/// A security-relevant change of the workload
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct AuditRecord {
+    /// seconds since the epoch
+    pub timestamp: u64,
+    pub action: AuditAction,
+    pub image: ImageRef,
+    /// what made the change, e.g. `watcher` or `scanner`
+    pub actor: String,
+    /// details of the change, e.g. the error of a failed lookup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+/// Receives audit records.
+///
+/// Records are passed to the sink while the workload is being changed, so it must be quick.
+pub trait AuditSink: Send + Sync {
+    fn record(&self, record: &AuditRecord);
+}
+
+/// Writes audit records as JSON, one record per line.
+pub struct JsonLines<W: Write + Send> {
+    writer: parking_lot::Mutex<W>,
+}
+
+impl<W: Write + Send> JsonLines<W> {
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer: parking_lot::Mutex::new(writer),
+        }
+    }
+}
+
+impl JsonLines<File> {
+    /// Append records to a file, creating it if necessary.
+    pub fn open(path: &Path) -> std::io::Result<Self> {
+        Ok(Self::new(
+            OpenOptions::new().create(true).append(true).open(path)?,
+        ))
+    }
+}
+
+impl<W: Write + Send> AuditSink for JsonLines<W> {
+    fn record(&self, record: &AuditRecord) {
+        let mut line = match serde_json::to_vec(record) {
+            Ok(line) => line,
+            Err(err) => {
+                warn!("Failed to serialize audit record: {err}");
+                return;
+            }
+        };
+        line.push(b'\n');
+
+        // a single write, so that records don't get torn apart
+        let mut writer = self.writer.lock();
+        if let Err(err) = writer.write_all(&line).and_then(|()| writer.flush()) {
+            warn!("Failed to write audit record: {err}");
+        }
+    }
+}
+
+/// Turns the events of the workload into audit records.
+struct Auditor<S> {
+    sink: S,
+    /// the SBOM state of each known image, by its label
+    known: parking_lot::Mutex<HashMap<ImageRef, &'static str>>,
+}
+
+impl<S: AuditSink> Auditor<S> {
+    fn audit(&self, evt: &Event<ImageRef, Image>) {
+        let mut known = self.known.lock();
+        match evt {
+            Event::Added(image, state) | Event::Modified(image, state) => {
+                self.changed(&mut known, image, Some(&state.sbom))
+            }
+            Event::Removed(image) => self.changed(&mut known, image, None),
+            Event::Restart(state) => {
+                let removed = known
+                    .keys()
+                    .filter(|image| !state.contains_key(*image))
+                    .cloned()
+                    .collect::<Vec<_>>();
+                for image in removed {
+                    self.changed(&mut known, &image, None);
+                }
+                for (image, state) in state {
+                    self.changed(&mut known, image, Some(&state.sbom));
+                }
+            }
+            // only delivered to chunked subscriptions
+            Event::RestartChunk { .. } => {}
+        }
+    }
+
+    /// Record the change of an image to `sbom`, or its removal if `None`.
+    fn changed(
+        &self,
+        known: &mut HashMap<ImageRef, &'static str>,
+        image: &ImageRef,
+        sbom: Option<&SbomState>,
+    ) {
+        let Some(sbom) = sbom else {
+            if known.remove(image).is_some() {
+                self.record(AuditAction::ImageRemoved, image, None);
+            }
+            return;
+        };
+
+        let before = known.insert(image.clone(), sbom.label());
+        if before.is_none() {
+            self.record(AuditAction::ImageAdded, image, None);
+        }
+        if before == Some(sbom.label()) {
+            return;
+        }
+        match sbom {
+            SbomState::Found(_) => self.record(AuditAction::SbomFound, image, None),
+            SbomState::Err(err) => self.record(AuditAction::SbomFailed, image, Some(err.clone())),
+            SbomState::Scheduled | SbomState::Missing | SbomState::Unsupported => {}
+        }
+    }
+
+    fn record(&self, action: AuditAction, image: &ImageRef, detail: Option<String>) {
+        self.sink.record(&AuditRecord {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            action,
+            image: image.clone(),
+            actor: current_actor().to_string(),
+            detail,
+        });
+    }
+}
+
+/// Pass audit records of all changes of the workload to `sink`, from now on.
+///
+/// The images present when attaching the sink are recorded as being added. Dropping the returned
+/// handle detaches the sink again.
+pub async fn attach(map: &WorkloadState, sink: impl AuditSink + 'static) -> HookHandle {
+    let auditor = Auditor {
+        sink,
+        known: Default::default(),
+    };
+
+    // attach while holding the lock, so that no change gets missed in between
+    let state = map.lock_state_for_read().await;
+    auditor.audit(&Event::Restart((*state).clone()));
+    let hook = map.add_hook(move |evt| auditor.audit(evt));
+    drop(state);
+    hook
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::{ImageBuilder, PodRef, SBOM};
+    use std::sync::Arc;
+
+    #[derive(Clone, Default)]
+    struct Records(Arc<parking_lot::Mutex<Vec<AuditRecord>>>);
+
+    impl AuditSink for Records {
+        fn record(&self, record: &AuditRecord) {
+            self.0.lock().push(record.clone());
+        }
+    }
+
+    impl Records {
+        /// The records since the last call, summarized
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.0.lock())
+                .into_iter()
+                .map(|record| {
+                    let summary =
+                        format!("{:?} {} by {}", record.action, record.image, record.actor);
+                    match record.detail {
+                        Some(detail) => format!("{summary}: {detail}"),
+                        None => summary,
+                    }
+                })
+                .collect::<Vec<_>>()
+        }
+    }
+
+    fn image(name: &str) -> ImageRef {
+        ImageRef(name.to_string())
+    }
+
+    fn running(pods: usize, sbom: SbomState) -> Image {
+        let mut image = ImageBuilder::default();
+        for i in 0..pods {
+            image.pod(PodRef {
+                namespace: "default".to_string(),
+                name: format!("pod-{i}"),
+            });
+        }
+        image.sbom(sbom).build()
+    }
+
+    #[tokio::test]
+    async fn audit_transitions() {
+        let map = WorkloadState::default();
+        map.mutate_state(image("a"), |_| Some(running(1, SbomState::Scheduled)))
+            .await;
+
+        let records = Records::default();
+        let _hook = attach(&map, records.clone()).await;
+        assert_eq!(records.take(), ["ImageAdded a by system"]);
+
+        as_actor(
+            "watcher",
+            map.mutate_state(image("b"), |_| Some(running(1, SbomState::Scheduled))),
+        )
+        .await;
+        as_actor("scanner", async {
+            map.mutate_state(image("b"), |_| {
+                Some(running(1, SbomState::Err("timeout".to_string())))
+            })
+            .await;
+            map.mutate_state(image("b"), |_| {
+                Some(running(
+                    1,
+                    SbomState::Found(vec![SBOM::new("{}".to_string())]),
+                ))
+            })
+            .await;
+        })
+        .await;
+        assert_eq!(
+            records.take(),
+            [
+                "ImageAdded b by watcher",
+                "SbomFailed b by scanner: timeout",
+                "SbomFound b by scanner",
+            ]
+        );
+
+        // pod changes aren't relevant
+        map.mutate_state(image("b"), |image| {
+            image.map(|image| Image {
+                pods: running(2, SbomState::Scheduled).pods,
+                ..image
+            })
+        })
+        .await;
+        assert!(records.take().is_empty());
+
+        // a restart reports the images gone in the meantime
+        map.set_state(HashMap::from([(
+            image("b"),
+            running(2, SbomState::Missing),
+        )]))
+        .await;
+        map.remove_state(image("b")).await;
+        assert_eq!(
+            records.take(),
+            ["ImageRemoved a by system", "ImageRemoved b by system"]
+        );
+    }
+
+    #[test]
+    fn json_lines() {
+        let sink = JsonLines::new(Vec::new());
+        for action in [AuditAction::ImageAdded, AuditAction::SbomFailed] {
+            sink.record(&AuditRecord {
+                timestamp: 1,
+                action,
+                image: image("a"),
+                actor: SYSTEM_ACTOR.to_string(),
+                detail: None,
+            });
+        }
+
+        let written = String::from_utf8(sink.writer.into_inner()).unwrap();
+        assert_eq!(
+            written.lines().collect::<Vec<_>>(),
+            [
+                r#"{"timestamp":1,"action":"imageAdded","image":"a","actor":"system"}"#,
+                r#"{"timestamp":1,"action":"sbomFailed","image":"a","actor":"system"}"#,
+            ]
+        );
+    }
+}
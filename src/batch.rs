@@ -0,0 +1,185 @@
+use crate::bombastic::{self, SbomSourceRouter, ScanOptions};
+use crate::export::{self, Filter, Format};
+use crate::store::image_store;
+use crate::workload::WorkloadState;
+use anyhow::bail;
+use bommer_api::data::{Image, ImageRef, SbomState};
+use k8s_openapi::api::core::v1::Pod;
+use kube::api::ListParams;
+use kube::runtime::watcher;
+use kube::Api;
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::io::BufWriter;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct BatchOptions {
+    /// Exit once the initial sync and all SBOM lookups are complete, instead of watching
+    #[arg(long)]
+    pub exit_after_sync: bool,
+
+    /// Maximum time to wait for the sync to complete, in seconds
+    #[arg(long, requires = "exit_after_sync")]
+    pub sync_timeout: Option<u64>,
+
+    /// Write the resulting workload, as JSON, to this file
+    #[arg(long, requires = "exit_after_sync")]
+    pub output: Option<PathBuf>,
+}
+
+/// Number of images, by SBOM state
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Summary {
+    pub images: usize,
+    /// exempt from having an SBOM by the image policy, not counted by state
+    pub exempt: usize,
+    pub found: usize,
+    pub missing: usize,
+    pub unsupported: usize,
+    pub failed: usize,
+    pub scheduled: usize,
+}
+
+impl Summary {
+    pub fn new<'a>(images: impl IntoIterator<Item = &'a Image>) -> Self {
+        let mut summary = Self::default();
+
+        for image in images {
+            summary.images += 1;
+            if image.classification.is_exempt() {
+                summary.exempt += 1;
+                continue;
+            }
+            match image.sbom {
+                SbomState::Scheduled => summary.scheduled += 1,
+                SbomState::Err(_) => summary.failed += 1,
+                SbomState::Missing => summary.missing += 1,
+                SbomState::Unsupported => summary.unsupported += 1,
+                SbomState::Found(_) => summary.found += 1,
+            }
+        }
+
+        summary
+    }
+
+    /// All lookups completed, without an error
+    pub fn is_resolved(&self) -> bool {
+        self.failed == 0 && self.scheduled == 0
+    }
+}
+
+impl Display for Summary {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "images: {}", self.images)?;
+        writeln!(f, "exempt: {}", self.exempt)?;
+        writeln!(f, "found: {}", self.found)?;
+        writeln!(f, "missing: {}", self.missing)?;
+        writeln!(f, "unsupported: {}", self.unsupported)?;
+        writeln!(f, "failed: {}", self.failed)?;
+        write!(f, "scheduled: {}", self.scheduled)
+    }
+}
+
+/// List all pods once, look up their SBOMs, and report the result.
+///
+/// The exit code is only successful if all lookups completed without an error.
+pub async fn run(
+    api: Api<Pod>,
+    source: SbomSourceRouter,
+    scan: ScanOptions,
+    options: &BatchOptions,
+) -> anyhow::Result<ExitCode> {
+    let state = sync(api, source, scan, options.sync_timeout).await?;
+    let summary = Summary::new(state.values());
+    println!("{summary}");
+
+    if let Some(output) = &options.output {
+        let workload = Filter::default().apply(state);
+        export::write(
+            Format::Json,
+            &workload,
+            BufWriter::new(File::create(output)?),
+        )?;
+        info!("Wrote workload to: {}", output.display());
+    }
+
+    Ok(match summary.is_resolved() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// List all pods once, and wait until their SBOMs have been looked up.
+///
+/// If the sync doesn't complete within `timeout` seconds, the state is returned as it is,
+/// leaving some images scheduled. The scan is meant to run once, see
+/// [`Config::single_scan_options`](crate::config::Config::single_scan_options).
+pub async fn sync(
+    api: Api<Pod>,
+    source: SbomSourceRouter,
+    scan: ScanOptions,
+    timeout: Option<u64>,
+) -> anyhow::Result<HashMap<ImageRef, Image>> {
+    let pods = api.list(&ListParams::default()).await?.items;
+    info!("Found {} pods", pods.len());
+
+    // a single "restart" event, instead of a watch
+    let (store, runner) = image_store(futures::stream::iter([Ok(watcher::Event::Restarted(pods))]));
+    runner.await?;
+
+    let expected = store.get_state().await.into_keys().collect::<HashSet<_>>();
+
+    let (map, pipeline) = bombastic::store(store, source, scan);
+
+    let synced = async {
+        let synced = wait_for_sync(&map, &expected);
+        match timeout {
+            Some(timeout) => tokio::time::timeout(Duration::from_secs(timeout), synced)
+                .await
+                .is_ok(),
+            None => {
+                synced.await;
+                true
+            }
+        }
+    };
+
+    let completed = tokio::select! {
+        result = pipeline => {
+            result?;
+            bail!("SBOM pipeline ended unexpectedly");
+        }
+        completed = synced => completed,
+    };
+
+    if !completed {
+        warn!("Timed out waiting for the sync to complete");
+    }
+
+    Ok(map.get_state().await)
+}
+
+/// Wait until all expected images are present, and none of them is still scheduled.
+async fn wait_for_sync(map: &WorkloadState, expected: &HashSet<ImageRef>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(500));
+
+    loop {
+        interval.tick().await;
+
+        let state = map.get_state().await;
+        let complete = state.len() == expected.len()
+            && expected.iter().all(|image| state.contains_key(image))
+            && !state
+                .values()
+                .any(|image| matches!(image.sbom, SbomState::Scheduled));
+
+        if complete {
+            break;
+        }
+    }
+}
@@ -0,0 +1,361 @@
+//! Persistent cache of SBOM lookup results, keyed by image digest.
+//!
+//! The cache file starts with a header line, carrying the format version and a checksum of the
+//! rest of the file, which is the JSON serialized payload. A file which can't be read, or doesn't
+//! match its checksum, is discarded with a warning, starting with an empty cache.
+
+use anyhow::{anyhow, bail, Context};
+use bommer_api::data::{ImageRef, SbomState};
+use std::collections::HashMap;
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+const FORMAT: &str = "bommer-sbom-cache";
+
+/// The current version of the payload, see [`migrate`] for reading older ones.
+const VERSION: u32 = 1;
+
+#[derive(Debug, serde::Serialize, serde::Deserialize)]
+struct Header {
+    format: String,
+    version: u32,
+    /// SHA-256 of the payload, hex encoded
+    checksum: String,
+}
+
+#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
+struct Payload {
+    entries: HashMap<String, CacheEntry>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CacheEntry {
+    state: SbomState,
+    /// last lookup, in seconds since the epoch
+    checked: u64,
+    /// number of lookups of this digest
+    attempts: u32,
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    entries: HashMap<String, CacheEntry>,
+    /// changed since the last flush
+    dirty: bool,
+}
+
+/// Lookup results, surviving restarts.
+///
+/// Only found and missing SBOMs are handed out, and only until they are older than the TTL.
+/// After that, images get looked up again once they are seen.
+#[derive(Debug)]
+pub struct SbomCache {
+    path: PathBuf,
+    ttl: Duration,
+    inner: parking_lot::Mutex<Inner>,
+}
+
+impl SbomCache {
+    /// Open the cache, starting cold if the file doesn't exist or can't be used.
+    pub fn open(path: PathBuf, ttl: Duration) -> Self {
+        let entries = match read(&path) {
+            Ok(Some(entries)) => {
+                info!(
+                    "Loaded {} cached SBOM results from {}",
+                    entries.len(),
+                    path.display()
+                );
+                entries
+            }
+            Ok(None) => Default::default(),
+            Err(err) => {
+                warn!(
+                    "Discarding SBOM cache {}, starting cold: {err:#}",
+                    path.display()
+                );
+                Default::default()
+            }
+        };
+
+        Self {
+            path,
+            ttl,
+            inner: parking_lot::Mutex::new(Inner {
+                entries,
+                dirty: false,
+            }),
+        }
+    }
+
+    /// Get the cached state of an image, if it is still fresh.
+    pub fn get(&self, image: &ImageRef) -> Option<SbomState> {
+        let digest = image.parts().digest?;
+        let inner = self.inner.lock();
+        let entry = inner.entries.get(digest)?;
+
+        let fresh = now().saturating_sub(entry.checked) < self.ttl.as_secs();
+        match &entry.state {
+            SbomState::Found(_) | SbomState::Missing if fresh => Some(entry.state.clone()),
+            _ => None,
+        }
+    }
+
+    /// Record the outcome of a lookup.
+    pub fn record(&self, image: &ImageRef, state: &SbomState) {
+        let Some(digest) = image.parts().digest else {
+            return;
+        };
+
+        let mut inner = self.inner.lock();
+        let attempts = inner
+            .entries
+            .get(digest)
+            .map(|entry| entry.attempts)
+            .unwrap_or_default();
+        inner.entries.insert(
+            digest.to_string(),
+            CacheEntry {
+                state: state.clone(),
+                checked: now(),
+                attempts: attempts.saturating_add(1),
+            },
+        );
+        inner.dirty = true;
+    }
+
+    /// Write the cache to its file, if it changed.
+    pub fn flush(&self) -> anyhow::Result<()> {
+        let payload = {
+            let mut inner = self.inner.lock();
+            if !std::mem::take(&mut inner.dirty) {
+                return Ok(());
+            }
+            serde_json::to_vec(&Payload {
+                entries: inner.entries.clone(),
+            })?
+        };
+
+        let result = write(&self.path, &payload);
+        if result.is_err() {
+            // try again next time
+            self.inner.lock().dirty = true;
+        }
+        result
+    }
+
+    /// Periodically flush the cache, and a last time once shutting down.
+    pub async fn run(
+        self: Arc<Self>,
+        interval: Duration,
+        shutdown: CancellationToken,
+    ) -> anyhow::Result<()> {
+        loop {
+            let cancelled = tokio::select! {
+                _ = tokio::time::sleep(interval) => false,
+                _ = shutdown.cancelled() => true,
+            };
+            if let Err(err) = self.flush() {
+                warn!("Failed to write SBOM cache: {err:#}");
+            }
+            if cancelled {
+                return Ok(());
+            }
+        }
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+fn checksum(payload: &[u8]) -> String {
+    openssl::sha::sha256(payload)
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+fn read(path: &Path) -> anyhow::Result<Option<HashMap<String, CacheEntry>>> {
+    let data = match std::fs::read(path) {
+        Ok(data) => data,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    let (header, payload) = data
+        .iter()
+        .position(|b| *b == b'\n')
+        .map(|n| (&data[..n], &data[n + 1..]))
+        .ok_or_else(|| anyhow!("missing header"))?;
+
+    let header: Header = serde_json::from_slice(header).context("invalid header")?;
+    if header.format != FORMAT {
+        bail!("unknown format: {}", header.format);
+    }
+    if header.checksum != checksum(payload) {
+        bail!("checksum mismatch");
+    }
+
+    let payload = migrate(header.version, serde_json::from_slice(payload)?)?;
+    Ok(Some(serde_json::from_value::<Payload>(payload)?.entries))
+}
+
+/// Bring a payload of an older version up to the current one.
+///
+/// Each future version adds a step converting from its predecessor, so that older files can still
+/// be read after an upgrade.
+fn migrate(version: u32, payload: serde_json::Value) -> anyhow::Result<serde_json::Value> {
+    match version {
+        VERSION => Ok(payload),
+        version if version > VERSION => {
+            bail!("unsupported version {version}, newer than {VERSION}")
+        }
+        version => bail!("unsupported version {version}"),
+    }
+}
+
+fn write(path: &Path, payload: &[u8]) -> anyhow::Result<()> {
+    let header = serde_json::to_vec(&Header {
+        format: FORMAT.to_string(),
+        version: VERSION,
+        checksum: checksum(payload),
+    })?;
+
+    // write to a temporary file first, so that we never leave a truncated cache behind
+    let temp = path.with_extension("tmp");
+    let mut file = std::fs::File::create(&temp)?;
+    file.write_all(&header)?;
+    file.write_all(b"\n")?;
+    file.write_all(payload)?;
+    file.sync_all()?;
+    std::fs::rename(&temp, path)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::SBOM;
+
+    const TTL: Duration = Duration::from_secs(3600);
+
+    fn image(name: &str) -> ImageRef {
+        ImageRef(format!("quay.io/{name}@sha256:{name}"))
+    }
+
+    fn found() -> SbomState {
+        SbomState::Found(vec![SBOM::new("{}".to_string())])
+    }
+
+    /// Write a cache file with the given header, and a valid payload.
+    fn write_with_header(path: &Path, format: &str, version: u32) {
+        let payload = serde_json::to_vec(&Payload::default()).unwrap();
+        let header = serde_json::to_vec(&Header {
+            format: format.to_string(),
+            version,
+            checksum: checksum(&payload),
+        })
+        .unwrap();
+        std::fs::write(path, [header, b"\n".to_vec(), payload].concat()).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        let cache = SbomCache::open(path.clone(), TTL);
+        cache.record(&image("a"), &found());
+        cache.record(&image("b"), &SbomState::Missing);
+        cache.record(&image("c"), &SbomState::Err("timeout".to_string()));
+        // no digest, nothing to key it by
+        cache.record(&ImageRef("quay.io/d:1".to_string()), &found());
+        cache.flush().unwrap();
+        assert!(!path.with_extension("tmp").exists());
+
+        let cache = SbomCache::open(path.clone(), TTL);
+        assert!(
+            matches!(cache.get(&image("a")), Some(SbomState::Found(sboms)) if sboms.len() == 1)
+        );
+        assert_eq!(cache.get(&image("b")), Some(SbomState::Missing));
+        // failed lookups are recorded, but never handed out
+        assert_eq!(cache.get(&image("c")), None);
+        assert_eq!(cache.inner.lock().entries.len(), 3);
+        assert_eq!(read(&path).unwrap().unwrap()["sha256:a"].attempts, 1);
+    }
+
+    #[test]
+    fn checksum_mismatch() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        let cache = SbomCache::open(path.clone(), TTL);
+        cache.record(&image("a"), &SbomState::Missing);
+        cache.flush().unwrap();
+
+        let data = std::fs::read_to_string(&path).unwrap();
+        std::fs::write(&path, data.replace("missing", "scheduled")).unwrap();
+        assert_eq!(read(&path).unwrap_err().to_string(), "checksum mismatch");
+
+        // starts cold
+        let cache = SbomCache::open(path, TTL);
+        assert_eq!(cache.get(&image("a")), None);
+        assert!(cache.inner.lock().entries.is_empty());
+    }
+
+    #[test]
+    fn rejects_unknown_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("cache.json");
+
+        write_with_header(&path, "something-else", VERSION);
+        assert_eq!(
+            read(&path).unwrap_err().to_string(),
+            "unknown format: something-else"
+        );
+
+        write_with_header(&path, FORMAT, VERSION + 1);
+        assert_eq!(
+            read(&path).unwrap_err().to_string(),
+            format!("unsupported version {}, newer than {VERSION}", VERSION + 1)
+        );
+        assert!(SbomCache::open(path.clone(), TTL)
+            .inner
+            .lock()
+            .entries
+            .is_empty());
+
+        write_with_header(&path, FORMAT, VERSION);
+        assert_eq!(read(&path).unwrap().unwrap().len(), 0);
+    }
+
+    #[test]
+    fn expires_after_ttl() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = SbomCache::open(dir.path().join("cache.json"), TTL);
+        cache.record(&image("a"), &found());
+        cache.record(&image("b"), &SbomState::Missing);
+        assert!(cache.get(&image("a")).is_some());
+
+        // pretend the lookups happened a while ago
+        for (digest, age) in [("sha256:a", TTL.as_secs() - 1), ("sha256:b", TTL.as_secs())] {
+            cache.inner.lock().entries.get_mut(digest).unwrap().checked = now() - age;
+        }
+        assert!(cache.get(&image("a")).is_some());
+        assert_eq!(cache.get(&image("b")), None);
+
+        // looking it up again makes it fresh
+        cache.record(&image("b"), &SbomState::Missing);
+        assert_eq!(cache.get(&image("b")), Some(SbomState::Missing));
+        assert_eq!(cache.inner.lock().entries["sha256:b"].attempts, 2);
+    }
+}
@@ -1,3 +1,4 @@
+use crate::telemetry;
 use bommer_api::data::SBOM;
 use packageurl::PackageUrl;
 use reqwest::{StatusCode, Url};
@@ -30,6 +31,7 @@ impl BombasticSource {
             .client
             .get(self.url.join("/api/v1/sbom")?)
             .query(&[("purl", purl.to_string())])
+            .headers(telemetry::trace_headers())
             .send()
             .await?;
 
@@ -40,8 +42,6 @@ impl BombasticSource {
 
         let response = response.error_for_status()?;
 
-        Ok(Some(SBOM {
-            data: response.text().await?,
-        }))
+        Ok(Some(SBOM::new(response.text().await?)))
     }
 }
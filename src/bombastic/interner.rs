@@ -0,0 +1,119 @@
+//! Content-addressed storage of SBOM data, sharing it between images with identical SBOMs.
+
+use bommer_api::data::{SbomState, SBOM};
+use std::collections::HashMap;
+use std::sync::{Arc, Weak};
+
+/// Don't bother pruning released data below this number of entries
+const MIN_PRUNE: usize = 64;
+
+#[derive(Debug, Default)]
+struct Inner {
+    /// data by its SHA-256 digest, released once no image uses it anymore
+    entries: HashMap<String, Weak<str>>,
+    /// number of entries after the last pruning
+    pruned: usize,
+}
+
+/// Interns the data of SBOMs by its digest, so that images built from the same base, or re-using
+/// the same SBOM otherwise, share a single copy of it.
+///
+/// The store only keeps weak references, so data gets released once the last image using it is
+/// gone.
+#[derive(Debug, Default)]
+pub struct SbomInterner {
+    inner: parking_lot::Mutex<Inner>,
+}
+
+impl SbomInterner {
+    /// Replace the data of an SBOM with a shared copy, if there is one, or share it from now on.
+    pub fn intern(&self, mut sbom: SBOM) -> SBOM {
+        let digest = sbom.content_digest();
+        let mut inner = self.inner.lock();
+
+        match inner.entries.get(&digest).and_then(Weak::upgrade) {
+            Some(data) => sbom.data = data,
+            None => {
+                inner.entries.insert(digest, Arc::downgrade(&sbom.data));
+                inner.prune();
+            }
+        }
+
+        sbom
+    }
+
+    /// Intern the SBOMs of a lookup result.
+    pub fn intern_state(&self, state: SbomState) -> SbomState {
+        match state {
+            SbomState::Found(sboms) => {
+                SbomState::Found(sboms.into_iter().map(|sbom| self.intern(sbom)).collect())
+            }
+            state => state,
+        }
+    }
+
+    /// Number of distinct SBOMs which are still in use.
+    pub fn len(&self) -> usize {
+        self.inner
+            .lock()
+            .entries
+            .values()
+            .filter(|data| data.strong_count() > 0)
+            .count()
+    }
+}
+
+impl Inner {
+    /// Drop the entries of released data, once the number of entries doubled since the last time.
+    fn prune(&mut self) {
+        if self.entries.len() >= MIN_PRUNE.max(self.pruned * 2) {
+            self.entries.retain(|_, data| data.strong_count() > 0);
+            self.pruned = self.entries.len();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn share_identical_data() {
+        let interner = SbomInterner::default();
+
+        let a = interner.intern(SBOM::new(r#"{"bomFormat":"CycloneDX"}"#.to_string()));
+        let b = interner.intern(SBOM::new(r#"{"bomFormat":"CycloneDX"}"#.to_string()));
+        let c = interner.intern(SBOM::new("{}".to_string()));
+        assert!(Arc::ptr_eq(&a.data, &b.data));
+        assert!(!Arc::ptr_eq(&a.data, &c.data));
+        assert_eq!(interner.len(), 2);
+
+        // still in use by the other image
+        drop(a);
+        assert_eq!(interner.len(), 2);
+        drop(b);
+        assert_eq!(interner.len(), 1);
+
+        let SbomState::Found(sboms) =
+            interner.intern_state(SbomState::Found(vec![SBOM::new("{}".to_string())]))
+        else {
+            panic!("must stay found");
+        };
+        assert!(Arc::ptr_eq(&sboms[0].data, &c.data));
+        assert_eq!(
+            interner.intern_state(SbomState::Missing),
+            SbomState::Missing
+        );
+    }
+
+    #[test]
+    fn prune_released_data() {
+        let interner = SbomInterner::default();
+
+        for i in 0..MIN_PRUNE * 4 {
+            interner.intern(SBOM::new(format!("{{\"serialNumber\":{i}}}")));
+        }
+        assert_eq!(interner.len(), 0);
+        assert!(interner.inner.lock().entries.len() < MIN_PRUNE * 4);
+    }
+}
@@ -0,0 +1,182 @@
+//! Resolving manifest lists (multi-arch images) to the images of single architectures, using the
+//! distribution API of the registry.
+//!
+//! SBOMs are usually published for the image of each architecture, while pods report the digest
+//! of the manifest list. Registries are accessed anonymously, so only public images get resolved.
+
+use super::registry;
+use anyhow::{bail, Context};
+use bommer_api::data::ImageRef;
+use reqwest::header::{self, HeaderValue};
+use reqwest::{Response, StatusCode};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// Media types of manifest lists, along with the ones of single manifests, which aren't resolved
+const ACCEPT: &str = "application/vnd.oci.image.index.v1+json, \
+    application/vnd.docker.distribution.manifest.list.v2+json, \
+    application/vnd.oci.image.manifest.v1+json, \
+    application/vnd.docker.distribution.manifest.v2+json";
+
+/// Host of the registry API of Docker Hub, which differs from the registry in image references
+const DOCKER_HUB: &str = "registry-1.docker.io";
+
+#[derive(Clone, Debug)]
+pub struct ManifestResolver {
+    client: reqwest::Client,
+    /// architectures to resolve, e.g. the ones of the nodes
+    architectures: Vec<String>,
+}
+
+/// The parts of a manifest (list) we need, manifests of single images have no `manifests`
+#[derive(Debug, serde::Deserialize)]
+struct Manifest {
+    manifests: Option<Vec<Descriptor>>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Descriptor {
+    digest: String,
+    platform: Option<Platform>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+struct Platform {
+    architecture: String,
+    os: String,
+}
+
+/// Docker Hub returns both fields, other registries only one of them
+#[derive(Debug, serde::Deserialize)]
+struct Token {
+    token: Option<String>,
+    access_token: Option<String>,
+}
+
+impl ManifestResolver {
+    pub fn new(architectures: Vec<String>) -> Self {
+        Self {
+            client: reqwest::Client::builder()
+                .timeout(Duration::from_secs(10))
+                .build()
+                .unwrap_or_default(),
+            architectures,
+        }
+    }
+
+    /// The images of the architectures to resolve, `None` if the image isn't a manifest list.
+    ///
+    /// Architectures missing from the manifest list are left out, as they can't run the image.
+    /// Only Linux images count, and for an architecture with several variants, the first one.
+    pub async fn resolve(
+        &self,
+        image: &ImageRef,
+    ) -> anyhow::Result<Option<BTreeMap<String, ImageRef>>> {
+        let parts = image.parts();
+        let (Some(digest), Some((name, _))) = (parts.digest, image.0.rsplit_once('@')) else {
+            return Ok(None);
+        };
+        // some runtimes only report the digest
+        if parts.repository.is_empty() {
+            return Ok(None);
+        }
+
+        let (host, repository) = match registry(image) {
+            "docker.io" if !parts.repository.contains('/') => {
+                (DOCKER_HUB, format!("library/{}", parts.repository))
+            }
+            "docker.io" => (DOCKER_HUB, parts.repository.to_string()),
+            registry => (registry, parts.repository.to_string()),
+        };
+        let url = format!("https://{host}/v2/{repository}/manifests/{digest}");
+
+        let mut response = self.get(&url, None).await?;
+        if response.status() == StatusCode::UNAUTHORIZED {
+            let token = self.token(&response).await?;
+            response = self.get(&url, Some(&token)).await?;
+        }
+        let body = response.error_for_status()?.text().await?;
+        let manifest = serde_json::from_str::<Manifest>(&body)
+            .with_context(|| format!("Invalid manifest of {image}"))?;
+
+        let Some(manifests) = manifest.manifests else {
+            return Ok(None);
+        };
+
+        let mut images = BTreeMap::new();
+        for Descriptor { digest, platform } in manifests {
+            let Some(Platform { architecture, os }) = platform else {
+                continue;
+            };
+            if os == "linux" && self.architectures.contains(&architecture) {
+                images
+                    .entry(architecture)
+                    .or_insert_with(|| ImageRef(format!("{name}@{digest}")));
+            }
+        }
+
+        Ok(Some(images))
+    }
+
+    async fn get(&self, url: &str, token: Option<&str>) -> reqwest::Result<Response> {
+        let mut request = self
+            .client
+            .get(url)
+            .header(header::ACCEPT, HeaderValue::from_static(ACCEPT));
+        if let Some(token) = token {
+            request = request.bearer_auth(token);
+        }
+        request.send().await
+    }
+
+    /// Get an anonymous token, as asked for by the challenge of a response.
+    async fn token(&self, response: &Response) -> anyhow::Result<String> {
+        let Some(params) = response
+            .headers()
+            .get(header::WWW_AUTHENTICATE)
+            .and_then(|value| value.to_str().ok())
+            .and_then(challenge)
+        else {
+            bail!("Registry requires authentication, but didn't ask for a bearer token");
+        };
+        let Some(realm) = params.get("realm") else {
+            bail!("Registry asked for a bearer token, but without a realm");
+        };
+
+        let query = params
+            .iter()
+            .filter(|(key, _)| matches!(**key, "service" | "scope"))
+            .collect::<Vec<_>>();
+        let body = self
+            .client
+            .get(*realm)
+            .query(&query)
+            .send()
+            .await?
+            .error_for_status()?
+            .text()
+            .await?;
+
+        let token = serde_json::from_str::<Token>(&body)?;
+        token
+            .token
+            .or(token.access_token)
+            .context("Token response without a token")
+    }
+}
+
+/// The parameters of a bearer challenge, e.g.
+/// `Bearer realm="https://auth.docker.io/token",service="registry.docker.io"`.
+fn challenge(value: &str) -> Option<BTreeMap<&str, &str>> {
+    let mut rest = value.strip_prefix("Bearer ")?;
+
+    let mut params = BTreeMap::new();
+    while let Some((key, value)) = rest.split_once("=\"") {
+        // values are quoted, and may contain commas, e.g. a scope of several actions
+        let (value, tail) = value.split_once('"')?;
+        params.insert(key.trim_start_matches([',', ' ']), value);
+        rest = tail;
+    }
+
+    Some(params)
+}
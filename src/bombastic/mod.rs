@@ -1,65 +1,202 @@
+mod cache;
 mod client;
+mod interner;
+mod manifest;
+mod queue;
+mod source;
 
+pub use cache::SbomCache;
 pub use client::BombasticSource;
+pub use interner::SbomInterner;
+pub use manifest::ManifestResolver;
+pub use queue::{LookupQueue, LookupQueueStatus, DEFAULT_LOOKUP_QUEUE_CAPACITY};
+pub use source::{registry, SbomSource, SbomSourceRouter};
 
-use crate::pubsub::Output;
+use crate::audit;
+use crate::policy::ImagePolicy;
+use crate::pubsub::{Output, ShrinkPolicy};
+use crate::reload::Setting;
 use crate::store::Store;
-use crate::workload::WorkloadState;
+use crate::workload::{LimitDecision, Progress, WorkloadState};
 use anyhow::bail;
-use bommer_api::data::{Event, Image, ImageRef, PodRef, SbomState, SBOM};
+use bommer_api::data::{
+    ArchitectureSbom, Event, ImageBuilder, ImageRef, PodRef, SbomState, SbomStateTransition,
+    WorkloadDiff,
+};
 use futures::FutureExt;
 use packageurl::PackageUrl;
+use std::collections::{BTreeMap, HashMap};
 use std::future::Future;
+use std::sync::Arc;
 use std::time::Duration;
-use tracing::{info, warn};
+use tracing::{debug, field, info, info_span, warn, Instrument};
+
+#[derive(Clone, Debug, Default)]
+pub struct ScanOptions {
+    /// interval for re-scanning images with a missing or failed SBOM, disabled if `None`
+    pub rescan_interval: Setting<Option<Duration>>,
+    /// reject invalid state transitions, instead of only logging them
+    pub strict_transitions: Setting<bool>,
+    /// results of earlier lookups, possibly from before a restart
+    pub cache: Option<Arc<SbomCache>>,
+    /// when to release unused memory of the pods, after they got removed
+    pub shrink: Option<ShrinkPolicy>,
+    /// classifies the images, by what is expected of their SBOM
+    pub policy: Arc<ImagePolicy>,
+    /// resolves manifest lists without an SBOM of their own, disabled if `None`
+    pub manifests: Option<ManifestResolver>,
+}
 
 pub fn store(
     store: Store<ImageRef, PodRef, ()>,
-    source: BombasticSource,
+    source: SbomSourceRouter,
+    options: ScanOptions,
 ) -> (WorkloadState, impl Future<Output = anyhow::Result<()>>) {
     let map = WorkloadState::default();
 
-    (map.clone(), async move {
-        let (result, _, _) = futures::future::select_all([
-            runner(store, map.clone()).boxed_local(),
-            scanner(map.clone(), source).boxed_local(),
-            rescanner(map).boxed_local(),
-        ])
-        .await;
+    (map.clone(), run(map, store, source, options))
+}
+
+/// Feed the SBOM state of the images of a store into an existing workload state.
+pub async fn run(
+    map: WorkloadState,
+    store: Store<ImageRef, PodRef, ()>,
+    source: SbomSourceRouter,
+    options: ScanOptions,
+) -> anyhow::Result<()> {
+    let interner = Arc::new(SbomInterner::default());
+    let (result, _, _) = futures::future::select_all([
+        audit::as_actor(
+            "watcher",
+            runner(
+                store,
+                map.clone(),
+                options.cache.clone(),
+                interner.clone(),
+                options.policy,
+            ),
+        )
+        .boxed_local(),
+        audit::as_actor(
+            "scanner",
+            scanner(
+                map.clone(),
+                source,
+                options.manifests,
+                options.strict_transitions,
+                options.cache,
+                interner,
+            ),
+        )
+        .boxed_local(),
+        audit::as_actor("rescanner", rescanner(map, options.rescan_interval)).boxed_local(),
+    ])
+    .await;
 
-        result
-    })
+    result
+}
+
+/// Create the PURL of an image, which requires the image to be pinned by its digest.
+pub fn purl(image: &ImageRef) -> anyhow::Result<PackageUrl<'_>> {
+    if let Some((base, digest)) = image.0.rsplit_once('@') {
+        if let Some(name) = base.split('/').last() {
+            let mut purl = PackageUrl::new("oci", name)?;
+            if digest.starts_with("sha256:") {
+                purl.with_version(digest);
+                return Ok(purl);
+            }
+        }
+    }
+    bail!("Unable to create PURL for: {image}");
 }
 
 struct Scanner {
     map: WorkloadState,
-    source: BombasticSource,
+    /// consulted for each image, as the source may depend on its registry
+    source: SbomSourceRouter,
+    manifests: Option<ManifestResolver>,
+    /// reject invalid state transitions, instead of only logging them
+    strict_transitions: Setting<bool>,
+    cache: Option<Arc<SbomCache>>,
+    /// shares the data of identical SBOMs between images
+    interner: Arc<SbomInterner>,
 }
 
 impl Scanner {
-    async fn lookup(&self, image: &ImageRef) -> Result<Option<SBOM>, anyhow::Error> {
-        if let Some((base, digest)) = image.0.rsplit_once('@') {
-            if let Some(name) = base.split('/').last() {
-                let mut purl = PackageUrl::new("oci", name)?;
-                if digest.starts_with("sha256:") {
-                    purl.with_version(digest);
-                    return Ok::<_, anyhow::Error>(self.source.lookup_sbom(purl).await?);
-                }
+    async fn lookup(&self, image: &ImageRef) -> SbomState {
+        match self.source.source(image) {
+            Some(source) => match source.lookup(image).await {
+                Ok(Some(result)) => SbomState::Found(vec![self.interner.intern(result)]),
+                Ok(None) => SbomState::Missing,
+                Err(err) => SbomState::Err(err.to_string()),
+            },
+            None => SbomState::Unsupported,
+        }
+    }
+
+    /// Look up the SBOMs of the architectures of a manifest list, `None` if the image isn't one.
+    async fn lookup_architectures(
+        &self,
+        image: &ImageRef,
+    ) -> Option<BTreeMap<String, ArchitectureSbom>> {
+        let images = match self.manifests.as_ref()?.resolve(image).await {
+            Ok(Some(images)) if !images.is_empty() => images,
+            Ok(_) => return None,
+            Err(err) => {
+                debug!("{image}: failed to resolve manifest list: {err:#}");
+                return None;
             }
+        };
+
+        let mut architectures = BTreeMap::new();
+        for (architecture, image) in images {
+            let sbom = self.lookup(&image).await;
+            architectures.insert(architecture, ArchitectureSbom { image, sbom });
         }
-        bail!("Unable to create PURL for: {image}");
+        Some(architectures)
     }
 
     async fn scan(&self, image: &ImageRef) {
-        let state = match self.lookup(image).await {
-            Ok(Some(result)) => SbomState::Found(result),
-            Ok(None) => SbomState::Missing,
-            Err(err) => SbomState::Err(err.to_string()),
-        };
+        let span = info_span!("sbom_lookup", %image, result = field::Empty);
+        let mut state = self.lookup(image).instrument(span.clone()).await;
+
+        // SBOMs are usually published for each architecture, rather than for the manifest list
+        let mut architectures = BTreeMap::new();
+        if matches!(state, SbomState::Missing | SbomState::Err(_)) {
+            if let Some(resolved) = self
+                .lookup_architectures(image)
+                .instrument(span.clone())
+                .await
+            {
+                state = SbomState::combine(resolved.values().map(|resolved| &resolved.sbom));
+                architectures = resolved;
+            }
+        }
+
+        span.record("result", state.label());
+        if let SbomState::Found(_) = &state {
+            debug!("{} distinct SBOMs in use", self.interner.len());
+        }
+        if let Some(cache) = &self.cache {
+            cache.record(image, &state);
+        }
         self.map
             .mutate_state(image.clone(), |current| {
                 current.map(|mut current| {
-                    current.sbom = state;
+                    match SbomStateTransition::validate(&current.sbom, &state) {
+                        Ok(()) => {
+                            current.sbom = state;
+                            current.architectures = architectures;
+                        }
+                        Err(err) if self.strict_transitions.get() => {
+                            warn!("{image}: rejecting {err}");
+                        }
+                        Err(err) => {
+                            warn!("{image}: {err}");
+                            current.sbom = state;
+                            current.architectures = architectures;
+                        }
+                    }
                     current
                 })
             })
@@ -67,32 +204,68 @@ impl Scanner {
     }
 }
 
-/// directly scan incoming changes
-async fn scanner(map: WorkloadState, source: BombasticSource) -> anyhow::Result<()> {
+/// queue scheduled images for a lookup, and work through the queue
+async fn scanner(
+    map: WorkloadState,
+    source: SbomSourceRouter,
+    manifests: Option<ManifestResolver>,
+    strict_transitions: Setting<bool>,
+    cache: Option<Arc<SbomCache>>,
+    interner: Arc<SbomInterner>,
+) -> anyhow::Result<()> {
     let scanner = Scanner {
         map: map.clone(),
         source,
+        manifests,
+        strict_transitions,
+        cache,
+        interner,
     };
 
+    // the lookups must not hold up the subscription, which would queue its events instead
+    tokio::select! {
+        result = feeder(map) => result,
+        result = worker(scanner) => result,
+    }
+}
+
+/// look up the queued images
+async fn worker(scanner: Scanner) -> anyhow::Result<()> {
+    let queue = scanner.map.lookup_queue();
+    loop {
+        let image = queue.pop().await;
+        // changed while queued, e.g. removed, or already looked up
+        if let Some(SbomState::Scheduled) = scanner.map.get(&image).await.map(|image| image.sbom) {
+            // FIXME: need to parallelize processing
+            scanner.scan(&image).await;
+        }
+    }
+}
+
+/// hand over scheduled images to the lookup queue
+async fn feeder(map: WorkloadState) -> anyhow::Result<()> {
+    let queue = map.lookup_queue();
     loop {
         info!("Starting subscription ... ");
-        let mut sub = map.subscribe(128).await;
+        // changes of the pods don't affect the lookups
+        let mut sub = map.named("scanner").subscribe_sbom_only(128).await;
         while let Some(evt) = sub.recv().await {
-            // FIXME: need to parallelize processing
             match evt {
-                Event::Added(image, state) | Event::Modified(image, state) => {
-                    if let SbomState::Scheduled = state.sbom {
-                        scanner.scan(&image).await;
-                    }
-                }
+                Event::Added(image, sbom) | Event::Modified(image, sbom) => match sbom {
+                    SbomState::Scheduled => queue.push(image),
+                    _ => queue.remove(&image),
+                },
+                Event::Removed(image) => queue.remove(&image),
                 Event::Restart(state) => {
-                    for (image, state) in state {
-                        if let SbomState::Scheduled = state.sbom {
-                            scanner.scan(&image).await;
+                    queue.retain(|image| matches!(state.get(image), Some(SbomState::Scheduled)));
+                    for (image, sbom) in state {
+                        if let SbomState::Scheduled = sbom {
+                            queue.push(image);
                         }
                     }
                 }
-                Event::Removed(_) => {}
+                // only delivered to chunked subscriptions
+                Event::RestartChunk { .. } => {}
             }
         }
 
@@ -102,10 +275,22 @@ async fn scanner(map: WorkloadState, source: BombasticSource) -> anyhow::Result<
     }
 }
 
-/// periodically re-scan changes
-async fn rescanner(map: WorkloadState) -> anyhow::Result<()> {
+/// periodically re-scan changes, disabled if there is no interval
+async fn rescanner(
+    map: WorkloadState,
+    mut interval: Setting<Option<Duration>>,
+) -> anyhow::Result<()> {
     loop {
-        tokio::time::sleep(Duration::from_secs(15)).await;
+        let Some(period) = interval.get() else {
+            interval.changed().await;
+            continue;
+        };
+
+        // a changed interval starts the wait over
+        tokio::select! {
+            _ = tokio::time::sleep(period) => {}
+            _ = interval.changed() => continue,
+        }
 
         map.iter_mut(|_k, state| match &state.sbom {
             SbomState::Err(_) | SbomState::Missing => {
@@ -119,21 +304,55 @@ async fn rescanner(map: WorkloadState) -> anyhow::Result<()> {
     }
 }
 
-async fn runner(store: Store<ImageRef, PodRef, ()>, map: WorkloadState) -> anyhow::Result<()> {
+/// The state of an image first seen, taken from the cache if possible
+fn initial_state(
+    cache: &Option<Arc<SbomCache>>,
+    interner: &SbomInterner,
+    image: &ImageRef,
+) -> SbomState {
+    cache
+        .as_ref()
+        .and_then(|cache| cache.get(image))
+        .map(|state| interner.intern_state(state))
+        .unwrap_or(SbomState::Scheduled)
+}
+
+async fn runner(
+    store: Store<ImageRef, PodRef, ()>,
+    map: WorkloadState,
+    cache: Option<Arc<SbomCache>>,
+    interner: Arc<SbomInterner>,
+    policy: Arc<ImagePolicy>,
+) -> anyhow::Result<()> {
     loop {
-        let mut sub = store.subscribe(32).await;
+        let (mut sub, synced) = store.subscribe_synced(32).await;
+        // until the store synced, its restart events don't carry the actual pods yet
+        let mut pending_sync = !synced;
         while let Some(evt) = sub.recv().await {
             match evt {
                 Event::Added(image, state) | Event::Modified(image, state) => {
-                    map.mutate_state(image, |current| match current {
-                        Some(mut current) => {
-                            current.pods = state.owners;
-                            Some(current)
-                        }
-                        None => Some(Image {
-                            pods: state.owners,
-                            sbom: SbomState::Scheduled,
-                        }),
+                    match map.image_limit().admit(&image) {
+                        LimitDecision::Admit => {}
+                        LimitDecision::Refuse => continue,
+                        LimitDecision::Evict(oldest) => map.remove_state(oldest).await,
+                    }
+                    let sbom = initial_state(&cache, &interner, &image);
+                    let classification = policy.classify_image(&image, &state.owners);
+                    map.mutate_state(image, |current| {
+                        let mut image = match current {
+                            Some(mut current) => {
+                                current.set_pods(state.owners);
+                                current
+                            }
+                            None => state
+                                .owners
+                                .into_iter()
+                                .collect::<ImageBuilder>()
+                                .sbom(sbom)
+                                .build(),
+                        };
+                        image.classification = classification;
+                        Some(image)
                     })
                     .await;
                 }
@@ -141,23 +360,240 @@ async fn runner(store: Store<ImageRef, PodRef, ()>, map: WorkloadState) -> anyho
                     map.mutate_state(image, |_| None).await;
                 }
                 Event::Restart(state) => {
-                    map.set_state(
-                        state
+                    let images = state.len();
+
+                    // annotations are provided by users, and the history can't be restored, so we
+                    // need to carry them over from the current images, without losing a change
+                    // made meanwhile
+                    let mut queued = 0;
+                    map.replace_state(|current| {
+                        let mut state = state
                             .into_iter()
                             .map(|(k, v)| {
-                                (
-                                    k,
-                                    Image {
-                                        pods: v.owners,
-                                        sbom: SbomState::Scheduled,
-                                    },
-                                )
+                                let sbom = initial_state(&cache, &interner, &k);
+                                let classification = policy.classify_image(&k, &v.owners);
+                                let mut image = match current.get(&k).cloned() {
+                                    Some(mut image) => {
+                                        image.set_pods(v.owners);
+                                        // the architectures aren't cached, they return with a lookup
+                                        image.sbom = sbom;
+                                        image.architectures.clear();
+                                        image
+                                    }
+                                    None => v
+                                        .owners
+                                        .into_iter()
+                                        .collect::<ImageBuilder>()
+                                        .sbom(sbom)
+                                        .build(),
+                                };
+                                image.classification = classification;
+                                (k, image)
                             })
-                            .collect(),
-                    )
+                            .collect::<HashMap<_, _>>();
+                        map.image_limit().trim(&mut state);
+                        debug!("Resynced workload: {}", WorkloadDiff::new(current, &state));
+                        queued = state
+                            .values()
+                            .filter(|image| image.sbom == SbomState::Scheduled)
+                            .count();
+                        state
+                    })
                     .await;
+
+                    if !std::mem::take(&mut pending_sync) {
+                        map.startup().complete(Progress {
+                            synced: true,
+                            pods: store.owners().await,
+                            images,
+                            queued,
+                        });
+                    }
                 }
+                // only delivered to chunked subscriptions
+                Event::RestartChunk { .. } => {}
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::store::image_store;
+    use crate::testutil::{pod, pod_events, wait_for, FakeSource};
+    use bommer_api::data::SBOM;
+    use kube::runtime::watcher;
+
+    const NGINX: &str = "docker.io/library/nginx@sha256:1234";
+
+    fn image(image: &str) -> ImageRef {
+        ImageRef(image.to_string())
+    }
+
+    fn image_ref(n: usize) -> ImageRef {
+        image(&format!("quay.io/app-{n}@sha256:{n:04}"))
+    }
+
+    #[tokio::test]
+    async fn resync_keeps_annotations() {
+        let (pods, events) = pod_events();
+        let (store, store_runner) = image_store(events);
+        let map = WorkloadState::default();
+        tokio::spawn(store_runner);
+        tokio::spawn(runner(
+            store,
+            map.clone(),
+            None,
+            Default::default(),
+            Default::default(),
+        ));
+
+        pods.unbounded_send(Ok(watcher::Event::Restarted(vec![pod(
+            "default",
+            "a",
+            &[NGINX],
+        )])))
+        .unwrap();
+        wait_for(&map, |state| state.contains_key(&image(NGINX))).await;
+        assert!(map.set_annotation(image(NGINX), "owner", "team-a").await);
+
+        pods.unbounded_send(Ok(watcher::Event::Restarted(vec![
+            pod("default", "a", &[NGINX]),
+            pod("default", "b", &[NGINX]),
+        ])))
+        .unwrap();
+        wait_for(&map, |state| {
+            state
+                .get(&image(NGINX))
+                .is_some_and(|image| image.pods.len() == 2)
+        })
+        .await;
+
+        let annotations = map.get(&image(NGINX)).await.unwrap().annotations;
+        assert_eq!(annotations.get("owner").map(String::as_str), Some("team-a"));
+    }
+
+    /// A source which doesn't answer while its gate is locked, and finds no SBOMs after that
+    struct Stalled(Arc<tokio::sync::RwLock<()>>);
+
+    impl SbomSource for Stalled {
+        fn lookup<'a>(
+            &'a self,
+            _image: &'a ImageRef,
+        ) -> futures::future::BoxFuture<'a, anyhow::Result<Option<SBOM>>> {
+            async move {
+                let _open = self.0.read().await;
+                Ok(None)
+            }
+            .boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn lookups_deferred_while_stalled() {
+        const IMAGES: usize = 2000;
+
+        let map = WorkloadState::default();
+        let queue = map.lookup_queue().clone();
+        queue.set_capacity(8);
+        map.set_state(
+            (0..IMAGES)
+                .map(|n| {
+                    let image = ImageBuilder::default()
+                        .pod(PodRef {
+                            namespace: "default".to_string(),
+                            name: format!("pod-{n}"),
+                        })
+                        .build();
+                    (image_ref(n), image)
+                })
+                .collect(),
+        )
+        .await;
+
+        let gate = Arc::new(tokio::sync::RwLock::new(()));
+        let stalled = gate.clone().write_owned().await;
+        tokio::spawn(scanner(
+            map.clone(),
+            SbomSourceRouter::new(Stalled(gate)),
+            None,
+            Default::default(),
+            None,
+            Default::default(),
+        ));
+
+        // one image is being looked up, the queue stays bounded
+        let wait = async {
+            while queue.status().deferrals == 0 || queue.status().depth < 8 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(
+            queue.status(),
+            LookupQueueStatus {
+                capacity: 8,
+                depth: 8,
+                deferred: IMAGES - 9,
+                deferrals: (IMAGES - 8) as u64,
+                requeued: 1,
+            }
+        );
+
+        // all deferred images get looked up eventually
+        drop(stalled);
+        wait_for(&map, |state| {
+            state.values().all(|image| image.sbom == SbomState::Missing)
+        })
+        .await;
+        assert_eq!(
+            queue.status(),
+            LookupQueueStatus {
+                capacity: 8,
+                depth: 0,
+                deferred: 0,
+                deferrals: (IMAGES - 8) as u64,
+                requeued: (IMAGES - 8) as u64,
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn unsupported_without_route() {
+        let quay = image("quay.io/app@sha256:1234");
+        let ghcr = image("ghcr.io/app@sha256:1234");
+        let map = WorkloadState::default();
+        map.set_state(
+            [quay.clone(), ghcr.clone(), image(NGINX)]
+                .into_iter()
+                .map(|image| (image, ImageBuilder::default().build()))
+                .collect(),
+        )
+        .await;
+
+        let source = FakeSource(HashMap::from([(quay.clone(), None)]));
+        tokio::spawn(scanner(
+            map.clone(),
+            SbomSourceRouter::without_default().with_route("quay.io", source),
+            None,
+            Default::default(),
+            None,
+            Default::default(),
+        ));
+
+        wait_for(&map, |state| {
+            state
+                .values()
+                .all(|image| image.sbom != SbomState::Scheduled)
+        })
+        .await;
+        let state = map.get_state().await;
+        assert_eq!(state[&quay].sbom, SbomState::Missing);
+        assert_eq!(state[&ghcr].sbom, SbomState::Unsupported);
+        assert_eq!(state[&image(NGINX)].sbom, SbomState::Unsupported);
+    }
+}
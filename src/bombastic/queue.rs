@@ -0,0 +1,213 @@
+use bommer_api::data::ImageRef;
+use std::collections::{HashSet, VecDeque};
+use std::sync::Arc;
+use tokio::sync::Notify;
+use tracing::{info, warn};
+
+/// Default number of images waiting for a lookup, before further images get deferred
+pub const DEFAULT_LOOKUP_QUEUE_CAPACITY: usize = 1024;
+
+/// The handoff of images from the watcher to the SBOM lookups.
+///
+/// The queue is bounded, so a slow SBOM provider doesn't let it grow with every new image. Once
+/// full, images are only marked as deferred, which costs a single entry per image, and moved into
+/// the queue as lookups free up room. The workload itself keeps being updated either way, the
+/// images just stay [`bommer_api::data::SbomState::Scheduled`] for longer.
+#[derive(Clone, Debug, Default)]
+pub struct LookupQueue {
+    inner: Arc<parking_lot::Mutex<QueueInner>>,
+    /// notified when an image got queued
+    ready: Arc<Notify>,
+}
+
+#[derive(Debug)]
+struct QueueInner {
+    capacity: usize,
+    queue: VecDeque<ImageRef>,
+    /// images in the queue
+    queued: HashSet<ImageRef>,
+    /// images waiting for room in the queue, in order of their deferral, cleaned up lazily
+    deferred_order: VecDeque<ImageRef>,
+    /// images waiting for room in the queue
+    deferred: HashSet<ImageRef>,
+    deferrals: u64,
+    requeued: u64,
+    /// reached the capacity, only logging when this changes
+    at_capacity: bool,
+}
+
+impl Default for QueueInner {
+    fn default() -> Self {
+        Self {
+            capacity: DEFAULT_LOOKUP_QUEUE_CAPACITY,
+            queue: Default::default(),
+            queued: Default::default(),
+            deferred_order: Default::default(),
+            deferred: Default::default(),
+            deferrals: 0,
+            requeued: 0,
+            at_capacity: false,
+        }
+    }
+}
+
+/// The state of the lookup queue
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LookupQueueStatus {
+    /// maximum number of queued images
+    pub capacity: usize,
+    /// images waiting for a lookup
+    pub depth: usize,
+    /// images waiting for room in the queue
+    pub deferred: usize,
+    /// images which got deferred, as the queue was full
+    pub deferrals: u64,
+    /// deferred images which were moved into the queue
+    pub requeued: u64,
+}
+
+impl QueueInner {
+    fn has_room(&self) -> bool {
+        self.queue.len() < self.capacity
+    }
+
+    /// Move deferred images into the queue, as long as there is room.
+    fn reconcile(&mut self) -> bool {
+        let mut requeued = false;
+        while self.has_room() {
+            let Some(image) = self.deferred_order.pop_front() else {
+                break;
+            };
+            // removed while deferred
+            if !self.deferred.remove(&image) {
+                continue;
+            }
+            self.queued.insert(image.clone());
+            self.queue.push_back(image);
+            self.requeued += 1;
+            requeued = true;
+        }
+        self.check_capacity();
+        requeued
+    }
+
+    /// Drop removed images from the order of deferrals, once they make up most of it.
+    fn compact(&mut self) {
+        if self.deferred_order.len() > 2 * self.deferred.len() + 64 {
+            let deferred = &self.deferred;
+            self.deferred_order.retain(|image| deferred.contains(image));
+        }
+    }
+
+    fn check_capacity(&mut self) {
+        let at_capacity = !self.deferred.is_empty();
+        if at_capacity != self.at_capacity {
+            self.at_capacity = at_capacity;
+            match at_capacity {
+                true => warn!(
+                    "Lookup queue is full ({} images), deferring further lookups",
+                    self.capacity
+                ),
+                false => info!("Lookup queue caught up with deferred lookups"),
+            }
+        }
+    }
+}
+
+impl LookupQueue {
+    /// Change the maximum number of queued images, which must not be zero.
+    ///
+    /// Lowering it doesn't drop images already queued, it only defers new ones.
+    pub fn set_capacity(&self, capacity: usize) {
+        let mut inner = self.inner.lock();
+        inner.capacity = capacity.max(1);
+        if inner.reconcile() {
+            self.ready.notify_one();
+        }
+    }
+
+    /// Queue a lookup for an image, or defer it if the queue is full.
+    ///
+    /// Images already queued or deferred keep their position.
+    pub fn push(&self, image: ImageRef) {
+        let mut inner = self.inner.lock();
+        if inner.queued.contains(&image) || inner.deferred.contains(&image) {
+            return;
+        }
+
+        // deferred images go first, so that images can't overtake them
+        if inner.has_room() && inner.deferred.is_empty() {
+            inner.queued.insert(image.clone());
+            inner.queue.push_back(image);
+            drop(inner);
+            self.ready.notify_one();
+        } else {
+            inner.deferred.insert(image.clone());
+            inner.deferred_order.push_back(image);
+            inner.deferrals += 1;
+            inner.check_capacity();
+        }
+    }
+
+    /// Drop a pending lookup of an image, queued or deferred.
+    pub fn remove(&self, image: &ImageRef) {
+        let mut inner = self.inner.lock();
+        if inner.queued.remove(image) {
+            inner.queue.retain(|queued| queued != image);
+        } else if inner.deferred.remove(image) {
+            inner.compact();
+        }
+        if inner.reconcile() {
+            self.ready.notify_one();
+        }
+    }
+
+    /// Only keep pending lookups of images matching the predicate.
+    pub fn retain(&self, mut f: impl FnMut(&ImageRef) -> bool) {
+        let mut inner = self.inner.lock();
+        let QueueInner {
+            queue,
+            queued,
+            deferred,
+            ..
+        } = &mut *inner;
+        queue.retain(|image| f(image));
+        queued.retain(|image| f(image));
+        deferred.retain(|image| f(image));
+        inner.compact();
+        if inner.reconcile() {
+            self.ready.notify_one();
+        }
+    }
+
+    /// Wait for the next image to look up.
+    ///
+    /// Taking an image makes room for a deferred one.
+    pub async fn pop(&self) -> ImageRef {
+        loop {
+            // a permit is stored if nobody waits yet, so nothing gets missed in between
+            let ready = self.ready.notified();
+            {
+                let mut inner = self.inner.lock();
+                if let Some(image) = inner.queue.pop_front() {
+                    inner.queued.remove(&image);
+                    inner.reconcile();
+                    return image;
+                }
+            }
+            ready.await;
+        }
+    }
+
+    pub fn status(&self) -> LookupQueueStatus {
+        let inner = self.inner.lock();
+        LookupQueueStatus {
+            capacity: inner.capacity,
+            depth: inner.queue.len(),
+            deferred: inner.deferred.len(),
+            deferrals: inner.deferrals,
+            requeued: inner.requeued,
+        }
+    }
+}
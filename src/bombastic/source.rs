@@ -0,0 +1,153 @@
+//! Sources of SBOMs, and routing images to them by their registry.
+
+use super::{purl, BombasticSource};
+use anyhow::bail;
+use bommer_api::data::{ImageRef, SBOM};
+use futures::future::BoxFuture;
+use futures::FutureExt;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+/// The registry of images which don't name one, e.g. `nginx:latest`
+const IMPLIED_REGISTRY: &str = "docker.io";
+
+/// Looks up the SBOM of an image, `None` if there is none.
+pub trait SbomSource: Send + Sync {
+    fn lookup<'a>(&'a self, image: &'a ImageRef) -> BoxFuture<'a, anyhow::Result<Option<SBOM>>>;
+}
+
+impl SbomSource for BombasticSource {
+    fn lookup<'a>(&'a self, image: &'a ImageRef) -> BoxFuture<'a, anyhow::Result<Option<SBOM>>> {
+        async move { Ok(self.lookup_sbom(purl(image)?).await?) }.boxed()
+    }
+}
+
+/// Routes images to a source by their registry, falling back to a default source, if any.
+///
+/// Registries are matched as they appear in the image reference, including the port (e.g.
+/// `localhost:5000`). Images without a registry belong to `docker.io`.
+#[derive(Clone)]
+pub struct SbomSourceRouter {
+    default: Option<Arc<dyn SbomSource>>,
+    routes: HashMap<String, Arc<dyn SbomSource>>,
+}
+
+impl SbomSourceRouter {
+    pub fn new(default: impl SbomSource + 'static) -> Self {
+        Self {
+            default: Some(Arc::new(default)),
+            routes: Default::default(),
+        }
+    }
+
+    /// A router only knowing the routes added to it, images of other registries are unsupported.
+    pub fn without_default() -> Self {
+        Self {
+            default: None,
+            routes: Default::default(),
+        }
+    }
+
+    /// Look up the images of `registry` using `source`, replacing an earlier route.
+    pub fn with_route(
+        mut self,
+        registry: impl Into<String>,
+        source: impl SbomSource + 'static,
+    ) -> Self {
+        self.routes.insert(registry.into(), Arc::new(source));
+        self
+    }
+
+    /// The source to look up the SBOM of an image with, `None` if its registry is unsupported.
+    pub fn source(&self, image: &ImageRef) -> Option<&dyn SbomSource> {
+        self.routes
+            .get(registry(image))
+            .or(self.default.as_ref())
+            .map(|source| source.as_ref())
+    }
+}
+
+/// The registry of an image, as it appears in the image reference, `docker.io` if it has none.
+pub fn registry(image: &ImageRef) -> &str {
+    image.parts().registry.unwrap_or(IMPLIED_REGISTRY)
+}
+
+impl SbomSource for SbomSourceRouter {
+    fn lookup<'a>(&'a self, image: &'a ImageRef) -> BoxFuture<'a, anyhow::Result<Option<SBOM>>> {
+        match self.source(image) {
+            Some(source) => source.lookup(image),
+            None => {
+                let registry = registry(image);
+                async move { bail!("No SBOM source configured for registry {registry}") }.boxed()
+            }
+        }
+    }
+}
+
+impl From<BombasticSource> for SbomSourceRouter {
+    fn from(source: BombasticSource) -> Self {
+        Self::new(source)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::FakeSource;
+
+    const IMAGES: [&str; 4] = [
+        "quay.io/org/app@sha256:1234",
+        "localhost:5000/app@sha256:1234",
+        "library/nginx@sha256:1234",
+        "docker.io/library/redis@sha256:1234",
+    ];
+
+    /// A source answering each of [`IMAGES`] with an SBOM naming the source
+    fn source(name: &str) -> FakeSource {
+        FakeSource(
+            IMAGES
+                .iter()
+                .map(|image| {
+                    (
+                        ImageRef(image.to_string()),
+                        Some(SBOM::new(name.to_string())),
+                    )
+                })
+                .collect(),
+        )
+    }
+
+    async fn lookup(router: &SbomSourceRouter, image: &str) -> anyhow::Result<String> {
+        let sbom = router.lookup(&ImageRef(image.to_string())).await?;
+        Ok(sbom.map(|sbom| sbom.data.to_string()).unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn route_by_registry() {
+        let router = SbomSourceRouter::new(source("default"))
+            .with_route("quay.io", source("quay"))
+            .with_route("localhost:5000", source("local"))
+            .with_route("docker.io", source("hub"));
+
+        let sources = [
+            lookup(&router, IMAGES[0]).await.unwrap(),
+            lookup(&router, IMAGES[1]).await.unwrap(),
+            lookup(&router, IMAGES[2]).await.unwrap(),
+            lookup(&router, IMAGES[3]).await.unwrap(),
+        ];
+        assert_eq!(sources, ["quay", "local", "hub", "hub"]);
+
+        // without a route, images go to the default source
+        let router = SbomSourceRouter::new(source("default")).with_route("quay.io", source("quay"));
+        assert_eq!(lookup(&router, IMAGES[3]).await.unwrap(), "default");
+
+        let router = SbomSourceRouter::without_default().with_route("quay.io", source("quay"));
+        assert_eq!(lookup(&router, IMAGES[0]).await.unwrap(), "quay");
+        assert!(router.source(&ImageRef(IMAGES[1].to_string())).is_none());
+        let err = lookup(&router, IMAGES[1]).await.unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "No SBOM source configured for registry localhost:5000"
+        );
+    }
+}
@@ -0,0 +1,166 @@
+use anyhow::{anyhow, Context};
+use kube::config::{KubeConfigOptions, Kubeconfig};
+use kube::{Client, Config};
+use std::path::PathBuf;
+use tracing::info;
+
+#[derive(Clone, Debug, Default)]
+pub struct KubeOptions {
+    /// explicit kubeconfig file, takes precedence over the KUBECONFIG variable
+    pub kubeconfig: Option<PathBuf>,
+    /// kubeconfig context to use
+    pub context: Option<String>,
+}
+
+/// Where the cluster configuration was loaded from.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConfigSource {
+    /// the `--kubeconfig` flag
+    Flag(PathBuf),
+    /// the `KUBECONFIG` environment variable
+    Env,
+    /// the pod's service account
+    InCluster,
+    /// the default kubeconfig location (`~/.kube/config`)
+    DefaultPath,
+}
+
+impl KubeOptions {
+    /// Create a client, resolving the configuration in the following order:
+    ///
+    /// * explicit `--kubeconfig` flag
+    /// * `KUBECONFIG` environment variable
+    /// * in-cluster configuration (skipped when a context was requested)
+    /// * default kubeconfig path
+    pub async fn client(&self) -> anyhow::Result<Client> {
+        let (config, source) = self.config().await?;
+        info!(?source, context = ?self.context, cluster_url = %config.cluster_url, "Using cluster configuration");
+        Ok(Client::try_from(config)?)
+    }
+
+    async fn config(&self) -> anyhow::Result<(Config, ConfigSource)> {
+        if let Some(path) = &self.kubeconfig {
+            let kubeconfig = Kubeconfig::read_from(path)
+                .with_context(|| format!("Failed to read kubeconfig: {}", path.display()))?;
+            let config = self.load_kubeconfig(kubeconfig).await?;
+            return Ok((config, ConfigSource::Flag(path.clone())));
+        }
+
+        if let Some(kubeconfig) =
+            Kubeconfig::from_env().context("Failed to read kubeconfig from KUBECONFIG")?
+        {
+            let config = self.load_kubeconfig(kubeconfig).await?;
+            return Ok((config, ConfigSource::Env));
+        }
+
+        if self.context.is_none() {
+            if let Ok(config) = Config::incluster() {
+                return Ok((config, ConfigSource::InCluster));
+            }
+        }
+
+        let kubeconfig = Kubeconfig::read().context("Failed to read default kubeconfig")?;
+        let config = self.load_kubeconfig(kubeconfig).await?;
+        Ok((config, ConfigSource::DefaultPath))
+    }
+
+    async fn load_kubeconfig(&self, kubeconfig: Kubeconfig) -> anyhow::Result<Config> {
+        if let Some(context) = &self.context {
+            if !kubeconfig.contexts.iter().any(|c| &c.name == context) {
+                let available = kubeconfig
+                    .contexts
+                    .iter()
+                    .map(|c| c.name.as_str())
+                    .collect::<Vec<_>>();
+                return Err(anyhow!(
+                    "Context '{context}' not found in kubeconfig, available contexts: [{}]",
+                    available.join(", ")
+                ));
+            }
+        }
+
+        let options = KubeConfigOptions {
+            context: self.context.clone(),
+            ..Default::default()
+        };
+
+        Ok(Config::from_custom_kubeconfig(kubeconfig, &options).await?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+
+    fn kubeconfig(dir: &Path, name: &str, contexts: &[&str]) -> PathBuf {
+        let mut clusters = String::new();
+        let mut entries = String::new();
+        for context in contexts {
+            clusters.push_str(&format!(
+                "- name: {context}\n  cluster:\n    server: https://{context}.example.com\n"
+            ));
+            entries.push_str(&format!(
+                "- name: {context}\n  context:\n    cluster: {context}\n    user: user\n"
+            ));
+        }
+        let content = format!(
+            "apiVersion: v1\nkind: Config\ncurrent-context: {}\nclusters:\n{clusters}users:\n- name: user\n  user:\n    token: token\ncontexts:\n{entries}",
+            contexts[0]
+        );
+
+        let path = dir.join(name);
+        std::fs::write(&path, content).unwrap();
+        path
+    }
+
+    #[tokio::test]
+    async fn flag_beats_env() {
+        // the only test reading the environment, the others pass the kubeconfig explicitly
+        let dir = tempfile::tempdir().unwrap();
+        let env = kubeconfig(dir.path(), "env", &["env"]);
+        let flag = kubeconfig(dir.path(), "flag", &["flag"]);
+        std::env::set_var("KUBECONFIG", &env);
+
+        let options = KubeOptions {
+            kubeconfig: Some(flag.clone()),
+            context: None,
+        };
+        let (config, source) = options.config().await.unwrap();
+        assert_eq!(source, ConfigSource::Flag(flag));
+        assert_eq!(config.cluster_url.host(), Some("flag.example.com"));
+
+        let (config, source) = KubeOptions::default().config().await.unwrap();
+        assert_eq!(source, ConfigSource::Env);
+        assert_eq!(config.cluster_url.host(), Some("env.example.com"));
+
+        std::env::remove_var("KUBECONFIG");
+    }
+
+    #[tokio::test]
+    async fn explicit_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = kubeconfig(dir.path(), "config", &["first", "second"]);
+
+        let options = KubeOptions {
+            kubeconfig: Some(path),
+            context: Some("second".into()),
+        };
+        let (config, _) = options.config().await.unwrap();
+        assert_eq!(config.cluster_url.host(), Some("second.example.com"));
+    }
+
+    #[tokio::test]
+    async fn unknown_context() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = kubeconfig(dir.path(), "config", &["first", "second"]);
+
+        let options = KubeOptions {
+            kubeconfig: Some(path),
+            context: Some("third".into()),
+        };
+        let err = options.config().await.unwrap_err().to_string();
+        assert!(err.contains("'third' not found"), "{err}");
+        assert!(err.contains("[first, second]"), "{err}");
+    }
+}
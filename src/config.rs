@@ -0,0 +1,611 @@
+//! Central configuration
+//!
+//! The effective configuration is assembled from the following layers, later ones taking
+//! precedence over earlier ones:
+//!
+//! * built-in defaults
+//! * the configuration file (TOML, `bommer.toml` unless set using `--config`)
+//! * legacy, un-prefixed environment variables (`BIND_ADDR`, `BOMBASTIC_URL`, `KUBE_CONTEXT`,
+//!   `RUST_LOG`)
+//! * environment variables, prefixed with `BOMMER_` (e.g. `BOMMER_BIND_API`)
+//! * command line flags
+
+use crate::admission::{FailurePolicy, Mode, Policy};
+use crate::alert::AlertConfig;
+use crate::bombastic::{
+    BombasticSource, ManifestResolver, SbomSourceRouter, ScanOptions, DEFAULT_LOOKUP_QUEUE_CAPACITY,
+};
+use crate::cluster::KubeOptions;
+use crate::leader::{LeaseOptions, Standby};
+use crate::policy::ImagePolicy;
+use crate::pubsub::{BroadcastConfig, ShrinkPolicy};
+use crate::server::{EarlyWebsocket, RecordOptions};
+use crate::workload::LimitStrategy;
+use figment::providers::{Env, Format, Serialized, Toml};
+use figment::value::{Dict, Map};
+use figment::{Figment, Metadata, Profile, Provider};
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use url::Url;
+
+pub const ENV_PREFIX: &str = "BOMMER_";
+
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct Config {
+    /// address the API listener binds to, empty to disable
+    pub bind_api: String,
+    /// address the metrics and health listener binds to, empty to disable
+    pub bind_metrics: String,
+    /// record the number of entries and the size of broadcast events, which costs serializing them
+    pub event_metrics: bool,
+    /// base URL of the bombastic instance
+    pub bombastic_url: Url,
+    /// base URL of the bombastic instance to use instead, by registry
+    pub sbom_sources: BTreeMap<String, Url>,
+    /// only look up images of the registries in `sbom_sources`, instead of using `bombastic_url`
+    pub sbom_sources_only: bool,
+    /// architectures of the nodes, resolving manifest lists to their images, empty to disable
+    pub architectures: Vec<String>,
+    /// interval for re-scanning images with a missing or failed SBOM, in seconds
+    pub rescan_interval_secs: u64,
+    /// reject unexpected SBOM state transitions, instead of only logging them
+    pub strict_transitions: bool,
+    /// file lookup results are persisted to, disabled if not set
+    pub sbom_cache_file: Option<PathBuf>,
+    /// time after which cached lookup results get verified again, in seconds
+    pub sbom_cache_ttl_secs: u64,
+    /// interval for writing the cache file, in seconds
+    pub sbom_cache_flush_secs: u64,
+    /// explicit kubeconfig file
+    pub kubeconfig: Option<PathBuf>,
+    /// kubeconfig context to use
+    pub context: Option<String>,
+    /// log filter, using the `RUST_LOG` syntax
+    pub log_filter: String,
+    /// bearer token for the admin API, which is disabled if not set
+    pub admin_token: Option<String>,
+    /// address the admission webhook listener binds to, empty to disable
+    pub webhook_bind: String,
+    /// TLS certificate chain (PEM) of the webhook listener
+    pub webhook_tls_cert: Option<PathBuf>,
+    /// TLS private key (PEM) of the webhook listener
+    pub webhook_tls_key: Option<PathBuf>,
+    /// whether images without an SBOM only cause a warning, or get rejected
+    pub webhook_mode: Mode,
+    /// namespaces with this label set to `true` are exempt from the webhook, empty to disable
+    pub webhook_exempt_label: String,
+    /// timeout for looking up images not known yet, in milliseconds, zero to disable
+    pub webhook_lookup_timeout_ms: u64,
+    /// whether to allow or reject pods whose images can't be evaluated
+    pub webhook_failure_policy: FailurePolicy,
+    /// whether to hold back or reject websocket connections arriving before the initial sync
+    pub early_websocket: EarlyWebsocket,
+    /// take part in leader election, only the leader watches pods and looks up SBOMs
+    pub leader_election: bool,
+    /// name of the lease used for leader election
+    pub leader_lease_name: String,
+    /// namespace of the lease, the namespace bommer runs in if not set
+    pub leader_lease_namespace: Option<String>,
+    /// identity in the leader election, the host (pod) name if not set
+    pub leader_identity: Option<String>,
+    /// URL this instance can be reached at, which standby instances redirect to
+    pub leader_url: Option<Url>,
+    /// time after which the lease can be taken over if not renewed, in seconds
+    pub leader_lease_duration_secs: u64,
+    /// interval for renewing, or trying to acquire, the lease, in seconds
+    pub leader_renew_interval_secs: u64,
+    /// whether a standby instance reports not-ready, or redirects to the leader
+    pub standby: Standby,
+    /// file the baseline gets persisted to, and loaded from at startup
+    pub baseline_file: Option<PathBuf>,
+    /// time given to all tasks for stopping, in seconds, after which they are abandoned
+    pub shutdown_deadline_secs: u64,
+    /// release unused memory once less than this fraction of a map is in use, zero to disable
+    pub shrink_occupancy: f64,
+    /// leave maps with less capacity than this alone
+    pub shrink_min_capacity: usize,
+    /// time to wait for a subscriber which can't keep up, in milliseconds
+    pub broadcast_send_timeout_ms: u64,
+    /// additional time to keep retrying a subscriber before evicting it, in milliseconds
+    pub broadcast_retry_budget_ms: u64,
+    /// remove subscribers which have been idle for this long, in seconds, zero to disable
+    pub listener_idle_timeout_secs: u64,
+    /// maximum number of tracked images, zero for no limit
+    pub max_images: usize,
+    /// whether new images beyond the maximum are refused, or evict the oldest ones
+    pub max_images_strategy: LimitStrategy,
+    /// file audit records get appended to, as JSON lines, disabled if not set
+    pub audit_file: Option<PathBuf>,
+    /// number of images waiting for an SBOM lookup, before further images get deferred
+    pub lookup_queue_capacity: usize,
+    /// file the events sent to websocket clients get recorded to, disabled if not set
+    pub record_file: Option<PathBuf>,
+    /// size of the recording after which it gets rotated, in bytes
+    pub record_max_bytes: u64,
+    /// rules for alerting on the SBOM coverage, and where to send the alerts to
+    pub alerts: AlertConfig,
+    /// rules classifying images, by what is expected of their SBOM
+    pub policy: ImagePolicy,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            bind_api: "[::]:8080".to_string(),
+            bind_metrics: "127.0.0.1:9090".to_string(),
+            event_metrics: false,
+            bombastic_url: Url::parse("http://localhost:8080").expect("default URL must parse"),
+            sbom_sources: Default::default(),
+            sbom_sources_only: false,
+            architectures: Vec::new(),
+            rescan_interval_secs: 15,
+            strict_transitions: false,
+            sbom_cache_file: None,
+            sbom_cache_ttl_secs: 24 * 60 * 60,
+            sbom_cache_flush_secs: 60,
+            kubeconfig: None,
+            context: None,
+            log_filter: "info".to_string(),
+            admin_token: None,
+            webhook_bind: String::new(),
+            webhook_tls_cert: None,
+            webhook_tls_key: None,
+            webhook_mode: Mode::Audit,
+            webhook_exempt_label: "bommer.io/exempt".to_string(),
+            webhook_lookup_timeout_ms: 0,
+            webhook_failure_policy: FailurePolicy::Ignore,
+            early_websocket: EarlyWebsocket::Wait,
+            leader_election: false,
+            leader_lease_name: "bommer".to_string(),
+            leader_lease_namespace: None,
+            leader_identity: None,
+            leader_url: None,
+            leader_lease_duration_secs: 15,
+            leader_renew_interval_secs: 5,
+            standby: Standby::NotReady,
+            baseline_file: None,
+            shutdown_deadline_secs: 20,
+            shrink_occupancy: 0.25,
+            shrink_min_capacity: 64,
+            broadcast_send_timeout_ms: 1000,
+            broadcast_retry_budget_ms: 0,
+            listener_idle_timeout_secs: 300,
+            max_images: 0,
+            max_images_strategy: LimitStrategy::Refuse,
+            audit_file: None,
+            lookup_queue_capacity: DEFAULT_LOOKUP_QUEUE_CAPACITY,
+            record_file: None,
+            record_max_bytes: 64 * 1024 * 1024,
+            alerts: Default::default(),
+            policy: Default::default(),
+        }
+    }
+}
+
+/// Settings which can be overridden from the command line.
+#[derive(Clone, Debug, Default, clap::Args, serde::Serialize)]
+pub struct Overrides {
+    /// Address the API listener binds to, empty to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_api: Option<String>,
+
+    /// Address the metrics and health listener binds to, empty to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bind_metrics: Option<String>,
+
+    /// Record the number of entries and the size of broadcast events, which costs serializing them
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_metrics: Option<bool>,
+
+    /// Base URL of the bombastic instance
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub bombastic_url: Option<Url>,
+
+    /// Only look up images of the registries in `sbom_sources`, instead of using `bombastic_url`
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sbom_sources_only: Option<bool>,
+
+    /// Architectures of the nodes, resolving manifest lists to their images, empty to disable
+    #[arg(long, value_delimiter = ',')]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub architectures: Option<Vec<String>>,
+
+    /// Interval for re-scanning images with a missing or failed SBOM, in seconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub rescan_interval_secs: Option<u64>,
+
+    /// Reject unexpected SBOM state transitions, instead of only logging them
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_transitions: Option<bool>,
+
+    /// File lookup results are persisted to, disabled if not set
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sbom_cache_file: Option<PathBuf>,
+
+    /// Time after which cached lookup results get verified again, in seconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sbom_cache_ttl_secs: Option<u64>,
+
+    /// Interval for writing the cache file, in seconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sbom_cache_flush_secs: Option<u64>,
+
+    /// Path to a kubeconfig file, takes precedence over the KUBECONFIG variable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kubeconfig: Option<PathBuf>,
+
+    /// Name of the kubeconfig context to use
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub context: Option<String>,
+
+    /// Log filter, using the RUST_LOG syntax
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub log_filter: Option<String>,
+
+    /// Address the admission webhook listener binds to, empty to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_bind: Option<String>,
+
+    /// TLS certificate chain (PEM) of the webhook listener
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_tls_cert: Option<PathBuf>,
+
+    /// TLS private key (PEM) of the webhook listener
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_tls_key: Option<PathBuf>,
+
+    /// Whether images without an SBOM only cause a warning, or get rejected
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_mode: Option<Mode>,
+
+    /// Namespaces with this label set to `true` are exempt from the webhook, empty to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_exempt_label: Option<String>,
+
+    /// Timeout for looking up images not known yet, in milliseconds, zero to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_lookup_timeout_ms: Option<u64>,
+
+    /// Whether to allow or reject pods whose images can't be evaluated
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub webhook_failure_policy: Option<FailurePolicy>,
+
+    /// Whether to hold back or reject websocket connections arriving before the initial sync
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub early_websocket: Option<EarlyWebsocket>,
+
+    /// Take part in leader election, only the leader watches pods and looks up SBOMs
+    #[arg(long, num_args = 0..=1, default_missing_value = "true")]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_election: Option<bool>,
+
+    /// Name of the lease used for leader election
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_lease_name: Option<String>,
+
+    /// Namespace of the lease, the namespace bommer runs in if not set
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_lease_namespace: Option<String>,
+
+    /// Identity in the leader election, the host (pod) name if not set
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_identity: Option<String>,
+
+    /// URL this instance can be reached at, which standby instances redirect to
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_url: Option<Url>,
+
+    /// Time after which the lease can be taken over if not renewed, in seconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_lease_duration_secs: Option<u64>,
+
+    /// Interval for renewing, or trying to acquire, the lease, in seconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub leader_renew_interval_secs: Option<u64>,
+
+    /// Whether a standby instance reports not-ready, or redirects to the leader
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub standby: Option<Standby>,
+
+    /// File the baseline gets persisted to, and loaded from at startup
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub baseline_file: Option<PathBuf>,
+
+    /// Time given to all tasks for stopping, in seconds, after which they are abandoned
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shutdown_deadline_secs: Option<u64>,
+
+    /// Release unused memory once less than this fraction of a map is in use, zero to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shrink_occupancy: Option<f64>,
+
+    /// Leave maps with less capacity than this alone
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub shrink_min_capacity: Option<usize>,
+
+    /// Time to wait for a subscriber which can't keep up, in milliseconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broadcast_send_timeout_ms: Option<u64>,
+
+    /// Additional time to keep retrying a subscriber before evicting it, in milliseconds
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub broadcast_retry_budget_ms: Option<u64>,
+
+    /// Remove subscribers which have been idle for this long, in seconds, zero to disable
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub listener_idle_timeout_secs: Option<u64>,
+
+    /// Maximum number of tracked images, zero for no limit
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_images: Option<usize>,
+
+    /// Whether new images beyond the maximum are refused, or evict the oldest ones
+    #[arg(long, value_enum)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_images_strategy: Option<LimitStrategy>,
+
+    /// File audit records get appended to, as JSON lines
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub audit_file: Option<PathBuf>,
+
+    /// Number of images waiting for an SBOM lookup, before further images get deferred
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub lookup_queue_capacity: Option<usize>,
+
+    /// File the events sent to websocket clients get recorded to
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record_file: Option<PathBuf>,
+
+    /// Size of the recording after which it gets rotated, in bytes
+    #[arg(long)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub record_max_bytes: Option<u64>,
+}
+
+impl Config {
+    /// Build the layered configuration, see the module documentation for the precedence.
+    pub fn figment(file: &Path, overrides: &Overrides) -> Figment {
+        Figment::new()
+            .merge(Named("default", Config::default()))
+            .merge(Toml::file(file))
+            .merge(Env::raw().filter_map(|key| {
+                if key == "bind_addr" {
+                    Some("bind_api".into())
+                } else if key == "bombastic_url" {
+                    Some(key.into())
+                } else if key == "kube_context" {
+                    Some("context".into())
+                } else if key == "rust_log" {
+                    Some("log_filter".into())
+                } else {
+                    None
+                }
+            }))
+            .merge(Env::prefixed(ENV_PREFIX))
+            .merge(Named("command line", overrides))
+    }
+
+    pub fn load(figment: &Figment) -> anyhow::Result<Self> {
+        Ok(figment.extract()?)
+    }
+
+    pub fn bind_api(&self) -> Option<String> {
+        Some(self.bind_api.clone()).filter(|addr| !addr.is_empty())
+    }
+
+    pub fn bind_metrics(&self) -> Option<String> {
+        Some(self.bind_metrics.clone()).filter(|addr| !addr.is_empty())
+    }
+
+    pub fn bind_webhook(&self) -> Option<String> {
+        Some(self.webhook_bind.clone()).filter(|addr| !addr.is_empty())
+    }
+
+    pub fn admission_policy(&self) -> Policy {
+        Policy {
+            mode: self.webhook_mode,
+            exempt_label: self.webhook_exempt_label.clone(),
+            lookup_timeout: Some(Duration::from_millis(self.webhook_lookup_timeout_ms))
+                .filter(|timeout| !timeout.is_zero()),
+            failure_policy: self.webhook_failure_policy,
+        }
+    }
+
+    /// Options for the leader election, `None` if disabled.
+    pub fn lease_options(&self) -> Option<LeaseOptions> {
+        if !self.leader_election {
+            return None;
+        }
+
+        let identity = self
+            .leader_identity
+            .clone()
+            .or_else(|| std::env::var("HOSTNAME").ok())
+            .unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+
+        Some(LeaseOptions {
+            name: self.leader_lease_name.clone(),
+            namespace: self.leader_lease_namespace.clone(),
+            identity,
+            url: self.leader_url.clone(),
+            duration: Duration::from_secs(self.leader_lease_duration_secs),
+            renew_interval: Duration::from_secs(self.leader_renew_interval_secs),
+        })
+    }
+
+    /// The sources of SBOMs, `bombastic_url` unless overridden for an image's registry
+    pub fn sbom_sources(&self) -> SbomSourceRouter {
+        let router = match self.sbom_sources_only {
+            true => SbomSourceRouter::without_default(),
+            false => BombasticSource::new(self.bombastic_url.clone()).into(),
+        };
+        self.sbom_sources
+            .iter()
+            .fold(router, |router, (registry, url)| {
+                router.with_route(registry, BombasticSource::new(url.clone()))
+            })
+    }
+
+    /// Resolves manifest lists to the images of the architectures, `None` if there are none
+    pub fn manifest_resolver(&self) -> Option<ManifestResolver> {
+        (!self.architectures.is_empty()).then(|| ManifestResolver::new(self.architectures.clone()))
+    }
+
+    /// Options of a scan which only runs once, without re-scanning or caching
+    pub fn single_scan_options(&self) -> ScanOptions {
+        ScanOptions {
+            policy: Arc::new(self.policy.clone()),
+            manifests: self.manifest_resolver(),
+            ..Default::default()
+        }
+    }
+
+    pub fn rescan_interval(&self) -> Duration {
+        Duration::from_secs(self.rescan_interval_secs)
+    }
+
+    pub fn shutdown_deadline(&self) -> Duration {
+        Duration::from_secs(self.shutdown_deadline_secs)
+    }
+
+    /// When to release unused memory of the workload and the pods, `None` if disabled
+    pub fn shrink_policy(&self) -> Option<ShrinkPolicy> {
+        (self.shrink_occupancy > 0.0).then_some(ShrinkPolicy {
+            min_occupancy: self.shrink_occupancy,
+            min_capacity: self.shrink_min_capacity,
+        })
+    }
+
+    /// Where to record the events sent to websocket clients, `None` if disabled
+    pub fn record_options(&self) -> Option<RecordOptions> {
+        self.record_file.clone().map(|file| RecordOptions {
+            file,
+            max_bytes: self.record_max_bytes,
+        })
+    }
+
+    pub fn broadcast_config(&self) -> BroadcastConfig {
+        BroadcastConfig {
+            send_timeout: Duration::from_millis(self.broadcast_send_timeout_ms),
+            retry_budget: Duration::from_millis(self.broadcast_retry_budget_ms),
+            ..Default::default()
+        }
+    }
+
+    pub fn sbom_cache_ttl(&self) -> Duration {
+        Duration::from_secs(self.sbom_cache_ttl_secs)
+    }
+
+    pub fn sbom_cache_flush_interval(&self) -> Duration {
+        Duration::from_secs(self.sbom_cache_flush_secs)
+    }
+
+    pub fn kube_options(&self) -> KubeOptions {
+        KubeOptions {
+            kubeconfig: self.kubeconfig.clone(),
+            context: self.context.clone(),
+        }
+    }
+
+    /// A copy of the configuration, with secrets replaced, so that it can be shown.
+    pub fn masked(&self) -> Self {
+        let mut masked = self.clone();
+        if masked.bombastic_url.password().is_some() {
+            let _ = masked.bombastic_url.set_password(Some("***"));
+        }
+        if masked.admin_token.is_some() {
+            masked.admin_token = Some("***".to_string());
+        }
+        masked
+    }
+
+    /// Print the effective configuration, along with the origin of each value.
+    pub fn show(&self, figment: &Figment) -> anyhow::Result<()> {
+        let Value::Object(values) = serde_json::to_value(self.masked())? else {
+            unreachable!("configuration must serialize into a map");
+        };
+
+        for (key, value) in values {
+            if value.is_null() {
+                println!("# {key} is not set");
+                continue;
+            }
+            let source = figment
+                .find_metadata(&key)
+                .map(|metadata| describe(metadata, &key))
+                .unwrap_or_default();
+            println!("{key} = {value} # {source}");
+        }
+
+        Ok(())
+    }
+}
+
+fn describe(metadata: &Metadata, key: &str) -> String {
+    match &metadata.source {
+        Some(source) => format!("{} ({source})", metadata.name),
+        None if metadata.name.contains("environment") => {
+            let mut name = metadata.interpolate(&Profile::Default, &[key]);
+            if metadata.name.starts_with(&format!("`{ENV_PREFIX}`")) {
+                name.insert_str(0, ENV_PREFIX);
+            }
+            format!("environment variable {name}")
+        }
+        None => metadata.name.to_string(),
+    }
+}
+
+/// Serialized values, reported under a given name.
+struct Named<T>(&'static str, T);
+
+impl<T: serde::Serialize> Provider for Named<T> {
+    fn metadata(&self) -> Metadata {
+        Metadata::named(self.0)
+    }
+
+    fn data(&self) -> Result<Map<Profile, Dict>, figment::Error> {
+        Serialized::defaults(&self.1).data()
+    }
+}
@@ -0,0 +1,272 @@
+//! Capturing the internal state, e.g. for a bug report.
+
+use crate::alert::AlertStatus;
+use crate::bombastic::LookupQueueStatus;
+use crate::config::Config;
+use crate::pubsub::{DropReason, DroppedEvent, ListenerInfo, ListenerTotals, UNNAMED_LISTENER};
+use crate::server::{Feeds, ServerConfig};
+use crate::workload::{Progress, WorkloadState};
+use anyhow::Context;
+use bommer_api::data::SbomState;
+use reqwest::header;
+use std::collections::{BTreeMap, HashSet};
+use std::process::ExitCode;
+use std::time::{SystemTime, UNIX_EPOCH};
+use url::Url;
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Diagnostics {
+    pub version: String,
+    pub workload: WorkloadDiagnostics,
+    /// progress of the initial sync of the pod watcher
+    pub watcher: Progress,
+    /// not set without leader election
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub leader: Option<LeaderDiagnostics>,
+    pub listeners: Vec<ListenerInfo>,
+    /// totals of all listeners ever registered, by name
+    pub listener_totals: BTreeMap<String, ListenerTotals>,
+    /// the most recent events which couldn't be delivered to a listener, oldest first
+    pub dropped_events: Vec<DroppedEvent>,
+    /// the state of the alert rules
+    #[serde(default)]
+    pub alerts: Vec<AlertStatus>,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct WorkloadDiagnostics {
+    pub images: usize,
+    pub pods: usize,
+    /// number of images, by SBOM state
+    pub sbom: BTreeMap<String, usize>,
+    /// images waiting for their SBOM to be looked up
+    pub lookup_queue: usize,
+    /// the handoff of those images to the lookups
+    #[serde(default)]
+    pub lookup_handoff: LookupQueueStatus,
+}
+
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct LeaderDiagnostics {
+    pub is_leader: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub holder: Option<String>,
+    pub transitions: u64,
+}
+
+impl Diagnostics {
+    pub async fn collect(config: &ServerConfig, map: &WorkloadState, feeds: &Feeds) -> Self {
+        let state = map.get_state().await;
+
+        let mut sbom = BTreeMap::<String, usize>::new();
+        for image in state.values() {
+            *sbom.entry(image.sbom.label().to_string()).or_default() += 1;
+        }
+
+        let workload = WorkloadDiagnostics {
+            images: state.len(),
+            pods: state
+                .values()
+                .flat_map(|image| &image.pods)
+                .collect::<HashSet<_>>()
+                .len(),
+            lookup_queue: state
+                .values()
+                .filter(|image| matches!(image.sbom, SbomState::Scheduled))
+                .count(),
+            sbom,
+            lookup_handoff: map.lookup_queue().status(),
+        };
+
+        let leader = config.leader.as_ref().map(|leader| {
+            let leader = leader.borrow();
+            LeaderDiagnostics {
+                is_leader: leader.is_leader,
+                holder: leader.holder.clone(),
+                transitions: leader.transitions,
+            }
+        });
+
+        // including the websocket clients, which listen to the views of the workload
+        let mut listeners = map.listeners().await;
+        listeners.extend(feeds.listeners().await);
+        let mut listener_totals = map.listener_totals().await;
+        for (name, totals) in feeds.listener_totals().await {
+            *listener_totals.entry(name).or_default() += totals;
+        }
+        let mut dropped_events = map.dropped_events().await;
+        dropped_events.extend(feeds.dropped_events().await);
+        dropped_events.sort_by_key(|evt| evt.timestamp);
+
+        Self {
+            version: env!("CARGO_PKG_VERSION").to_string(),
+            workload,
+            watcher: map.startup().progress(),
+            leader,
+            listeners,
+            listener_totals,
+            dropped_events,
+            alerts: config
+                .alerts
+                .as_ref()
+                .map(|alerts| alerts.status())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct DiagnoseOptions {
+    /// The running backend, e.g. `http://localhost:8080`
+    #[arg(long)]
+    pub endpoint: Url,
+
+    /// Token for the admin API, defaults to the configured admin token
+    #[arg(long, env = "BOMMER_ADMIN_TOKEN", hide_env_values = true)]
+    pub token: Option<String>,
+
+    /// Print the full diagnostics as JSON, instead of a summary
+    #[arg(long)]
+    pub json: bool,
+}
+
+/// Run the `diagnose` command.
+pub async fn run(options: &DiagnoseOptions, config: &Config) -> anyhow::Result<ExitCode> {
+    let token = options
+        .token
+        .as_ref()
+        .or(config.admin_token.as_ref())
+        .context("The diagnostics require an admin token")?;
+
+    let url = options.endpoint.join("/api/v1/admin/diagnostics")?;
+    let body = reqwest::Client::new()
+        .get(url)
+        .header(header::AUTHORIZATION, format!("Bearer {token}"))
+        .send()
+        .await?
+        .error_for_status()?
+        .text()
+        .await?;
+    let diagnostics: Diagnostics = serde_json::from_str(&body)?;
+
+    match options.json {
+        true => println!("{}", serde_json::to_string_pretty(&diagnostics)?),
+        false => print_summary(&diagnostics),
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+fn print_summary(diagnostics: &Diagnostics) {
+    let Diagnostics {
+        version,
+        workload,
+        watcher,
+        leader,
+        listeners,
+        listener_totals,
+        dropped_events,
+        alerts,
+    } = diagnostics;
+
+    println!("Version:    {version}");
+    println!(
+        "Workload:   {} images, {} pods, {} SBOM lookups queued",
+        workload.images, workload.pods, workload.lookup_queue
+    );
+    println!(
+        "            lookup queue: {}/{} queued, {} deferred",
+        workload.lookup_handoff.depth,
+        workload.lookup_handoff.capacity,
+        workload.lookup_handoff.deferred
+    );
+    for (state, count) in &workload.sbom {
+        println!("            {state}: {count}");
+    }
+    println!(
+        "Watcher:    {} ({} pods, {} images)",
+        match watcher.synced {
+            true => "synced",
+            false => "syncing",
+        },
+        watcher.pods,
+        watcher.images
+    );
+    if let Some(leader) = leader {
+        println!(
+            "Leader:     {} (current leader: {}, {} transitions)",
+            leader.is_leader,
+            leader.holder.as_deref().unwrap_or("unknown"),
+            leader.transitions
+        );
+    }
+
+    println!("Listeners:  {}", listeners.len());
+    for listener in listeners {
+        println!(
+            "            {} {} (priority {}, {}s old): {}/{} queued, at most {}, {} delivered",
+            listener.name.as_deref().unwrap_or(UNNAMED_LISTENER),
+            listener.id,
+            listener.priority,
+            listener.age_secs,
+            listener.queued,
+            listener.capacity,
+            listener.high_water,
+            listener.delivered
+        );
+    }
+    for (name, totals) in listener_totals {
+        println!(
+            "            {name}: {} delivered, {} evicted, {} reaped in total",
+            totals.delivered, totals.evicted, totals.reaped
+        );
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    println!("Dropped:    {} events", dropped_events.len());
+    for evt in dropped_events {
+        println!(
+            "            {}s ago: {} {} (listener {} {}, {})",
+            now.saturating_sub(evt.timestamp),
+            evt.kind,
+            evt.key.as_deref().unwrap_or_default(),
+            evt.name.as_deref().unwrap_or(UNNAMED_LISTENER),
+            evt.listener,
+            match evt.reason {
+                DropReason::Timeout => "evicted",
+                DropReason::Closed => "closed",
+                DropReason::Idle => "reaped",
+                DropReason::Lagged => "skipped",
+            }
+        );
+    }
+
+    println!(
+        "Alerts:     {} rules, {} firing",
+        alerts.len(),
+        alerts.iter().filter(|alert| alert.firing).count()
+    );
+    for alert in alerts {
+        println!(
+            "            {} ({}): {}{}, {} notified, {} failed",
+            alert.rule,
+            alert.namespace.as_deref().unwrap_or("all namespaces"),
+            match alert.firing {
+                true => "firing",
+                false => "ok",
+            },
+            match alert.since {
+                Some(since) => format!(" for {}s", now.saturating_sub(since)),
+                None => String::new(),
+            },
+            alert.notifications,
+            alert.failed_notifications
+        );
+    }
+}
@@ -0,0 +1,449 @@
+use crate::batch::{self, Summary};
+use crate::bombastic;
+use crate::config::Config;
+use anyhow::Context;
+use bommer_api::data::{Image, ImageRef, SbomState, SBOM};
+use bommer_api::sbom::SbomFormat;
+use clap::ArgGroup;
+use k8s_openapi::api::core::v1::Pod;
+use kube::Api;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use tracing::info;
+use url::Url;
+
+/// Format of an export
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Format {
+    /// The workload, as returned by the API
+    #[default]
+    Json,
+    /// One line per image
+    Csv,
+    /// A CycloneDX BOM, listing the images as components
+    Cyclonedx,
+    /// A CycloneDX BOM, listing the images as components, along with the components of their
+    /// SBOMs
+    #[serde(rename = "cyclonedx-aggregate")]
+    CyclonedxAggregate,
+}
+
+impl Format {
+    pub fn content_type(&self) -> &'static str {
+        match self {
+            Self::Json => "application/json",
+            Self::Csv => "text/csv",
+            Self::Cyclonedx | Self::CyclonedxAggregate => "application/vnd.cyclonedx+json",
+        }
+    }
+}
+
+/// SBOM state to filter for, named like [`SbomState::label`]
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SbomStateFilter {
+    Scheduled,
+    Error,
+    Missing,
+    Unsupported,
+    Found,
+}
+
+impl SbomStateFilter {
+    fn matches(&self, state: &SbomState) -> bool {
+        matches!(
+            (self, state),
+            (Self::Scheduled, SbomState::Scheduled)
+                | (Self::Error, SbomState::Err(_))
+                | (Self::Missing, SbomState::Missing)
+                | (Self::Unsupported, SbomState::Unsupported)
+                | (Self::Found, SbomState::Found(_))
+        )
+    }
+}
+
+#[derive(Clone, Debug, Default, clap::Args)]
+pub struct Filter {
+    /// Only export pods of this namespace
+    #[arg(long)]
+    pub namespace: Option<String>,
+
+    /// Only export images with this SBOM state
+    #[arg(long, value_enum)]
+    pub sbom_state: Option<SbomStateFilter>,
+}
+
+impl Filter {
+    /// Apply the filter, returning the remaining images in a stable order.
+    ///
+    /// Filtering by namespace drops images which have no pods left in that namespace.
+    pub fn apply(&self, state: HashMap<ImageRef, Image>) -> BTreeMap<ImageRef, Image> {
+        state
+            .into_iter()
+            .filter(|(_, image)| match &self.sbom_state {
+                Some(sbom_state) => sbom_state.matches(&image.sbom),
+                None => true,
+            })
+            .filter_map(|(image_ref, mut image)| {
+                if let Some(namespace) = &self.namespace {
+                    image.pods.retain(|pod| &pod.namespace == namespace);
+                    if image.pods.is_empty() {
+                        return None;
+                    }
+                }
+                Some((image_ref, image))
+            })
+            .collect()
+    }
+}
+
+/// Write the workload in the requested format.
+///
+/// This is used by the `export` command as well as the HTTP endpoint, so that both produce the
+/// same output.
+pub fn write<W: Write>(
+    format: Format,
+    workload: &BTreeMap<ImageRef, Image>,
+    mut writer: W,
+) -> anyhow::Result<()> {
+    match format {
+        Format::Json => serde_json::to_writer_pretty(&mut writer, workload)?,
+        Format::Csv => write_csv(workload, &mut writer)?,
+        Format::Cyclonedx => serde_json::to_writer_pretty(&mut writer, &cyclonedx(workload))?,
+        Format::CyclonedxAggregate => {
+            serde_json::to_writer_pretty(&mut writer, &cyclonedx_aggregate(workload))?
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+fn write_csv<W: Write>(
+    workload: &BTreeMap<ImageRef, Image>,
+    writer: &mut W,
+) -> std::io::Result<()> {
+    writeln!(writer, "image,sbom,pods,namespaces,error")?;
+
+    for (image_ref, image) in workload {
+        let namespaces = image
+            .pods
+            .iter()
+            .map(|pod| pod.namespace.as_str())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect::<Vec<_>>()
+            .join(" ");
+        let error = match &image.sbom {
+            SbomState::Err(err) => err.as_str(),
+            _ => "",
+        };
+
+        writeln!(
+            writer,
+            "{},{},{},{},{}",
+            csv_field(&image_ref.0),
+            image.sbom.label(),
+            image.pods.len(),
+            csv_field(&namespaces),
+            csv_field(error)
+        )?;
+    }
+
+    Ok(())
+}
+
+/// Quote a CSV field, if required
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+fn cyclonedx(workload: &BTreeMap<ImageRef, Image>) -> serde_json::Value {
+    let components = workload
+        .iter()
+        .map(|(image_ref, image)| image_component(image_ref, image))
+        .collect();
+
+    bom(components)
+}
+
+/// A BOM of the images, with the components of their (CycloneDX) SBOMs nested in them.
+///
+/// Components used by several images are only nested in the first one, so that each shows up
+/// once. The dependencies of each image list all of its components.
+fn cyclonedx_aggregate(workload: &BTreeMap<ImageRef, Image>) -> serde_json::Value {
+    let mut seen = HashSet::new();
+    let mut dependencies = Vec::new();
+
+    let components = workload
+        .iter()
+        .map(|(image_ref, image)| {
+            let mut component = image_component(image_ref, image);
+            let mut nested = Vec::new();
+            let mut depends_on = BTreeSet::new();
+
+            for mut package in image.sbom.sboms().iter().flat_map(sbom_components) {
+                let Some(key) = component_key(&package) else {
+                    continue;
+                };
+                // e.g. listed by the SBOMs of several architectures
+                if !depends_on.insert(key.clone()) {
+                    continue;
+                }
+                if seen.insert(key.clone()) {
+                    package["bom-ref"] = key.into();
+                    nested.push(package);
+                }
+            }
+
+            if !nested.is_empty() {
+                component["components"] = nested.into();
+            }
+            if !depends_on.is_empty() {
+                dependencies.push(serde_json::json!({
+                    "ref": image_ref.0,
+                    "dependsOn": depends_on,
+                }));
+            }
+            component
+        })
+        .collect();
+
+    let mut bom = bom(components);
+    bom["dependencies"] = dependencies.into();
+    bom
+}
+
+/// The component describing an image
+fn image_component(image_ref: &ImageRef, image: &Image) -> serde_json::Value {
+    let parts = image_ref.parts();
+    let mut component = serde_json::json!({
+        "type": "container",
+        "bom-ref": image_ref.0,
+        "name": parts.repository,
+        "properties": [
+            { "name": "bommer:sbom", "value": image.sbom.label() },
+            { "name": "bommer:pods", "value": image.pods.len().to_string() },
+        ],
+    });
+    if let Some(version) = parts.digest.or(parts.tag) {
+        component["version"] = version.into();
+    }
+    if let Ok(purl) = bombastic::purl(image_ref) {
+        component["purl"] = purl.to_string().into();
+    }
+    component
+}
+
+/// The top-level components of a CycloneDX SBOM, none for SPDX or invalid ones
+fn sbom_components(sbom: &SBOM) -> Vec<serde_json::Value> {
+    if sbom.format != SbomFormat::CycloneDx {
+        return Vec::new();
+    }
+
+    match serde_json::from_str::<serde_json::Value>(&sbom.data) {
+        Ok(serde_json::Value::Object(mut document)) => match document.remove("components") {
+            Some(serde_json::Value::Array(components)) => components,
+            _ => Vec::new(),
+        },
+        _ => Vec::new(),
+    }
+}
+
+/// What identifies a component across SBOMs: its package URL, or else its group, name, and
+/// version
+fn component_key(component: &serde_json::Value) -> Option<String> {
+    if let Some(purl) = component["purl"].as_str() {
+        return Some(purl.to_string());
+    }
+
+    let mut key = component["name"].as_str()?.to_string();
+    if let Some(group) = component["group"].as_str() {
+        key = format!("{group}/{key}");
+    }
+    if let Some(version) = component["version"].as_str() {
+        key = format!("{key}@{version}");
+    }
+    Some(key)
+}
+
+fn bom(components: Vec<serde_json::Value>) -> serde_json::Value {
+    serde_json::json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.4",
+        "version": 1,
+        "metadata": {
+            "tools": [{ "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") }],
+        },
+        "components": components,
+    })
+}
+
+#[derive(Clone, Debug, clap::Args)]
+#[command(group(ArgGroup::new("source").required(true).args(["endpoint", "direct"])))]
+pub struct ExportOptions {
+    /// Output format
+    #[arg(short, long, value_enum, default_value_t)]
+    pub format: Format,
+
+    /// File to write the export to, `-` for stdout
+    #[arg(short, long, default_value = "-")]
+    pub output: PathBuf,
+
+    /// Fetch the workload from a running backend, e.g. `http://localhost:8080`
+    #[arg(long)]
+    pub endpoint: Option<Url>,
+
+    /// Scan the cluster once, instead of asking a running backend
+    #[arg(long)]
+    pub direct: bool,
+
+    /// Maximum time to wait for a direct scan to complete, in seconds
+    #[arg(long, requires = "direct")]
+    pub sync_timeout: Option<u64>,
+
+    #[command(flatten)]
+    pub filter: Filter,
+
+    /// Fail if any of the exported images has no completed SBOM lookup
+    #[arg(long)]
+    pub strict: bool,
+}
+
+/// Run the `export` command.
+pub async fn run(options: &ExportOptions, config: &Config) -> anyhow::Result<ExitCode> {
+    let state = match &options.endpoint {
+        Some(endpoint) => fetch(endpoint).await?,
+        None => {
+            let client = config.kube_options().client().await?;
+            batch::sync(
+                Api::<Pod>::all(client),
+                config.sbom_sources(),
+                config.single_scan_options(),
+                options.sync_timeout,
+            )
+            .await?
+        }
+    };
+
+    let workload = options.filter.apply(state);
+
+    if options.output == Path::new("-") {
+        write(options.format, &workload, std::io::stdout().lock())?;
+    } else {
+        let file = File::create(&options.output)
+            .with_context(|| format!("Failed to create {}", options.output.display()))?;
+        write(options.format, &workload, BufWriter::new(file))?;
+        info!("Wrote export to: {}", options.output.display());
+    }
+
+    for (image_ref, image) in &workload {
+        match &image.sbom {
+            SbomState::Err(err) => eprintln!("Failed to look up SBOM of {image_ref}: {err}"),
+            SbomState::Scheduled => eprintln!("SBOM lookup of {image_ref} did not complete"),
+            _ => {}
+        }
+    }
+
+    Ok(
+        match options.strict && !Summary::new(workload.values()).is_resolved() {
+            true => ExitCode::FAILURE,
+            false => ExitCode::SUCCESS,
+        },
+    )
+}
+
+/// Fetch the current workload from a running backend.
+async fn fetch(endpoint: &Url) -> anyhow::Result<HashMap<ImageRef, Image>> {
+    let url = endpoint.join("/api/v1/workload")?;
+    info!("Fetching workload from: {url}");
+
+    let body = reqwest::get(url).await?.error_for_status()?.text().await?;
+
+    Ok(serde_json::from_str(&body)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::ImageBuilder;
+    use serde_json::json;
+
+    fn sbom(components: serde_json::Value) -> SBOM {
+        SBOM::new(json!({ "bomFormat": "CycloneDX", "components": components }).to_string())
+    }
+
+    fn image(name: &str, sbom: SbomState) -> (ImageRef, Image) {
+        (
+            ImageRef(format!("quay.io/{name}@sha256:1234")),
+            ImageBuilder::default().sbom(sbom).build(),
+        )
+    }
+
+    #[test]
+    fn aggregate() {
+        let openssl = json!({ "name": "openssl", "purl": "pkg:rpm/openssl@3.0" });
+        let zlib = json!({ "name": "zlib", "version": "1.2" });
+        let busybox = json!({ "group": "alpine", "name": "busybox" });
+
+        let workload = [
+            // the SBOMs of both architectures list openssl
+            image(
+                "a",
+                SbomState::Found(vec![sbom(json!([openssl, zlib])), sbom(json!([openssl]))]),
+            ),
+            image("b", SbomState::Found(vec![sbom(json!([openssl, busybox]))])),
+            image(
+                "c",
+                SbomState::Found(vec![SBOM::new(
+                    json!({ "spdxVersion": "SPDX-2.3" }).to_string(),
+                )]),
+            ),
+            image("d", SbomState::Missing),
+        ]
+        .into_iter()
+        .collect::<BTreeMap<_, _>>();
+
+        let mut output = Vec::new();
+        write(Format::CyclonedxAggregate, &workload, &mut output).unwrap();
+        let bom = serde_json::from_slice::<serde_json::Value>(&output).unwrap();
+
+        let nested = |image: usize| {
+            bom["components"][image]["components"]
+                .as_array()
+                .map(|components| {
+                    components
+                        .iter()
+                        .map(|component| component["bom-ref"].as_str().unwrap())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default()
+        };
+        assert_eq!(bom["components"].as_array().unwrap().len(), 4);
+        assert_eq!(nested(0), ["pkg:rpm/openssl@3.0", "zlib@1.2"]);
+        // shared components are only nested once
+        assert_eq!(nested(1), ["alpine/busybox"]);
+        assert!(nested(2).is_empty());
+        assert!(nested(3).is_empty());
+
+        assert_eq!(
+            bom["dependencies"],
+            json!([
+                {
+                    "ref": "quay.io/a@sha256:1234",
+                    "dependsOn": ["pkg:rpm/openssl@3.0", "zlib@1.2"],
+                },
+                {
+                    "ref": "quay.io/b@sha256:1234",
+                    "dependsOn": ["alpine/busybox", "pkg:rpm/openssl@3.0"],
+                },
+            ])
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Leader election, based on a Kubernetes `Lease`.
+//!
+//! Only the leader runs the pod watcher and the SBOM pipeline. Standby instances keep trying to
+//! acquire the lease, which they can once the leader stopped renewing it.
+
+use k8s_openapi::api::coordination::v1::{Lease, LeaseSpec};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::{MicroTime, ObjectMeta};
+use k8s_openapi::chrono::{self, Utc};
+use kube::api::PostParams;
+use kube::{Api, Client};
+use std::collections::BTreeMap;
+use std::future::Future;
+use std::time::Duration;
+use tokio::sync::watch;
+use tokio::time::Instant;
+use tracing::{debug, info, warn};
+use url::Url;
+
+/// Annotation on the lease, carrying the URL of the leader
+pub const ANNOTATION_URL: &str = "bommer.io/leader-url";
+
+/// How a standby instance serves the API
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Standby {
+    /// Report not-ready, until acquiring leadership
+    #[default]
+    NotReady,
+    /// Redirect API requests to the URL advertised by the leader
+    Redirect,
+}
+
+#[derive(Clone, Debug)]
+pub struct LeaseOptions {
+    /// name of the lease
+    pub name: String,
+    /// namespace of the lease, the client's default namespace if `None`
+    pub namespace: Option<String>,
+    /// identity of this instance
+    pub identity: String,
+    /// URL this instance can be reached at, advertised to standby instances
+    pub url: Option<Url>,
+    /// time after which a lease which wasn't renewed can be taken over
+    pub duration: Duration,
+    /// interval for renewing, or trying to acquire, the lease
+    pub renew_interval: Duration,
+}
+
+/// The leadership, as seen by this instance
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Leadership {
+    pub is_leader: bool,
+    /// identity of the current leader, if known
+    pub holder: Option<String>,
+    /// URL advertised by the current leader
+    pub url: Option<Url>,
+    /// number of times this instance acquired or lost leadership
+    pub transitions: u64,
+}
+
+pub struct LeaderElection {
+    api: Api<Lease>,
+    options: LeaseOptions,
+}
+
+impl LeaderElection {
+    pub fn new(client: Client, options: LeaseOptions) -> Self {
+        let api = match &options.namespace {
+            Some(namespace) => Api::namespaced(client, namespace),
+            None => Api::default_namespaced(client),
+        };
+        Self { api, options }
+    }
+
+    /// Start taking part in the election, returns the leadership status and the election loop.
+    pub fn run(
+        self,
+    ) -> (
+        watch::Receiver<Leadership>,
+        impl Future<Output = anyhow::Result<()>>,
+    ) {
+        let (tx, rx) = watch::channel(Leadership::default());
+        (rx, self.elect(tx))
+    }
+
+    async fn elect(self, tx: watch::Sender<Leadership>) -> anyhow::Result<()> {
+        info!(
+            "Taking part in leader election as '{}', using lease '{}'",
+            self.options.identity, self.options.name
+        );
+
+        let mut interval = tokio::time::interval(self.options.renew_interval);
+        // we stay the leader until our last renewal expired, even if we can't reach the API
+        let mut leading_until: Option<Instant> = None;
+
+        loop {
+            interval.tick().await;
+            let attempt = Instant::now();
+
+            let observed = match self.acquire_or_renew().await {
+                Ok(lease) => {
+                    let (holder, url) = observed(&lease);
+                    leading_until = (holder.as_deref() == Some(&self.options.identity))
+                        .then(|| attempt + self.options.duration);
+                    Some((holder, url))
+                }
+                Err(kube::Error::Api(err)) if err.code == 409 => {
+                    // someone else updated the lease in the meantime, the next round will tell
+                    debug!("Conflict updating the lease");
+                    None
+                }
+                Err(err) => {
+                    warn!("Failed to acquire or renew the lease: {err}");
+                    None
+                }
+            };
+
+            let is_leader = leading_until.is_some_and(|until| until > Instant::now());
+
+            tx.send_if_modified(|current| {
+                let mut modified = false;
+
+                if current.is_leader != is_leader {
+                    match is_leader {
+                        true => info!("Acquired leadership"),
+                        false => warn!("Lost leadership"),
+                    }
+                    current.is_leader = is_leader;
+                    current.transitions += 1;
+                    modified = true;
+                }
+
+                if let Some((holder, url)) = observed {
+                    if current.holder != holder {
+                        info!("Current leader: {}", holder.as_deref().unwrap_or("<none>"));
+                        current.holder = holder;
+                        modified = true;
+                    }
+                    if current.url != url {
+                        current.url = url;
+                        modified = true;
+                    }
+                }
+
+                modified
+            });
+        }
+    }
+
+    /// Acquire the lease if it is free or expired, renew it if we hold it already.
+    ///
+    /// Updates are guarded by the lease's resource version, so two instances can't acquire it at
+    /// the same time. Returns the lease as seen afterwards.
+    async fn acquire_or_renew(&self) -> Result<Lease, kube::Error> {
+        let now = Utc::now();
+        let duration = self
+            .options
+            .duration
+            .as_secs()
+            .try_into()
+            .unwrap_or(i32::MAX);
+
+        let Some(mut lease) = self.api.get_opt(&self.options.name).await? else {
+            let lease = Lease {
+                metadata: ObjectMeta {
+                    name: Some(self.options.name.clone()),
+                    annotations: self.annotations(),
+                    ..Default::default()
+                },
+                spec: Some(LeaseSpec {
+                    holder_identity: Some(self.options.identity.clone()),
+                    lease_duration_seconds: Some(duration),
+                    acquire_time: Some(MicroTime(now)),
+                    renew_time: Some(MicroTime(now)),
+                    lease_transitions: Some(0),
+                }),
+            };
+            return self.api.create(&PostParams::default(), &lease).await;
+        };
+
+        let spec = lease.spec.get_or_insert_with(Default::default);
+
+        if spec.holder_identity.as_deref() != Some(&self.options.identity) {
+            if !is_expired(spec, now) {
+                return Ok(lease);
+            }
+
+            info!(
+                "Taking over expired lease from: {}",
+                spec.holder_identity.as_deref().unwrap_or("<none>")
+            );
+            spec.holder_identity = Some(self.options.identity.clone());
+            spec.acquire_time = Some(MicroTime(now));
+            spec.lease_transitions = Some(spec.lease_transitions.unwrap_or_default() + 1);
+            lease.metadata.annotations = self.annotations();
+        }
+
+        spec.renew_time = Some(MicroTime(now));
+        spec.lease_duration_seconds = Some(duration);
+
+        self.api
+            .replace(&self.options.name, &PostParams::default(), &lease)
+            .await
+    }
+
+    fn annotations(&self) -> Option<BTreeMap<String, String>> {
+        self.options
+            .url
+            .as_ref()
+            .map(|url| BTreeMap::from([(ANNOTATION_URL.to_string(), url.to_string())]))
+    }
+}
+
+/// Holder and advertised URL of a lease
+fn observed(lease: &Lease) -> (Option<String>, Option<Url>) {
+    let holder = lease
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.holder_identity.clone());
+    let url = lease
+        .metadata
+        .annotations
+        .as_ref()
+        .and_then(|annotations| annotations.get(ANNOTATION_URL))
+        .and_then(|url| Url::parse(url).ok());
+    (holder, url)
+}
+
+fn is_expired(spec: &LeaseSpec, now: chrono::DateTime<Utc>) -> bool {
+    match (&spec.renew_time, spec.lease_duration_seconds) {
+        (Some(renew_time), Some(duration)) => {
+            renew_time.0 + chrono::Duration::seconds(duration.into()) < now
+        }
+        _ => true,
+    }
+}
+
+/// Wait until this instance is, or is no longer, the leader.
+pub async fn wait_until(
+    status: &mut watch::Receiver<Leadership>,
+    is_leader: bool,
+) -> anyhow::Result<()> {
+    while status.borrow_and_update().is_leader != is_leader {
+        status.changed().await?;
+    }
+    Ok(())
+}
@@ -0,0 +1,4 @@
+//! The parts of the server which stand on their own, so that they can be benchmarked and tested
+//! without a cluster.
+
+pub mod pubsub;
@@ -0,0 +1,60 @@
+use parking_lot::Mutex;
+use std::sync::Arc;
+use tracing::info;
+use tracing_subscriber::prelude::*;
+use tracing_subscriber::{reload, EnvFilter, Registry};
+
+/// Allows to inspect and change the active log filter at runtime.
+#[derive(Clone)]
+pub struct LogControl {
+    handle: reload::Handle<EnvFilter, Registry>,
+    current: Arc<Mutex<String>>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    #[error("Invalid filter: {0}")]
+    Filter(#[from] tracing_subscriber::filter::ParseError),
+    #[error("Failed to apply filter: {0}")]
+    Reload(#[from] reload::Error),
+    #[cfg(feature = "otel")]
+    #[error("Failed to set up trace export: {0}")]
+    Telemetry(#[from] opentelemetry::trace::TraceError),
+}
+
+/// Install the global subscriber, using `filter` as the initial log filter.
+pub fn init(filter: &str) -> Result<LogControl, Error> {
+    let (layer, handle) = reload::Layer::new(EnvFilter::try_new(filter)?);
+
+    let registry = tracing_subscriber::registry()
+        .with(layer)
+        .with(tracing_subscriber::fmt::layer().with_writer(std::io::stderr));
+    #[cfg(feature = "otel")]
+    let registry = registry.with(crate::telemetry::layer()?);
+    registry.init();
+
+    Ok(LogControl {
+        handle,
+        current: Arc::new(Mutex::new(filter.to_string())),
+    })
+}
+
+impl LogControl {
+    /// The currently active filter
+    pub fn current(&self) -> String {
+        self.current.lock().clone()
+    }
+
+    /// Replace the active filter. An invalid filter is rejected, keeping the current one.
+    pub fn set(&self, filter: &str) -> Result<(), Error> {
+        let new_filter = EnvFilter::try_new(filter)?;
+
+        let mut current = self.current.lock();
+        self.handle.reload(new_filter)?;
+        *current = filter.to_string();
+
+        info!(filter, "Changed log filter");
+
+        Ok(())
+    }
+}
@@ -1,61 +1,249 @@
+mod admission;
+mod alert;
+mod audit;
+mod batch;
 mod bombastic;
-mod pubsub;
+mod cluster;
+mod config;
+mod diagnostics;
+mod export;
+mod leader;
+mod logging;
+mod policy;
+mod reload;
+mod replay;
 mod server;
+mod shutdown;
+mod simulate;
 mod store;
+mod telemetry;
+#[cfg(test)]
+mod testutil;
 mod workload;
 
-use crate::bombastic::BombasticSource;
-use crate::server::ServerConfig;
+use crate::admission::Admission;
+use crate::alert::Alerting;
+use crate::batch::BatchOptions;
+use crate::bombastic::{SbomCache, SbomSourceRouter, ScanOptions};
+use crate::config::{Config, Overrides};
+use crate::diagnostics::DiagnoseOptions;
+use crate::export::ExportOptions;
+use crate::leader::LeaderElection;
+use crate::policy::PolicyTestOptions;
+use crate::reload::Reloader;
+use crate::replay::ReplayOptions;
+use crate::server::{ServerConfig, Webhook};
+use crate::shutdown::Shutdown;
+use crate::simulate::SimulateOptions;
 use crate::store::image_store;
-use futures::FutureExt;
+use crate::workload::WorkloadState;
+use anyhow::Context;
+use bommer::pubsub;
+use clap::Parser;
 use k8s_openapi::api::core::v1::Pod;
-use kube::{runtime::watcher, Api, Client};
+use kube::{runtime::watcher, Api};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::{info, warn};
 
+#[derive(Clone, Debug, Parser)]
+#[command(author, version, about)]
+struct Cli {
+    /// Configuration file
+    #[arg(short, long, env = "BOMMER_CONFIG", default_value = "bommer.toml")]
+    config: PathBuf,
+
+    #[command(flatten)]
+    overrides: Overrides,
+
+    #[command(flatten)]
+    batch: BatchOptions,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+enum Command {
+    /// Inspect the configuration
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Export a snapshot of the workload
+    Export(ExportOptions),
+    /// Capture the internal state of a running backend, e.g. for a bug report
+    Diagnose(DiagnoseOptions),
+    /// Run the pipeline against a simulated cluster, checking it stays consistent under churn
+    Simulate(SimulateOptions),
+    /// Replay a recording of the events sent to websocket clients
+    Replay(ReplayOptions),
+    /// Inspect the image policy
+    Policy {
+        #[command(subcommand)]
+        command: PolicyCommand,
+    },
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+enum ConfigCommand {
+    /// Show the effective configuration, and where each value came from
+    Show,
+}
+
+#[derive(Clone, Debug, clap::Subcommand)]
+enum PolicyCommand {
+    /// Classify an image, explaining which rule matched
+    Test(PolicyTestOptions),
+}
+
 #[tokio::main]
-async fn main() -> anyhow::Result<()> {
-    tracing_subscriber::fmt::init();
+async fn main() -> anyhow::Result<ExitCode> {
+    let cli = Cli::parse();
 
-    let client = Client::try_default().await?;
+    let figment = Config::figment(&cli.config, &cli.overrides);
+    let config = Config::load(&figment)?;
 
-    let api: Api<Pod> = Api::all(client);
+    if let Some(Command::Config {
+        command: ConfigCommand::Show,
+    }) = cli.command
+    {
+        config.show(&figment)?;
+        return Ok(ExitCode::SUCCESS);
+    }
 
-    let stream = watcher(
-        api,
-        watcher::Config {
-            ..Default::default()
-        },
+    let log = logging::init(&config.log_filter)?;
+
+    let reloader = Reloader::new(
+        cli.config.clone(),
+        cli.overrides.clone(),
+        config.clone(),
+        log.clone(),
+    );
+    let (rescan_interval, strict_transitions, admission_policy) = (
+        reloader.rescan_interval(),
+        reloader.strict_transitions(),
+        reloader.admission_policy(),
     );
+    tokio::spawn(reloader.run());
 
-    let url =
-        std::env::var("BOMBASTIC_URL").unwrap_or_else(|_| "http://localhost:8080".to_string());
-    let source = BombasticSource::new(url.parse()?);
+    match &cli.command {
+        Some(Command::Export(options)) => return export::run(options, &config).await,
+        Some(Command::Diagnose(options)) => return diagnostics::run(options, &config).await,
+        Some(Command::Simulate(options)) => return simulate::run(options, &config).await,
+        Some(Command::Replay(options)) => return replay::run(options).await,
+        Some(Command::Policy {
+            command: PolicyCommand::Test(options),
+        }) => return policy::test(options, &config.policy),
+        _ => {}
+    }
 
-    let (store, runner) = image_store(stream);
+    let client = config.kube_options().client().await?;
 
-    if false {
-        let store = store.clone();
-        tokio::spawn(async move {
-            loop {
-                info!("Starting event stream");
-                let mut sub = store.subscribe(16).await;
-                while let Some(evt) = sub.recv().await {
-                    info!("Event: {evt:?}");
-                }
-            }
-        });
+    let api: Api<Pod> = Api::all(client.clone());
+
+    let source = config.sbom_sources();
+    if cli.batch.exit_after_sync {
+        return batch::run(api, source, config.single_scan_options(), &cli.batch).await;
     }
 
-    // SBOM scanner
+    let policy = Arc::new(config.policy.clone());
+
+    let map = WorkloadState::default();
+    map.set_shrink_policy(config.shrink_policy()).await;
+    map.set_broadcast_config(config.broadcast_config()).await;
+    if config.listener_idle_timeout_secs > 0 {
+        map.reap_idle_listeners(Duration::from_secs(config.listener_idle_timeout_secs));
+    }
+    map.set_image_limit(config.max_images, config.max_images_strategy)
+        .await;
+    map.lookup_queue()
+        .set_capacity(config.lookup_queue_capacity);
+    let _audit = match &config.audit_file {
+        Some(path) => {
+            let sink = audit::JsonLines::open(path)
+                .with_context(|| format!("Failed to open audit file: {}", path.display()))?;
+            Some(audit::attach(&map, sink).await)
+        }
+        None => None,
+    };
+
+    if let Some(path) = &config.baseline_file {
+        map.load_baseline(path)
+            .await
+            .with_context(|| format!("Failed to load baseline: {}", path.display()))?;
+    }
 
-    let (map, runner2) = bombastic::store(store.clone(), source);
+    // leader election
+
+    let mut shutdown = Shutdown::new(config.shutdown_deadline());
+
+    let leader = match config.lease_options() {
+        Some(options) => {
+            let (leader, election) = LeaderElection::new(client.clone(), options).run();
+            shutdown.add_cancellable("leader election", election);
+            Some(leader)
+        }
+        None => None,
+    };
+
+    // SBOM cache
+
+    let cache = config
+        .sbom_cache_file
+        .clone()
+        .map(|path| Arc::new(SbomCache::open(path, config.sbom_cache_ttl())));
+
+    if let Some(cache) = &cache {
+        shutdown.add(
+            "SBOM cache",
+            cache
+                .clone()
+                .run(config.sbom_cache_flush_interval(), shutdown.token()),
+        );
+    }
+
+    // pod watcher and SBOM scanner, only run by the leader
+
+    {
+        let (map, source) = (map.clone(), source.clone());
+        let options = ScanOptions {
+            rescan_interval,
+            strict_transitions,
+            cache: cache.clone(),
+            shrink: config.shrink_policy(),
+            policy: policy.clone(),
+            manifests: config.manifest_resolver(),
+        };
+        match leader.clone() {
+            None => shutdown.add_cancellable("pipeline", pipeline(api, source, map, options)),
+            Some(mut leader) => shutdown.add_cancellable("pipeline", async move {
+                loop {
+                    leader::wait_until(&mut leader, true).await?;
+                    tokio::select! {
+                        result = pipeline(
+                            api.clone(),
+                            source.clone(),
+                            map.clone(),
+                            options.clone(),
+                        ) => return result,
+                        result = leader::wait_until(&mut leader, false) => result?,
+                    }
+                    // once leading again, start over with a new initial sync
+                    map.startup().reset();
+                }
+            }),
+        }
+    }
 
     {
         let map = map.clone();
         tokio::spawn(async move {
             loop {
                 info!("Starting SBOM stream");
-                let mut sub = map.subscribe(16).await;
+                let mut sub = map.named("event-log").subscribe(16).await;
                 while let Some(evt) = sub.recv().await {
                     info!("Event: {evt:?}");
                 }
@@ -64,24 +252,96 @@ async fn main() -> anyhow::Result<()> {
         });
     }
 
+    // alerts
+
+    config.alerts.validate()?;
+    let alerts = match config.alerts.rules.is_empty() {
+        true => None,
+        false => {
+            let alerts = Alerting::new(config.alerts.clone());
+            shutdown.add_cancellable("alerts", alerts.clone().run(map.clone()));
+            Some(alerts)
+        }
+    };
+
     // server
 
-    let bind_addr = std::env::var("BIND_ADDR").unwrap_or_else(|_| "[::]:8080".to_string());
+    let server_config = ServerConfig {
+        bind_api: config.bind_api(),
+        bind_metrics: config.bind_metrics(),
+        event_metrics: config.event_metrics,
+        admin_token: config.admin_token.clone(),
+        early_websocket: config.early_websocket,
+        leader,
+        standby: config.standby,
+        baseline_file: config.baseline_file.clone(),
+        record: config.record_options(),
+        alerts,
+    };
 
-    info!("Binding to {bind_addr}");
+    let webhook = match config.bind_webhook() {
+        Some(bind_addr) => Some(Webhook {
+            bind_addr,
+            tls_cert: config
+                .webhook_tls_cert
+                .clone()
+                .context("The webhook listener requires a TLS certificate")?,
+            tls_key: config
+                .webhook_tls_key
+                .clone()
+                .context("The webhook listener requires a TLS key")?,
+            admission: Admission::new(admission_policy, policy, map.clone(), source, client),
+        }),
+        None => None,
+    };
 
-    let config = ServerConfig { bind_addr };
+    let token = shutdown.token();
+    shutdown.add(
+        "HTTP server",
+        server::run(server_config, map, log, webhook, token),
+    );
 
-    let server = server::run(config, map);
+    let result = shutdown.run().await;
+    telemetry::shutdown().await;
+    result
+}
 
-    let (result, _, _) = futures::future::select_all([
-        server.boxed_local(),
-        runner.boxed_local(),
-        runner2.boxed_local(),
-    ])
-    .await;
+/// Watch the pods and look up their SBOMs, feeding the workload state.
+async fn pipeline(
+    api: Api<Pod>,
+    source: SbomSourceRouter,
+    map: WorkloadState,
+    options: ScanOptions,
+) -> anyhow::Result<()> {
+    let stream = watcher(
+        api,
+        watcher::Config {
+            ..Default::default()
+        },
+    );
 
-    result?;
+    let (store, runner) = image_store(stream);
+    store.set_shrink_policy(options.shrink).await;
 
-    Ok(())
+    if false {
+        let store = store.clone();
+        tokio::spawn(async move {
+            loop {
+                info!("Starting event stream");
+                let mut sub = store.subscribe(16).await;
+                while let Some(evt) = sub.recv().await {
+                    info!("Event: {evt:?}");
+                }
+            }
+        });
+    }
+
+    // SBOM scanner
+
+    let runner2 = bombastic::run(map, store, source, options);
+
+    tokio::select! {
+        result = runner => result,
+        result = runner2 => result,
+    }
 }
@@ -0,0 +1,203 @@
+//! Classifying images by what is expected of their SBOM, e.g. exempting third-party operators.
+//!
+//! Rules are checked in order, the first matching one classifies the image. An image used in
+//! several namespaces gets the strictest classification of all of them.
+
+use crate::bombastic;
+use bommer_api::data::{Classification, ImageRef, PodRef};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::process::ExitCode;
+
+#[derive(Clone, Debug, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct ImagePolicy {
+    /// classification of images no rule matches
+    pub default: Classification,
+    pub rules: Vec<PolicyRule>,
+}
+
+/// Classifies the images matching all of its conditions
+#[derive(Clone, Debug, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PolicyRule {
+    pub name: String,
+    /// registry, as it appears in the image reference, `docker.io` for images without one
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub registry: Option<String>,
+    /// repository, without registry, tag, or digest, `*` matching any number of characters
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub repository: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub namespace: Option<String>,
+    /// labels of the pod, only known to the admission webhook
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+    pub classification: Classification,
+}
+
+/// What an image is checked against
+#[derive(Clone, Copy, Debug)]
+pub struct Subject<'a> {
+    pub image: &'a ImageRef,
+    /// `None` if unknown, which no rule for a namespace matches
+    pub namespace: Option<&'a str>,
+    /// `None` if unknown, which no rule for labels matches
+    pub labels: Option<&'a BTreeMap<String, String>>,
+}
+
+impl PolicyRule {
+    /// Why the rule doesn't match, `None` if it does.
+    fn mismatch(&self, subject: &Subject) -> Option<String> {
+        let parts = subject.image.parts();
+
+        if let Some(registry) = &self.registry {
+            let actual = bombastic::registry(subject.image);
+            if registry != actual {
+                return Some(format!("registry is {actual}, not {registry}"));
+            }
+        }
+
+        if let Some(pattern) = &self.repository {
+            if !glob(pattern, parts.repository) {
+                return Some(format!(
+                    "repository {} doesn't match {pattern}",
+                    parts.repository
+                ));
+            }
+        }
+
+        if let Some(namespace) = &self.namespace {
+            match subject.namespace {
+                Some(actual) if actual == namespace => {}
+                Some(actual) => return Some(format!("namespace is {actual}, not {namespace}")),
+                None => return Some(format!("namespace unknown, needs to be {namespace}")),
+            }
+        }
+
+        for (key, value) in &self.labels {
+            match subject.labels.map(|labels| labels.get(key)) {
+                Some(Some(actual)) if actual == value => {}
+                Some(Some(actual)) => {
+                    return Some(format!("label {key} is {actual}, not {value}"));
+                }
+                Some(None) => return Some(format!("label {key} is not set")),
+                None => return Some(format!("labels unknown, needs {key}={value}")),
+            }
+        }
+
+        None
+    }
+}
+
+impl ImagePolicy {
+    /// The first rule matching, along with its position, `None` if the default applies.
+    pub fn rule(&self, subject: &Subject) -> Option<(usize, &PolicyRule)> {
+        self.rules
+            .iter()
+            .enumerate()
+            .find(|(_, rule)| rule.mismatch(subject).is_none())
+    }
+
+    pub fn classify(&self, subject: &Subject) -> Classification {
+        self.rule(subject)
+            .map_or(self.default, |(_, rule)| rule.classification)
+    }
+
+    /// Classify an image by the namespaces of its pods, the strictest classification winning.
+    pub fn classify_image(&self, image: &ImageRef, pods: &HashSet<PodRef>) -> Classification {
+        let namespaces = pods
+            .iter()
+            .map(|pod| pod.namespace.as_str())
+            .collect::<BTreeSet<_>>();
+
+        // an image without pods has no namespace to go by
+        if namespaces.is_empty() {
+            return self.classify(&Subject {
+                image,
+                namespace: None,
+                labels: None,
+            });
+        }
+
+        namespaces
+            .into_iter()
+            .map(|namespace| {
+                self.classify(&Subject {
+                    image,
+                    namespace: Some(namespace),
+                    labels: None,
+                })
+            })
+            .max()
+            .unwrap_or(self.default)
+    }
+}
+
+/// Match `value` against `pattern`, in which `*` matches any number of characters.
+fn glob(pattern: &str, value: &str) -> bool {
+    let mut parts = pattern.split('*');
+    let first = parts.next().unwrap_or_default();
+    let Some(mut rest) = value.strip_prefix(first) else {
+        return false;
+    };
+
+    let mut parts = parts.collect::<Vec<_>>();
+    let Some(last) = parts.pop() else {
+        // no wildcard at all
+        return rest.is_empty();
+    };
+
+    for part in parts {
+        match rest.find(part) {
+            Some(at) => rest = &rest[at + part.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct PolicyTestOptions {
+    /// The image reference to classify
+    pub image: ImageRef,
+
+    /// Namespace of the pod using the image
+    #[arg(short, long)]
+    pub namespace: Option<String>,
+
+    /// Labels of the pod using the image, as `key=value`, can be given several times
+    #[arg(short, long = "label", value_parser = parse_label)]
+    pub labels: Vec<(String, String)>,
+}
+
+fn parse_label(label: &str) -> Result<(String, String), String> {
+    label
+        .split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected key=value, got: {label}"))
+}
+
+/// Run the `policy test` command, explaining which rule classifies the image.
+pub fn test(options: &PolicyTestOptions, policy: &ImagePolicy) -> anyhow::Result<ExitCode> {
+    let labels = options.labels.iter().cloned().collect::<BTreeMap<_, _>>();
+    let subject = Subject {
+        image: &options.image,
+        namespace: options.namespace.as_deref(),
+        // without any, labels are unknown, like for images of the workload
+        labels: Some(&labels).filter(|labels| !labels.is_empty()),
+    };
+
+    for (n, rule) in policy.rules.iter().enumerate() {
+        match rule.mismatch(&subject) {
+            Some(reason) => println!("#{} {}: no match, {reason}", n + 1, rule.name),
+            None => {
+                println!("#{} {}: match", n + 1, rule.name);
+                println!("{}: {}", options.image, rule.classification);
+                return Ok(ExitCode::SUCCESS);
+            }
+        }
+    }
+
+    println!("{}: {} (no rule matched)", options.image, policy.default);
+    Ok(ExitCode::SUCCESS)
+}
@@ -1,14 +1,26 @@
 use bommer_api::data::Event;
-use futures::{stream, StreamExt};
-use std::collections::hash_map::Entry;
-use std::collections::HashMap;
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use serde_json::value::RawValue;
+use std::cmp::Reverse;
+use std::collections::hash_map::{Entry, RandomState};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::hash::Hash;
-use std::ops::{Deref, DerefMut};
+use std::future::Future;
+use std::hash::{BuildHasher, Hash};
+use std::io::{BufRead, Write};
+use std::marker::PhantomData;
+use std::ops::{AddAssign, Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use tokio::sync::{mpsc, RwLock};
-use tracing::debug;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::mpsc::error::{SendError, SendTimeoutError, TryRecvError, TrySendError};
+use tokio::sync::{mpsc, watch, Notify, OwnedRwLockReadGuard, RwLock};
+use tokio::time::{Instant, MissedTickBehavior};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, warn};
 
 pub struct Subscription<K, V>
 where
@@ -17,6 +29,10 @@ where
 {
     rx: mpsc::Receiver<Event<K, V>>,
     unsubscribe: Option<Box<dyn FnOnce() + Send + Sync + 'static>>,
+    /// the number of unread events, for subscriptions from [`State::subscribe_counted`]
+    unread: Option<Arc<AtomicUsize>>,
+    /// activity of the listener, shared with the state, if it was registered with one
+    activity: Option<Arc<Activity>>,
 }
 
 impl<K, V> Subscription<K, V>
@@ -31,8 +47,190 @@ where
         Self {
             rx,
             unsubscribe: Some(Box::new(unsubscribe)),
+            unread: None,
+            activity: None,
         }
     }
+
+    /// A subscription of a listener registered with a state.
+    fn registered(
+        rx: mpsc::Receiver<Event<K, V>>,
+        (unsubscribe, activity): (impl FnOnce() + Send + Sync + 'static, Arc<Activity>),
+    ) -> Self {
+        let mut sub = Self::new(rx, unsubscribe);
+        sub.activity = Some(activity);
+        sub
+    }
+
+    /// Record activity of the remote end the events get forwarded to, e.g. a pong of a websocket
+    /// client.
+    ///
+    /// Once activity was recorded, the listener is considered idle when there was none for the
+    /// idle timeout, see [`State::reap_idle_listeners`]. Derived subscriptions don't track
+    /// activity.
+    pub fn touch(&self) {
+        if let Some(activity) = &self.activity {
+            activity.touch();
+        }
+    }
+
+    /// Whether the listener was removed for being idle, which ends the subscription.
+    pub fn is_reaped(&self) -> bool {
+        self.activity
+            .as_ref()
+            .is_some_and(|activity| activity.reaped.load(Ordering::Relaxed))
+    }
+
+    /// Receive the next event, see [`mpsc::Receiver::recv`].
+    ///
+    /// This shadows the method of the receiver, so that counted subscriptions keep track of the
+    /// events read. Receiving events in any other way (e.g. by polling) doesn't count them.
+    pub async fn recv(&mut self) -> Option<Event<K, V>> {
+        let evt = self.rx.recv().await;
+        if evt.is_some() {
+            self.read();
+        }
+        evt
+    }
+
+    /// Receive the next event, if one is available, see [`mpsc::Receiver::try_recv`].
+    pub fn try_recv(&mut self) -> Result<Event<K, V>, TryRecvError> {
+        let evt = self.rx.try_recv();
+        if evt.is_ok() {
+            self.read();
+        }
+        evt
+    }
+
+    fn read(&self) {
+        if let Some(unread) = &self.unread {
+            unread.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+}
+
+impl<K, V> Subscription<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + Send + Sync + 'static,
+{
+    /// Tie the subscription to `token`, cancelling the token unsubscribes and ends the stream.
+    ///
+    /// This works with all kinds of subscriptions, the ones of derived states included, as the
+    /// subscription gets dropped when the token is cancelled. Events received before that are
+    /// still delivered.
+    pub fn with_cancellation(mut self, token: CancellationToken) -> Self {
+        let (tx, rx) = mpsc::channel(self.rx.max_capacity());
+
+        tokio::spawn(async move {
+            loop {
+                // check for cancellation first, so that nothing gets forwarded once cancelled
+                tokio::select! {
+                    biased;
+                    _ = token.cancelled() => break,
+                    _ = tx.closed() => break,
+                    evt = self.recv() => {
+                        let Some(evt) = evt else {
+                            break;
+                        };
+                        // don't wait for a slow receiver once cancelled
+                        tokio::select! {
+                            biased;
+                            _ = token.cancelled() => break,
+                            result = tx.send(evt) => if result.is_err() {
+                                break;
+                            },
+                        }
+                    }
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the inner subscription
+        Subscription::new(rx, || {})
+    }
+
+    /// Hold back modifications for `window`, dropping them if the value returned to the one the
+    /// receiver already knows in the meantime.
+    ///
+    /// So a value flapping from `A` to `B` and back to `A` within the window isn't announced at
+    /// all, and one changing from `A` to `B` to `C` is only announced as `C`. A modification is
+    /// delivered at most `window` after it arrived, even if the value keeps changing. All other
+    /// events are delivered right away, dropping the held back modifications they supersede. As
+    /// only modifications are delayed, events of different keys may get reordered.
+    pub fn deduplicate_events(mut self, window: Duration) -> Self
+    where
+        V: PartialEq,
+    {
+        let (tx, rx) = mpsc::channel(self.rx.max_capacity());
+
+        tokio::spawn(async move {
+            // the values the receiver knows, to tell whether a change was reverted
+            let mut known = HashMap::new();
+            // held back modifications, and when they are due
+            let mut pending = HashMap::<K, (V, Instant)>::new();
+            // the window is fixed, so modifications become due in the order they arrived
+            let mut due = VecDeque::<(Instant, K)>::new();
+
+            loop {
+                let next = due.front().map(|(at, _)| *at);
+                let evt = tokio::select! {
+                    _ = tx.closed() => break,
+                    _ = tokio::time::sleep_until(next.unwrap_or_else(Instant::now)), if next.is_some() => {
+                        let Some((at, key)) = due.pop_front() else {
+                            continue;
+                        };
+                        match take_due(&mut pending, at, key) {
+                            Some(evt) => evt,
+                            None => continue,
+                        }
+                    }
+                    evt = self.recv() => match evt {
+                        None => {
+                            // the subscription ended, there is nothing left to wait for
+                            for (at, key) in due.drain(..) {
+                                if let Some(evt) = take_due(&mut pending, at, key) {
+                                    let _ = tx.send(evt).await;
+                                }
+                            }
+                            break;
+                        }
+                        Some(Event::Modified(key, value)) => {
+                            if known.get(&key) == Some(&value) {
+                                pending.remove(&key);
+                            } else if let Some((pending, _)) = pending.get_mut(&key) {
+                                *pending = value;
+                            } else {
+                                let at = Instant::now() + window;
+                                pending.insert(key.clone(), (value, at));
+                                due.push_back((at, key));
+                            }
+                            continue;
+                        }
+                        Some(evt @ (Event::Added(..) | Event::Removed(_))) => {
+                            if let Event::Added(key, _) | Event::Removed(key) = &evt {
+                                pending.remove(key);
+                            }
+                            evt
+                        }
+                        Some(evt @ (Event::Restart(_) | Event::RestartChunk { .. })) => {
+                            pending.clear();
+                            due.clear();
+                            evt
+                        }
+                    },
+                };
+
+                apply_event(&mut known, evt.clone());
+                if tx.send(evt).await.is_err() {
+                    break;
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the inner subscription
+        Subscription::new(rx, || {})
+    }
 }
 
 impl<K, V> Drop for Subscription<K, V>
@@ -47,6 +245,119 @@ where
     }
 }
 
+/// Keeps a hook attached to a [`State`], detaching it when dropped, see [`State::add_hook`].
+pub struct HookHandle {
+    detach: Option<Box<dyn FnOnce() + Send + Sync + 'static>>,
+}
+
+impl Drop for HookHandle {
+    fn drop(&mut self) {
+        if let Some(detach) = self.detach.take() {
+            detach();
+        }
+    }
+}
+
+impl Debug for HookHandle {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("HookHandle").finish_non_exhaustive()
+    }
+}
+
+/// Keeps a listener registered with a [`State`], unsubscribing it when dropped.
+pub struct SubscriptionHandle {
+    unsubscribe: Option<Box<dyn FnOnce() + Send + Sync + 'static>>,
+}
+
+impl Drop for SubscriptionHandle {
+    fn drop(&mut self) {
+        if let Some(unsubscribe) = self.unsubscribe.take() {
+            unsubscribe();
+        }
+    }
+}
+
+/// A subscription which knows how long each event was queued, from [`State::named_subscribe`].
+///
+/// Events are queued for the listener along with when they were sent, and unwrapped when
+/// received. The time includes waiting for room in the channel.
+pub struct NamedSubscription<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + Send + Sync + 'static,
+{
+    name: Arc<str>,
+    rx: mpsc::Receiver<Enveloped<Event<K, V>>>,
+    /// dropped along with the subscription, unsubscribing it
+    _handle: SubscriptionHandle,
+}
+
+impl<K, V> NamedSubscription<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + Send + Sync + 'static,
+{
+    /// The name the listener was registered with.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Receive the next event, along with the time it spent queued.
+    pub async fn recv_with_timing(&mut self) -> Option<(Event<K, V>, Duration)> {
+        let Enveloped { event, enqueued_at } = self.rx.recv().await?;
+        Some((event, enqueued_at.elapsed()))
+    }
+
+    /// Receive the next event, dropping the time it spent queued.
+    pub async fn recv(&mut self) -> Option<Event<K, V>> {
+        self.rx.recv().await.map(|enveloped| enveloped.event)
+    }
+}
+
+/// An event, along with when it was queued for a listener
+#[derive(Clone, Debug)]
+struct Enveloped<E> {
+    event: E,
+    enqueued_at: Instant,
+}
+
+impl<E> Enveloped<E> {
+    fn now(event: E) -> Self {
+        Self {
+            event,
+            enqueued_at: Instant::now(),
+        }
+    }
+}
+
+/// Changes the keys of a subscription from [`State::subscribe_keys`].
+///
+/// Changes are applied before the events of writes to the state made after them, while events
+/// still queued when making a change are already filtered by it. Once the subscription is gone,
+/// changes are ignored.
+#[derive(Clone, Debug)]
+pub struct KeySetHandle<K> {
+    tx: mpsc::UnboundedSender<KeySetChange<K>>,
+}
+
+#[derive(Debug)]
+enum KeySetChange<K> {
+    Add(K),
+    Remove(K),
+}
+
+impl<K> KeySetHandle<K> {
+    /// Include a key, announcing it as [`Event::Added`] if it's present.
+    pub fn add(&self, key: K) {
+        let _ = self.tx.send(KeySetChange::Add(key));
+    }
+
+    /// Exclude a key, announcing it as [`Event::Removed`] if it's present.
+    pub fn remove(&self, key: K) {
+        let _ = self.tx.send(KeySetChange::Remove(key));
+    }
+}
+
 impl<K, V> Deref for Subscription<K, V>
 where
     K: Clone + Debug + Eq + Hash + Send + Sync,
@@ -69,200 +380,4787 @@ where
     }
 }
 
-#[derive(Clone, Debug)]
-pub struct State<K, V>
-where
-    K: Clone + Debug + Eq + Hash,
-    V: Clone + Debug + PartialEq,
-{
-    inner: Arc<RwLock<Inner<K, V>>>,
-}
+/// Priority of subscriptions which don't ask for a specific one
+pub const DEFAULT_PRIORITY: u8 = 128;
 
-#[derive(Debug)]
-struct Inner<K, V>
-where
-    K: Clone + Debug + Eq + Hash,
-    V: Clone + Debug + PartialEq,
-{
-    /// last known state
-    state: HashMap<K, V>,
-    /// listeners
-    listeners: HashMap<uuid::Uuid, mpsc::Sender<Event<K, V>>>,
+/// Whether listeners of a priority are guaranteed to receive all events, instead of being evicted
+/// when they can't keep up, see [`State::subscribe_with_priority`].
+fn is_guaranteed(priority: u8) -> bool {
+    priority > DEFAULT_PRIORITY
 }
 
-impl<K, V> Inner<K, V>
-where
-    K: Clone + Debug + Eq + Hash,
-    V: Clone + Debug + PartialEq,
-{
-    async fn broadcast(&mut self, evt: Event<K, V>) {
-        let listeners = stream::iter(&self.listeners);
-        let listeners = listeners.map(|(id, l)| {
-            let evt = evt.clone();
-            async move {
-                if let Err(_) = l.send_timeout(evt, Duration::from_secs(1)).await {
-                    Some(*id)
-                } else {
-                    None
-                }
-            }
-        });
-        let failed: Vec<uuid::Uuid> = listeners
-            .buffer_unordered(10)
-            .filter_map(|s| async move { s })
-            .collect()
-            .await;
+/// How often a broadcast waiting for a guaranteed listener warns about the stalled writes
+const GUARANTEED_SEND_WARNING: Duration = Duration::from_secs(10);
 
-        // remove failed subscribers
+/// Time to wait for room in the channel of a guaranteed listener, before evicting it after all
+pub const GUARANTEED_SEND_TIMEOUT: Duration = Duration::from_secs(300);
 
-        for id in failed {
-            debug!(?id, "Removing failed listener");
-            self.listeners.remove(&id);
-        }
-    }
+/// Number of dropped events which are kept for diagnostics
+const MAX_DROPPED_EVENTS: usize = 100;
+
+/// A listener, as reported by [`State::listeners`]
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerInfo {
+    pub id: String,
+    /// name the listener was registered with, see [`State::named`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub priority: u8,
+    /// events waiting to be received
+    pub queued: usize,
+    /// the most events which were waiting to be received at the same time
+    pub high_water: usize,
+    pub capacity: usize,
+    /// events delivered to the listener, including the ones still queued
+    pub delivered: u64,
+    /// seconds since the listener was registered
+    pub age_secs: u64,
 }
 
-impl<K, V> State<K, V>
-where
-    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
-    V: Clone + Debug + PartialEq + Send + Sync + 'static,
-{
-    pub async fn subscribe(&self, buffer: impl Into<Option<usize>>) -> Subscription<K, V> {
-        let (tx, rx) = mpsc::channel(buffer.into().unwrap_or(16));
+/// Totals of all listeners registered with the same name, including the ones gone already
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ListenerTotals {
+    pub delivered: u64,
+    /// listeners removed as they didn't keep up with the events
+    pub evicted: u64,
+    /// listeners removed as they were idle for too long
+    #[serde(default)]
+    pub reaped: u64,
+}
 
-        let mut lock = self.inner.write().await;
+impl AddAssign for ListenerTotals {
+    fn add_assign(&mut self, other: Self) {
+        self.delivered += other.delivered;
+        self.evicted += other.evicted;
+        self.reaped += other.reaped;
+    }
+}
 
-        // we can "unwrap" here, as we just created the channel and are in control of the two
-        // possible error conditions (full, no receiver).
-        tx.try_send(Event::Restart(lock.state.clone()))
-            .expect("Channel must have enough capacity");
+/// The name listeners are accounted for, if they weren't registered with one
+pub const UNNAMED_LISTENER: &str = "unnamed";
 
-        let id = loop {
-            let id = uuid::Uuid::new_v4();
-            if let Entry::Vacant(entry) = lock.listeners.entry(id) {
-                entry.insert(tx);
-                break id;
-            }
-        };
+/// A value, serialized to JSON once.
+///
+/// Values of events get cloned for each listener, and serialized by each of them, e.g. for
+/// sending them to a client. Serializing an encoded value only copies the JSON, which is shared
+/// by all clones.
+#[derive(Clone, Debug)]
+pub struct Encoded(Arc<RawValue>);
 
-        let inner = self.inner.clone();
+impl Encoded {
+    pub fn new(value: &impl serde::Serialize) -> serde_json::Result<Self> {
+        Ok(Self(serde_json::value::to_raw_value(value)?.into()))
+    }
 
-        Subscription::new(rx, move || {
-            tokio::spawn(async move {
-                inner.write().await.listeners.remove(&id);
-            });
-        })
+    /// The serialized value.
+    pub fn json(&self) -> &str {
+        self.0.get()
     }
+}
 
-    pub async fn get_state(&self) -> HashMap<K, V> {
-        self.inner.read().await.state.clone()
+impl PartialEq for Encoded {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.0, &other.0) || self.0.get() == other.0.get()
     }
+}
 
-    pub async fn set_state(&self, state: HashMap<K, V>) {
-        let mut lock = self.inner.write().await;
-        lock.state = state.clone();
-        Inner::broadcast(&mut lock, Event::Restart(state)).await;
+impl serde::Serialize for Encoded {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
     }
+}
 
-    pub async fn mutate_state<F>(&self, key: K, f: F)
-    where
-        F: FnOnce(Option<V>) -> Option<V>,
-    {
-        let mut lock = self.inner.write().await;
+/// Why an event couldn't be delivered
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub enum DropReason {
+    /// the listener didn't take the event in time, and got evicted as a slow consumer
+    Timeout,
+    /// the receiver was gone already
+    Closed,
+    /// the listener was idle for too long, see [`State::reap_idle_listeners`]
+    Idle,
+    /// the channel was full, and the event got skipped, see [`Transport::Broadcast`]
+    Lagged,
+}
 
-        let evt = match lock.state.entry(key.clone()) {
-            Entry::Vacant(entry) => {
-                if let Some(state) = f(None) {
-                    entry.insert(state.clone());
-                    Some(Event::Added(key, state))
-                } else {
-                    None
-                }
-            }
-            Entry::Occupied(mut entry) => match f(Some(entry.get().clone())) {
-                Some(state) => {
-                    if entry.get() != &state {
-                        *entry.get_mut() = state.clone();
-                        Some(Event::Modified(key, state))
-                    } else {
-                        None
-                    }
-                }
-                None => {
-                    entry.remove();
-                    Some(Event::Removed(key))
-                }
-            },
-        };
+/// An event which couldn't be delivered, causing its listener to be removed, unless it was skipped
+/// for a lagging listener
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DroppedEvent {
+    pub listener: String,
+    /// name of the listener, see [`State::named`]
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub name: Option<String>,
+    pub reason: DropReason,
+    /// kind of event, e.g. `modified`
+    pub kind: String,
+    /// key of the event, not set for restarts
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+    /// seconds since the epoch
+    pub timestamp: u64,
+}
 
-        if let Some(evt) = evt {
-            Inner::broadcast(&mut lock, evt).await;
+/// When to release the unused capacity of a map, after entries got removed.
+///
+/// Maps keep their capacity when entries get removed, so without shrinking them, they hold on to
+/// the memory of their peak size.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ShrinkPolicy {
+    /// shrink once less than this fraction of the capacity is in use, should be well below `0.5`
+    pub min_occupancy: f64,
+    /// leave maps with less capacity than this alone
+    pub min_capacity: usize,
+}
+
+impl ShrinkPolicy {
+    /// The capacity to shrink to, if a map with `len` entries and `capacity` should be shrunk.
+    ///
+    /// This leaves room for twice the entries, so that the map doesn't need to grow again right
+    /// away.
+    pub fn target(&self, len: usize, capacity: usize) -> Option<usize> {
+        (capacity >= self.min_capacity && (len as f64) < capacity as f64 * self.min_occupancy)
+            .then_some(len * 2)
+    }
+
+    pub fn shrink_map<K: Eq + Hash, V>(&self, map: &mut HashMap<K, V>) {
+        if let Some(target) = self.target(map.len(), map.capacity()) {
+            map.shrink_to(target);
         }
     }
 
-    pub async fn remove_state(&self, key: K) {
-        let mut lock = self.inner.write().await;
+    pub fn shrink_set<T: Eq + Hash>(&self, set: &mut HashSet<T>) {
+        if let Some(target) = self.target(set.len(), set.capacity()) {
+            set.shrink_to(target);
+        }
+    }
+}
 
-        if let Some(_) = lock.state.remove(&key) {
-            Inner::broadcast(&mut lock, Event::Removed(key.clone())).await;
+/// How events are passed on: how long a broadcast waits for a listener whose channel is full,
+/// before evicting it, and how many transformations a derived state runs at the same time.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BroadcastConfig {
+    /// time to wait for room in the channel, per attempt
+    pub send_timeout: Duration,
+    /// additional time to keep retrying once the first attempt timed out, zero to evict right away
+    pub retry_budget: Duration,
+    /// transformations running at the same time, per state derived by [`State::map_async`]
+    pub concurrency: usize,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            send_timeout: Duration::from_secs(1),
+            retry_budget: Duration::ZERO,
+            concurrency: 16,
         }
     }
+}
 
-    pub async fn iter_mut<F>(&self, f: F)
-    where
-        F: Fn(&K, &V) -> Output<V>,
-    {
-        let mut lock = self.inner.write().await;
+impl BroadcastConfig {
+    /// Send an event to a listener, retrying until the retry budget is used up.
+    ///
+    /// Returns the number of events queued for the listener once delivered.
+    async fn send<T>(&self, tx: &mpsc::Sender<T>, evt: T) -> Result<usize, DropReason> {
+        let queued = |tx: &mpsc::Sender<T>| tx.max_capacity() - tx.capacity();
 
-        let mut ops = Vec::new();
+        let mut evt = match tx.try_send(evt) {
+            Ok(()) => return Ok(queued(tx)),
+            Err(TrySendError::Closed(_)) => return Err(DropReason::Closed),
+            Err(TrySendError::Full(evt)) => evt,
+        };
 
-        for (k, v) in &mut lock.state {
-            match f(k, v) {
-                Output::Drop => {
-                    ops.push((k.clone(), None));
-                }
-                Output::Keep => {}
-                Output::Modify(state) => {
-                    if v != &state {
-                        ops.push((k.clone(), Some(state)));
+        let deadline = Instant::now() + self.send_timeout + self.retry_budget;
+        let mut timeout = self.send_timeout;
+        loop {
+            match tx.send_timeout(evt, timeout).await {
+                Ok(()) => return Ok(queued(tx)),
+                Err(SendTimeoutError::Closed(_)) => return Err(DropReason::Closed),
+                Err(SendTimeoutError::Timeout(retry)) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(DropReason::Timeout);
                     }
+                    debug!("Listener still full after {timeout:?}, retrying");
+                    timeout = self.send_timeout.min(remaining);
+                    evt = retry;
                 }
             }
         }
+    }
 
-        for (k, v) in ops.into_iter() {
-            match v {
-                None => {
-                    lock.state.remove(&k);
-                    Inner::broadcast(&mut lock, Event::Removed(k)).await;
+    /// Send an event to a listener which must not miss any.
+    ///
+    /// This waits for up to [`GUARANTEED_SEND_TIMEOUT`], warning about the stalled writes every
+    /// now and then. A listener which didn't make room by then is considered stuck, and evicted
+    /// after all, instead of stalling the state for good.
+    async fn send_guaranteed<T>(tx: &mpsc::Sender<T>, evt: T) -> Result<usize, DropReason> {
+        let start = Instant::now();
+        let deadline = start + GUARANTEED_SEND_TIMEOUT;
+        loop {
+            let wait =
+                GUARANTEED_SEND_WARNING.min(deadline.saturating_duration_since(Instant::now()));
+            match tokio::time::timeout(wait, tx.reserve()).await {
+                Ok(Ok(permit)) => {
+                    permit.send(evt);
+                    return Ok(tx.max_capacity() - tx.capacity());
                 }
-                Some(state) => {
-                    lock.state.insert(k.clone(), state.clone());
-                    Inner::broadcast(&mut lock, Event::Modified(k, state)).await;
+                Ok(Err(_)) => return Err(DropReason::Closed),
+                Err(_) if Instant::now() >= deadline => {
+                    error!(
+                        "Guaranteed listener stuck for {:?}, evicting it",
+                        start.elapsed()
+                    );
+                    return Err(DropReason::Timeout);
                 }
+                Err(_) => warn!(
+                    "Guaranteed listener full for {:?}, writes to the state are stalled",
+                    start.elapsed()
+                ),
             }
         }
     }
 }
 
-#[allow(unused)]
-pub enum Output<T> {
-    Drop,
-    Keep,
-    Modify(T),
-}
-
-impl<K, V> Default for State<K, V>
+#[derive(Clone, Debug)]
+pub struct State<K, V>
 where
     K: Clone + Debug + Eq + Hash,
     V: Clone + Debug + PartialEq,
 {
-    fn default() -> Self {
-        Self {
-            inner: Arc::new(RwLock::new(Inner {
-                state: Default::default(),
-                listeners: Default::default(),
+    inner: Arc<RwLock<Inner<K, V>>>,
+    /// shared with [`Inner`], allowing to add hooks without acquiring the lock
+    hooks: Arc<Hooks<K, V>>,
+    /// the name listeners registered using this handle get, see [`State::named`]
+    name: Option<Arc<str>>,
+}
+
+#[derive(Debug)]
+struct Inner<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    /// last known state
+    state: HashMap<K, V>,
+    /// snapshot of the state to compare against, if one was taken
+    baseline: Option<HashMap<K, V>>,
+    /// listeners
+    listeners: HashMap<uuid::Uuid, Listener<K, V>>,
+    /// the listeners in the order events get delivered: highest priority first, and in the
+    /// order of registration within the same priority
+    order: Vec<(Reverse<u8>, u64, uuid::Uuid)>,
+    /// sequence number of the next listener, keeping track of the registration order
+    next_seq: u64,
+    /// the most recent events which couldn't be delivered, oldest first
+    dropped: VecDeque<DroppedEvent>,
+    /// notified whenever a listener gets added
+    subscribed: Arc<Notify>,
+    /// hooks, called for each event before it gets broadcast
+    hooks: Arc<Hooks<K, V>>,
+    /// when to release unused capacity of the state, never if `None`
+    shrink: Option<ShrinkPolicy>,
+    /// totals of listeners gone already, by name
+    retired: HashMap<String, ListenerTotals>,
+    /// how long to wait for listeners which can't keep up
+    broadcast: BroadcastConfig,
+    /// when the most recent event got broadcast, `None` if there was none yet
+    last_event: Option<Instant>,
+    /// the latest state, for [`State::subscribe_latest`], only kept while there are receivers
+    latest: Option<watch::Sender<Arc<HashMap<K, V>>>>,
+    /// how events get to the listeners
+    transport: Transport,
+}
+
+/// How events get to the listeners of a state, chosen when creating it, see
+/// [`State::with_transport`]
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Transport {
+    /// Each listener gets every event, waiting for room in its channel up to the send timeout,
+    /// after which the listener gets evicted, see [`BroadcastConfig`].
+    #[default]
+    Mpsc,
+    /// Listeners are independent and lossy, like the receivers of a broadcast channel: if the
+    /// channel of a listener is full, the event gets skipped for it, without waiting. Once there
+    /// is room again, the listener catches up with an [`Event::Restart`] carrying the current
+    /// state, instead of the events it missed.
+    ///
+    /// Listeners with a guaranteed priority, and named subscriptions from
+    /// [`State::named_subscribe`], still receive every event.
+    Broadcast,
+}
+
+/// The channel events of a listener are sent to
+#[derive(Clone, Debug)]
+enum Sink<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    Plain(mpsc::Sender<Event<K, V>>),
+    /// skipping events while full, see [`Transport::Broadcast`]
+    Lossy(mpsc::Sender<Event<K, V>>),
+    /// along with when each event got queued, see [`NamedSubscription`]
+    Enveloped(mpsc::Sender<Enveloped<Event<K, V>>>),
+}
+
+impl<K, V> Sink<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn max_capacity(&self) -> usize {
+        match self {
+            Self::Plain(tx) | Self::Lossy(tx) => tx.max_capacity(),
+            Self::Enveloped(tx) => tx.max_capacity(),
+        }
+    }
+
+    fn queued(&self) -> usize {
+        match self {
+            Self::Plain(tx) | Self::Lossy(tx) => tx.max_capacity() - tx.capacity(),
+            Self::Enveloped(tx) => tx.max_capacity() - tx.capacity(),
+        }
+    }
+
+    /// Send an event, see [`BroadcastConfig::send`] and [`BroadcastConfig::send_guaranteed`].
+    ///
+    /// Lossy channels fail with [`DropReason::Lagged`] right away if they are full.
+    async fn send(
+        &self,
+        config: &BroadcastConfig,
+        guaranteed: bool,
+        evt: Event<K, V>,
+    ) -> Result<usize, DropReason> {
+        match (self, guaranteed) {
+            (Self::Plain(tx) | Self::Lossy(tx), true) => {
+                BroadcastConfig::send_guaranteed(tx, evt).await
+            }
+            (Self::Plain(tx), false) => config.send(tx, evt).await,
+            (Self::Lossy(tx), false) => match tx.try_send(evt) {
+                Ok(()) => Ok(tx.max_capacity() - tx.capacity()),
+                Err(TrySendError::Full(_)) => Err(DropReason::Lagged),
+                Err(TrySendError::Closed(_)) => Err(DropReason::Closed),
+            },
+            (Self::Enveloped(tx), true) => {
+                BroadcastConfig::send_guaranteed(tx, Enveloped::now(evt)).await
+            }
+            (Self::Enveloped(tx), false) => config.send(tx, Enveloped::now(evt)).await,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Listener<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    tx: Sink<K, V>,
+    /// listeners with a higher priority receive events first
+    priority: u8,
+    seq: u64,
+    name: Option<Arc<str>>,
+    created: Instant,
+    delivered: u64,
+    /// when the most recent event got delivered, or the listener got registered
+    last_delivery: Instant,
+    high_water: usize,
+    /// the number of unread events, shared with the subscription, if it counts them
+    unread: Option<Arc<AtomicUsize>>,
+    activity: Arc<Activity>,
+    /// events got skipped, so the next one needs to be a restart, see [`Transport::Broadcast`]
+    lagged: bool,
+}
+
+impl<K, V> Listener<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn name(&self) -> &str {
+        self.name.as_deref().unwrap_or(UNNAMED_LISTENER)
+    }
+
+    fn queued(&self) -> usize {
+        self.tx.queued()
+    }
+
+    /// Whether the listener has been idle for longer than `timeout`.
+    ///
+    /// That is the case if events have been waiting for it without anything being delivered, or
+    /// if it tracks activity and there was none.
+    fn is_idle(&self, timeout: Duration, now: Instant) -> bool {
+        let stuck =
+            self.queued() > 0 && now.saturating_duration_since(self.last_delivery) > timeout;
+        let silent = self
+            .activity
+            .inbound()
+            .is_some_and(|at| now.saturating_duration_since(at) > timeout);
+        stuck || silent
+    }
+}
+
+/// Activity of a listener, shared between the state and the subscription.
+#[derive(Debug)]
+struct Activity {
+    since: Instant,
+    /// milliseconds from `since` to the most recent inbound activity, [`u64::MAX`] if none yet
+    inbound: AtomicU64,
+    /// whether the listener was removed for being idle
+    reaped: AtomicBool,
+}
+
+impl Activity {
+    fn new() -> Self {
+        Self {
+            since: Instant::now(),
+            inbound: AtomicU64::new(u64::MAX),
+            reaped: AtomicBool::new(false),
+        }
+    }
+
+    fn touch(&self) {
+        let millis = self.since.elapsed().as_millis().min(u64::MAX as u128 - 1) as u64;
+        self.inbound.store(millis, Ordering::Relaxed);
+    }
+
+    fn inbound(&self) -> Option<Instant> {
+        match self.inbound.load(Ordering::Relaxed) {
+            u64::MAX => None,
+            millis => Some(self.since + Duration::from_millis(millis)),
+        }
+    }
+}
+
+type Hook<K, V> = Box<dyn Fn(&Event<K, V>) + Send + Sync + 'static>;
+
+/// The hooks of a state, along with the ID they can be detached by.
+struct Hooks<K, V>(parking_lot::RwLock<Vec<(u64, Hook<K, V>)>>, AtomicU64)
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq;
+
+impl<K, V> Default for Hooks<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn default() -> Self {
+        Self(Default::default(), Default::default())
+    }
+}
+
+impl<K, V> Debug for Hooks<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Hooks")
+            .field("len", &self.0.read().len())
+            .finish()
+    }
+}
+
+impl<K, V> Inner<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    /// Remove a listener, keeping its totals, along with the reason if it was dropped.
+    fn unregister(
+        &mut self,
+        id: &uuid::Uuid,
+        reason: Option<DropReason>,
+    ) -> Option<Listener<K, V>> {
+        let listener = self.listeners.remove(id)?;
+        self.order.retain(|(_, _, other)| other != id);
+        let totals = self.retired.entry(listener.name().to_string()).or_default();
+        totals.delivered += listener.delivered;
+        match reason {
+            Some(DropReason::Timeout) => totals.evicted += 1,
+            Some(DropReason::Idle) => totals.reaped += 1,
+            Some(DropReason::Closed | DropReason::Lagged) | None => {}
+        }
+        Some(listener)
+    }
+
+    /// Remove the listeners idle for longer than `timeout`, returning how many got removed.
+    async fn reap_idle(inner: &RwLock<Self>, timeout: Duration) -> usize {
+        let idle = {
+            let lock = inner.read().await;
+            let now = Instant::now();
+            lock.listeners
+                .iter()
+                .filter(|(_, l)| l.is_idle(timeout, now))
+                .map(|(id, _)| *id)
+                .collect::<Vec<_>>()
+        };
+        if idle.is_empty() {
+            return 0;
+        }
+
+        let mut lock = inner.write().await;
+        let now = Instant::now();
+        let mut reaped = 0;
+        for id in idle {
+            // events may have been delivered in the meantime
+            if !lock
+                .listeners
+                .get(&id)
+                .is_some_and(|l| l.is_idle(timeout, now))
+            {
+                continue;
+            }
+            let Some(listener) = lock.unregister(&id, Some(DropReason::Idle)) else {
+                continue;
+            };
+            listener.activity.reaped.store(true, Ordering::Relaxed);
+            warn!(
+                "Removing idle listener {id} ({}), registered {:?} ago, with {} events queued",
+                listener.name(),
+                listener.created.elapsed(),
+                listener.queued()
+            );
+            reaped += 1;
+        }
+
+        reaped
+    }
+
+    /// Release unused capacity of the state, if the policy asks for it.
+    fn shrink(&mut self) {
+        if let Some(policy) = &self.shrink {
+            policy.shrink_map(&mut self.state);
+        }
+    }
+
+    async fn broadcast(&mut self, evt: Event<K, V>) {
+        self.last_event = Some(Instant::now());
+
+        for (_, hook) in self.hooks.0.read().iter() {
+            hook(&evt);
+        }
+
+        // the state already carries the change, so it can't be ahead of the snapshot
+        if let Some(latest) = &self.latest {
+            match latest.receiver_count() {
+                0 => self.latest = None,
+                _ => latest.send_modify(|latest| {
+                    // only copied if a receiver still holds on to the previous snapshot
+                    let latest = Arc::make_mut(latest);
+                    match &evt {
+                        Event::Added(k, v) | Event::Modified(k, v) => {
+                            latest.insert(k.clone(), v.clone());
+                        }
+                        Event::Removed(k) => {
+                            latest.remove(k);
+                        }
+                        Event::Restart(_) | Event::RestartChunk { .. } => {
+                            latest.clone_from(&self.state)
+                        }
+                    }
+                }),
+            }
+        }
+
+        // lagging listeners catch up with the current state, which already carries the change
+        let restart = self
+            .listeners
+            .values()
+            .any(|l| l.lagged)
+            .then(|| Event::Restart(self.state.clone()));
+
+        // highest priority first, only sending concurrently within the same priority
+        let config = self.broadcast;
+        let mut results = Vec::with_capacity(self.listeners.len());
+
+        let mut start = 0;
+        while start < self.order.len() {
+            let Reverse(priority) = self.order[start].0;
+            let guaranteed = is_guaranteed(priority);
+            let tier = self.order[start..]
+                .iter()
+                .take_while(|(p, _, _)| p.0 == priority)
+                .count();
+
+            let mut sends = Vec::with_capacity(tier);
+            for (_, _, id) in &self.order[start..start + tier] {
+                let Some(l) = self.listeners.get(id) else {
+                    continue;
+                };
+                let id = *id;
+                let evt = match (l.lagged, &restart) {
+                    (true, Some(restart)) => restart.clone(),
+                    _ => evt.clone(),
+                };
+                sends.push(async move {
+                    // count the event before it can be read, so that the count can't underflow
+                    if let Some(unread) = &l.unread {
+                        unread.fetch_add(1, Ordering::Relaxed);
+                    }
+                    let result = l.tx.send(&config, guaranteed, evt).await;
+                    if let (Some(unread), Err(_)) = (&l.unread, &result) {
+                        unread.fetch_sub(1, Ordering::Relaxed);
+                    }
+                    (id, result)
+                });
+            }
+            results.extend(
+                stream::iter(sends)
+                    .buffer_unordered(10)
+                    .collect::<Vec<_>>()
+                    .await,
+            );
+            start += tier;
+        }
+
+        let mut failed = Vec::new();
+        for (id, result) in results {
+            match result {
+                Ok(queued) => {
+                    if let Some(listener) = self.listeners.get_mut(&id) {
+                        listener.delivered += 1;
+                        listener.last_delivery = Instant::now();
+                        listener.high_water = listener.high_water.max(queued);
+                        listener.lagged = false;
+                    }
+                }
+                Err(reason) => failed.push((id, reason)),
+            }
+        }
+
+        if failed.is_empty() {
+            return;
+        }
+
+        // remove failed subscribers
+
+        let kind = evt.kind();
+        let key = match &evt {
+            Event::Added(key, _) | Event::Modified(key, _) | Event::Removed(key) => {
+                Some(format!("{key:?}"))
+            }
+            Event::Restart(_) | Event::RestartChunk { .. } => None,
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+
+        for (id, reason) in failed {
+            let name = match reason {
+                // the listener stays, and catches up once there is room again
+                DropReason::Lagged => {
+                    let Some(listener) = self.listeners.get_mut(&id) else {
+                        continue;
+                    };
+                    if !std::mem::replace(&mut listener.lagged, true) {
+                        debug!(
+                            ?id,
+                            name = listener.name(),
+                            "Listener lagging, skipping events"
+                        );
+                    }
+                    listener.name.clone()
+                }
+                _ => {
+                    let Some(listener) = self.unregister(&id, Some(reason)) else {
+                        continue;
+                    };
+                    match reason {
+                        DropReason::Timeout => warn!(
+                            "Evicting slow listener {id} ({}), registered {:?} ago, with {} events queued",
+                            listener.name(),
+                            listener.created.elapsed(),
+                            listener.queued()
+                        ),
+                        DropReason::Closed | DropReason::Idle | DropReason::Lagged => {
+                            debug!(?id, name = listener.name(), "Removing closed listener")
+                        }
+                    }
+                    listener.name
+                }
+            };
+
+            if self.dropped.len() >= MAX_DROPPED_EVENTS {
+                self.dropped.pop_front();
+            }
+            self.dropped.push_back(DroppedEvent {
+                listener: id.to_string(),
+                name: name.as_deref().map(str::to_string),
+                reason,
+                kind: kind.to_string(),
+                key: key.clone(),
+                timestamp,
+            });
+        }
+    }
+}
+
+impl<K, V> State<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    /// A handle to the same state, registering its listeners under `name`.
+    ///
+    /// The name tells listeners apart in the metrics and diagnostics, e.g.
+    /// `state.named("websocket").subscribe(None)`. Listeners of the same kind should share a name.
+    pub fn named(&self, name: impl Into<String>) -> Self {
+        Self {
+            name: Some(Arc::from(name.into())),
+            ..self.clone()
+        }
+    }
+
+    pub async fn subscribe(&self, buffer: impl Into<Option<usize>>) -> Subscription<K, V> {
+        self.subscribe_with_priority(buffer, DEFAULT_PRIORITY).await
+    }
+
+    /// Subscribe, registering the listener under `name`, a shorthand for
+    /// `state.named(name).subscribe(None)`.
+    pub async fn subscribe_with_name(&self, name: &str) -> Subscription<K, V> {
+        self.named(name).subscribe(None).await
+    }
+
+    /// Subscribe, along with the number of events sent to the subscription, but not read yet.
+    ///
+    /// This allows writers to back off while the subscriber is falling behind. The count includes
+    /// an event still waiting for room in the channel, and only covers events read using
+    /// [`Subscription::recv`] or [`Subscription::try_recv`].
+    pub async fn subscribe_counted(
+        &self,
+        buffer: impl Into<Option<usize>>,
+    ) -> (Subscription<K, V>, Arc<AtomicUsize>) {
+        let (tx, rx) = mpsc::channel(buffer.into().unwrap_or(16));
+        let unread = Arc::new(AtomicUsize::new(1));
+
+        let mut lock = self.inner.write().await;
+
+        tx.try_send(Event::Restart(lock.state.clone()))
+            .expect("Channel must have enough capacity");
+
+        let mut sub = Subscription::registered(
+            rx,
+            self.register(
+                &mut lock,
+                Sink::Plain(tx),
+                DEFAULT_PRIORITY,
+                Some(unread.clone()),
+            ),
+        );
+        sub.unread = Some(unread.clone());
+
+        (sub, unread)
+    }
+
+    /// Subscribe with a priority, listeners with a higher priority receive events first.
+    ///
+    /// Within the same priority, events are delivered in the order the listeners subscribed.
+    /// Other subscriptions use [`DEFAULT_PRIORITY`].
+    ///
+    /// Listeners with a priority above [`DEFAULT_PRIORITY`] are meant for critical consumers,
+    /// e.g. persisting the state, and don't get evicted for being slow: broadcasts wait for room
+    /// in their channel for up to [`GUARANTEED_SEND_TIMEOUT`], instead of applying the
+    /// [`BroadcastConfig::send_timeout`]. Such a listener which stops receiving events stalls all
+    /// writes to the state in the meantime, so it must keep up, while slower listeners of a lower
+    /// priority are evicted as usual.
+    pub async fn subscribe_with_priority(
+        &self,
+        buffer: impl Into<Option<usize>>,
+        priority: u8,
+    ) -> Subscription<K, V> {
+        let (tx, rx) = mpsc::channel(buffer.into().unwrap_or(16));
+
+        let mut lock = self.inner.write().await;
+
+        // we can "unwrap" here, as we just created the channel and are in control of the two
+        // possible error conditions (full, no receiver).
+        tx.try_send(Event::Restart(lock.state.clone()))
+            .expect("Channel must have enough capacity");
+
+        Subscription::registered(
+            rx,
+            self.register(&mut lock, Sink::Plain(tx), priority, None),
+        )
+    }
+
+    /// Subscribe, starting with the events of `entries` instead of the current state, e.g. from a
+    /// replay buffer. Live events are delivered after them.
+    ///
+    /// **Panics** if `buffer` doesn't leave room for at least one live event after the provided
+    /// ones, so it must be at least `entries.count() + 1`.
+    pub async fn subscribe_backfill(
+        &self,
+        buffer: usize,
+        entries: impl IntoIterator<Item = Event<K, V>>,
+    ) -> Subscription<K, V> {
+        let entries = entries.into_iter().collect::<Vec<_>>();
+        assert!(
+            buffer > entries.len(),
+            "buffer of {buffer} too small for {} backfilled events, plus a live one",
+            entries.len()
+        );
+
+        let (tx, rx) = mpsc::channel(buffer);
+        for evt in entries {
+            tx.try_send(evt).expect("Channel must have enough capacity");
+        }
+
+        let mut lock = self.inner.write().await;
+        Subscription::registered(
+            rx,
+            self.register(&mut lock, Sink::Plain(tx), DEFAULT_PRIORITY, None),
+        )
+    }
+
+    /// Subscribe, delivering the initial state in chunks of at most `chunk_size` entries.
+    ///
+    /// Instead of a single [`Event::Restart`], the subscription starts with a sequence of
+    /// [`Event::RestartChunk`] events, the last one being flagged with `is_last`. This keeps
+    /// single events small, even when the state is large. Later events are delivered as usual.
+    pub async fn subscribe_chunked(
+        &self,
+        buffer: impl Into<Option<usize>>,
+        chunk_size: usize,
+    ) -> Subscription<K, V> {
+        let chunk_size = chunk_size.max(1);
+
+        let mut lock = self.inner.write().await;
+
+        let mut chunks = Vec::with_capacity(lock.state.len() / chunk_size + 1);
+        let mut entries = lock.state.iter().peekable();
+        loop {
+            let chunk = entries
+                .by_ref()
+                .take(chunk_size)
+                .map(|(k, v)| (k.clone(), v.clone()))
+                .collect::<HashMap<_, _>>();
+            let is_last = entries.peek().is_none();
+            chunks.push(Event::RestartChunk {
+                entries: chunk,
+                is_last,
+            });
+            if is_last {
+                break;
+            }
+        }
+
+        // make room for all chunks, so that we can deliver them without waiting
+        let (tx, rx) = mpsc::channel(buffer.into().unwrap_or(16) + chunks.len());
+
+        for chunk in chunks {
+            tx.try_send(chunk)
+                .expect("Channel must have enough capacity");
+        }
+
+        Subscription::registered(
+            rx,
+            self.register(&mut lock, Sink::Plain(tx), DEFAULT_PRIORITY, None),
+        )
+    }
+
+    /// Subscribe under `name`, receiving each event along with the time it spent queued, e.g. to
+    /// observe how far behind a listener is.
+    pub async fn named_subscribe(&self, name: &str) -> NamedSubscription<K, V> {
+        let named = self.named(name);
+        let (tx, rx) = mpsc::channel(16);
+
+        let mut lock = self.inner.write().await;
+
+        tx.try_send(Enveloped::now(Event::Restart(lock.state.clone())))
+            .expect("Channel must have enough capacity");
+
+        let (unsubscribe, _) =
+            named.register(&mut lock, Sink::Enveloped(tx), DEFAULT_PRIORITY, None);
+        NamedSubscription {
+            name: Arc::from(name),
+            rx,
+            _handle: SubscriptionHandle {
+                unsubscribe: Some(Box::new(unsubscribe)),
+            },
+        }
+    }
+
+    /// Subscribe, passing each event to `handler` on a background task.
+    ///
+    /// Dropping the returned handle aborts the task, which unsubscribes. The handler runs on the
+    /// task, so it must not block, and events queue up while it runs.
+    pub async fn subscribe_in_background<F>(&self, handler: F) -> SubscriptionHandle
+    where
+        F: Fn(Event<K, V>) + Send + 'static,
+    {
+        let mut sub = self.subscribe(None).await;
+        let task = tokio::spawn(async move {
+            while let Some(evt) = sub.recv().await {
+                handler(evt);
+            }
+        });
+
+        SubscriptionHandle {
+            unsubscribe: Some(Box::new(move || task.abort())),
+        }
+    }
+
+    /// Subscribe using a channel provided by the caller.
+    ///
+    /// Just like with [`Self::subscribe`], the first event is an [`Event::Restart`], carrying the
+    /// current state. Dropping the returned handle unsubscribes. If the channel is full, this waits
+    /// for room for the initial event, and fails if the receiver was dropped.
+    pub async fn subscribe_into(
+        &self,
+        tx: mpsc::Sender<Event<K, V>>,
+    ) -> Result<SubscriptionHandle, SendError<()>> {
+        // wait before taking the lock, waiting for a channel we don't own would stall all writers
+        let permit = tx.reserve_owned().await?;
+        let mut lock = self.inner.write().await;
+        let tx = permit.send(Event::Restart(lock.state.clone()));
+
+        let (unsubscribe, _) = self.register(&mut lock, Sink::Plain(tx), DEFAULT_PRIORITY, None);
+        Ok(SubscriptionHandle {
+            unsubscribe: Some(Box::new(unsubscribe)),
+        })
+    }
+
+    /// Register a listener, returning the function to unregister it again, and its activity.
+    fn register(
+        &self,
+        lock: &mut Inner<K, V>,
+        tx: Sink<K, V>,
+        priority: u8,
+        unread: Option<Arc<AtomicUsize>>,
+    ) -> (impl FnOnce() + Send + Sync + 'static, Arc<Activity>) {
+        let tx = match (lock.transport, tx) {
+            (Transport::Broadcast, Sink::Plain(tx)) => Sink::Lossy(tx),
+            (_, tx) => tx,
+        };
+        let seq = lock.next_seq;
+        lock.next_seq += 1;
+        let activity = Arc::new(Activity::new());
+
+        let id = loop {
+            let id = uuid::Uuid::new_v4();
+            if let Entry::Vacant(entry) = lock.listeners.entry(id) {
+                entry.insert(Listener {
+                    tx,
+                    priority,
+                    seq,
+                    name: self.name.clone(),
+                    created: Instant::now(),
+                    delivered: 0,
+                    last_delivery: Instant::now(),
+                    high_water: 0,
+                    unread,
+                    activity: activity.clone(),
+                    lagged: false,
+                });
+                break id;
+            }
+        };
+        // sequence numbers only grow, so the listener goes last within its priority
+        let position = lock
+            .order
+            .partition_point(|(p, _, _)| *p <= Reverse(priority));
+        lock.order.insert(position, (Reverse(priority), seq, id));
+        lock.subscribed.notify_waiters();
+
+        let inner = self.inner.clone();
+
+        let unsubscribe = move || {
+            tokio::spawn(async move {
+                inner.write().await.unregister(&id, None);
+            });
+        };
+
+        (unsubscribe, activity)
+    }
+
+    /// Wait until there are at least `n` listeners, e.g. for tests to wait for subscriber tasks
+    /// to be set up.
+    pub async fn await_n_subscribers(&self, n: usize) {
+        let subscribed = self.inner.read().await.subscribed.clone();
+
+        loop {
+            // register for the notification first, so that we can't miss a new listener
+            let notified = subscribed.notified();
+            if self.inner.read().await.listeners.len() >= n {
+                return;
+            }
+            notified.await;
+        }
+    }
+
+    /// Subscribe to a single key, only publishing its value once it was stable for `window`.
+    ///
+    /// The receiver starts out with the current value of the key. Every change of the key
+    /// (including its removal) restarts the timer, so a flickering value will only be published
+    /// once it settled down.
+    pub async fn subscribe_debounced(
+        &self,
+        key: K,
+        window: Duration,
+    ) -> watch::Receiver<Option<V>> {
+        let mut sub = self.subscribe(None).await;
+
+        // the first event is always the restart, carrying the current state
+        let initial = match sub.recv().await {
+            Some(Event::Restart(mut state)) => state.remove(&key),
+            _ => None,
+        };
+
+        let (tx, rx) = watch::channel(initial);
+
+        tokio::spawn(async move {
+            let mut pending: Option<Option<V>> = None;
+            let sleep = tokio::time::sleep(window);
+            tokio::pin!(sleep);
+
+            loop {
+                tokio::select! {
+                    evt = sub.recv() => {
+                        let value = match evt {
+                            None => break,
+                            Some(Event::Added(k, v) | Event::Modified(k, v)) if k == key => Some(v),
+                            Some(Event::Removed(k)) if k == key => None,
+                            Some(Event::Restart(mut state)) => state.remove(&key),
+                            Some(_) => continue,
+                        };
+                        pending = Some(value);
+                        sleep.as_mut().reset(Instant::now() + window);
+                    }
+                    _ = &mut sleep, if pending.is_some() => {
+                        if let Some(value) = pending.take() {
+                            tx.send_if_modified(|current| {
+                                if *current != value {
+                                    *current = value;
+                                    true
+                                } else {
+                                    false
+                                }
+                            });
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        rx
+    }
+
+    /// Subscribe to the latest snapshot of the whole state, dropping intermediate ones, for
+    /// consumers which poll it, e.g. for metrics.
+    ///
+    /// Unlike [`State::into_snapshot_stream`], a consumer never falls behind: the receiver only
+    /// holds the most recent snapshot, so a slow consumer skips all snapshots taken while it was
+    /// busy, and gets the current one on its next poll. No listener gets registered, which could
+    /// fall behind or get evicted: the snapshot is replaced while broadcasting each event, holding
+    /// the same lock as the change, so it is never older than the state. Receivers should only
+    /// borrow the snapshot briefly, as one still held when the next event arrives needs to be
+    /// copied.
+    pub async fn subscribe_latest(&self) -> watch::Receiver<Arc<HashMap<K, V>>> {
+        let mut lock = self.inner.write().await;
+        match &lock.latest {
+            Some(latest) => latest.subscribe(),
+            None => {
+                let (tx, rx) = watch::channel(Arc::new(lock.state.clone()));
+                lock.latest = Some(tx);
+                rx
+            }
+        }
+    }
+
+    /// Subscribe, coalescing events which happen within `window`.
+    ///
+    /// Once an event arrives, further events are collected until `window` has passed, and then
+    /// delivered as a single event per key: an `Added` followed by `Modified` events still is an
+    /// `Added`, carrying the latest value, and an `Added` immediately followed by `Removed`
+    /// yields no event at all. A `Restart` supersedes all pending events and is delivered
+    /// right away.
+    pub async fn subscribe_coalesced(
+        &self,
+        buffer: impl Into<Option<usize>>,
+        window: Duration,
+    ) -> Subscription<K, V> {
+        let buffer = buffer.into();
+        let mut sub = self.subscribe(buffer).await;
+        let (tx, rx) = mpsc::channel(buffer.unwrap_or(16));
+
+        tokio::spawn(async move {
+            let mut pending = Coalesced::default();
+            let flush = tokio::time::sleep(window);
+            tokio::pin!(flush);
+
+            loop {
+                tokio::select! {
+                    evt = sub.recv() => {
+                        let Some(evt) = evt else {
+                            break;
+                        };
+                        if let Some(evt) = pending.push(evt) {
+                            // a restart, which we pass on right away
+                            if tx.send(evt).await.is_err() {
+                                break;
+                            }
+                        } else if pending.len() == 1 {
+                            // first pending event, start the window
+                            flush.as_mut().reset(Instant::now() + window);
+                        }
+                    }
+                    _ = &mut flush, if !pending.is_empty() => {
+                        for evt in pending.drain() {
+                            if tx.send(evt).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the inner subscription
+        Subscription::new(rx, || {})
+    }
+
+    /// Subscribe to a set of keys, which can be changed using the handle, e.g. for a watch list.
+    ///
+    /// Only events of keys in the set are delivered, starting with a restart carrying their
+    /// current values. Adding a key which is present delivers it as [`Event::Added`], and removing
+    /// one as [`Event::Removed`], so the subscriber sees the keys come and go like the entries of
+    /// the state. This keeps a copy of the whole state, to know the values of keys added later.
+    pub async fn subscribe_keys(
+        &self,
+        buffer: impl Into<Option<usize>>,
+        keys: HashSet<K>,
+    ) -> (Subscription<K, V>, KeySetHandle<K>) {
+        let buffer = buffer.into();
+        let mut sub = self.subscribe(buffer).await;
+        let (tx, rx) = mpsc::channel(buffer.unwrap_or(16));
+        let (changes_tx, mut changes) = mpsc::unbounded_channel();
+
+        tokio::spawn(async move {
+            let mut filter = KeyFilter::new(keys);
+
+            loop {
+                // changes first, so that they are applied before the events of later writes
+                let evt = tokio::select! {
+                    biased;
+                    Some(change) = changes.recv() => filter.change(change),
+                    evt = sub.recv() => match evt {
+                        Some(evt) => filter.apply(evt),
+                        None => break,
+                    },
+                    _ = tx.closed() => break,
+                };
+                if let Some(evt) = evt {
+                    if tx.send(evt).await.is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the inner subscription
+        (
+            Subscription::new(rx, || {}),
+            KeySetHandle { tx: changes_tx },
+        )
+    }
+
+    /// Subscribe to a single field of the values, as selected by `f`.
+    ///
+    /// Values are projected to the field, and modifications are only delivered if the field
+    /// actually changed. Added and removed entries, as well as restarts, are always delivered.
+    pub async fn subscribe_field<V2, F>(
+        &self,
+        buffer: impl Into<Option<usize>>,
+        f: F,
+    ) -> Subscription<K, V2>
+    where
+        V2: Clone + Debug + PartialEq + Send + Sync + 'static,
+        F: Fn(&V) -> V2 + Send + 'static,
+    {
+        let buffer = buffer.into();
+        let mut sub = self.subscribe(buffer).await;
+        let (tx, rx) = mpsc::channel(buffer.unwrap_or(16));
+
+        tokio::spawn(async move {
+            let mut field = FieldChanges::new(f);
+
+            loop {
+                tokio::select! {
+                    evt = sub.recv() => {
+                        let Some(evt) = evt else {
+                            break;
+                        };
+                        if let Some(evt) = field.apply(evt) {
+                            if tx.send(evt).await.is_err() {
+                                break;
+                            }
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the inner subscription
+        Subscription::new(rx, || {})
+    }
+
+    /// Stream the whole state, as a snapshot after each change.
+    ///
+    /// The events are applied to a local copy of the state, so the state isn't locked to take
+    /// snapshots, and each snapshot reflects exactly one change. Restarts which don't change
+    /// anything aren't delivered. Snapshots are shared, so handing them to multiple consumers
+    /// doesn't copy the map, the local copy is only copied for the next change if a snapshot
+    /// of it is still around.
+    pub async fn into_snapshot_stream(&self) -> impl Stream<Item = Arc<HashMap<K, V>>> {
+        let sub = self.subscribe(None).await;
+
+        stream::unfold(
+            (sub, None::<Arc<HashMap<K, V>>>),
+            |(mut sub, mut current)| async move {
+                loop {
+                    match sub.recv().await? {
+                        Event::Restart(state) if current.as_deref() == Some(&state) => continue,
+                        Event::Restart(state) => current = Some(Arc::new(state)),
+                        evt => apply_event(Arc::make_mut(current.get_or_insert_default()), evt),
+                    }
+                    let snapshot = current.clone()?;
+                    return Some((snapshot, (sub, current)));
+                }
+            },
+        )
+    }
+
+    /// Stream the changes of the entries, as `(key, before, after)`.
+    ///
+    /// Added entries have no value before, removed ones no value after. The current state is only
+    /// taken as the starting point, it isn't reported as changes. Later restarts are reported as
+    /// the changes between the state before and after the restart.
+    pub async fn watch_key_transitions(&self) -> impl Stream<Item = (K, Option<V>, Option<V>)> {
+        let mut sub = self.subscribe(None).await;
+        let mut transitions = Transitions::default();
+        if let Some(Event::Restart(state)) = sub.recv().await {
+            transitions.known = state;
+        }
+
+        stream::unfold(
+            (sub, transitions, VecDeque::new()),
+            |(mut sub, mut transitions, mut pending)| async move {
+                loop {
+                    if let Some(transition) = pending.pop_front() {
+                        return Some((transition, (sub, transitions, pending)));
+                    }
+                    pending.extend(transitions.apply(sub.recv().await?));
+                }
+            },
+        )
+    }
+
+    /// Derive a live state, mapping each entry using `f`.
+    ///
+    /// The derived state is kept in sync by a background task, which ends once the derived state
+    /// (including all its clones) got dropped. If multiple entries map to the same key, their
+    /// values get combined using `reduce`, which should therefore not depend on the order of its
+    /// arguments. Once all entries of a derived key are gone, the key gets removed too.
+    pub async fn map<K2, V2, F, R>(&self, f: F, reduce: R) -> State<K2, V2>
+    where
+        K2: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+        V2: Clone + Debug + PartialEq + Send + Sync + 'static,
+        F: Fn(&K, &V) -> (K2, V2) + Send + 'static,
+        R: Fn(V2, V2) -> V2 + Send + 'static,
+    {
+        let mut sub = self.subscribe(None).await;
+        let mut projection = Projection::new(f, reduce);
+        let derived = State::default();
+
+        // apply the initial state right away, so that the derived state starts out in sync
+        if let Some(evt) = sub.recv().await {
+            projection.apply(&derived, evt).await;
+        }
+
+        let inner = Arc::downgrade(&derived.inner);
+        let hooks = derived.hooks.clone();
+
+        tokio::spawn(async move {
+            while let Some(evt) = sub.recv().await {
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                let derived = State {
+                    inner,
+                    hooks: hooks.clone(),
+                    name: None,
+                };
+                projection.apply(&derived, evt).await;
+            }
+        });
+
+        derived
+    }
+
+    /// Derive a live state, transforming each value using the async function `f`, e.g. for
+    /// enriching it with the result of a lookup against an external API.
+    ///
+    /// Up to [`BroadcastConfig::concurrency`] transformations run at the same time, in no
+    /// particular order. Values changing again while waiting for their turn are only transformed
+    /// once, using the latest value, and results of a transformation which got overtaken by a
+    /// later change of its key are discarded. If `f` returns `None`, the key gets removed from the
+    /// derived state.
+    ///
+    /// Unlike [`State::map`], the derived state starts out empty, and catches up as the
+    /// transformations complete. It is kept in sync by a background task, which ends once the
+    /// derived state (including all its clones) got dropped.
+    pub async fn map_async<V2, F, Fut>(&self, f: F) -> State<K, V2>
+    where
+        V2: Clone + Debug + PartialEq + Send + Sync + 'static,
+        F: Fn(K, V) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Option<V2>> + Send + 'static,
+    {
+        let concurrency = self.inner.read().await.broadcast.concurrency.max(1);
+        let mut sub = self.subscribe(None).await;
+        let derived = State::default();
+
+        let inner = Arc::downgrade(&derived.inner);
+        let hooks = derived.hooks.clone();
+
+        tokio::spawn(async move {
+            let mut transforms = AsyncTransforms::new(f, concurrency);
+
+            loop {
+                tokio::select! {
+                    evt = sub.recv() => {
+                        let Some(evt) = evt else {
+                            break;
+                        };
+                        let Some(inner) = inner.upgrade() else {
+                            break;
+                        };
+                        let derived = State {
+                            inner,
+                            hooks: hooks.clone(),
+                            name: None,
+                        };
+                        transforms.push(&derived, evt).await;
+                    }
+                    Some((key, generation, value)) = transforms.running.next() => {
+                        let Some(inner) = inner.upgrade() else {
+                            break;
+                        };
+                        let derived = State {
+                            inner,
+                            hooks: hooks.clone(),
+                            name: None,
+                        };
+                        transforms.complete(&derived, key, generation, value).await;
+                    }
+                }
+                transforms.start();
+            }
+        });
+
+        derived
+    }
+
+    /// Seed the state from NDJSON, one `[key, value]` pair per line.
+    ///
+    /// All entries are merged into the current state and announced to subscribers with a single
+    /// [`Event::Restart`]. Malformed lines don't abort the load, but are collected in the
+    /// returned report. Only failing to read from `r` is considered an error.
+    pub async fn load_ndjson<R: BufRead>(&self, r: R) -> std::io::Result<LoadReport>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+    {
+        let (entries, report) = read_ndjson(r)?;
+
+        let mut lock = self.inner.write().await;
+        lock.state.extend(entries);
+        let state = lock.state.clone();
+        Inner::broadcast(&mut lock, Event::Restart(state)).await;
+
+        Ok(report)
+    }
+
+    /// Reconstruct a state from a snapshot, and the events which happened after it was taken.
+    ///
+    /// Events are applied in order, without being broadcast. A restart delivered in chunks only
+    /// replaces the state once its last chunk was applied.
+    pub fn replay_from_snapshot(snapshot: HashMap<K, V>, events: Vec<Event<K, V>>) -> State<K, V> {
+        let mut state = snapshot;
+        let mut chunks: Option<HashMap<K, V>> = None;
+
+        for evt in events {
+            match evt {
+                Event::RestartChunk { entries, is_last } => {
+                    chunks.get_or_insert_with(HashMap::new).extend(entries);
+                    if is_last {
+                        state = chunks.take().unwrap_or_default();
+                    }
+                }
+                evt => apply_event(&mut state, evt),
+            }
+        }
+
+        State::from(state)
+    }
+
+    /// Take a snapshot of the current state as baseline, to compare against later on.
+    pub async fn set_baseline(&self) {
+        let mut lock = self.inner.write().await;
+        lock.baseline = Some(lock.state.clone());
+    }
+
+    pub async fn clear_baseline(&self) {
+        self.inner.write().await.baseline = None;
+    }
+
+    pub async fn baseline(&self) -> Option<HashMap<K, V>> {
+        self.inner.read().await.baseline.clone()
+    }
+
+    /// Get the baseline, along with the current state.
+    pub async fn baseline_and_state(&self) -> Option<(HashMap<K, V>, HashMap<K, V>)> {
+        let lock = self.inner.read().await;
+        let baseline = lock.baseline.clone()?;
+        Some((baseline, lock.state.clone()))
+    }
+
+    /// Write the baseline as NDJSON, in the format read by [`State::load_baseline_ndjson`].
+    ///
+    /// Returns `false` if there is no baseline.
+    pub async fn save_baseline_ndjson<W: Write>(&self, mut w: W) -> std::io::Result<bool>
+    where
+        K: serde::Serialize,
+        V: serde::Serialize,
+    {
+        let lock = self.inner.read().await;
+        let Some(baseline) = &lock.baseline else {
+            return Ok(false);
+        };
+
+        for entry in baseline {
+            serde_json::to_writer(&mut w, &entry)?;
+            w.write_all(b"\n")?;
+        }
+        w.flush()?;
+
+        Ok(true)
+    }
+
+    /// Replace the baseline with one read from NDJSON, one `[key, value]` pair per line.
+    ///
+    /// Malformed lines are handled the same way as by [`State::load_ndjson`].
+    pub async fn load_baseline_ndjson<R: BufRead>(&self, r: R) -> std::io::Result<LoadReport>
+    where
+        K: serde::de::DeserializeOwned,
+        V: serde::de::DeserializeOwned,
+    {
+        let (entries, report) = read_ndjson(r)?;
+        self.inner.write().await.baseline = Some(entries.into_iter().collect());
+        Ok(report)
+    }
+
+    /// The current listeners, along with the number of events waiting for them.
+    pub async fn listeners(&self) -> Vec<ListenerInfo> {
+        let lock = self.inner.read().await;
+        let mut listeners = lock.listeners.iter().collect::<Vec<_>>();
+        listeners.sort_by_key(|(_, l)| l.seq);
+
+        listeners
+            .into_iter()
+            .map(|(id, l)| ListenerInfo {
+                id: id.to_string(),
+                name: l.name.as_deref().map(str::to_string),
+                priority: l.priority,
+                queued: l.queued(),
+                high_water: l.high_water,
+                capacity: l.tx.max_capacity(),
+                delivered: l.delivered,
+                age_secs: l.created.elapsed().as_secs(),
+            })
+            .collect()
+    }
+
+    /// Totals of all listeners ever registered, by name, see [`State::named`].
+    pub async fn listener_totals(&self) -> BTreeMap<String, ListenerTotals> {
+        let lock = self.inner.read().await;
+
+        let mut totals = lock
+            .retired
+            .iter()
+            .map(|(name, totals)| (name.clone(), *totals))
+            .collect::<BTreeMap<_, _>>();
+        for listener in lock.listeners.values() {
+            totals
+                .entry(listener.name().to_string())
+                .or_default()
+                .delivered += listener.delivered;
+        }
+
+        totals
+    }
+
+    /// The most recent events which couldn't be delivered, oldest first.
+    pub async fn dropped_events(&self) -> Vec<DroppedEvent> {
+        self.inner.read().await.dropped.iter().cloned().collect()
+    }
+
+    /// Check the internal consistency of the state, **panicking** with a description of the first
+    /// violation found.
+    ///
+    /// Listeners must have distinct sequence numbers, which were handed out already, and only
+    /// listeners skipping events may have lagged behind. The latest state published to
+    /// [`State::subscribe_latest`] must match the state. Only available in debug builds, as
+    /// it inspects the full state while holding the lock.
+    #[cfg(debug_assertions)]
+    pub async fn assert_invariants(&self) {
+        self.assert_invariants_with(|_, _| Ok(())).await
+    }
+
+    /// Check the internal consistency of the state, like [`Self::assert_invariants`], and
+    /// additionally each entry using `check`, returning a description of what is wrong with it.
+    #[cfg(debug_assertions)]
+    pub async fn assert_invariants_with<F>(&self, check: F)
+    where
+        F: Fn(&K, &V) -> Result<(), String>,
+    {
+        let lock = self.inner.read().await;
+
+        let mut seqs = HashSet::new();
+        for (id, listener) in &lock.listeners {
+            assert!(
+                listener.seq < lock.next_seq,
+                "listener {id} has sequence number {}, which wasn't handed out yet (next: {})",
+                listener.seq,
+                lock.next_seq
+            );
+            assert!(
+                seqs.insert(listener.seq),
+                "listener {id} shares its sequence number {} with another listener",
+                listener.seq
+            );
+            assert!(
+                !listener.lagged || matches!(listener.tx, Sink::Lossy(_)),
+                "listener {id} lagged behind, but doesn't skip events"
+            );
+        }
+
+        let mut order = lock
+            .listeners
+            .iter()
+            .map(|(id, l)| (Reverse(l.priority), l.seq, *id))
+            .collect::<Vec<_>>();
+        order.sort();
+        assert!(
+            order == lock.order,
+            "the delivery order doesn't match the listeners"
+        );
+
+        if let Some(latest) = &lock.latest {
+            assert!(
+                **latest.borrow() == lock.state,
+                "the latest state published differs from the state"
+            );
+        }
+
+        assert!(
+            lock.dropped.len() <= MAX_DROPPED_EVENTS,
+            "{} dropped events kept, more than the limit of {MAX_DROPPED_EVENTS}",
+            lock.dropped.len()
+        );
+
+        for (key, value) in &lock.state {
+            if let Err(err) = check(key, value) {
+                panic!("invalid entry {key:?}: {err}\n{value:#?}");
+            }
+        }
+    }
+
+    /// Get the current value of a single key.
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.inner.read().await.state.get(key).cloned()
+    }
+
+    /// Get an entry matching `pred`, e.g. any image whose SBOM is still scheduled.
+    ///
+    /// If several entries match, which one is returned is arbitrary, as the state is a `HashMap`.
+    /// For a deterministic result, use [`Self::find_first`].
+    pub async fn find(&self, pred: impl Fn(&K, &V) -> bool) -> Option<(K, V)> {
+        self.inner
+            .read()
+            .await
+            .state
+            .iter()
+            .find(|(key, value)| pred(key, value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    /// Get the entry with the smallest key matching `pred`, like [`Self::find`], but deterministic.
+    pub async fn find_first(&self, pred: impl Fn(&K, &V) -> bool) -> Option<(K, V)>
+    where
+        K: Ord,
+    {
+        self.inner
+            .read()
+            .await
+            .state
+            .iter()
+            .filter(|(key, value)| pred(key, value))
+            .min_by(|(a, _), (b, _)| a.cmp(b))
+            .map(|(key, value)| (key.clone(), value.clone()))
+    }
+
+    /// Get all entries matching `pred`, in no particular order.
+    pub async fn find_all(&self, pred: impl Fn(&K, &V) -> bool) -> Vec<(K, V)> {
+        self.inner
+            .read()
+            .await
+            .state
+            .iter()
+            .filter(|(key, value)| pred(key, value))
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .collect()
+    }
+
+    pub async fn get_state(&self) -> HashMap<K, V> {
+        self.inner.read().await.state.clone()
+    }
+
+    /// Attach a hook, which gets called with every event, right before it gets broadcast.
+    ///
+    /// Hooks are called synchronously, while holding the write lock, and in the order they were
+    /// added. So they should be quick. As the hook is attached to the state itself, it also
+    /// applies to all clones of it. Dropping the returned handle detaches the hook again.
+    pub fn add_hook<F>(&self, hook: F) -> HookHandle
+    where
+        F: Fn(&Event<K, V>) + Send + Sync + 'static,
+    {
+        let id = self.hooks.1.fetch_add(1, Ordering::Relaxed);
+        self.hooks.0.write().push((id, Box::new(hook)));
+
+        let hooks = Arc::downgrade(&self.hooks);
+        HookHandle {
+            detach: Some(Box::new(move || {
+                if let Some(hooks) = hooks.upgrade() {
+                    hooks.0.write().retain(|(hook, _)| *hook != id);
+                }
+            })),
+        }
+    }
+
+    /// Attach a hook, like [`State::add_hook`], which stays attached as long as the returned
+    /// state (or one of its clones) is around. Hooks can be chained:
+    /// `state.with_hook(log_hook).with_hook(metrics_hook)`.
+    pub fn with_hook<F>(&self, hook: F) -> HookedState<K, V>
+    where
+        F: Fn(&Event<K, V>) + Send + Sync + 'static,
+    {
+        HookedState {
+            state: self.clone(),
+            hooks: vec![Arc::new(self.add_hook(hook))],
+        }
+    }
+
+    /// Expire entries inserted through the returned [`TtlState`] after `default_ttl`, unless
+    /// their TTL gets refreshed.
+    ///
+    /// Expired entries are removed by a background task, announcing them as [`Event::Removed`].
+    /// The task scans for expired entries every `default_ttl` (but at least every second), so
+    /// entries may outlive their TTL by up to one scan. It stops once all clones of the returned
+    /// state are dropped. Entries inserted in other ways, e.g. by [`State::mutate_state`], don't
+    /// expire. Neither do entries which got removed in other ways and inserted again, or which
+    /// were part of a new state set by [`State::set_state`].
+    ///
+    /// **Note:** Must be called from within a Tokio runtime.
+    pub fn ttl_entries(&self, default_ttl: Duration) -> TtlState<K, V> {
+        let deadlines = Arc::<parking_lot::Mutex<HashMap<K, Deadline>>>::default();
+
+        // forget the deadlines of entries removed in other ways
+        let hook = self.add_hook({
+            let deadlines = Arc::downgrade(&deadlines);
+            move |evt| {
+                let Some(deadlines) = deadlines.upgrade() else {
+                    return;
+                };
+                match evt {
+                    Event::Removed(key) => {
+                        deadlines.lock().remove(key);
+                    }
+                    Event::Restart(_) => deadlines.lock().clear(),
+                    Event::Added(..) | Event::Modified(..) | Event::RestartChunk { .. } => {}
+                }
+            }
+        });
+
+        let (task, weak) = (self.clone(), Arc::downgrade(&deadlines));
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(default_ttl.clamp(MIN_TTL_SCAN, MAX_TTL_SCAN));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(deadlines) = weak.upgrade() else {
+                    break;
+                };
+                TtlState::expire(&task, &deadlines).await;
+            }
+        });
+
+        TtlState {
+            state: self.clone(),
+            default_ttl,
+            deadlines,
+            _hook: Arc::new(hook),
+        }
+    }
+
+    /// Remove listeners which have been idle for longer than `timeout`, ending their
+    /// subscriptions.
+    ///
+    /// A listener is idle if events have been waiting for it all that time, without any being
+    /// delivered. Listeners which forward the events to a remote end, and record its activity
+    /// using [`Subscription::touch`], are also idle if the remote end went silent. This catches
+    /// listeners which never fail to take events, e.g. websockets kept open by a buffering proxy
+    /// long after the client is gone.
+    ///
+    /// A background task sweeps the listeners every quarter of `timeout` (but at least every
+    /// 30 seconds), until the state is dropped. Candidates are looked up holding the read lock,
+    /// the write lock is only taken for removing them.
+    ///
+    /// **Note:** Must be called from within a Tokio runtime.
+    pub fn reap_idle_listeners(&self, timeout: Duration) {
+        let inner = Arc::downgrade(&self.inner);
+        tokio::spawn(async move {
+            let mut interval =
+                tokio::time::interval((timeout / 4).clamp(MIN_IDLE_SWEEP, MAX_IDLE_SWEEP));
+            interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                let Some(inner) = inner.upgrade() else {
+                    break;
+                };
+                Inner::reap_idle(&inner, timeout).await;
+            }
+        });
+    }
+
+    /// Acquire the read lock of the state, for getting a consistent view across multiple
+    /// operations.
+    ///
+    /// **Note:** As long as the guard is held, all writers (including the ones broadcasting
+    /// events to subscribers) are blocked. Drop it as soon as possible.
+    pub async fn lock_state_for_read(&self) -> StateReadGuard<K, V> {
+        StateReadGuard {
+            guard: self.inner.clone().read_owned().await,
+        }
+    }
+
+    /// Release unused capacity after entries got removed, according to `policy`, never if `None`.
+    pub async fn set_shrink_policy(&self, policy: Option<ShrinkPolicy>) {
+        let mut lock = self.inner.write().await;
+        lock.shrink = policy;
+        lock.shrink();
+    }
+
+    /// Set how long broadcasts wait for listeners which can't keep up, before evicting them.
+    pub async fn set_broadcast_config(&self, config: BroadcastConfig) {
+        self.inner.write().await.broadcast = config;
+    }
+
+    /// The number of entries the state has room for, without allocating.
+    pub async fn capacity(&self) -> usize {
+        self.inner.read().await.state.capacity()
+    }
+
+    /// When the most recent event got broadcast, `None` if there was none yet.
+    ///
+    /// The state gets fed by watching the cluster, so a long time without events hints at the
+    /// watch having stalled.
+    pub async fn last_event_at(&self) -> Option<Instant> {
+        self.inner.read().await.last_event
+    }
+
+    pub async fn set_state(&self, state: HashMap<K, V>) {
+        let mut lock = self.inner.write().await;
+        lock.state = state.clone();
+        lock.shrink();
+        Inner::broadcast(&mut lock, Event::Restart(state)).await;
+    }
+
+    /// Replace the whole state with one derived from the current one, announced as
+    /// [`Event::Restart`].
+    ///
+    /// Unlike a [`State::get_state`] followed by a [`State::set_state`], no change made in
+    /// between gets lost, as the write lock is held while `f` runs.
+    pub async fn replace_state<F>(&self, f: F)
+    where
+        F: FnOnce(&HashMap<K, V>) -> HashMap<K, V>,
+    {
+        let mut lock = self.inner.write().await;
+        let state = f(&lock.state);
+        lock.state = state.clone();
+        lock.shrink();
+        Inner::broadcast(&mut lock, Event::Restart(state)).await;
+    }
+
+    /// Add, modify, or remove (by returning `None`) the value of a key.
+    ///
+    /// Setting a value equal to the current one is not announced. So re-applying unchanged
+    /// values, e.g. when a Kubernetes informer resyncs, never causes [`Event::Modified`] events
+    /// which only repeat the previous one, and there is no need to deduplicate them downstream.
+    /// Values flapping back and forth can be collapsed by a subscriber using
+    /// [`Subscription::deduplicate_events`].
+    ///
+    /// `f` works on a copy of the value, which is only stored once `f` returned. If `f` panics,
+    /// the state is left untouched and nothing is announced, so subscribers stay in sync.
+    pub async fn mutate_state<F>(&self, key: K, f: F)
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        let mut lock = self.inner.write().await;
+
+        let evt = match lock.state.entry(key.clone()) {
+            Entry::Vacant(entry) => {
+                if let Some(state) = f(None) {
+                    entry.insert(state.clone());
+                    Some(Event::Added(key, state))
+                } else {
+                    None
+                }
+            }
+            Entry::Occupied(mut entry) => match f(Some(entry.get().clone())) {
+                Some(state) => {
+                    if entry.get() != &state {
+                        *entry.get_mut() = state.clone();
+                        Some(Event::Modified(key, state))
+                    } else {
+                        None
+                    }
+                }
+                None => {
+                    entry.remove();
+                    lock.shrink();
+                    Some(Event::Removed(key))
+                }
+            },
+        };
+
+        if let Some(evt) = evt {
+            Inner::broadcast(&mut lock, evt).await;
+        }
+    }
+
+    /// Replace the value of a key, returning the previous one, `None` if it was absent.
+    ///
+    /// Unlike a [`State::get`] followed by a write, nobody can change the value in between. The
+    /// change is announced like with [`State::mutate_state`], as [`Event::Added`] or
+    /// [`Event::Modified`], and not at all if the value is unchanged.
+    pub async fn atomic_swap(&self, key: K, new_value: V) -> Option<V> {
+        let mut previous = None;
+        self.mutate_state(key, |current| {
+            previous = current;
+            Some(new_value)
+        })
+        .await;
+        previous
+    }
+
+    pub async fn remove_state(&self, key: K) {
+        let mut lock = self.inner.write().await;
+
+        if let Some(_) = lock.state.remove(&key) {
+            lock.shrink();
+            Inner::broadcast(&mut lock, Event::Removed(key.clone())).await;
+        }
+    }
+
+    /// Remove a random entry, and return it, e.g. for spreading work across several workers.
+    ///
+    /// **Note:** Picking the entry is O(n) in the number of keys, as it requires iterating over
+    /// the underlying `HashMap`.
+    pub fn pop_random<'a, R: rand::Rng>(
+        &'a self,
+        rng: &mut R,
+    ) -> impl Future<Output = Option<(K, V)>> + 'a {
+        // picked right away, so that the future doesn't hold on to `rng`, which might not be
+        // `Send` (like `ThreadRng`)
+        let pick = rng.gen::<f64>();
+
+        async move {
+            let mut lock = self.inner.write().await;
+
+            if lock.state.is_empty() {
+                return None;
+            }
+
+            let len = lock.state.len();
+            let n = ((pick * len as f64) as usize).min(len - 1);
+            let key = lock.state.keys().nth(n)?.clone();
+            let value = lock.state.remove(&key)?;
+            lock.shrink();
+            Inner::broadcast(&mut lock, Event::Removed(key.clone())).await;
+
+            Some((key, value))
+        }
+    }
+
+    /// Modify or remove entries, as decided by `f`.
+    ///
+    /// Just like with [`Self::mutate_state`], modifications which don't change the value are not
+    /// announced.
+    ///
+    /// The changes are collected first, and only applied and announced once `f` was called for
+    /// all entries. If `f` panics for any of them, none of the changes are applied, leaving the
+    /// state and the subscribers as they were.
+    pub async fn iter_mut<F>(&self, f: F)
+    where
+        F: Fn(&K, &V) -> Output<V>,
+    {
+        let mut lock = self.inner.write().await;
+
+        let mut ops = Vec::new();
+
+        for (k, v) in &lock.state {
+            match f(k, v) {
+                Output::Drop => {
+                    ops.push((k.clone(), None));
+                }
+                Output::Keep => {}
+                Output::Modify(state) => {
+                    if v != &state {
+                        ops.push((k.clone(), Some(state)));
+                    }
+                }
+            }
+        }
+
+        for (k, v) in ops.into_iter() {
+            match v {
+                None => {
+                    lock.state.remove(&k);
+                    lock.shrink();
+                    Inner::broadcast(&mut lock, Event::Removed(k)).await;
+                }
+                Some(state) => {
+                    lock.state.insert(k.clone(), state.clone());
+                    Inner::broadcast(&mut lock, Event::Modified(k, state)).await;
+                }
+            }
+        }
+    }
+
+    /// Replace all values with the result of `f`, returning how many of them changed.
+    ///
+    /// Only changed values are announced, as [`Event::Modified`]. E.g. a migration adding a
+    /// default to all values announces nothing for values which already had it. Like with
+    /// [`Self::iter_mut`], the changes are only applied once `f` was called for all values.
+    pub async fn transform_values<F>(&self, f: F) -> usize
+    where
+        F: Fn(V) -> V,
+    {
+        let mut lock = self.inner.write().await;
+
+        let changed = lock
+            .state
+            .iter()
+            .filter_map(|(k, v)| {
+                let state = f(v.clone());
+                (v != &state).then(|| (k.clone(), state))
+            })
+            .collect::<Vec<_>>();
+
+        let count = changed.len();
+        for (k, state) in changed {
+            lock.state.insert(k.clone(), state.clone());
+            Inner::broadcast(&mut lock, Event::Modified(k, state)).await;
+        }
+        count
+    }
+}
+
+/// Maintains a derived state, see [`State::map`].
+struct Projection<K, V, K2, V2, F, R> {
+    f: F,
+    reduce: R,
+    /// mapped entries, by their source key
+    sources: HashMap<K, (K2, V2)>,
+    /// source keys, by their derived key
+    groups: HashMap<K2, HashSet<K>>,
+    _marker: PhantomData<fn(&V)>,
+}
+
+impl<K, V, K2, V2, F, R> Projection<K, V, K2, V2, F, R>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+    K2: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V2: Clone + Debug + PartialEq + Send + Sync + 'static,
+    F: Fn(&K, &V) -> (K2, V2),
+    R: Fn(V2, V2) -> V2,
+{
+    fn new(f: F, reduce: R) -> Self {
+        Self {
+            f,
+            reduce,
+            sources: Default::default(),
+            groups: Default::default(),
+            _marker: PhantomData,
+        }
+    }
+
+    async fn apply(&mut self, derived: &State<K2, V2>, evt: Event<K, V>) {
+        match evt {
+            Event::Added(key, value) | Event::Modified(key, value) => {
+                let previous = self.remove(&key);
+                let derived_key = self.insert(key, &value);
+                if let Some(previous) = previous.filter(|previous| *previous != derived_key) {
+                    self.update(derived, previous).await;
+                }
+                self.update(derived, derived_key).await;
+            }
+            Event::Removed(key) => {
+                if let Some(previous) = self.remove(&key) {
+                    self.update(derived, previous).await;
+                }
+            }
+            Event::Restart(state) => {
+                self.sources.clear();
+                self.groups.clear();
+                for (key, value) in state {
+                    self.insert(key, &value);
+                }
+                let state = self
+                    .groups
+                    .keys()
+                    .filter_map(|key| Some((key.clone(), self.reduced(key)?)))
+                    .collect();
+                derived.set_state(state).await;
+            }
+            // only delivered to chunked subscriptions
+            Event::RestartChunk { .. } => {}
+        }
+    }
+
+    fn insert(&mut self, key: K, value: &V) -> K2 {
+        let (derived_key, derived_value) = (self.f)(&key, value);
+        self.groups
+            .entry(derived_key.clone())
+            .or_default()
+            .insert(key.clone());
+        self.sources
+            .insert(key, (derived_key.clone(), derived_value));
+        derived_key
+    }
+
+    /// Remove a source entry, returning the derived key it was mapped to.
+    fn remove(&mut self, key: &K) -> Option<K2> {
+        let (derived_key, _) = self.sources.remove(key)?;
+        if let Entry::Occupied(mut group) = self.groups.entry(derived_key.clone()) {
+            group.get_mut().remove(key);
+            if group.get().is_empty() {
+                group.remove();
+            }
+        }
+        Some(derived_key)
+    }
+
+    fn reduced(&self, derived_key: &K2) -> Option<V2> {
+        self.groups
+            .get(derived_key)?
+            .iter()
+            .filter_map(|key| self.sources.get(key))
+            .map(|(_, value)| value.clone())
+            .reduce(&self.reduce)
+    }
+
+    /// Re-calculate a derived key, after its source entries changed.
+    async fn update(&mut self, derived: &State<K2, V2>, derived_key: K2) {
+        let value = self.reduced(&derived_key);
+        derived.mutate_state(derived_key, |_| value).await;
+    }
+}
+
+/// The last known value of each key, see [`State::watch_key_transitions`].
+struct Transitions<K, V> {
+    known: HashMap<K, V>,
+    /// the state being assembled from restart chunks
+    chunks: Option<HashMap<K, V>>,
+}
+
+impl<K, V> Default for Transitions<K, V> {
+    fn default() -> Self {
+        Self {
+            known: Default::default(),
+            chunks: None,
+        }
+    }
+}
+
+impl<K, V> Transitions<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    /// Apply an event, returning the transitions it caused.
+    fn apply(&mut self, evt: Event<K, V>) -> Vec<(K, Option<V>, Option<V>)> {
+        match evt {
+            Event::Added(key, value) | Event::Modified(key, value) => {
+                match self.known.insert(key.clone(), value.clone()) {
+                    Some(before) if before == value => vec![],
+                    before => vec![(key, before, Some(value))],
+                }
+            }
+            Event::Removed(key) => match self.known.remove(&key) {
+                Some(before) => vec![(key, Some(before), None)],
+                None => vec![],
+            },
+            Event::Restart(state) => {
+                self.chunks = None;
+                self.restart(state)
+            }
+            Event::RestartChunk { entries, is_last } => {
+                self.chunks
+                    .get_or_insert_with(Default::default)
+                    .extend(entries);
+                match is_last {
+                    true => {
+                        let state = self.chunks.take().unwrap_or_default();
+                        self.restart(state)
+                    }
+                    false => vec![],
+                }
+            }
+        }
+    }
+
+    fn restart(&mut self, state: HashMap<K, V>) -> Vec<(K, Option<V>, Option<V>)> {
+        let before = std::mem::replace(&mut self.known, state);
+
+        let mut transitions = Vec::new();
+        for (key, value) in &self.known {
+            match before.get(key) {
+                Some(before) if before == value => {}
+                before => transitions.push((key.clone(), before.cloned(), Some(value.clone()))),
+            }
+        }
+        for (key, value) in before {
+            if !self.known.contains_key(&key) {
+                transitions.push((key, Some(value), None));
+            }
+        }
+        transitions
+    }
+}
+
+/// Filters events by a set of keys, see [`State::subscribe_keys`].
+struct KeyFilter<K, V> {
+    keys: HashSet<K>,
+    /// the whole state, for announcing keys once they get added to the set
+    state: HashMap<K, V>,
+}
+
+impl<K, V> KeyFilter<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug,
+{
+    fn new(keys: HashSet<K>) -> Self {
+        Self {
+            keys,
+            state: Default::default(),
+        }
+    }
+
+    /// Track an event, returning it if it concerns the keys in the set.
+    fn apply(&mut self, evt: Event<K, V>) -> Option<Event<K, V>> {
+        let included = match &evt {
+            Event::Added(key, _) | Event::Modified(key, _) | Event::Removed(key) => {
+                self.keys.contains(key)
+            }
+            Event::Restart(_) | Event::RestartChunk { .. } => true,
+        };
+        apply_event(&mut self.state, evt.clone());
+
+        Some(match evt {
+            _ if !included => return None,
+            Event::Restart(state) => Event::Restart(self.select(state)),
+            Event::RestartChunk { entries, is_last } => Event::RestartChunk {
+                entries: self.select(entries),
+                is_last,
+            },
+            evt => evt,
+        })
+    }
+
+    /// Change the set, returning the event announcing the key, if it's present.
+    fn change(&mut self, change: KeySetChange<K>) -> Option<Event<K, V>> {
+        match change {
+            KeySetChange::Add(key) => {
+                if !self.keys.insert(key.clone()) {
+                    return None;
+                }
+                let value = self.state.get(&key)?.clone();
+                Some(Event::Added(key, value))
+            }
+            KeySetChange::Remove(key) => {
+                if !self.keys.remove(&key) || !self.state.contains_key(&key) {
+                    return None;
+                }
+                Some(Event::Removed(key))
+            }
+        }
+    }
+
+    fn select(&self, state: HashMap<K, V>) -> HashMap<K, V> {
+        state
+            .into_iter()
+            .filter(|(key, _)| self.keys.contains(key))
+            .collect()
+    }
+}
+
+/// Tracks a single field of the values, see [`State::subscribe_field`].
+struct FieldChanges<K, V2, F> {
+    f: F,
+    /// the last field value delivered, for each key
+    current: HashMap<K, V2>,
+    /// the state being assembled from restart chunks
+    chunks: Option<HashMap<K, V2>>,
+}
+
+impl<K, V2, F> FieldChanges<K, V2, F>
+where
+    K: Clone + Debug + Eq + Hash,
+    V2: Clone + Debug + PartialEq,
+{
+    fn new(f: F) -> Self {
+        Self {
+            f,
+            current: Default::default(),
+            chunks: None,
+        }
+    }
+
+    /// Project an event to the field, returning `None` if the field didn't change.
+    fn apply<V>(&mut self, evt: Event<K, V>) -> Option<Event<K, V2>>
+    where
+        V: Clone + Debug,
+        F: Fn(&V) -> V2,
+    {
+        Some(match evt {
+            Event::Added(key, value) => {
+                let value = (self.f)(&value);
+                self.current.insert(key.clone(), value.clone());
+                Event::Added(key, value)
+            }
+            Event::Modified(key, value) => {
+                let value = (self.f)(&value);
+                if self.current.get(&key) == Some(&value) {
+                    return None;
+                }
+                self.current.insert(key.clone(), value.clone());
+                Event::Modified(key, value)
+            }
+            Event::Removed(key) => {
+                self.current.remove(&key);
+                Event::Removed(key)
+            }
+            Event::Restart(state) => {
+                self.chunks = None;
+                self.current = self.project(state);
+                Event::Restart(self.current.clone())
+            }
+            Event::RestartChunk { entries, is_last } => {
+                let entries = self.project(entries);
+                let chunks = self.chunks.get_or_insert_with(Default::default);
+                chunks.extend(entries.clone());
+                if is_last {
+                    self.current = self.chunks.take().unwrap_or_default();
+                }
+                Event::RestartChunk { entries, is_last }
+            }
+        })
+    }
+
+    fn project<V>(&self, state: HashMap<K, V>) -> HashMap<K, V2>
+    where
+        F: Fn(&V) -> V2,
+    {
+        state
+            .into_iter()
+            .map(|(key, value)| {
+                let value = (self.f)(&value);
+                (key, value)
+            })
+            .collect()
+    }
+}
+
+/// Events of a coalescing subscription, waiting to be delivered.
+struct Coalesced<K, V> {
+    /// keys, in the order of their first event
+    order: Vec<K>,
+    events: HashMap<K, Pending<V>>,
+}
+
+enum Pending<V> {
+    Added(V),
+    Modified(V),
+    Removed,
+}
+
+impl<K, V> Default for Coalesced<K, V> {
+    fn default() -> Self {
+        Self {
+            order: Default::default(),
+            events: Default::default(),
+        }
+    }
+}
+
+impl<K, V> Coalesced<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn len(&self) -> usize {
+        self.events.len()
+    }
+
+    fn is_empty(&self) -> bool {
+        self.events.is_empty()
+    }
+
+    /// Merge an event into the pending ones, returning events which must be delivered right away.
+    fn push(&mut self, evt: Event<K, V>) -> Option<Event<K, V>> {
+        let (key, next) = match evt {
+            Event::Added(key, value) => (key, Pending::Added(value)),
+            Event::Modified(key, value) => (key, Pending::Modified(value)),
+            Event::Removed(key) => (key, Pending::Removed),
+            Event::Restart(_) | Event::RestartChunk { .. } => {
+                self.order.clear();
+                self.events.clear();
+                return Some(evt);
+            }
+        };
+
+        let merged = match (self.events.remove(&key), next) {
+            (None, next) => {
+                self.order.push(key.clone());
+                Some(next)
+            }
+            // the key came and went, nothing to report
+            (Some(Pending::Added(_)), Pending::Removed) => None,
+            (Some(Pending::Added(_)), Pending::Modified(value)) => Some(Pending::Added(value)),
+            (Some(Pending::Removed), Pending::Added(value)) => Some(Pending::Modified(value)),
+            (Some(_), next) => Some(next),
+        };
+
+        if let Some(merged) = merged {
+            self.events.insert(key, merged);
+        }
+
+        None
+    }
+
+    fn drain(&mut self) -> Vec<Event<K, V>> {
+        let mut events = std::mem::take(&mut self.events);
+        std::mem::take(&mut self.order)
+            .into_iter()
+            .filter_map(|key| {
+                Some(match events.remove(&key)? {
+                    Pending::Added(value) => Event::Added(key, value),
+                    Pending::Modified(value) => Event::Modified(key, value),
+                    Pending::Removed => Event::Removed(key),
+                })
+            })
+            .collect()
+    }
+}
+
+/// Transformations of a state derived by [`State::map_async`], which are running or waiting for
+/// their turn.
+struct AsyncTransforms<K, V, V2, F> {
+    f: F,
+    concurrency: usize,
+    /// latest value of keys waiting for their turn
+    pending: HashMap<K, V>,
+    /// order in which pending keys take their turn, keys may be gone from `pending` already
+    order: VecDeque<K>,
+    /// generation of the latest change of each key, results of earlier ones are outdated
+    generations: HashMap<K, u64>,
+    next_generation: u64,
+    running: FuturesUnordered<BoxFuture<'static, (K, u64, Option<V2>)>>,
+}
+
+impl<K, V, V2, F, Fut> AsyncTransforms<K, V, V2, F>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+    V2: Clone + Debug + PartialEq + Send + Sync + 'static,
+    F: Fn(K, V) -> Fut,
+    Fut: Future<Output = Option<V2>> + Send + 'static,
+{
+    fn new(f: F, concurrency: usize) -> Self {
+        Self {
+            f,
+            concurrency,
+            pending: Default::default(),
+            order: Default::default(),
+            generations: Default::default(),
+            next_generation: 0,
+            running: Default::default(),
+        }
+    }
+
+    /// Record a change of `key`, outdating all earlier ones.
+    fn bump(&mut self, key: K) {
+        self.next_generation += 1;
+        self.generations.insert(key, self.next_generation);
+    }
+
+    fn schedule(&mut self, key: K, value: V) {
+        self.bump(key.clone());
+        if self.pending.insert(key.clone(), value).is_none() {
+            self.order.push_back(key);
+        }
+    }
+
+    async fn push(&mut self, derived: &State<K, V2>, evt: Event<K, V>) {
+        match evt {
+            Event::Added(key, value) | Event::Modified(key, value) => self.schedule(key, value),
+            Event::Removed(key) => {
+                self.generations.remove(&key);
+                self.pending.remove(&key);
+                derived.remove_state(key).await;
+            }
+            Event::Restart(state) => {
+                // outdate everything, and drop what is gone from the source right away
+                self.generations.clear();
+                self.pending.clear();
+                self.order.clear();
+                let mut current = derived.get_state().await;
+                current.retain(|key, _| state.contains_key(key));
+                derived.set_state(current).await;
+                for (key, value) in state {
+                    self.schedule(key, value);
+                }
+            }
+            Event::RestartChunk { entries, .. } => {
+                for (key, value) in entries {
+                    self.schedule(key, value);
+                }
+            }
+        }
+    }
+
+    /// Start transforming pending keys, as long as there is room.
+    fn start(&mut self) {
+        while self.running.len() < self.concurrency {
+            let Some(key) = self.order.pop_front() else {
+                break;
+            };
+            let Some(value) = self.pending.remove(&key) else {
+                continue;
+            };
+            let generation = self.generations.get(&key).copied().unwrap_or_default();
+            let transform = (self.f)(key.clone(), value);
+            self.running
+                .push(async move { (key, generation, transform.await) }.boxed());
+        }
+    }
+
+    /// Apply the result of a transformation, unless the key changed meanwhile.
+    async fn complete(
+        &mut self,
+        derived: &State<K, V2>,
+        key: K,
+        generation: u64,
+        value: Option<V2>,
+    ) {
+        if self.generations.get(&key) != Some(&generation) {
+            return;
+        }
+        self.generations.remove(&key);
+        match value {
+            Some(value) => derived.mutate_state(key, |_| Some(value)).await,
+            None => derived.remove_state(key).await,
+        }
+    }
+}
+
+/// A [`State`] with hooks attached, see [`State::with_hook`].
+///
+/// The hooks get detached once the last clone of it is dropped.
+#[derive(Clone, Debug)]
+pub struct HookedState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    state: State<K, V>,
+    hooks: Vec<Arc<HookHandle>>,
+}
+
+impl<K, V> HookedState<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    /// Attach another hook, keeping the ones attached so far.
+    pub fn with_hook<F>(&self, hook: F) -> HookedState<K, V>
+    where
+        F: Fn(&Event<K, V>) + Send + Sync + 'static,
+    {
+        let mut hooks = self.hooks.clone();
+        hooks.push(Arc::new(self.state.add_hook(hook)));
+        HookedState {
+            state: self.state.clone(),
+            hooks,
+        }
+    }
+
+    /// The plain state, detaching the hooks unless a clone of this state is still around.
+    pub fn into_inner(self) -> State<K, V> {
+        self.state
+    }
+}
+
+impl<K, V> Deref for HookedState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    type Target = State<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+/// A [`State`] split into shards, each with its own lock, to reduce contention between writers.
+///
+/// Keys are assigned to shards by their hash, and each shard broadcasts the events of its keys
+/// to its own listeners. So writes to keys of different shards neither wait for each other, nor
+/// for each other's broadcasts.
+///
+/// **Consistency:** Shards are read one after the other. So [`ShardedState::get_state`], as
+/// well as the initial [`Event::Restart`] of a subscription, are only consistent per shard: a
+/// write to a shard which was read already is missing, while a later write to a shard read
+/// afterwards is included. Events are ordered per key (and per shard), but not across shards.
+#[derive(Clone, Debug)]
+pub struct ShardedState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    shards: Arc<[State<K, V>]>,
+    hasher: RandomState,
+}
+
+impl<K, V> ShardedState<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    /// Create an empty state, using (at least one) `shards` shards.
+    pub fn new(shards: usize) -> Self {
+        Self {
+            shards: (0..shards.max(1)).map(|_| State::default()).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &State<K, V> {
+        let n = self.hasher.hash_one(key) % self.shards.len() as u64;
+        &self.shards[n as usize]
+    }
+
+    pub async fn mutate_state<F>(&self, key: K, f: F)
+    where
+        F: FnOnce(Option<V>) -> Option<V>,
+    {
+        self.shard(&key).mutate_state(key, f).await
+    }
+
+    pub async fn atomic_swap(&self, key: K, new_value: V) -> Option<V> {
+        self.shard(&key).atomic_swap(key, new_value).await
+    }
+
+    /// Replace all values, shard by shard, see [`State::transform_values`].
+    pub async fn transform_values<F>(&self, f: F) -> usize
+    where
+        F: Fn(V) -> V,
+    {
+        let mut changed = 0;
+        for shard in self.shards.iter() {
+            changed += shard.transform_values(&f).await;
+        }
+        changed
+    }
+
+    pub async fn remove_state(&self, key: K) {
+        self.shard(&key).remove_state(key).await
+    }
+
+    pub async fn get(&self, key: &K) -> Option<V> {
+        self.shard(key).get(key).await
+    }
+
+    /// Replace the state, shard by shard.
+    pub async fn set_state(&self, state: HashMap<K, V>) {
+        let mut shards = vec![HashMap::new(); self.shards.len()];
+        for (k, v) in state {
+            let n = self.hasher.hash_one(&k) % self.shards.len() as u64;
+            shards[n as usize].insert(k, v);
+        }
+
+        for (shard, state) in self.shards.iter().zip(shards) {
+            shard.set_state(state).await;
+        }
+    }
+
+    /// Get the state, which is only consistent per shard.
+    pub async fn get_state(&self) -> HashMap<K, V> {
+        let mut state = HashMap::new();
+        for shard in self.shards.iter() {
+            state.extend(shard.get_state().await);
+        }
+        state
+    }
+
+    /// Subscribe to all shards, starting with a single [`Event::Restart`] of all of them.
+    ///
+    /// Later restarts of a single shard (e.g. by [`Self::set_state`]) only carry the keys of that
+    /// shard, so they are delivered as the changes they make instead: keys no longer present are
+    /// removed, all others are added or modified.
+    pub async fn subscribe(&self, buffer: impl Into<Option<usize>>) -> Subscription<K, V> {
+        let buffer = buffer.into().unwrap_or(16);
+
+        let mut subs = Vec::with_capacity(self.shards.len());
+        for shard in self.shards.iter() {
+            subs.push(shard.subscribe(buffer).await);
+        }
+
+        // the initial restart of each shard is waiting for us already
+        let mut state = HashMap::new();
+        let mut keys = Vec::with_capacity(subs.len());
+        for sub in &mut subs {
+            let shard = match sub.recv().await {
+                Some(Event::Restart(shard)) => shard,
+                _ => HashMap::new(),
+            };
+            keys.push(shard.keys().cloned().collect::<HashSet<_>>());
+            state.extend(shard);
+        }
+
+        let (tx, rx) = mpsc::channel(buffer);
+        tx.try_send(Event::Restart(state))
+            .expect("Channel must have enough capacity");
+
+        tokio::spawn(async move {
+            let mut events = stream::select_all(subs.into_iter().enumerate().map(|(n, sub)| {
+                stream::unfold(sub, move |mut sub| async move {
+                    let evt = sub.recv().await?;
+                    Some(((n, evt), sub))
+                })
+                .boxed()
+            }));
+
+            loop {
+                tokio::select! {
+                    evt = events.next() => {
+                        let Some((n, evt)) = evt else {
+                            break;
+                        };
+                        for evt in shard_changes(&mut keys[n], evt) {
+                            if tx.send(evt).await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                    _ = tx.closed() => break,
+                }
+            }
+        });
+
+        // dropping the receiver ends the task, which drops (and unsubscribes) the shard subscriptions
+        Subscription::new(rx, || {})
+    }
+}
+
+/// Translate an event of a shard into events for the whole state, tracking the keys of the shard.
+fn shard_changes<K, V>(keys: &mut HashSet<K>, evt: Event<K, V>) -> Vec<Event<K, V>>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug,
+{
+    match evt {
+        Event::Added(k, v) => {
+            keys.insert(k.clone());
+            vec![Event::Added(k, v)]
+        }
+        Event::Modified(k, v) => vec![Event::Modified(k, v)],
+        Event::Removed(k) => {
+            keys.remove(&k);
+            vec![Event::Removed(k)]
+        }
+        Event::Restart(state) => {
+            let mut events = keys
+                .iter()
+                .filter(|k| !state.contains_key(*k))
+                .map(|k| Event::Removed(k.clone()))
+                .collect::<Vec<_>>();
+            for (k, v) in state {
+                match keys.contains(&k) {
+                    true => events.push(Event::Modified(k, v)),
+                    false => events.push(Event::Added(k, v)),
+                }
+            }
+            *keys = events
+                .iter()
+                .filter_map(|evt| match evt {
+                    Event::Added(k, _) | Event::Modified(k, _) => Some(k.clone()),
+                    _ => None,
+                })
+                .collect();
+            events
+        }
+        // only delivered to chunked subscriptions
+        Event::RestartChunk { .. } => vec![],
+    }
+}
+
+/// Loads the value of a key missing from a [`ReadThroughState`].
+pub type Loader<K, V> = Arc<dyn Fn(K) -> BoxFuture<'static, Option<V>> + Send + Sync>;
+
+/// A [`State`], which loads missing values on demand.
+///
+/// Keys for which the loader didn't return a value are remembered for `negative_ttl`, during
+/// which they are not being loaded again.
+#[derive(Clone)]
+pub struct ReadThroughState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    state: State<K, V>,
+    loader: Loader<K, V>,
+    negative_ttl: Duration,
+    /// keys which could not be loaded, and until when we don't try again
+    negative: Arc<parking_lot::Mutex<NegativeCache<K>>>,
+}
+
+impl<K, V> ReadThroughState<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    pub fn new(state: State<K, V>, loader: Loader<K, V>, negative_ttl: Duration) -> Self {
+        Self {
+            state,
+            loader,
+            negative_ttl,
+            negative: Default::default(),
+        }
+    }
+
+    /// Get the value of a key, loading (and storing) it if it is missing.
+    pub async fn inspect(&self, key: K) -> Option<V> {
+        if let Some(value) = self.state.get(&key).await {
+            return Some(value);
+        }
+
+        if self.negative.lock().contains(&key) {
+            return None;
+        }
+
+        match (self.loader)(key.clone()).await {
+            Some(value) => {
+                // someone else might have been quicker, keep what is there
+                let mut result = None;
+                self.state
+                    .mutate_state(key, |current| {
+                        let current = current.unwrap_or(value);
+                        result = Some(current.clone());
+                        Some(current)
+                    })
+                    .await;
+                result
+            }
+            None => {
+                self.negative
+                    .lock()
+                    .insert(key, Instant::now() + self.negative_ttl);
+                None
+            }
+        }
+    }
+}
+
+/// Don't prune the negative cache of a [`ReadThroughState`] below this many keys.
+const MIN_NEGATIVE_PRUNE: usize = 64;
+
+/// Keys which could not be loaded, along with when they expire.
+///
+/// Expired keys are dropped when they are looked up, and all at once whenever the cache doubled
+/// in size since the last time, so that keys which are never looked up again don't pile up.
+#[derive(Debug)]
+struct NegativeCache<K> {
+    keys: HashMap<K, Instant>,
+    prune_at: usize,
+}
+
+impl<K> Default for NegativeCache<K> {
+    fn default() -> Self {
+        Self {
+            keys: HashMap::new(),
+            prune_at: MIN_NEGATIVE_PRUNE,
+        }
+    }
+}
+
+impl<K: Eq + Hash> NegativeCache<K> {
+    fn contains(&mut self, key: &K) -> bool {
+        match self.keys.get(key) {
+            Some(until) if *until > Instant::now() => true,
+            Some(_) => {
+                self.keys.remove(key);
+                false
+            }
+            None => false,
+        }
+    }
+
+    fn insert(&mut self, key: K, until: Instant) {
+        self.keys.insert(key, until);
+
+        if self.keys.len() >= self.prune_at {
+            let now = Instant::now();
+            self.keys.retain(|_, until| *until > now);
+            self.prune_at = (self.keys.len() * 2).max(MIN_NEGATIVE_PRUNE);
+        }
+    }
+}
+
+impl<K, V> Deref for ReadThroughState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    type Target = State<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+/// Shortest interval of sweeping for idle listeners.
+const MIN_IDLE_SWEEP: Duration = Duration::from_millis(10);
+/// Longest interval of sweeping for idle listeners.
+const MAX_IDLE_SWEEP: Duration = Duration::from_secs(30);
+
+/// Shortest interval of scanning for expired entries of a [`TtlState`].
+const MIN_TTL_SCAN: Duration = Duration::from_millis(10);
+/// Longest interval of scanning for expired entries of a [`TtlState`].
+const MAX_TTL_SCAN: Duration = Duration::from_secs(1);
+
+/// When an entry of a [`TtlState`] expires, and the TTL it was inserted with
+#[derive(Clone, Copy, Debug)]
+struct Deadline {
+    at: Instant,
+    ttl: Duration,
+}
+
+/// A [`State`] whose entries expire, see [`State::ttl_entries`].
+#[derive(Clone, Debug)]
+pub struct TtlState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    state: State<K, V>,
+    default_ttl: Duration,
+    /// entries inserted through this state, and when they expire
+    deadlines: Arc<parking_lot::Mutex<HashMap<K, Deadline>>>,
+    /// forgets the deadlines of entries removed in other ways, until all clones are dropped
+    _hook: Arc<HookHandle>,
+}
+
+impl<K, V> TtlState<K, V>
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    /// Insert (or replace) the value of a key, expiring after `ttl`, or the default TTL if `None`.
+    pub async fn insert(&self, key: K, value: V, ttl: Option<Duration>) {
+        let ttl = ttl.unwrap_or(self.default_ttl);
+        self.state
+            .mutate_state(key.clone(), |_| {
+                // while holding the write lock, so that a concurrent scan can't expire it early
+                let at = Instant::now() + ttl;
+                self.deadlines.lock().insert(key, Deadline { at, ttl });
+                Some(value)
+            })
+            .await;
+    }
+
+    /// Restart the TTL of an entry, e.g. when it got accessed, returns `false` if the entry
+    /// doesn't expire (anymore).
+    ///
+    /// The entry keeps the TTL it was inserted with.
+    pub fn refresh_ttl(&self, key: K) -> bool {
+        match self.deadlines.lock().get_mut(&key) {
+            Some(deadline) => {
+                deadline.at = Instant::now() + deadline.ttl;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Remove the expired entries of `state`.
+    async fn expire(state: &State<K, V>, deadlines: &parking_lot::Mutex<HashMap<K, Deadline>>) {
+        let now = Instant::now();
+        let expired = deadlines
+            .lock()
+            .iter()
+            .filter(|(_, deadline)| deadline.at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+
+        for key in expired {
+            state
+                .mutate_state(key.clone(), |current| {
+                    // check again, the entry might have been refreshed or re-inserted meanwhile
+                    let mut deadlines = deadlines.lock();
+                    match deadlines.get(&key) {
+                        Some(deadline) if deadline.at <= Instant::now() => {
+                            deadlines.remove(&key);
+                            None
+                        }
+                        _ => current,
+                    }
+                })
+                .await;
+        }
+    }
+}
+
+impl<K, V> Deref for TtlState<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    type Target = State<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.state
+    }
+}
+
+/// A read lock on the state of a [`State`], released when dropped.
+pub struct StateReadGuard<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    guard: OwnedRwLockReadGuard<Inner<K, V>>,
+}
+
+impl<K, V> Deref for StateReadGuard<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    type Target = HashMap<K, V>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.guard.state
+    }
+}
+
+/// Apply a single event to a plain map.
+pub fn apply_event<K, V>(state: &mut HashMap<K, V>, evt: Event<K, V>)
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug,
+{
+    match evt {
+        Event::Added(k, v) | Event::Modified(k, v) => {
+            state.insert(k, v);
+        }
+        Event::Removed(k) => {
+            state.remove(&k);
+        }
+        Event::Restart(restart) => *state = restart,
+        Event::RestartChunk { entries, .. } => state.extend(entries),
+    }
+}
+
+/// Take a held back modification which became due, see [`Subscription::deduplicate_events`].
+///
+/// Returns `None` if it was dropped, or dropped and held back again, becoming due later.
+fn take_due<K, V>(
+    pending: &mut HashMap<K, (V, Instant)>,
+    at: Instant,
+    key: K,
+) -> Option<Event<K, V>>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug,
+{
+    match pending.get(&key) {
+        Some((_, deadline)) if *deadline == at => pending
+            .remove(&key)
+            .map(|(value, _)| Event::Modified(key, value)),
+        _ => None,
+    }
+}
+
+/// Read `[key, value]` pairs from NDJSON, collecting malformed lines in the report.
+fn read_ndjson<K, V, R>(r: R) -> std::io::Result<(Vec<(K, V)>, LoadReport)>
+where
+    K: serde::de::DeserializeOwned,
+    V: serde::de::DeserializeOwned,
+    R: BufRead,
+{
+    let mut report = LoadReport::default();
+    let mut entries = Vec::new();
+
+    for (n, line) in r.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        match serde_json::from_str::<(K, V)>(&line) {
+            Ok(entry) => entries.push(entry),
+            Err(error) => report.errors.push(LineError { line: n + 1, error }),
+        }
+    }
+
+    report.loaded = entries.len();
+
+    Ok((entries, report))
+}
+
+/// Outcome of [`State::load_ndjson`].
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    /// number of entries which got loaded
+    pub loaded: usize,
+    /// lines which could not be parsed
+    pub errors: Vec<LineError>,
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to parse line {line}: {error}")]
+pub struct LineError {
+    pub line: usize,
+    #[source]
+    pub error: serde_json::Error,
+}
+
+pub enum Output<T> {
+    Drop,
+    Keep,
+    Modify(T),
+}
+
+impl<K, V> Default for State<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn default() -> Self {
+        Self::from(HashMap::new())
+    }
+}
+
+impl<K, V> State<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    /// An empty state, whose listeners receive events using `transport`.
+    pub fn with_transport(transport: Transport) -> Self {
+        Self::new(HashMap::new(), transport)
+    }
+
+    fn new(state: HashMap<K, V>, transport: Transport) -> Self {
+        let hooks = Arc::new(Hooks::default());
+        Self {
+            inner: Arc::new(RwLock::new(Inner {
+                state,
+                baseline: None,
+                listeners: Default::default(),
+                order: Vec::new(),
+                next_seq: 0,
+                dropped: Default::default(),
+                subscribed: Default::default(),
+                hooks: hooks.clone(),
+                shrink: None,
+                retired: Default::default(),
+                broadcast: Default::default(),
+                last_event: None,
+                latest: None,
+                transport,
             })),
+            hooks,
+            name: None,
+        }
+    }
+}
+
+impl<K, V> From<HashMap<K, V>> for State<K, V>
+where
+    K: Clone + Debug + Eq + Hash,
+    V: Clone + Debug + PartialEq,
+{
+    fn from(state: HashMap<K, V>) -> Self {
+        Self::new(state, Transport::default())
+    }
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ParseError {
+    #[error("Line {0}: expected 'key=value'")]
+    Syntax(usize),
+    #[error("Failed to read input: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+impl State<String, String> {
+    /// Load a state from `key=value` lines.
+    ///
+    /// Keys and values are trimmed, empty lines and lines starting with `#` are ignored. If a
+    /// key occurs more than once, the last value wins.
+    pub fn load_from_reader(r: impl BufRead) -> Result<Self, ParseError> {
+        let mut state = HashMap::new();
+
+        for (n, line) in r.lines().enumerate() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let (key, value) = line.split_once('=').ok_or(ParseError::Syntax(n + 1))?;
+            state.insert(key.trim().to_string(), value.trim().to_string());
+        }
+
+        Ok(Self::from(state))
+    }
+}
+
+impl FromStr for State<String, String> {
+    type Err = ParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Self::load_from_reader(s.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::panic::AssertUnwindSafe;
+
+    #[tokio::test(start_paused = true)]
+    async fn debounced_publishes_settled_value() {
+        let state = State::from(HashMap::from([("a", 1), ("b", 1)]));
+        let mut rx = state
+            .subscribe_debounced("a", Duration::from_millis(100))
+            .await;
+        assert_eq!(*rx.borrow(), Some(1));
+
+        // flickering, never stable for the whole window
+        for value in 2..=4 {
+            state.mutate_state("a", |_| Some(value)).await;
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            assert_eq!(*rx.borrow(), Some(1));
+        }
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*rx.borrow_and_update(), Some(4));
+
+        // other keys don't restart the timer, or get published
+        state.mutate_state("b", |_| Some(2)).await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!rx.has_changed().unwrap());
+
+        state.remove_state("a").await;
+        rx.changed().await.unwrap();
+        assert_eq!(*rx.borrow(), None);
+    }
+
+    #[tokio::test]
+    async fn load_ndjson_merges_with_single_restart() {
+        let state = State::from(HashMap::from([("a".to_string(), 1), ("b".to_string(), 1)]));
+        let mut sub = state.subscribe(None).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        let input = r#"["b", 2]
+["c", 3]
+
+not json
+["d", "not a number"]
+["e", 5]
+"#;
+        let report = state.load_ndjson(input.as_bytes()).await.unwrap();
+
+        assert_eq!(report.loaded, 3);
+        let lines = report.errors.iter().map(|e| e.line).collect::<Vec<_>>();
+        assert_eq!(lines, vec![4, 5]);
+
+        let expected = HashMap::from([
+            ("a".to_string(), 1),
+            ("b".to_string(), 2),
+            ("c".to_string(), 3),
+            ("e".to_string(), 5),
+        ]);
+        assert_eq!(state.get_state().await, expected);
+
+        match sub.try_recv() {
+            Ok(Event::Restart(state)) => assert_eq!(state, expected),
+            other => panic!("expected a restart, got {other:?}"),
+        }
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn subscribe_into_waits_on_full_channel() {
+        let state = State::from(HashMap::from([("a", 1)]));
+
+        let (tx, mut rx) = mpsc::channel(1);
+        tx.try_send(Event::Removed("x")).unwrap();
+        let subscribe = tokio::spawn({
+            let state = state.clone();
+            async move { state.subscribe_into(tx).await }
+        });
+        tokio::task::yield_now().await;
+        assert!(state.listeners().await.is_empty());
+
+        // writers are not held up by the full channel
+        state.set_state(HashMap::from([("a", 2)])).await;
+
+        // once there is room, the listener gets registered, starting with the current state
+        assert!(matches!(rx.recv().await, Some(Event::Removed("x"))));
+        let _handle = subscribe.await.unwrap().unwrap();
+        assert_eq!(state.listeners().await.len(), 1);
+        match rx.recv().await {
+            Some(Event::Restart(initial)) => assert_eq!(initial, HashMap::from([("a", 2)])),
+            other => panic!("expected a restart, got {other:?}"),
+        }
+        state.mutate_state("b", |_| Some(1)).await;
+        assert!(matches!(rx.recv().await, Some(Event::Added("b", 1))));
+    }
+
+    #[tokio::test]
+    async fn subscribe_into_closed_channel() {
+        let state = State::from(HashMap::from([("a", 1)]));
+
+        let (tx, rx) = mpsc::channel(4);
+        drop(rx);
+        assert!(state.subscribe_into(tx).await.is_err());
+        assert!(state.listeners().await.is_empty());
+
+        let (tx, mut rx) = mpsc::channel(4);
+        let _handle = state.subscribe_into(tx).await.unwrap();
+        assert_eq!(state.listeners().await.len(), 1);
+        assert!(matches!(rx.recv().await, Some(Event::Restart(initial)) if initial["a"] == 1));
+    }
+
+    #[tokio::test]
+    async fn read_lock_blocks_writers() {
+        let state = State::from(HashMap::from([("a", 1), ("b", 2)]));
+
+        let guard = state.lock_state_for_read().await;
+        let writer = tokio::spawn({
+            let state = state.clone();
+            async move { state.mutate_state("a", |_| Some(10)).await }
+        });
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!writer.is_finished());
+        assert_eq!(guard.get("a"), Some(&1));
+        assert_eq!(guard.values().sum::<i32>(), 3);
+
+        drop(guard);
+        writer.await.unwrap();
+        assert_eq!(state.get_state().await.get("a"), Some(&10));
+    }
+
+    #[tokio::test]
+    async fn parse_key_values() {
+        let state: State<String, String> = "
+            # comment
+            a = 1
+            b=2
+
+            a = 3
+            url = http://example.com/?x=y
+        "
+        .parse()
+        .unwrap();
+
+        let state = state.get_state().await;
+        assert_eq!(state.len(), 3);
+        assert_eq!(state["a"], "3");
+        assert_eq!(state["b"], "2");
+        assert_eq!(state["url"], "http://example.com/?x=y");
+    }
+
+    #[test]
+    fn parse_reports_line() {
+        let err = State::load_from_reader("a=1\n\nnot a pair\n".as_bytes()).unwrap_err();
+        assert!(matches!(err, ParseError::Syntax(3)), "{err}");
+    }
+
+    #[tokio::test]
+    async fn hooks_see_events_in_order() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+
+        let hooked = state
+            .with_hook({
+                let seen = seen.clone();
+                move |evt| seen.lock().push(format!("first {evt:?}"))
+            })
+            .with_hook({
+                let seen = seen.clone();
+                move |evt| seen.lock().push(format!("second {evt:?}"))
+            });
+
+        hooked.mutate_state("b", |_| Some(2)).await;
+        // hooks are attached to the state, not only to the returned handle
+        state.remove_state("a").await;
+
+        assert_eq!(
+            *seen.lock(),
+            vec![
+                "first Added(\"b\", 2)",
+                "second Added(\"b\", 2)",
+                "first Removed(\"a\")",
+                "second Removed(\"a\")",
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn chunked_initial_state() {
+        let state = State::from((0..5).map(|n| (n, n * 10)).collect::<HashMap<_, _>>());
+
+        let mut sub = state.subscribe_chunked(None, 2).await;
+        let mut initial = HashMap::new();
+        for n in 0..3 {
+            match sub.recv().await {
+                Some(Event::RestartChunk { entries, is_last }) => {
+                    assert!(entries.len() <= 2);
+                    assert_eq!(is_last, n == 2);
+                    initial.extend(entries);
+                }
+                other => panic!("expected a chunk, got {other:?}"),
+            }
+        }
+        assert_eq!(initial, state.get_state().await);
+
+        state.mutate_state(5, |_| Some(50)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added(5, 50))));
+
+        // an empty state still completes the restart
+        let state = State::<i32, i32>::from(HashMap::new());
+        let mut sub = state.subscribe_chunked(None, 2).await;
+        match sub.recv().await {
+            Some(Event::RestartChunk { entries, is_last }) => {
+                assert!(entries.is_empty());
+                assert!(is_last);
+            }
+            other => panic!("expected a chunk, got {other:?}"),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn coalesced_events() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let mut sub = state
+            .subscribe_coalesced(None, Duration::from_millis(100))
+            .await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        state.mutate_state("b", |_| Some(1)).await;
+        state.mutate_state("b", |_| Some(2)).await;
+        state.mutate_state("c", |_| Some(1)).await;
+        state.remove_state("c").await;
+        state.mutate_state("a", |_| Some(2)).await;
+
+        let start = Instant::now();
+        let mut events = vec![sub.recv().await.unwrap(), sub.recv().await.unwrap()];
+        assert!(start.elapsed() >= Duration::from_millis(100));
+        events.sort_by_key(|evt| format!("{evt:?}"));
+        assert!(matches!(
+            events.as_slice(),
+            [Event::Added("b", 2), Event::Modified("a", 2)]
+        ));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(sub.try_recv().is_err());
+
+        // a restart replaces what is pending
+        state.mutate_state("d", |_| Some(1)).await;
+        state.set_state(HashMap::from([("e", 1)])).await;
+        match sub.recv().await {
+            Some(Event::Restart(state)) => assert_eq!(state, HashMap::from([("e", 1)])),
+            other => panic!("expected a restart, got {other:?}"),
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(sub.try_recv().is_err());
+    }
+
+    /// Wait for a (derived) state to become `expected`, **panicking** if it doesn't in time.
+    async fn wait_for_state<K, V>(state: &State<K, V>, expected: HashMap<K, V>)
+    where
+        K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+        V: Clone + Debug + PartialEq + Send + Sync + 'static,
+    {
+        let wait = async {
+            while state.get_state().await != expected {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        if tokio::time::timeout(Duration::from_secs(5), wait)
+            .await
+            .is_err()
+        {
+            panic!(
+                "expected {expected:?}, state is: {:?}",
+                state.get_state().await
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn map_reduces_collisions() {
+        let pods = State::from(HashMap::from([("a/web", 1), ("a/db", 2), ("b/web", 3)]));
+        let per_namespace = pods
+            .map(
+                |pod: &&str, replicas| (pod.split_once('/').unwrap().0, *replicas),
+                |a, b| a + b,
+            )
+            .await;
+        assert_eq!(
+            per_namespace.get_state().await,
+            HashMap::from([("a", 3), ("b", 3)])
+        );
+
+        pods.mutate_state("a/web", |_| Some(5)).await;
+        pods.mutate_state("c/web", |_| Some(1)).await;
+        wait_for_state(
+            &per_namespace,
+            HashMap::from([("a", 7), ("b", 3), ("c", 1)]),
+        )
+        .await;
+
+        // the key goes once all of its entries are gone
+        pods.remove_state("a/web").await;
+        wait_for_state(
+            &per_namespace,
+            HashMap::from([("a", 2), ("b", 3), ("c", 1)]),
+        )
+        .await;
+        pods.remove_state("a/db").await;
+        wait_for_state(&per_namespace, HashMap::from([("b", 3), ("c", 1)])).await;
+
+        // dropping the derived state ends its subscription, with the next event
+        drop(per_namespace);
+        pods.remove_state("b/web").await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pods.listeners().await.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn read_through_caches_misses() {
+        let loads = Arc::new(AtomicUsize::new(0));
+        let loader: Loader<u32, u32> = Arc::new({
+            let loads = loads.clone();
+            move |key| {
+                loads.fetch_add(1, Ordering::Relaxed);
+                async move { (key % 2 == 0).then_some(key * 10) }.boxed()
+            }
+        });
+        let state = ReadThroughState::new(State::default(), loader, Duration::from_secs(10));
+
+        assert_eq!(state.inspect(2).await, Some(20));
+        assert_eq!(state.inspect(2).await, Some(20));
+        assert_eq!(loads.load(Ordering::Relaxed), 1);
+
+        assert_eq!(state.inspect(1).await, None);
+        assert_eq!(state.inspect(1).await, None);
+        assert_eq!(loads.load(Ordering::Relaxed), 2);
+
+        // tried again once the miss expired
+        tokio::time::advance(Duration::from_secs(11)).await;
+        assert_eq!(state.inspect(1).await, None);
+        assert_eq!(loads.load(Ordering::Relaxed), 3);
+        assert_eq!(state.get_state().await, HashMap::from([(2, 20)]));
+    }
+
+    #[test]
+    fn negative_cache_prunes_when_growing() {
+        let mut cache = NegativeCache::default();
+        let expired = Instant::now();
+        for key in 0..MIN_NEGATIVE_PRUNE - 1 {
+            cache.insert(key, expired);
+        }
+        assert_eq!(cache.keys.len(), MIN_NEGATIVE_PRUNE - 1);
+
+        let until = Instant::now() + Duration::from_secs(60);
+        cache.insert(MIN_NEGATIVE_PRUNE, until);
+        assert_eq!(cache.keys.len(), 1);
+        assert!(cache.contains(&MIN_NEGATIVE_PRUNE));
+        assert!(!cache.contains(&0));
+    }
+
+    #[tokio::test]
+    async fn replay_events_onto_snapshot() {
+        let snapshot = HashMap::from([("a", 1), ("b", 2)]);
+        let events = vec![
+            Event::Modified("a", 10),
+            Event::Removed("b"),
+            Event::Added("c", 3),
+        ];
+        let state = State::replay_from_snapshot(snapshot.clone(), events);
+        assert_eq!(
+            state.get_state().await,
+            HashMap::from([("a", 10), ("c", 3)])
+        );
+
+        // the chunked restart only applies once complete
+        let events = vec![
+            Event::RestartChunk {
+                entries: HashMap::from([("x", 1)]),
+                is_last: false,
+            },
+            Event::RestartChunk {
+                entries: HashMap::from([("y", 2)]),
+                is_last: true,
+            },
+            Event::Added("z", 3),
+        ];
+        let state = State::replay_from_snapshot(snapshot.clone(), events.clone());
+        assert_eq!(
+            state.get_state().await,
+            HashMap::from([("x", 1), ("y", 2), ("z", 3)])
+        );
+        let state = State::replay_from_snapshot(snapshot.clone(), events[..1].to_vec());
+        assert_eq!(state.get_state().await, snapshot);
+    }
+
+    #[tokio::test]
+    async fn pop_random_drains_state() {
+        let entries = (0..20).map(|n| (n, n * 10)).collect::<HashMap<_, _>>();
+        let state = State::from(entries.clone());
+        let mut sub = state.subscribe(32).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        // the future must be Send, even with a thread local RNG
+        fn assert_send<T: Send>(_: &T) {}
+        let pop = state.pop_random(&mut rand::thread_rng());
+        assert_send(&pop);
+        let mut popped = HashMap::from_iter(pop.await);
+
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(42);
+        while let Some((key, value)) = state.pop_random(&mut rng).await {
+            assert!(popped.insert(key, value).is_none());
+        }
+        assert_eq!(popped, entries);
+        assert!(state.get_state().await.is_empty());
+
+        for _ in 0..20 {
+            assert!(matches!(sub.recv().await, Some(Event::Removed(_))));
+        }
+    }
+
+    #[tokio::test]
+    async fn await_subscribers() {
+        let state = State::from(HashMap::from([("a", 1)]));
+
+        let waiting = tokio::spawn({
+            let state = state.clone();
+            async move { state.await_n_subscribers(2).await }
+        });
+        let subscribers = (0..2)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    let mut sub = state.subscribe(None).await;
+                    while sub.recv().await.is_some() {}
+                })
+            })
+            .collect::<Vec<_>>();
+
+        tokio::time::timeout(Duration::from_secs(5), waiting)
+            .await
+            .expect("subscribers must be seen")
+            .unwrap();
+        assert_eq!(state.listeners().await.len(), 2);
+
+        // returns right away, if there are enough already
+        state.await_n_subscribers(1).await;
+
+        for subscriber in subscribers {
+            subscriber.abort();
+        }
+    }
+
+    #[tokio::test]
+    async fn invariants_hold() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let _sub = state.subscribe(None).await;
+        let _named = state.named_subscribe("named").await;
+        let _latest = state.subscribe_latest().await;
+
+        state.mutate_state("b", |_| Some(2)).await;
+        state.remove_state("a").await;
+        state.assert_invariants().await;
+        state
+            .assert_invariants_with(|_, value| match *value > 0 {
+                true => Ok(()),
+                false => Err("must be positive".to_string()),
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "invalid entry \"b\": must be positive")]
+    async fn invariants_check_entries() {
+        let state = State::from(HashMap::from([("a", 1), ("b", 0)]));
+        state
+            .assert_invariants_with(|_, value| match *value > 0 {
+                true => Ok(()),
+                false => Err("must be positive".to_string()),
+            })
+            .await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "shares its sequence number")]
+    async fn invariants_check_listeners() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let _first = state.subscribe(None).await;
+        let _second = state.subscribe(None).await;
+
+        for listener in state.inner.write().await.listeners.values_mut() {
+            listener.seq = 0;
+        }
+        state.assert_invariants().await;
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "the latest state published differs from the state")]
+    async fn invariants_check_latest() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let _latest = state.subscribe_latest().await;
+
+        state.inner.write().await.state.insert("b", 2);
+        state.assert_invariants().await;
+    }
+
+    #[derive(Clone, Debug)]
+    enum Op {
+        Mutate(u8, Option<u8>),
+        Set(HashMap<u8, u8>),
+        Remove(u8),
+        Subscribe,
+        Unsubscribe(usize),
+    }
+
+    fn op() -> impl proptest::strategy::Strategy<Value = Op> {
+        use proptest::prelude::*;
+        prop_oneof![
+            4 => (0..8u8, proptest::option::of(0..4u8)).prop_map(|(k, v)| Op::Mutate(k, v)),
+            1 => proptest::collection::hash_map(0..8u8, 0..4u8, 0..8).prop_map(Op::Set),
+            1 => (0..8u8).prop_map(Op::Remove),
+            1 => Just(Op::Subscribe),
+            1 => any::<usize>().prop_map(Op::Unsubscribe),
+        ]
+    }
+
+    /// Replay the events of a subscription, checking that changes only refer to known keys.
+    async fn replay<K, V>(mut sub: Subscription<K, V>, seen: Arc<parking_lot::Mutex<HashMap<K, V>>>)
+    where
+        K: Copy + Debug + Eq + Hash + Send + Sync + std::fmt::Display,
+        V: Clone + Debug + Send + Sync,
+    {
+        let mut restarted = false;
+        while let Some(evt) = sub.recv().await {
+            let mut seen = seen.lock();
+            match evt {
+                Event::Restart(state) => {
+                    *seen = state;
+                    restarted = true;
+                }
+                _ if !restarted => panic!("{evt:?} before the restart"),
+                Event::Added(key, value) => {
+                    assert!(seen.insert(key, value).is_none(), "added known key {key}");
+                }
+                Event::Modified(key, value) => {
+                    assert!(
+                        seen.insert(key, value).is_some(),
+                        "modified unknown key {key}"
+                    );
+                }
+                Event::Removed(key) => {
+                    assert!(seen.remove(&key).is_some(), "removed unknown key {key}");
+                }
+                Event::RestartChunk { .. } => panic!("unexpected {evt:?}"),
+            }
+        }
+    }
+
+    /// Run the operations on the state, returning the replayed states of the subscriptions
+    /// which are still alive.
+    async fn run_ops(
+        state: State<u8, u8>,
+        ops: Vec<Op>,
+    ) -> Vec<(
+        tokio::task::JoinHandle<()>,
+        Arc<parking_lot::Mutex<HashMap<u8, u8>>>,
+    )> {
+        let mut subs = Vec::new();
+        for op in ops {
+            match op {
+                Op::Mutate(key, value) => state.mutate_state(key, |_| value).await,
+                Op::Set(values) => state.set_state(values).await,
+                Op::Remove(key) => state.remove_state(key).await,
+                Op::Subscribe => {
+                    let seen = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+                    let sub = state.subscribe(None).await;
+                    subs.push((tokio::spawn(replay(sub, seen.clone())), seen));
+                }
+                Op::Unsubscribe(n) if !subs.is_empty() => {
+                    let (task, _) = subs.swap_remove(n % subs.len());
+                    task.abort();
+                }
+                Op::Unsubscribe(_) => {}
+            }
+        }
+        subs
+    }
+
+    proptest::proptest! {
+        #![proptest_config(proptest::prelude::ProptestConfig::with_cases(64))]
+
+        #[test]
+        fn subscribers_replay_state(
+            ops in proptest::collection::vec(proptest::collection::vec(op(), 0..40), 1..4)
+        ) {
+            let runtime = tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(4)
+                .enable_all()
+                .build()
+                .unwrap();
+            runtime.block_on(async move {
+                let state = State::<u8, u8>::default();
+                let writers = ops
+                    .into_iter()
+                    .map(|ops| tokio::spawn(run_ops(state.clone(), ops)))
+                    .collect::<Vec<_>>();
+
+                let mut subs = Vec::new();
+                for writer in writers {
+                    subs.extend(writer.await.unwrap());
+                }
+
+                let expected = state.get_state().await;
+                for (task, seen) in subs {
+                    tokio::time::timeout(Duration::from_secs(5), async {
+                        while *seen.lock() != expected {
+                            assert!(!task.is_finished(), "replaying events failed");
+                            tokio::time::sleep(Duration::from_millis(1)).await;
+                        }
+                    })
+                    .await
+                    .expect("replayed state never caught up");
+                }
+                state.assert_invariants().await;
+            });
+        }
+    }
+
+    #[tokio::test]
+    async fn subscribe_backfill() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let backfill = vec![
+            Event::Restart(HashMap::from([("a", 0)])),
+            Event::Modified("a", 1),
+        ];
+        let mut sub = state.subscribe_backfill(3, backfill).await;
+        state.mutate_state("b", |_| Some(2)).await;
+
+        assert!(
+            matches!(sub.recv().await, Some(Event::Restart(s)) if s == HashMap::from([("a", 0)]))
+        );
+        assert!(matches!(sub.recv().await, Some(Event::Modified("a", 1))));
+        assert!(matches!(sub.recv().await, Some(Event::Added("b", 2))));
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    #[should_panic(expected = "buffer of 2 too small for 2 backfilled events")]
+    async fn subscribe_backfill_needs_room() {
+        let state = State::<&str, i32>::default();
+        state
+            .subscribe_backfill(2, vec![Event::Added("a", 1), Event::Added("b", 2)])
+            .await;
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn subscribe_and_drop_while_broadcasting() {
+        let state = State::<u32, u32>::default();
+
+        let seen = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+        let replaying = tokio::spawn(replay(state.subscribe(None).await, seen.clone()));
+
+        let writer = {
+            let state = state.clone();
+            tokio::spawn(async move {
+                for n in 0..2000 {
+                    match n % 100 {
+                        0 => state.set_state(HashMap::from([(n % 7, n)])).await,
+                        _ => {
+                            state
+                                .mutate_state(n % 7, |_| Some(n).filter(|n| n % 5 != 0))
+                                .await
+                        }
+                    }
+                    tokio::task::yield_now().await;
+                }
+            })
+        };
+
+        let subscribers = (0..4)
+            .map(|_| {
+                let state = state.clone();
+                tokio::spawn(async move {
+                    for n in 0..200 {
+                        let mut sub = state.subscribe(1).await;
+                        match n % 3 {
+                            // dropped right away, possibly while being broadcast to
+                            0 => {}
+                            // dropped with events queued
+                            1 => tokio::task::yield_now().await,
+                            // dropped after reading
+                            _ => {
+                                sub.recv().await.unwrap();
+                            }
+                        }
+                        drop(sub);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        writer.await.unwrap();
+        for subscriber in subscribers {
+            subscriber.await.unwrap();
+        }
+
+        // only the replaying subscription is left, once the unsubscribe tasks ran
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while state.listeners().await.len() != 1 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("dropped subscriptions were never removed");
+        state.assert_invariants().await;
+
+        let expected = state.get_state().await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while *seen.lock() != expected {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        })
+        .await
+        .expect("replayed state never caught up");
+        replaying.abort();
+    }
+
+    #[tokio::test]
+    async fn snapshot_stream() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let snapshots = state.into_snapshot_stream().await;
+        futures::pin_mut!(snapshots);
+
+        state.mutate_state("b", |_| Some(2)).await;
+        state.mutate_state("a", |_| Some(10)).await;
+        // restarts without changes are skipped
+        state.set_state(HashMap::from([("a", 10), ("b", 2)])).await;
+        state.remove_state("b").await;
+
+        let first = snapshots.next().await.unwrap();
+        assert_eq!(*first, HashMap::from([("a", 1)]));
+        let second = snapshots.next().await.unwrap();
+        assert_eq!(*second, HashMap::from([("a", 1), ("b", 2)]));
+        // previous snapshots are left untouched
+        assert_eq!(*first, HashMap::from([("a", 1)]));
+        drop((first, second));
+
+        let third = snapshots.next().await.unwrap();
+        assert_eq!(*third, HashMap::from([("a", 10), ("b", 2)]));
+        let fourth = snapshots.next().await.unwrap();
+        assert_eq!(*fourth, HashMap::from([("a", 10)]));
+        assert!(!Arc::ptr_eq(&third, &fourth));
+    }
+
+    #[tokio::test]
+    async fn shrinks_after_mass_removal() {
+        let state = State::from((0..1000u32).map(|n| (n, n)).collect::<HashMap<_, _>>());
+        state
+            .set_shrink_policy(Some(ShrinkPolicy {
+                min_occupancy: 0.25,
+                min_capacity: 64,
+            }))
+            .await;
+        let peak = state.capacity().await;
+        assert!(peak >= 1000);
+
+        // scale down by 90%
+        for n in 0..900 {
+            state.remove_state(n).await;
+        }
+        let capacity = state.capacity().await;
+        assert!(capacity >= 100);
+        assert!(capacity < peak / 4, "{capacity} of {peak} still allocated");
+    }
+
+    #[test]
+    fn shrink_policy_target() {
+        let policy = ShrinkPolicy {
+            min_occupancy: 0.25,
+            min_capacity: 64,
+        };
+        assert_eq!(policy.target(10, 1024), Some(20));
+        assert_eq!(policy.target(256, 1024), None);
+        assert_eq!(policy.target(1, 32), None);
+    }
+
+    #[tokio::test]
+    async fn delivery_order() {
+        let state = State::<&str, i32>::default();
+
+        let mut subs = Vec::new();
+        for priority in [1, 200, DEFAULT_PRIORITY] {
+            for _ in 0..2 {
+                let mut sub = state.subscribe_with_priority(None, priority).await;
+                sub.recv().await.unwrap();
+                subs.push(sub);
+            }
+        }
+        // removing a listener keeps the order of the others
+        subs.remove(2);
+        while state.listeners().await.len() != 5 {
+            tokio::task::yield_now().await;
+        }
+
+        state.mutate_state("a", |_| Some(1)).await;
+        for sub in &mut subs {
+            assert!(matches!(sub.recv().await, Some(Event::Added("a", 1))));
+        }
+        state.assert_invariants().await;
+
+        let order = state.inner.read().await.order.clone();
+        let priorities = order.iter().map(|(p, _, _)| p.0).collect::<Vec<_>>();
+        assert_eq!(
+            priorities,
+            vec![200, DEFAULT_PRIORITY, DEFAULT_PRIORITY, 1, 1]
+        );
+        assert!(order.windows(2).all(|w| w[0] < w[1]));
+    }
+
+    #[tokio::test]
+    async fn cancel_subscription() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let token = CancellationToken::new();
+        let mut sub = state.subscribe(None).await.with_cancellation(token.clone());
+
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+        state.mutate_state("b", |_| Some(2)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added("b", 2))));
+
+        token.cancel();
+        assert!(sub.recv().await.is_none());
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while !state.listeners().await.is_empty() {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("cancelled subscription was never removed");
+
+        // a cancelled token ends new subscriptions right away
+        let mut sub = state.subscribe(None).await.with_cancellation(token);
+        assert!(sub.recv().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn panicking_mutations() {
+        let state = State::from(HashMap::from([("a", 1), ("b", 2)]));
+        let mut sub = state.subscribe(None).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        let result = AssertUnwindSafe(state.mutate_state("a", |_| panic!("mutation failed")))
+            .catch_unwind()
+            .await;
+        assert!(result.is_err());
+
+        // panics for one entry, possibly after the other one was changed already
+        let result = AssertUnwindSafe(state.iter_mut(|key, value| match *key {
+            "a" => Output::Modify(value + 10),
+            _ => panic!("mutation failed"),
+        }))
+        .catch_unwind()
+        .await;
+        assert!(result.is_err());
+
+        assert_eq!(state.get_state().await, HashMap::from([("a", 1), ("b", 2)]));
+        assert!(sub.try_recv().is_err());
+
+        // the state is still usable
+        state.mutate_state("a", |_| Some(10)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Modified("a", 10))));
+        state
+            .iter_mut(|key, _| match *key {
+                "a" => Output::Keep,
+                _ => Output::Drop,
+            })
+            .await;
+        assert!(matches!(sub.recv().await, Some(Event::Removed("b"))));
+        assert!(sub.try_recv().is_err());
+        state.assert_invariants().await;
+    }
+
+    #[tokio::test]
+    async fn count_unread_events() {
+        let state = State::<&str, i32>::default();
+        let (mut sub, unread) = state.subscribe_counted(2).await;
+        let unread = move || unread.load(Ordering::Relaxed);
+        assert_eq!(unread(), 1);
+
+        state.mutate_state("a", |_| Some(1)).await;
+        assert_eq!(unread(), 2);
+        sub.recv().await.unwrap();
+        sub.try_recv().unwrap();
+        assert_eq!(unread(), 0);
+        assert!(sub.try_recv().is_err());
+        assert_eq!(unread(), 0);
+
+        // counting an event waiting for room in the channel
+        state.mutate_state("a", |_| Some(2)).await;
+        state.mutate_state("a", |_| Some(3)).await;
+        let writer = tokio::spawn({
+            let state = state.clone();
+            async move { state.mutate_state("a", |_| Some(4)).await }
+        });
+        while unread() < 3 {
+            tokio::task::yield_now().await;
+        }
+        for n in 2..=4 {
+            assert!(matches!(sub.recv().await, Some(Event::Modified("a", v)) if v == n));
+        }
+        writer.await.unwrap();
+        assert_eq!(unread(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn ttl_entries_expire() {
+        let state = State::<&str, i32>::default();
+        let ttl = state.ttl_entries(Duration::from_secs(10));
+        let mut sub = state.subscribe(32).await;
+        let start = Instant::now();
+
+        ttl.insert("a", 1, None).await;
+        ttl.insert("b", 2, Some(Duration::from_secs(30))).await;
+        // inserted without a TTL
+        state.mutate_state("c", |_| Some(3)).await;
+        // removed, and inserted again without a TTL
+        ttl.insert("d", 4, None).await;
+        state.remove_state("d").await;
+        state.mutate_state("d", |_| Some(4)).await;
+        for _ in 0..7 {
+            sub.recv().await.unwrap();
+        }
+
+        tokio::time::advance(Duration::from_secs(20)).await;
+        assert!(ttl.refresh_ttl("b"));
+        assert!(!ttl.refresh_ttl("c"));
+        assert!(!ttl.refresh_ttl("d"));
+
+        assert!(matches!(sub.recv().await, Some(Event::Removed("a"))));
+        assert!(matches!(sub.recv().await, Some(Event::Removed("b"))));
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_secs(50) && elapsed <= Duration::from_secs(51));
+
+        // a new state doesn't expire
+        ttl.insert("e", 5, None).await;
+        state
+            .set_state(HashMap::from([("c", 3), ("d", 4), ("e", 5)]))
+            .await;
+        tokio::time::sleep(Duration::from_secs(60)).await;
+        assert_eq!(
+            state.get_state().await,
+            HashMap::from([("c", 3), ("d", 4), ("e", 5)])
+        );
+    }
+
+    #[tokio::test]
+    async fn latest_snapshot() {
+        let state = State::from(HashMap::from([("a", 0)]));
+        let mut rx = state.subscribe_latest().await;
+        assert_eq!(**rx.borrow_and_update(), HashMap::from([("a", 0)]));
+
+        // a consumer not keeping up skips to the most recent snapshot
+        for n in 1..=100 {
+            state.mutate_state("a", |_| Some(n)).await;
+        }
+        state.mutate_state("b", |_| Some(1)).await;
+        let expected = HashMap::from([("a", 100), ("b", 1)]);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while **rx.borrow_and_update() != expected {
+                rx.changed().await.unwrap();
+            }
+        })
+        .await
+        .expect("latest snapshot never caught up");
+        assert!(!rx.has_changed().unwrap());
+
+        // there is no listener, which could fall behind
+        assert!(state.listeners().await.is_empty());
+        drop(rx);
+        state.mutate_state("a", |_| Some(0)).await;
+        assert!(state.inner.read().await.latest.is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn map_async_transforms() {
+        let state = State::from(HashMap::from([("a", 1), ("b", 0), ("c", 5)]));
+        state
+            .set_broadcast_config(BroadcastConfig {
+                concurrency: 2,
+                ..Default::default()
+            })
+            .await;
+
+        let (running, most) = (Arc::new(AtomicUsize::new(0)), Arc::new(AtomicUsize::new(0)));
+        let derived = state
+            .map_async({
+                let (running, most) = (running.clone(), most.clone());
+                move |_, value: i32| {
+                    let (running, most) = (running.clone(), most.clone());
+                    async move {
+                        let now = running.fetch_add(1, Ordering::Relaxed) + 1;
+                        most.fetch_max(now, Ordering::Relaxed);
+                        // the larger the value, the faster
+                        tokio::time::sleep(Duration::from_millis(100 / (value as u64 + 1))).await;
+                        running.fetch_sub(1, Ordering::Relaxed);
+                        (value > 0).then_some(value * 10)
+                    }
+                }
+            })
+            .await;
+        wait_for_state(&derived, HashMap::from([("a", 10), ("c", 50)])).await;
+
+        // a slow transformation gets overtaken by a later, faster one
+        state.mutate_state("a", |_| Some(0)).await;
+        state.mutate_state("a", |_| Some(2)).await;
+        state.mutate_state("a", |_| Some(9)).await;
+        state.remove_state("c").await;
+        wait_for_state(&derived, HashMap::from([("a", 90)])).await;
+        tokio::time::sleep(Duration::from_secs(1)).await;
+        assert_eq!(derived.get_state().await, HashMap::from([("a", 90)]));
+        assert!(most.load(Ordering::Relaxed) <= 2);
+
+        // the task ends with the derived state
+        drop(derived);
+        state.mutate_state("a", |_| Some(1)).await;
+        while !state.listeners().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn last_event_at() {
+        let state = State::<u32, u32>::default();
+        assert_eq!(state.last_event_at().await, None);
+
+        let start = Instant::now();
+        state.mutate_state(1, |_| Some(1)).await;
+        assert_eq!(state.last_event_at().await, Some(start));
+
+        // unchanged values don't make an event
+        tokio::time::advance(Duration::from_secs(5)).await;
+        state.mutate_state(1, |_| Some(1)).await;
+        assert_eq!(state.last_event_at().await, Some(start));
+
+        state.remove_state(1).await;
+        assert_eq!(
+            state.last_event_at().await,
+            Some(start + Duration::from_secs(5))
+        );
+    }
+
+    #[tokio::test]
+    async fn subscribe_with_name() {
+        let state = State::<u32, u32>::default();
+        let sub = state.subscribe_with_name("websocket").await;
+        let _other = state.subscribe(None).await;
+
+        let listeners = state.listeners().await;
+        assert_eq!(listeners.len(), 2);
+        assert_eq!(listeners[0].name.as_deref(), Some("websocket"));
+        assert_eq!(listeners[0].capacity, 16);
+        assert_eq!(listeners[1].name, None);
+
+        // the name stays with the listener, once it's gone
+        drop(sub);
+        state.mutate_state(1, |_| Some(1)).await;
+        let dropped = state.dropped_events().await;
+        assert_eq!(dropped.len(), 1);
+        assert_eq!(dropped[0].name.as_deref(), Some("websocket"));
+        assert_eq!(dropped[0].reason, DropReason::Closed);
+        assert!(state.listener_totals().await.contains_key("websocket"));
+    }
+
+    #[test]
+    fn encoded() {
+        let value = BTreeMap::from([("name", "nginx")]);
+        let encoded = Encoded::new(&value).unwrap();
+        assert_eq!(encoded.json(), r#"{"name":"nginx"}"#);
+
+        // serializing copies the JSON, without nesting it as a string
+        let evt = Event::Modified(1, encoded.clone());
+        assert_eq!(
+            serde_json::to_value(&evt).unwrap(),
+            serde_json::to_value(Event::Modified(1, value.clone())).unwrap()
+        );
+
+        // equal by content, not only when shared
+        assert_eq!(encoded, Encoded::new(&value).unwrap());
+        assert_ne!(encoded, Encoded::new(&"nginx").unwrap());
+    }
+
+    #[tokio::test]
+    async fn watch_key_transitions() {
+        let state = State::<u32, u32>::default();
+        state.set_state(HashMap::from([(1, 1), (2, 2)])).await;
+        let mut transitions = Box::pin(state.watch_key_transitions().await);
+
+        state.mutate_state(3, |_| Some(3)).await;
+        state.mutate_state(1, |_| Some(10)).await;
+        state.remove_state(2).await;
+        assert_eq!(transitions.next().await, Some((3, None, Some(3))));
+        assert_eq!(transitions.next().await, Some((1, Some(1), Some(10))));
+        assert_eq!(transitions.next().await, Some((2, Some(2), None)));
+
+        // a restart only reports what changed
+        state
+            .set_state(HashMap::from([(1, 10), (3, 30), (4, 4)]))
+            .await;
+        let mut restart = vec![
+            transitions.next().await.unwrap(),
+            transitions.next().await.unwrap(),
+        ];
+        restart.sort();
+        assert_eq!(restart, [(3, Some(3), Some(30)), (4, None, Some(4))]);
+
+        // as does a restart sent in chunks
+        let mut chunked = Transitions {
+            known: HashMap::from([(1, 1), (2, 2)]),
+            chunks: None,
+        };
+        assert_eq!(
+            chunked.apply(Event::RestartChunk {
+                entries: HashMap::from([(1, 1)]),
+                is_last: false,
+            }),
+            []
+        );
+        let mut restart = chunked.apply(Event::RestartChunk {
+            entries: HashMap::from([(3, 3)]),
+            is_last: true,
+        });
+        restart.sort();
+        assert_eq!(restart, [(2, Some(2), None), (3, None, Some(3))]);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn atomic_swap() {
+        let state = State::<u32, u32>::default();
+        let mut sub = state.subscribe(None).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        assert_eq!(state.atomic_swap(1, 10).await, None);
+        assert!(matches!(sub.recv().await, Some(Event::Added(1, 10))));
+        assert_eq!(state.atomic_swap(1, 20).await, Some(10));
+        assert!(matches!(sub.recv().await, Some(Event::Modified(1, 20))));
+
+        // unchanged values aren't announced
+        assert_eq!(state.atomic_swap(1, 20).await, Some(20));
+        assert!(sub.try_recv().is_err());
+
+        // concurrent swaps each see the value of the one before
+        let mut previous = futures::future::join_all((0..10).map(|n| {
+            let state = state.clone();
+            tokio::spawn(async move { state.atomic_swap(2, n).await })
+        }))
+        .await
+        .into_iter()
+        .map(Result::unwrap)
+        .collect::<Vec<_>>();
+        previous.push(state.get(&2).await);
+        previous.sort();
+        assert_eq!(
+            previous,
+            [None]
+                .into_iter()
+                .chain((0..10).map(Some))
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn subscribe_with_priority() {
+        let state = State::<u32, u32>::default();
+        state
+            .set_broadcast_config(BroadcastConfig {
+                send_timeout: Duration::from_millis(10),
+                ..Default::default()
+            })
+            .await;
+        let mut critical = state.subscribe_with_priority(4, 200).await;
+        let _slow = state.subscribe(4).await;
+
+        // reads slowly, but still receives all events
+        let reader = tokio::spawn(async move {
+            let mut received = 0;
+            while received < 100 {
+                if let Some(Event::Added(..)) = critical.recv().await {
+                    received += 1;
+                }
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+            critical
+        });
+        for n in 0..100 {
+            state.mutate_state(n, |_| Some(n)).await;
+        }
+        let critical = reader.await.unwrap();
+
+        // while the one at the default priority got evicted
+        let listeners = state.listeners().await;
+        assert_eq!(listeners.len(), 1);
+        assert_eq!(listeners[0].priority, 200);
+
+        // one which doesn't read at all gets evicted eventually
+        for n in 0..4 {
+            state.mutate_state(n, |_| Some(n + 1)).await;
+        }
+        let start = Instant::now();
+        state.mutate_state(0, |_| Some(0)).await;
+        assert!(start.elapsed() >= GUARANTEED_SEND_TIMEOUT);
+        assert!(state.listeners().await.is_empty());
+        assert_eq!(
+            state
+                .dropped_events()
+                .await
+                .last()
+                .map(|dropped| dropped.reason),
+            Some(DropReason::Timeout)
+        );
+        drop(critical);
+    }
+
+    #[tokio::test]
+    async fn transform_values() {
+        let state = State::<u32, u32>::default();
+        state
+            .set_state(HashMap::from([(1, 1), (2, 10), (3, 3)]))
+            .await;
+        let mut sub = state.subscribe(None).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        // raise all values to at least 5, only announcing the ones which changed
+        assert_eq!(state.transform_values(|v| v.max(5)).await, 2);
+        let mut modified = Vec::new();
+        while let Ok(evt) = sub.try_recv() {
+            match evt {
+                Event::Modified(k, v) => modified.push((k, v)),
+                evt => panic!("expected a modification, got {evt:?}"),
+            }
+        }
+        modified.sort();
+        assert_eq!(modified, [(1, 5), (3, 5)]);
+        assert_eq!(
+            state.get_state().await,
+            HashMap::from([(1, 5), (2, 10), (3, 5)])
+        );
+
+        assert_eq!(state.transform_values(|v| v.max(5)).await, 0);
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn latest_updated_in_place() {
+        let state = State::<u32, u32>::default();
+        state.set_state(HashMap::from([(1, 1)])).await;
+        let rx = state.subscribe_latest().await;
+        let snapshot = Arc::as_ptr(&rx.borrow());
+
+        // without anybody holding on to the snapshot, events don't copy it
+        state.mutate_state(2, |_| Some(2)).await;
+        state.remove_state(1).await;
+        assert_eq!(Arc::as_ptr(&rx.borrow()), snapshot);
+        assert_eq!(**rx.borrow(), HashMap::from([(2, 2)]));
+
+        // a snapshot which is still held doesn't change
+        let held = rx.borrow().clone();
+        state.mutate_state(2, |_| Some(20)).await;
+        assert_eq!(*held, HashMap::from([(2, 2)]));
+        assert_eq!(**rx.borrow(), HashMap::from([(2, 20)]));
+
+        state.set_state(HashMap::from([(3, 3)])).await;
+        assert_eq!(**rx.borrow(), HashMap::from([(3, 3)]));
+        state.assert_invariants().await;
+    }
+
+    #[tokio::test]
+    async fn subscribe_keys() {
+        let state = State::<u32, u32>::default();
+        state
+            .set_state(HashMap::from([(1, 1), (2, 2), (3, 3)]))
+            .await;
+        let (mut sub, keys) = state.subscribe_keys(None, HashSet::from([1, 4])).await;
+        assert!(
+            matches!(sub.recv().await, Some(Event::Restart(state)) if state == HashMap::from([(1, 1)]))
+        );
+
+        // other keys are filtered out
+        state.mutate_state(2, |_| Some(20)).await;
+        state.mutate_state(1, |_| Some(10)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Modified(1, 10))));
+
+        // keys of the set show up once present
+        state.mutate_state(4, |_| Some(4)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added(4, 4))));
+
+        // changing the set makes the keys come and go
+        keys.add(2);
+        assert!(matches!(sub.recv().await, Some(Event::Added(2, 20))));
+        keys.remove(1);
+        assert!(matches!(sub.recv().await, Some(Event::Removed(1))));
+        state.mutate_state(1, |_| Some(100)).await;
+        state.remove_state(2).await;
+        assert!(matches!(sub.recv().await, Some(Event::Removed(2))));
+
+        // adding a key which isn't present doesn't deliver anything yet
+        keys.add(5);
+        state.mutate_state(5, |_| Some(5)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added(5, 5))));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn named_subscribe() {
+        let state = State::<u32, u32>::default();
+        let mut sub = state.named_subscribe("exporter").await;
+        assert_eq!(sub.name(), "exporter");
+        assert_eq!(state.listeners().await[0].name.as_deref(), Some("exporter"));
+
+        // the time queued counts from queueing the event, until receiving it
+        tokio::time::advance(Duration::from_secs(3)).await;
+        state.mutate_state(1, |_| Some(1)).await;
+        tokio::time::advance(Duration::from_secs(2)).await;
+        let (evt, queued) = sub.recv_with_timing().await.unwrap();
+        assert!(matches!(evt, Event::Restart(_)));
+        assert_eq!(queued, Duration::from_secs(5));
+        let (evt, queued) = sub.recv_with_timing().await.unwrap();
+        assert!(matches!(evt, Event::Added(1, 1)));
+        assert_eq!(queued, Duration::from_secs(2));
+
+        state.remove_state(1).await;
+        assert!(matches!(sub.recv().await, Some(Event::Removed(1))));
+
+        drop(sub);
+        state.mutate_state(2, |_| Some(2)).await;
+        assert!(state.listeners().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribe_in_background() {
+        let state = State::<u32, u32>::default();
+        let received = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let handle = state
+            .subscribe_in_background({
+                let received = received.clone();
+                move |evt| received.lock().push(evt)
+            })
+            .await;
+
+        state.mutate_state(1, |_| Some(1)).await;
+        state.remove_state(1).await;
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while received.lock().len() < 3 {
+                tokio::task::yield_now().await;
+            }
+        })
+        .await
+        .expect("handler never received the events");
+        assert!(matches!(
+            received.lock().as_slice(),
+            [Event::Restart(_), Event::Added(1, 1), Event::Removed(1)]
+        ));
+
+        // dropping the handle unsubscribes
+        drop(handle);
+        tokio::task::yield_now().await;
+        state.mutate_state(2, |_| Some(2)).await;
+        assert!(state.listeners().await.is_empty());
+        assert_eq!(received.lock().len(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn with_transport() {
+        let state = State::<u32, u32>::with_transport(Transport::Broadcast);
+        let mut sub = state.subscribe(2).await;
+
+        // a full listener doesn't hold up the writes, and isn't evicted
+        let start = Instant::now();
+        for n in 0..10 {
+            state.mutate_state(n, |_| Some(n)).await;
+        }
+        assert_eq!(start.elapsed(), Duration::ZERO);
+        assert_eq!(state.listeners().await.len(), 1);
+        let dropped = state.dropped_events().await;
+        assert_eq!(dropped.len(), 9);
+        assert!(dropped
+            .iter()
+            .all(|dropped| dropped.reason == DropReason::Lagged));
+
+        // it skipped the events, and catches up with the current state once there is room
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+        assert!(matches!(sub.recv().await, Some(Event::Added(0, 0))));
+        state.mutate_state(10, |_| Some(10)).await;
+        match sub.recv().await {
+            Some(Event::Restart(restart)) => assert_eq!(restart, state.get_state().await),
+            evt => panic!("expected a restart, got {evt:?}"),
+        }
+        state.mutate_state(11, |_| Some(11)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added(11, 11))));
+
+        // while the default transport evicts it
+        let state = State::<u32, u32>::default();
+        let _sub = state.subscribe(2).await;
+        for n in 0..10 {
+            state.mutate_state(n, |_| Some(n)).await;
+        }
+        assert!(state.listeners().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn find() {
+        let state = State::<u32, u32>::default();
+        state
+            .set_state(HashMap::from([(1, 10), (2, 25), (3, 30), (4, 45)]))
+            .await;
+
+        let odd = |_: &u32, v: &u32| v % 2 == 1;
+        assert!(matches!(state.find(odd).await, Some((2, 25) | (4, 45))));
+        let mut all = state.find_all(odd).await;
+        all.sort();
+        assert_eq!(all, [(2, 25), (4, 45)]);
+
+        // keys can be matched too
+        assert_eq!(state.find(|k, _| *k == 3).await, Some((3, 30)));
+
+        assert_eq!(state.find(|_, v| *v > 100).await, None);
+        assert!(state.find_all(|_, v| *v > 100).await.is_empty());
+
+        // the smallest of several matching keys, no matter the order of the map
+        let state = State::from((0..100u32).map(|n| (n, n % 10)).collect::<HashMap<_, _>>());
+        assert_eq!(state.find_first(|_, v| *v == 7).await, Some((7, 7)));
+        assert_eq!(
+            state.find_first(|k, v| *k > 50 && *v == 3).await,
+            Some((53, 3))
+        );
+        assert_eq!(state.find_first(|_, v| *v > 10).await, None);
+    }
+
+    #[tokio::test]
+    async fn replace_state() {
+        let state = State::<String, u32>::default();
+        state.set_state(HashMap::from([("a".to_string(), 1)])).await;
+        let mut subscription = state.subscribe(None).await;
+        assert!(matches!(subscription.recv().await, Some(Event::Restart(s)) if s.len() == 1));
+
+        state
+            .replace_state(|current| {
+                let mut next = current.clone();
+                next.insert("b".to_string(), current["a"] + 1);
+                next
+            })
+            .await;
+
+        match subscription.recv().await {
+            Some(Event::Restart(s)) => {
+                assert_eq!(
+                    s,
+                    HashMap::from([("a".to_string(), 1), ("b".to_string(), 2)])
+                )
+            }
+            other => panic!("unexpected event: {other:?}"),
+        }
+        assert_eq!(state.get_state().await.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn hooks_detach_on_drop() {
+        let state = State::<&str, u32>::default();
+        let seen = Arc::new(parking_lot::Mutex::new(Vec::new()));
+        let hook = |name: &'static str| {
+            let seen = seen.clone();
+            move |evt: &Event<&str, u32>| {
+                if let Event::Added(key, _) = evt {
+                    seen.lock().push(format!("{name} {key}"));
+                }
+            }
+        };
+
+        let handle = state.add_hook(hook("handle"));
+        // the intermediate state of the chain is dropped right away, but its hook stays attached
+        let hooked = state.with_hook(hook("first")).with_hook(hook("second"));
+        let clone = hooked.clone();
+        state.mutate_state("a", |_| Some(1)).await;
+
+        drop(handle);
+        drop(hooked);
+        state.mutate_state("b", |_| Some(1)).await;
+
+        drop(clone);
+        state.mutate_state("c", |_| Some(1)).await;
+
+        assert_eq!(
+            *seen.lock(),
+            vec!["handle a", "first a", "second a", "first b", "second b"]
+        );
+        assert!(state.hooks.0.read().is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deduplicate_events() {
+        let state = State::from(HashMap::from([("a", 1)]));
+        let window = Duration::from_secs(1);
+        let mut sub = state.subscribe(None).await.deduplicate_events(window);
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        // flapping back within the window isn't announced at all
+        state.mutate_state("a", |_| Some(2)).await;
+        state.mutate_state("a", |_| Some(1)).await;
+        tokio::time::sleep(window * 2).await;
+        assert!(sub.try_recv().is_err());
+
+        // intermediate values are skipped, other events are not held back
+        state.mutate_state("a", |_| Some(2)).await;
+        state.mutate_state("a", |_| Some(3)).await;
+        state.mutate_state("b", |_| Some(1)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Added("b", 1))));
+        let start = Instant::now();
+        assert!(matches!(sub.recv().await, Some(Event::Modified("a", 3))));
+        assert!(start.elapsed() <= window);
+
+        // a change which lasted longer than the window is announced, and so is reverting it
+        state.mutate_state("a", |_| Some(1)).await;
+        assert!(matches!(sub.recv().await, Some(Event::Modified("a", 1))));
+        assert_eq!(start.elapsed(), window * 2);
+
+        // removing the key supersedes its held back modification
+        state.mutate_state("b", |_| Some(2)).await;
+        state.remove_state("b").await;
+        assert!(matches!(sub.recv().await, Some(Event::Removed("b"))));
+        tokio::time::sleep(window * 2).await;
+        assert!(sub.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn sharded_state() {
+        let state = ShardedState::new(4);
+        state
+            .set_state(HashMap::from([("a", 1), ("b", 1), ("c", 1)]))
+            .await;
+
+        let mut sub = state.subscribe(None).await;
+        match sub.recv().await {
+            Some(Event::Restart(initial)) => {
+                assert_eq!(initial, HashMap::from([("a", 1), ("b", 1), ("c", 1)]))
+            }
+            other => panic!("expected a restart, got {other:?}"),
+        }
+
+        for key in ["a", "b", "c", "d"] {
+            state.mutate_state(key, |_| Some(2)).await;
+        }
+        let mut seen = HashMap::new();
+        for _ in 0..4 {
+            match sub.recv().await {
+                Some(Event::Added(k, v) | Event::Modified(k, v)) => seen.insert(k, v),
+                other => panic!("unexpected event: {other:?}"),
+            };
+        }
+        assert_eq!(seen, state.get_state().await);
+
+        // restarting the shards arrives as the changes they make
+        state.set_state(HashMap::from([("a", 3)])).await;
+        let mut removed = Vec::new();
+        let mut current = seen;
+        while current.len() != 1 || current.get("a") != Some(&3) {
+            match sub.recv().await {
+                Some(Event::Removed(k)) => {
+                    removed.push(k);
+                    current.remove(k);
+                }
+                Some(Event::Added(k, v) | Event::Modified(k, v)) => {
+                    current.insert(k, v);
+                }
+                other => panic!("unexpected event: {other:?}"),
+            }
+        }
+        removed.sort();
+        assert_eq!(removed, ["b", "c", "d"]);
+        assert_eq!(state.get(&"a").await, Some(3));
+    }
+
+    #[tokio::test]
+    async fn subscribe_keys_changes_first() {
+        let state = State::from((0..32).map(|n| (n, n)).collect::<HashMap<_, _>>());
+        let (mut sub, keys) = state.subscribe_keys(None, HashSet::new()).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(state)) if state.is_empty()));
+
+        // the change is made before the write, so it must take effect before the write's event
+        for n in 0..32 {
+            keys.add(n);
+            state.mutate_state(n, |_| Some(n + 100)).await;
+            assert!(matches!(sub.recv().await, Some(Event::Added(k, v)) if k == n && v == n));
+            assert!(
+                matches!(sub.recv().await, Some(Event::Modified(k, v)) if k == n && v == n + 100)
+            );
         }
     }
 }
@@ -0,0 +1,169 @@
+//! Re-reading the configuration at runtime.
+//!
+//! On `SIGHUP`, the configuration is loaded again and compared to the active one. Changes of
+//! reloadable settings are applied right away, all other changes only take effect after a restart.
+
+use crate::admission::Policy;
+use crate::config::{Config, Overrides};
+use crate::logging::LogControl;
+use serde_json::Value;
+use std::path::PathBuf;
+use std::time::Duration;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+/// Settings which are applied without a restart
+const RELOADABLE: &[&str] = &[
+    "log_filter",
+    "rescan_interval_secs",
+    "strict_transitions",
+    "webhook_mode",
+    "webhook_exempt_label",
+    "webhook_lookup_timeout_ms",
+    "webhook_failure_policy",
+];
+
+/// A setting which may change at runtime.
+#[derive(Clone, Debug)]
+pub struct Setting<T>(watch::Receiver<T>);
+
+impl<T> Setting<T> {
+    /// A setting which never changes.
+    pub fn fixed(value: T) -> Self {
+        let (_, rx) = watch::channel(value);
+        Self(rx)
+    }
+
+    pub fn get(&self) -> T
+    where
+        T: Clone,
+    {
+        self.0.borrow().clone()
+    }
+
+    /// Wait for the setting to change, which a fixed setting never does.
+    pub async fn changed(&mut self) {
+        if self.0.changed().await.is_err() {
+            futures::future::pending().await
+        }
+    }
+}
+
+impl<T: Default> Default for Setting<T> {
+    fn default() -> Self {
+        Self::fixed(T::default())
+    }
+}
+
+/// Applies changes of the configuration file on `SIGHUP`.
+pub struct Reloader {
+    file: PathBuf,
+    overrides: Overrides,
+    /// the active configuration, only reloadable settings get updated
+    current: Config,
+    log: LogControl,
+    rescan_interval: watch::Sender<Option<Duration>>,
+    strict_transitions: watch::Sender<bool>,
+    admission_policy: watch::Sender<Policy>,
+}
+
+impl Reloader {
+    pub fn new(file: PathBuf, overrides: Overrides, config: Config, log: LogControl) -> Self {
+        Self {
+            file,
+            overrides,
+            rescan_interval: watch::channel(Some(config.rescan_interval())).0,
+            strict_transitions: watch::channel(config.strict_transitions).0,
+            admission_policy: watch::channel(config.admission_policy()).0,
+            current: config,
+            log,
+        }
+    }
+
+    /// interval for re-scanning images with a missing or failed SBOM
+    pub fn rescan_interval(&self) -> Setting<Option<Duration>> {
+        Setting(self.rescan_interval.subscribe())
+    }
+
+    pub fn strict_transitions(&self) -> Setting<bool> {
+        Setting(self.strict_transitions.subscribe())
+    }
+
+    pub fn admission_policy(&self) -> Setting<Policy> {
+        Setting(self.admission_policy.subscribe())
+    }
+
+    /// Reload the configuration whenever the process receives a `SIGHUP`.
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+
+        while hangup.recv().await.is_some() {
+            info!("Received SIGHUP, reloading configuration");
+            if let Err(err) = self.reload() {
+                warn!("Failed to reload configuration: {err:#}");
+            }
+        }
+
+        Ok(())
+    }
+
+    fn reload(&mut self) -> anyhow::Result<()> {
+        let new = Config::load(&Config::figment(&self.file, &self.overrides))?;
+
+        let (old_masked, new_masked) = (to_map(&self.current.masked())?, to_map(&new.masked())?);
+        let mut merged = to_map(&self.current)?;
+        let new = to_map(&new)?;
+
+        for (key, value) in new {
+            let old_value = merged.get(&key).cloned().unwrap_or_default();
+            if old_value == value {
+                continue;
+            }
+
+            let (old_display, new_display) = (
+                old_masked.get(&key).cloned().unwrap_or_default(),
+                new_masked.get(&key).cloned().unwrap_or_default(),
+            );
+            if RELOADABLE.contains(&key.as_str()) {
+                info!("Changed {key}: {old_display} → {new_display}");
+                merged.insert(key, value);
+            } else {
+                warn!("Changing {key} requires a restart: {old_display} → {new_display}");
+            }
+        }
+
+        let merged: Config = serde_json::from_value(Value::Object(merged))?;
+        if merged.log_filter != self.current.log_filter {
+            if let Err(err) = self.log.set(&merged.log_filter) {
+                warn!("Failed to reload log filter: {err}");
+            }
+        }
+
+        update(&self.rescan_interval, Some(merged.rescan_interval()));
+        update(&self.strict_transitions, merged.strict_transitions);
+        update(&self.admission_policy, merged.admission_policy());
+        self.current = merged;
+
+        Ok(())
+    }
+}
+
+fn to_map(config: &Config) -> anyhow::Result<serde_json::Map<String, Value>> {
+    match serde_json::to_value(config)? {
+        Value::Object(values) => Ok(values),
+        _ => unreachable!("configuration must serialize into a map"),
+    }
+}
+
+/// Update a setting, only notifying receivers if it actually changed.
+fn update<T: PartialEq>(tx: &watch::Sender<T>, value: T) {
+    tx.send_if_modified(|current| match *current == value {
+        true => false,
+        false => {
+            *current = value;
+            true
+        }
+    });
+}
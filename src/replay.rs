@@ -0,0 +1,275 @@
+//! Replaying a recording of the events sent to websocket clients, see the `record_file` setting.
+
+use crate::server::Recorded;
+use actix_cors::Cors;
+use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer};
+use actix_ws::Message;
+use anyhow::{bail, Context};
+use bommer_api::data::{Event, Image, ImageRef};
+use futures::StreamExt;
+use serde_json::value::RawValue;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader, BufWriter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::spawn_local;
+use tracing::{info, warn};
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct ReplayOptions {
+    /// The recording to replay
+    pub file: PathBuf,
+
+    /// Speed relative to the recorded timing, e.g. `1` for the original speed, `0` for no delays
+    #[arg(long, default_value_t = 0.0)]
+    pub speed: f64,
+
+    /// Stop after the event with this sequence number
+    #[arg(long)]
+    pub until: Option<u64>,
+
+    /// File to write the workload to once replayed, `-` for stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+
+    /// Serve the recording to websocket clients, e.g. the console, on this address, instead of
+    /// replaying it once
+    #[arg(long)]
+    pub serve: Option<String>,
+}
+
+type Recording = Vec<Recorded<Box<RawValue>>>;
+
+/// Run the `replay` command.
+pub async fn run(options: &ReplayOptions) -> anyhow::Result<ExitCode> {
+    let mut recording = load(&options.file)?;
+    if let Some(until) = options.until {
+        recording.retain(|recorded| recorded.seq <= until);
+    }
+    info!(
+        "Loaded {} events from {}",
+        recording.len(),
+        options.file.display()
+    );
+
+    match &options.serve {
+        Some(bind_addr) => serve(Arc::new(recording), options.speed, bind_addr).await?,
+        None => replay(&recording, options).await?,
+    }
+
+    Ok(ExitCode::SUCCESS)
+}
+
+/// Read a recording, skipping a torn last line, e.g. if the backend stopped while writing it.
+fn load(file: &Path) -> anyhow::Result<Recording> {
+    let reader = BufReader::new(
+        File::open(file).with_context(|| format!("Failed to open {}", file.display()))?,
+    );
+
+    let mut recording = Vec::new();
+    let mut torn = None;
+    for (n, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(n) = torn {
+            bail!("Malformed event in line {n}");
+        }
+        match serde_json::from_str(&line) {
+            Ok(recorded) => recording.push(recorded),
+            Err(err) => {
+                warn!("Skipping malformed event in line {}: {err}", n + 1);
+                torn = Some(n + 1);
+            }
+        }
+    }
+
+    Ok(recording)
+}
+
+/// Wait for the time between two events, scaled by the speed.
+async fn delay(previous: Option<u64>, timestamp: u64, speed: f64) {
+    let Some(previous) = previous else {
+        return;
+    };
+    if speed > 0.0 {
+        let millis = timestamp.saturating_sub(previous) as f64 / speed;
+        tokio::time::sleep(Duration::from_secs_f64(millis / 1000.0)).await;
+    }
+}
+
+/// A client, mirroring the workload the same way the console does.
+#[derive(Default)]
+struct Mirror {
+    workload: HashMap<ImageRef, Image>,
+    /// chunks of the initial state, until we received the last one
+    pending: HashMap<ImageRef, Image>,
+}
+
+impl Mirror {
+    fn apply(&mut self, evt: Event<ImageRef, Image>) {
+        match evt {
+            Event::Added(image, state) | Event::Modified(image, state) => {
+                self.workload.insert(image, state);
+            }
+            Event::Removed(image) => {
+                self.workload.remove(&image);
+            }
+            Event::Restart(state) => {
+                self.pending.clear();
+                self.workload = state;
+            }
+            Event::RestartChunk { entries, is_last } => {
+                self.pending.extend(entries);
+                if is_last {
+                    self.workload = std::mem::take(&mut self.pending);
+                }
+            }
+        }
+    }
+}
+
+/// Replay the recording through a mirror of the workload, logging each event.
+async fn replay(recording: &Recording, options: &ReplayOptions) -> anyhow::Result<()> {
+    let mut mirror = Mirror::default();
+    let mut previous: Option<&Recorded<_>> = None;
+
+    for recorded in recording {
+        delay(
+            previous.map(|previous| previous.timestamp),
+            recorded.timestamp,
+            options.speed,
+        )
+        .await;
+
+        match previous.map(|previous| previous.seq) {
+            // the backend restarted, and appended to the same file
+            Some(seq) if recorded.seq <= seq => warn!("Recording starts over at #{}", recorded.seq),
+            Some(seq) if recorded.seq > seq + 1 => {
+                warn!("Missing events #{} to #{}", seq + 1, recorded.seq - 1)
+            }
+            _ => {}
+        }
+
+        let evt = serde_json::from_str::<Event<ImageRef, Image>>(recorded.event.get())
+            .with_context(|| format!("Failed to decode event #{}", recorded.seq))?;
+        let key = match &evt {
+            Event::Added(image, _) | Event::Modified(image, _) | Event::Removed(image) => {
+                image.to_string()
+            }
+            Event::Restart(_) | Event::RestartChunk { .. } => String::new(),
+        };
+        let (kind, entries) = (evt.kind(), evt.entries());
+        mirror.apply(evt);
+        info!(
+            "#{} {} {kind} {key} ({entries} entries) => {} images",
+            recorded.seq,
+            recorded.timestamp,
+            mirror.workload.len()
+        );
+
+        previous = Some(recorded);
+    }
+
+    if !mirror.pending.is_empty() {
+        warn!(
+            "Recording ends in the middle of the initial state, with {} images pending",
+            mirror.pending.len()
+        );
+    }
+
+    match &options.output {
+        Some(output) if output == Path::new("-") => {
+            serde_json::to_writer_pretty(std::io::stdout().lock(), &mirror.workload)?;
+        }
+        Some(output) => {
+            let file = File::create(output)
+                .with_context(|| format!("Failed to create {}", output.display()))?;
+            serde_json::to_writer_pretty(BufWriter::new(file), &mirror.workload)?;
+            info!("Wrote workload to: {}", output.display());
+        }
+        None => {}
+    }
+
+    Ok(())
+}
+
+/// Serve the recording on the workload stream of the API, replaying it from the start for each
+/// client.
+///
+/// Clients of a single namespace are not supported, as the recording covers all of them.
+async fn serve(recording: Arc<Recording>, speed: f64, bind_addr: &str) -> anyhow::Result<()> {
+    let recording = web::Data::from(recording);
+    let speed = web::Data::new(speed);
+
+    info!("Serving the recording on {bind_addr}");
+    HttpServer::new(move || {
+        App::new()
+            .app_data(recording.clone())
+            .app_data(speed.clone())
+            .wrap(Cors::permissive())
+            .service(workload_stream)
+    })
+    .bind(bind_addr)
+    .with_context(|| format!("Failed to bind to {bind_addr}"))?
+    .run()
+    .await?;
+
+    Ok(())
+}
+
+#[get("/api/v1/workload_stream")]
+async fn workload_stream(
+    req: HttpRequest,
+    stream: web::Payload,
+    recording: web::Data<Recording>,
+    speed: web::Data<f64>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let (res, mut session, mut msg_stream) = actix_ws::handle(&req, stream)?;
+    let (recording, speed) = (recording.into_inner(), **speed);
+    let mut pong = session.clone();
+
+    spawn_local(async move {
+        let send = async {
+            let mut previous = None;
+            for recorded in recording.iter() {
+                delay(previous, recorded.timestamp, speed).await;
+                previous = Some(recorded.timestamp);
+                if session
+                    .text(recorded.event.get().to_string())
+                    .await
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            info!("Replayed the recording to a client");
+            // keep showing the final state, until the client leaves
+            futures::future::pending().await
+        };
+
+        let receive = async {
+            while let Some(Ok(msg)) = msg_stream.next().await {
+                match msg {
+                    Message::Ping(data) => {
+                        let _ = pong.pong(&data).await;
+                    }
+                    Message::Close(_) => break,
+                    _ => {}
+                }
+            }
+        };
+
+        tokio::select! {
+            _ = send => {},
+            _ = receive => {},
+        }
+        let _ = session.close(None).await;
+    });
+
+    Ok(res)
+}
@@ -0,0 +1,72 @@
+use super::{Feeds, ServerConfig};
+use crate::diagnostics::Diagnostics;
+use crate::logging::LogControl;
+use crate::workload::WorkloadState;
+use actix_web::http::header;
+use actix_web::{get, put, web, HttpRequest, HttpResponse, Responder};
+
+/// Check the bearer token of the request against the configured admin token.
+///
+/// If no admin token is configured, the admin API is disabled.
+pub(super) fn authorize(req: &HttpRequest, config: &ServerConfig) -> Result<(), HttpResponse> {
+    let Some(token) = &config.admin_token else {
+        return Err(HttpResponse::Forbidden().body("Admin API is disabled"));
+    };
+
+    let provided = req
+        .headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "));
+
+    match provided {
+        Some(provided) if provided == token => Ok(()),
+        _ => Err(HttpResponse::Unauthorized()
+            .insert_header((header::WWW_AUTHENTICATE, "Bearer"))
+            .finish()),
+    }
+}
+
+#[get("/api/v1/admin/log-level")]
+pub async fn get_log_level(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    log: web::Data<LogControl>,
+) -> impl Responder {
+    if let Err(response) = authorize(&req, &config) {
+        return response;
+    }
+
+    HttpResponse::Ok().body(log.current())
+}
+
+#[put("/api/v1/admin/log-level")]
+pub async fn put_log_level(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    log: web::Data<LogControl>,
+    filter: String,
+) -> impl Responder {
+    if let Err(response) = authorize(&req, &config) {
+        return response;
+    }
+
+    match log.set(filter.trim()) {
+        Ok(()) => HttpResponse::NoContent().finish(),
+        Err(err) => HttpResponse::BadRequest().body(err.to_string()),
+    }
+}
+
+#[get("/api/v1/admin/diagnostics")]
+pub async fn get_diagnostics(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    map: web::Data<WorkloadState>,
+    feeds: web::Data<Feeds>,
+) -> impl Responder {
+    if let Err(response) = authorize(&req, &config) {
+        return response;
+    }
+
+    HttpResponse::Ok().json(Diagnostics::collect(&config, &map, &feeds).await)
+}
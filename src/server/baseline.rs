@@ -0,0 +1,54 @@
+use super::ServerConfig;
+use crate::workload::WorkloadState;
+use actix_web::error::ErrorInternalServerError;
+use actix_web::{delete, get, put, web, HttpResponse, Responder};
+use bommer_api::data::BaselineDiff;
+
+#[get("/api/v1/baseline")]
+pub async fn get_baseline(map: web::Data<WorkloadState>) -> impl Responder {
+    match map.baseline().await {
+        Some(baseline) => HttpResponse::Ok().json(baseline),
+        None => HttpResponse::NotFound().body("No baseline set"),
+    }
+}
+
+/// Take the current workload as the new baseline.
+#[put("/api/v1/baseline")]
+pub async fn put_baseline(
+    map: web::Data<WorkloadState>,
+    config: web::Data<ServerConfig>,
+) -> Result<HttpResponse, actix_web::Error> {
+    map.set_baseline().await;
+    persist(&map, &config).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+#[delete("/api/v1/baseline")]
+pub async fn delete_baseline(
+    map: web::Data<WorkloadState>,
+    config: web::Data<ServerConfig>,
+) -> Result<HttpResponse, actix_web::Error> {
+    map.clear_baseline().await;
+    persist(&map, &config).await?;
+    Ok(HttpResponse::NoContent().finish())
+}
+
+/// The images and pods added or removed since the baseline
+#[get("/api/v1/baseline/diff")]
+pub async fn get_baseline_diff(map: web::Data<WorkloadState>) -> impl Responder {
+    match map.baseline_and_state().await {
+        Some((baseline, current)) => {
+            HttpResponse::Ok().json(BaselineDiff::new(&baseline, &current))
+        }
+        None => HttpResponse::NotFound().body("No baseline set"),
+    }
+}
+
+async fn persist(map: &WorkloadState, config: &ServerConfig) -> Result<(), actix_web::Error> {
+    if let Some(path) = &config.baseline_file {
+        map.save_baseline(path)
+            .await
+            .map_err(ErrorInternalServerError)?;
+    }
+    Ok(())
+}
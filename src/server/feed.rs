@@ -0,0 +1,199 @@
+//! Views of the workload for the websocket clients, serializing its images once per view.
+
+use super::ws;
+use crate::pubsub::{DroppedEvent, Encoded, ListenerInfo, ListenerTotals, State, Subscription};
+use crate::workload::{by_ns, WorkloadState};
+use bommer_api::data::ImageRef;
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, Weak};
+use tokio::sync::Mutex;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+use tracing::warn;
+
+/// The workload, with its images serialized for the websocket clients
+type EncodedState = State<ImageRef, Encoded>;
+
+/// The views of the workload websocket clients subscribe to.
+///
+/// Instead of each client serializing every event on its own, the images of a view are
+/// serialized once, into a derived state shared by all clients of the view. Serializing an event
+/// for a client then only copies the already serialized image.
+#[derive(Clone)]
+pub struct Feeds {
+    map: WorkloadState,
+    views: Arc<Mutex<Views>>,
+}
+
+#[derive(Default)]
+struct Views {
+    /// the view of all namespaces, kept once created, so that its totals survive its clients
+    all: Option<EncodedState>,
+    /// views of a single namespace, as long as clients use them
+    namespaces: HashMap<String, Weak<NamespaceView>>,
+}
+
+/// The view of a single namespace
+struct NamespaceView {
+    state: EncodedState,
+    /// cancelled once the view stops following the workload
+    closed: CancellationToken,
+    runner: JoinHandle<()>,
+}
+
+impl Drop for NamespaceView {
+    fn drop(&mut self) {
+        self.runner.abort();
+    }
+}
+
+/// A view of the workload, kept alive by its clients.
+#[derive(Clone)]
+pub struct View {
+    state: EncodedState,
+    namespace: Option<Arc<NamespaceView>>,
+}
+
+impl View {
+    pub async fn subscribe(&self) -> Subscription<ImageRef, Encoded> {
+        self.subscribe_as("websocket").await
+    }
+
+    /// Subscribe like a websocket client, but registered under a different name.
+    pub async fn subscribe_as(&self, name: &str) -> Subscription<ImageRef, Encoded> {
+        self.state
+            .named(name)
+            .subscribe_chunked(32, ws::RESTART_CHUNK_SIZE)
+            .await
+    }
+
+    /// Wait until the view stops following the workload, which is never for all namespaces.
+    pub async fn closed(&self) {
+        match &self.namespace {
+            Some(namespace) => namespace.closed.cancelled().await,
+            None => futures::future::pending().await,
+        }
+    }
+}
+
+impl Feeds {
+    pub fn new(map: WorkloadState) -> Self {
+        Self {
+            map,
+            views: Default::default(),
+        }
+    }
+
+    /// The view of a single namespace, or of all of them, shared with other clients of the same
+    /// view.
+    pub async fn view(&self, namespace: Option<String>) -> View {
+        let mut views = self.views.lock().await;
+
+        let Some(namespace) = namespace else {
+            let state = match &views.all {
+                Some(state) => state.clone(),
+                None => views.all.insert(encode(&self.map).await).clone(),
+            };
+            return View {
+                state,
+                namespace: None,
+            };
+        };
+
+        views.namespaces.retain(|_, view| view.strong_count() > 0);
+        let view = match views.namespaces.get(&namespace).and_then(Weak::upgrade) {
+            Some(view) => view,
+            None => {
+                let view = Arc::new(self.namespace_view(namespace.clone()).await);
+                views.namespaces.insert(namespace, Arc::downgrade(&view));
+                view
+            }
+        };
+
+        View {
+            state: view.state.clone(),
+            namespace: Some(view),
+        }
+    }
+
+    async fn namespace_view(&self, namespace: String) -> NamespaceView {
+        let (workload, runner) = by_ns(&self.map, namespace.clone()).await;
+        let state = encode(&workload).await;
+
+        let closed = CancellationToken::new();
+        let runner = tokio::spawn({
+            let closed = closed.clone();
+            async move {
+                if let Err(err) = runner.await {
+                    warn!("Failed to follow the workload of namespace {namespace}: {err}");
+                }
+                closed.cancel();
+            }
+        });
+
+        NamespaceView {
+            state,
+            closed,
+            runner,
+        }
+    }
+
+    /// The states of the views currently in use.
+    async fn states(&self) -> Vec<EncodedState> {
+        let views = self.views.lock().await;
+        views
+            .all
+            .iter()
+            .cloned()
+            .chain(
+                views
+                    .namespaces
+                    .values()
+                    .filter_map(Weak::upgrade)
+                    .map(|view| view.state.clone()),
+            )
+            .collect()
+    }
+
+    /// The listeners of all views, see [`State::listeners`].
+    pub async fn listeners(&self) -> Vec<ListenerInfo> {
+        let mut listeners = Vec::new();
+        for state in self.states().await {
+            listeners.extend(state.listeners().await);
+        }
+        listeners
+    }
+
+    /// The totals of the listeners of all views, see [`State::listener_totals`].
+    ///
+    /// Views of a single namespace take their totals with them once their last client is gone.
+    pub async fn listener_totals(&self) -> BTreeMap<String, ListenerTotals> {
+        let mut totals = BTreeMap::<_, ListenerTotals>::new();
+        for state in self.states().await {
+            for (name, view) in state.listener_totals().await {
+                *totals.entry(name).or_default() += view;
+            }
+        }
+        totals
+    }
+
+    /// The events of all views which couldn't be delivered, see [`State::dropped_events`].
+    pub async fn dropped_events(&self) -> Vec<DroppedEvent> {
+        let mut dropped = Vec::new();
+        for state in self.states().await {
+            dropped.extend(state.dropped_events().await);
+        }
+        dropped
+    }
+}
+
+/// Derive the state sent to websocket clients.
+async fn encode(workload: &WorkloadState) -> EncodedState {
+    workload
+        .named("websocket-feed")
+        .map(
+            |image_ref, image| (image_ref.clone(), ws::encode(image)),
+            |encoded, _| encoded,
+        )
+        .await
+}
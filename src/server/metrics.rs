@@ -0,0 +1,444 @@
+use crate::leader::Standby;
+use crate::pubsub::UNNAMED_LISTENER;
+use crate::server::{Feeds, ServerConfig};
+use crate::workload::WorkloadState;
+use actix_web::{get, routes, web, HttpResponse, Responder};
+use bommer_api::data::{Event, Image, ImageRef};
+use prometheus::{
+    exponential_buckets, Encoder, Gauge, GaugeVec, HistogramOpts, HistogramVec, IntCounter,
+    IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::io::Write;
+
+/// Metrics, computed from the workload state when being scraped.
+#[derive(Clone)]
+pub struct Metrics {
+    registry: Registry,
+    images: IntGauge,
+    images_capacity: IntGauge,
+    images_limit: IntGauge,
+    images_refused: IntCounter,
+    images_evicted: IntCounter,
+    sbom: IntGaugeVec,
+    lookup_queue_depth: IntGauge,
+    lookup_deferred: IntGauge,
+    lookup_deferrals: IntCounter,
+    lookup_requeued: IntCounter,
+    leader: IntGauge,
+    leader_transitions: IntCounter,
+    event_entries: HistogramVec,
+    event_size: HistogramVec,
+    last_event_age: Gauge,
+    listeners: IntGaugeVec,
+    listener_queued: IntGaugeVec,
+    listener_high_water: IntGaugeVec,
+    listener_delivered: IntCounterVec,
+    listener_evictions: IntCounterVec,
+    listener_reaped: IntCounterVec,
+    alert_firing: IntGaugeVec,
+    alert_value: GaugeVec,
+    alert_notifications: IntCounterVec,
+    alert_notification_failures: IntCounterVec,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Self, prometheus::Error> {
+        let registry = Registry::new_custom(Some("bommer".into()), None)?;
+
+        let images = IntGauge::new("images", "Number of images in use")?;
+        let images_capacity = IntGauge::new(
+            "images_capacity",
+            "Number of images there is room for, without allocating",
+        )?;
+        let images_limit = IntGauge::new(
+            "images_limit",
+            "Maximum number of tracked images, 0 if unlimited",
+        )?;
+        let images_refused = IntCounter::new(
+            "images_refused_total",
+            "New images which weren't tracked, as the maximum number of images was reached",
+        )?;
+        let images_evicted = IntCounter::new(
+            "images_evicted_total",
+            "Images dropped to make room for new ones, as the maximum number of images was reached",
+        )?;
+        let sbom = IntGaugeVec::new(
+            Opts::new("images_sbom", "Number of images, by SBOM state"),
+            &["state"],
+        )?;
+
+        let lookup_queue_depth = IntGauge::new(
+            "lookup_queue_depth",
+            "Number of images waiting for an SBOM lookup",
+        )?;
+        let lookup_deferred = IntGauge::new(
+            "lookup_deferred",
+            "Number of images waiting for room in the lookup queue",
+        )?;
+        let lookup_deferrals = IntCounter::new(
+            "lookup_deferrals_total",
+            "Images whose lookup got deferred, as the lookup queue was full",
+        )?;
+        let lookup_requeued = IntCounter::new(
+            "lookup_requeued_total",
+            "Deferred images which were moved into the lookup queue",
+        )?;
+
+        let leader = IntGauge::new(
+            "leader",
+            "Whether this instance is the leader, always 1 without leader election",
+        )?;
+        let leader_transitions = IntCounter::new(
+            "leader_transitions_total",
+            "Number of times this instance acquired or lost leadership",
+        )?;
+
+        // from single changes, up to restarts of large clusters
+        let event_entries = HistogramVec::new(
+            HistogramOpts::new(
+                "event_entries",
+                "Number of entries of broadcast events, by kind of event",
+            )
+            .buckets(exponential_buckets(1.0, 4.0, 10)?),
+            &["kind"],
+        )?;
+        let event_size = HistogramVec::new(
+            HistogramOpts::new(
+                "event_size_bytes",
+                "Serialized size of broadcast events, by kind of event",
+            )
+            .buckets(exponential_buckets(256.0, 4.0, 12)?),
+            &["kind"],
+        )?;
+        let last_event_age = Gauge::new(
+            "last_event_age_seconds",
+            "Time since the most recent event got broadcast, -1 if there was none yet",
+        )?;
+
+        // listeners of the same kind share a name, so they are aggregated by it
+        let listeners = IntGaugeVec::new(
+            Opts::new("listeners", "Number of listeners of the workload, by name"),
+            &["name"],
+        )?;
+        let listener_queued = IntGaugeVec::new(
+            Opts::new(
+                "listener_queued_events",
+                "Events waiting to be received by the listeners, by name",
+            ),
+            &["name"],
+        )?;
+        let listener_high_water = IntGaugeVec::new(
+            Opts::new(
+                "listener_queued_events_high_water",
+                "The most events a current listener had waiting at the same time, by name",
+            ),
+            &["name"],
+        )?;
+        let listener_delivered = IntCounterVec::new(
+            Opts::new(
+                "listener_events_delivered_total",
+                "Events delivered to the listeners, by name",
+            ),
+            &["name"],
+        )?;
+        let listener_evictions = IntCounterVec::new(
+            Opts::new(
+                "listener_evictions_total",
+                "Listeners removed as they didn't keep up with the events, by name",
+            ),
+            &["name"],
+        )?;
+        let listener_reaped = IntCounterVec::new(
+            Opts::new(
+                "listener_reaped_total",
+                "Listeners removed as they were idle for too long, by name",
+            ),
+            &["name"],
+        )?;
+
+        let alert_firing = IntGaugeVec::new(
+            Opts::new("alert_firing", "Whether an alert rule is firing, by rule"),
+            &["rule"],
+        )?;
+        let alert_value = GaugeVec::new(
+            Opts::new(
+                "alert_value",
+                "The value of an alert rule at its last evaluation, by rule",
+            ),
+            &["rule"],
+        )?;
+        let alert_notifications = IntCounterVec::new(
+            Opts::new(
+                "alert_notifications_total",
+                "Notifications sent about alerts firing or resolving, by rule",
+            ),
+            &["rule"],
+        )?;
+        let alert_notification_failures = IntCounterVec::new(
+            Opts::new(
+                "alert_notification_failures_total",
+                "Notifications about alerts which couldn't be sent, by rule",
+            ),
+            &["rule"],
+        )?;
+
+        registry.register(Box::new(images.clone()))?;
+        registry.register(Box::new(images_capacity.clone()))?;
+        registry.register(Box::new(images_limit.clone()))?;
+        registry.register(Box::new(images_refused.clone()))?;
+        registry.register(Box::new(images_evicted.clone()))?;
+        registry.register(Box::new(sbom.clone()))?;
+        registry.register(Box::new(lookup_queue_depth.clone()))?;
+        registry.register(Box::new(lookup_deferred.clone()))?;
+        registry.register(Box::new(lookup_deferrals.clone()))?;
+        registry.register(Box::new(lookup_requeued.clone()))?;
+        registry.register(Box::new(leader.clone()))?;
+        registry.register(Box::new(leader_transitions.clone()))?;
+        registry.register(Box::new(event_entries.clone()))?;
+        registry.register(Box::new(event_size.clone()))?;
+        registry.register(Box::new(last_event_age.clone()))?;
+        registry.register(Box::new(listeners.clone()))?;
+        registry.register(Box::new(listener_queued.clone()))?;
+        registry.register(Box::new(listener_high_water.clone()))?;
+        registry.register(Box::new(listener_delivered.clone()))?;
+        registry.register(Box::new(listener_evictions.clone()))?;
+        registry.register(Box::new(listener_reaped.clone()))?;
+        registry.register(Box::new(alert_firing.clone()))?;
+        registry.register(Box::new(alert_value.clone()))?;
+        registry.register(Box::new(alert_notifications.clone()))?;
+        registry.register(Box::new(alert_notification_failures.clone()))?;
+
+        Ok(Self {
+            registry,
+            images,
+            images_capacity,
+            images_limit,
+            images_refused,
+            images_evicted,
+            sbom,
+            lookup_queue_depth,
+            lookup_deferred,
+            lookup_deferrals,
+            lookup_requeued,
+            leader,
+            leader_transitions,
+            event_entries,
+            event_size,
+            last_event_age,
+            listeners,
+            listener_queued,
+            listener_high_water,
+            listener_delivered,
+            listener_evictions,
+            listener_reaped,
+            alert_firing,
+            alert_value,
+            alert_notifications,
+            alert_notification_failures,
+        })
+    }
+
+    /// Record the number of entries and the serialized size of a broadcast event.
+    pub fn record_event(&self, evt: &Event<ImageRef, Image>) {
+        let kind = evt.kind();
+        self.event_entries
+            .with_label_values(&[kind])
+            .observe(evt.entries() as f64);
+
+        // count the bytes, instead of keeping a copy of a possibly huge event
+        let mut size = ByteCount(0);
+        if serde_json::to_writer(&mut size, evt).is_ok() {
+            self.event_size
+                .with_label_values(&[kind])
+                .observe(size.0 as f64);
+        }
+    }
+
+    async fn update(&self, config: &ServerConfig, map: &WorkloadState, feeds: &Feeds) {
+        self.leader.set(!config.is_standby() as _);
+        if let Some(leader) = &config.leader {
+            let transitions = leader.borrow().transitions;
+            self.leader_transitions
+                .inc_by(transitions.saturating_sub(self.leader_transitions.get()));
+        }
+
+        let state = map.get_state().await;
+
+        self.images.set(state.len() as _);
+        self.images_capacity.set(map.capacity().await as _);
+        self.last_event_age.set(
+            map.last_event_at()
+                .await
+                .map_or(-1.0, |at| at.elapsed().as_secs_f64()),
+        );
+
+        let limit = map.image_limit().status();
+        self.images_limit.set(limit.max_images as _);
+        self.images_refused
+            .inc_by(limit.refused.saturating_sub(self.images_refused.get()));
+        self.images_evicted
+            .inc_by(limit.evicted.saturating_sub(self.images_evicted.get()));
+
+        let lookups = map.lookup_queue().status();
+        self.lookup_queue_depth.set(lookups.depth as _);
+        self.lookup_deferred.set(lookups.deferred as _);
+        self.lookup_deferrals.inc_by(
+            lookups
+                .deferrals
+                .saturating_sub(self.lookup_deferrals.get()),
+        );
+        self.lookup_requeued
+            .inc_by(lookups.requeued.saturating_sub(self.lookup_requeued.get()));
+
+        self.sbom.reset();
+        for image in state.values() {
+            self.sbom.with_label_values(&[image.sbom.label()]).inc();
+        }
+
+        self.listeners.reset();
+        self.listener_queued.reset();
+        self.listener_high_water.reset();
+        // websocket clients listen to the views of the workload
+        let listeners = map.listeners().await.into_iter();
+        for listener in listeners.chain(feeds.listeners().await) {
+            let name = [listener.name.as_deref().unwrap_or(UNNAMED_LISTENER)];
+            self.listeners.with_label_values(&name).inc();
+            self.listener_queued
+                .with_label_values(&name)
+                .add(listener.queued as _);
+            let high_water = self.listener_high_water.with_label_values(&name);
+            high_water.set(high_water.get().max(listener.high_water as _));
+        }
+
+        let mut totals = map.listener_totals().await;
+        for (name, view) in feeds.listener_totals().await {
+            *totals.entry(name).or_default() += view;
+        }
+        for (name, totals) in totals {
+            let delivered = self.listener_delivered.with_label_values(&[&name]);
+            delivered.inc_by(totals.delivered.saturating_sub(delivered.get()));
+            let evictions = self.listener_evictions.with_label_values(&[&name]);
+            evictions.inc_by(totals.evicted.saturating_sub(evictions.get()));
+            let reaped = self.listener_reaped.with_label_values(&[&name]);
+            reaped.inc_by(totals.reaped.saturating_sub(reaped.get()));
+        }
+
+        for alert in config.alerts.iter().flat_map(|alerts| alerts.status()) {
+            let rule = [alert.rule.as_str()];
+            self.alert_firing
+                .with_label_values(&rule)
+                .set(alert.firing as _);
+            match alert.value {
+                Some(value) => self.alert_value.with_label_values(&rule).set(value),
+                None => {
+                    let _ = self.alert_value.remove_label_values(&rule);
+                }
+            }
+            let notifications = self.alert_notifications.with_label_values(&rule);
+            notifications.inc_by(alert.notifications.saturating_sub(notifications.get()));
+            let failures = self.alert_notification_failures.with_label_values(&rule);
+            failures.inc_by(alert.failed_notifications.saturating_sub(failures.get()));
+        }
+    }
+}
+
+/// A writer which only counts the bytes written to it.
+struct ByteCount(usize);
+
+impl Write for ByteCount {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+#[get("/metrics")]
+pub async fn get_metrics(
+    metrics: web::Data<Metrics>,
+    config: web::Data<ServerConfig>,
+    map: web::Data<WorkloadState>,
+    feeds: web::Data<Feeds>,
+) -> Result<HttpResponse, actix_web::Error> {
+    metrics.update(&config, &map, &feeds).await;
+
+    let encoder = TextEncoder::new();
+    let mut buffer = Vec::new();
+    encoder
+        .encode(&metrics.registry.gather(), &mut buffer)
+        .map_err(actix_web::error::ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(encoder.format_type())
+        .body(buffer))
+}
+
+#[get("/health/live")]
+pub async fn liveness() -> impl Responder {
+    HttpResponse::Ok().finish()
+}
+
+/// Ready once the initial sync completed, standby instances redirecting to the leader always are
+///
+/// Tracking the maximum number of images only degrades the instance, it stays ready, but reports
+/// the limit.
+#[routes]
+#[get("/health/ready")]
+#[get("/readyz")]
+pub async fn readiness(
+    config: web::Data<ServerConfig>,
+    map: web::Data<WorkloadState>,
+) -> impl Responder {
+    let redirecting = config.standby == Standby::Redirect && config.is_standby();
+    if !map.startup().is_synced() && !redirecting {
+        return HttpResponse::ServiceUnavailable().json(map.startup().progress());
+    }
+
+    let limit = map.image_limit().status();
+    match limit.at_capacity() {
+        true => HttpResponse::Ok().json(serde_json::json!({
+            "status": "degraded",
+            "imageLimit": limit,
+        })),
+        false => HttpResponse::Ok().finish(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bommer_api::data::{ImageBuilder, PodRef};
+    use std::collections::HashMap;
+
+    #[test]
+    fn restart_event_metrics() {
+        let metrics = Metrics::new().unwrap();
+        let image = |name: &str| {
+            let image = ImageBuilder::default()
+                .pod(PodRef {
+                    namespace: "default".to_string(),
+                    name: name.to_string(),
+                })
+                .build();
+            (ImageRef(name.to_string()), image)
+        };
+        let evt = Event::Restart(HashMap::from([image("a"), image("b"), image("c")]));
+        metrics.record_event(&evt);
+        metrics.record_event(&Event::Removed(ImageRef("a".to_string())));
+
+        let restart = metrics.event_entries.with_label_values(&["restart"]);
+        assert_eq!(restart.get_sample_count(), 1);
+        assert_eq!(restart.get_sample_sum(), 3.0);
+        let removed = metrics.event_entries.with_label_values(&["removed"]);
+        assert_eq!(removed.get_sample_sum(), 1.0);
+
+        let size = metrics.event_size.with_label_values(&["restart"]);
+        assert_eq!(
+            size.get_sample_sum(),
+            serde_json::to_vec(&evt).unwrap().len() as f64
+        );
+    }
+}
@@ -1,14 +1,131 @@
+mod admin;
+mod baseline;
+mod feed;
+mod metrics;
+mod record;
+pub(crate) mod webhook;
 mod ws;
 
-use crate::workload::{by_ns, WorkloadState};
+pub use feed::Feeds;
+pub use record::{RecordOptions, Recorded};
+pub use webhook::Webhook;
+
+use crate::alert::Alerting;
+use crate::export::{self, Filter, Format, SbomStateFilter};
+use crate::leader::{Leadership, Standby};
+use crate::logging::LogControl;
+use crate::server::metrics::Metrics;
+use crate::workload::WorkloadState;
 use actix_cors::Cors;
-use actix_web::{get, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_web::dev::{Server, Service};
+use actix_web::error::ErrorInternalServerError;
+use actix_web::http::header;
+use actix_web::{
+    delete, get, post, put, web, App, HttpRequest, HttpResponse, HttpServer, Responder,
+};
+use anyhow::Context;
+use bommer_api::data::{ImageRef, WorkloadSummary};
+use futures::future::{ready, Either};
+use futures::FutureExt;
 use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::sync::watch;
 use tokio::task::spawn_local;
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, info, info_span, warn, Instrument};
 
 #[derive(Clone, Debug)]
 pub struct ServerConfig {
-    pub bind_addr: String,
+    /// address of the API listener, disabled if `None`
+    pub bind_api: Option<String>,
+    /// address of the metrics and health listener, disabled if `None`
+    pub bind_metrics: Option<String>,
+    /// record the entries and size of broadcast events, on the metrics listener
+    pub event_metrics: bool,
+    /// bearer token for the admin API, which is disabled if not set
+    pub admin_token: Option<String>,
+    /// how to handle websocket connections arriving before the initial sync completed
+    pub early_websocket: EarlyWebsocket,
+    /// leadership status, `None` if leader election is disabled
+    pub leader: Option<watch::Receiver<Leadership>>,
+    /// how to serve the API while being a standby instance
+    pub standby: Standby,
+    /// file the baseline gets persisted to
+    pub baseline_file: Option<PathBuf>,
+    /// where to record the events sent to websocket clients, disabled if `None`
+    pub record: Option<RecordOptions>,
+    /// the state of the alert rules, `None` if there are none
+    pub alerts: Option<Alerting>,
+}
+
+impl ServerConfig {
+    /// Whether this instance waits for leadership, and so doesn't have a state of its own.
+    pub fn is_standby(&self) -> bool {
+        self.leader
+            .as_ref()
+            .is_some_and(|leader| !leader.borrow().is_leader)
+    }
+
+    /// Where to redirect a request to, if this is a standby instance configured to do so.
+    ///
+    /// Returns `Some(None)` if the request should be redirected, but the leader is unknown.
+    fn redirect(&self, req: &HttpRequest) -> Option<Option<String>> {
+        if self.standby != Standby::Redirect || !self.is_standby() {
+            return None;
+        }
+
+        let leader = self.leader.as_ref()?.borrow();
+        Some(leader.url.as_ref().map(|url| {
+            let mut url = url.clone();
+            url.set_path(req.path());
+            url.set_query(Some(req.query_string()).filter(|query| !query.is_empty()));
+            url.to_string()
+        }))
+    }
+}
+
+/// How to handle websocket connections arriving before the initial sync completed
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "lowercase")]
+pub enum EarlyWebsocket {
+    /// Accept the connection, but hold back the initial state until the sync completed
+    #[default]
+    Wait,
+    /// Reject the connection with 503 (Service Unavailable)
+    Reject,
+}
+
+#[get("/api/v1/info")]
+async fn get_info(
+    log: web::Data<LogControl>,
+    map: web::Data<WorkloadState>,
+    config: web::Data<ServerConfig>,
+) -> impl Responder {
+    HttpResponse::Ok().json(serde_json::json!({
+        "version": env!("CARGO_PKG_VERSION"),
+        "logFilter": log.current(),
+        "startup": map.startup().progress(),
+        "imageLimit": map.image_limit().status(),
+        "lookupQueue": map.lookup_queue().status(),
+        "leader": config.leader.as_ref().map(|leader| {
+            let leader = leader.borrow();
+            serde_json::json!({
+                "isLeader": leader.is_leader,
+                "holder": leader.holder,
+            })
+        }),
+    }))
 }
 
 #[get("/api/v1/workload")]
@@ -16,15 +133,45 @@ async fn get_workload(map: web::Data<WorkloadState>) -> impl Responder {
     HttpResponse::Ok().json(map.get_state().await.into_iter().collect::<HashMap<_, _>>())
 }
 
+#[get("/api/v1/workload/summary")]
+async fn get_workload_summary(map: web::Data<WorkloadState>) -> impl Responder {
+    let summary = WorkloadSummary::new(map.lock_state_for_read().await.values());
+    HttpResponse::Ok().json(summary)
+}
+
+/// Reject early websocket connections, if configured to do so.
+fn check_early_websocket(
+    config: &ServerConfig,
+    map: &WorkloadState,
+) -> Result<(), actix_web::Error> {
+    match (map.startup().is_synced(), config.early_websocket) {
+        (false, EarlyWebsocket::Reject) => Err(actix_web::error::ErrorServiceUnavailable(
+            "Initial sync not completed yet",
+        )),
+        _ => Ok(()),
+    }
+}
+
 #[get("/api/v1/workload_stream")]
 pub async fn workload_stream(
     req: HttpRequest,
     stream: web::Payload,
     map: web::Data<WorkloadState>,
+    feeds: web::Data<Feeds>,
+    config: web::Data<ServerConfig>,
 ) -> Result<HttpResponse, actix_web::Error> {
+    check_early_websocket(&config, &map)?;
+
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
-    let subscription = map.subscribe(32).await;
-    spawn_local(ws::run(subscription, session, msg_stream));
+    let (map, feeds) = (map.into_inner(), feeds.into_inner());
+    spawn_local(async move {
+        // don't hand out a misleading, incomplete state
+        map.startup().wait().await;
+        let view = feeds.view(None).await;
+        ws::run(view.subscribe().await, session, msg_stream)
+            .instrument(info_span!("websocket"))
+            .await
+    });
     Ok(res)
 }
 
@@ -33,23 +180,126 @@ pub async fn workload_stream_ns(
     req: HttpRequest,
     stream: web::Payload,
     map: web::Data<WorkloadState>,
+    feeds: web::Data<Feeds>,
+    config: web::Data<ServerConfig>,
     path: web::Path<String>,
 ) -> Result<HttpResponse, actix_web::Error> {
-    let (workload, runner) = by_ns(&map, path.into_inner()).await;
+    check_early_websocket(&config, &map)?;
+
     let (res, session, msg_stream) = actix_ws::handle(&req, stream)?;
-    let subscription = workload.subscribe(32).await;
+    let (map, feeds) = (map.into_inner(), feeds.into_inner());
+    let namespace = path.into_inner();
+
+    let span = info_span!("websocket", %namespace);
+    spawn_local(
+        async move {
+            map.startup().wait().await;
 
-    // run either of them to completion
-    spawn_local(async {
-        tokio::select! {
-            _ = ws::run(subscription, session, msg_stream) => {},
-            _ = runner => {},
+            // clients of the same namespace share its view
+            let view = feeds.view(Some(namespace)).await;
+            let subscription = view.subscribe().await;
+
+            // run either of them to completion
+            tokio::select! {
+                _ = ws::run(subscription, session, msg_stream) => {},
+                _ = view.closed() => debug!("Namespace view closed"),
+            }
         }
-    });
+        .instrument(span),
+    );
 
     Ok(res)
 }
 
+#[derive(Clone, Debug, serde::Deserialize)]
+pub struct AnnotationQuery {
+    image: String,
+    key: String,
+}
+
+/// Set an annotation of an image, requires the admin token.
+#[put("/api/v1/annotation")]
+async fn put_annotation(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    map: web::Data<WorkloadState>,
+    query: web::Query<AnnotationQuery>,
+    value: String,
+) -> impl Responder {
+    if let Err(response) = admin::authorize(&req, &config) {
+        return response;
+    }
+
+    let AnnotationQuery { image, key } = query.into_inner();
+    match map.set_annotation(ImageRef(image), key, value).await {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Remove an annotation of an image, requires the admin token.
+#[delete("/api/v1/annotation")]
+async fn delete_annotation(
+    req: HttpRequest,
+    config: web::Data<ServerConfig>,
+    map: web::Data<WorkloadState>,
+    query: web::Query<AnnotationQuery>,
+) -> impl Responder {
+    if let Err(response) = admin::authorize(&req, &config) {
+        return response;
+    }
+
+    let AnnotationQuery { image, key } = query.into_inner();
+    match map.remove_annotation(ImageRef(image), &key).await {
+        true => HttpResponse::NoContent().finish(),
+        false => HttpResponse::NotFound().finish(),
+    }
+}
+
+/// Retry the failed SBOM lookups of the provided images.
+#[post("/api/v1/retry")]
+async fn post_retry(
+    map: web::Data<WorkloadState>,
+    images: web::Json<Vec<ImageRef>>,
+) -> impl Responder {
+    let scheduled = map.retry(images.into_inner()).await;
+    HttpResponse::Ok().json(serde_json::json!({ "scheduled": scheduled }))
+}
+
+#[derive(Clone, Debug, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct ExportQuery {
+    #[serde(default)]
+    format: Format,
+    namespace: Option<String>,
+    sbom_state: Option<SbomStateFilter>,
+}
+
+#[get("/api/v1/export")]
+async fn get_export(
+    map: web::Data<WorkloadState>,
+    query: web::Query<ExportQuery>,
+) -> Result<HttpResponse, actix_web::Error> {
+    let ExportQuery {
+        format,
+        namespace,
+        sbom_state,
+    } = query.into_inner();
+
+    let workload = Filter {
+        namespace,
+        sbom_state,
+    }
+    .apply(map.get_state().await);
+
+    let mut body = Vec::new();
+    export::write(format, &workload, &mut body).map_err(ErrorInternalServerError)?;
+
+    Ok(HttpResponse::Ok()
+        .content_type(format.content_type())
+        .body(body))
+}
+
 /*
 #[get("/v1/images/{namespace}")]
 async fn get_containers_ns(path: web::Path<String>, store: web::Data<Store>) -> impl Responder {
@@ -57,10 +307,94 @@ async fn get_containers_ns(path: web::Path<String>, store: web::Data<Store>) ->
     HttpResponse::Ok().json(store.get_containers_ns(&ns).await)
 }*/
 
-pub async fn run(config: ServerConfig, map: WorkloadState) -> anyhow::Result<()> {
+/// Run the configured listeners, until one of them fails or the process gets terminated.
+pub async fn run(
+    config: ServerConfig,
+    map: WorkloadState,
+    log: LogControl,
+    webhook: Option<Webhook>,
+    shutdown: CancellationToken,
+) -> anyhow::Result<()> {
+    let mut servers = Vec::new();
+    let feeds = Feeds::new(map.clone());
+
+    if let Some(options) = config.record.clone() {
+        let (map, feeds) = (map.clone(), feeds.clone());
+        tokio::spawn(async move {
+            if let Err(err) = record::run(map, feeds, options).await {
+                warn!("Stopped recording events: {err:#}");
+            }
+        });
+    }
+
+    if let Some(bind_addr) = &config.bind_api {
+        info!("Binding API listener to {bind_addr}");
+        let server = api(config.clone(), map.clone(), feeds.clone(), log, bind_addr)
+            .with_context(|| format!("Failed to bind API listener to {bind_addr}"))?;
+        servers.push(("API", server));
+    }
+
+    if let Some(bind_addr) = &config.bind_metrics {
+        info!("Binding metrics listener to {bind_addr}");
+        let server = metrics(config.clone(), map, feeds, bind_addr)
+            .with_context(|| format!("Failed to bind metrics listener to {bind_addr}"))?;
+        servers.push(("metrics", server));
+    }
+
+    if let Some(webhook) = webhook {
+        let bind_addr = webhook.bind_addr.clone();
+        info!("Binding webhook listener to {bind_addr}");
+        let server = webhook::server(webhook)
+            .with_context(|| format!("Failed to bind webhook listener to {bind_addr}"))?;
+        servers.push(("webhook", server));
+    }
+
+    if servers.is_empty() {
+        warn!("No HTTP listener configured");
+        shutdown.cancelled().await;
+        return Ok(());
+    }
+
+    let handles = servers
+        .iter()
+        .map(|(_, server)| server.handle())
+        .collect::<Vec<_>>();
+
+    let servers = servers.into_iter().map(|(name, server)| {
+        async move {
+            server
+                .await
+                .with_context(|| format!("{name} listener failed"))
+        }
+        .boxed_local()
+    });
+
+    let result = tokio::select! {
+        (result, _, _) = futures::future::select_all(servers) => result,
+        _ = shutdown.cancelled() => Ok(()),
+    };
+
+    // once one is gone, take down all the others too, letting them finish ongoing requests
+    for handle in handles {
+        handle.stop(true).await;
+    }
+
+    result
+}
+
+fn api(
+    config: ServerConfig,
+    map: WorkloadState,
+    feeds: Feeds,
+    log: LogControl,
+    bind_addr: &str,
+) -> std::io::Result<Server> {
     let map = web::Data::new(map);
+    let feeds = web::Data::new(feeds);
+    let log = web::Data::new(log);
+    let config = web::Data::new(config);
 
-    HttpServer::new(move || {
+    Ok(HttpServer::new(move || {
         let cors = Cors::default()
             .send_wildcard()
             .allow_any_origin()
@@ -70,15 +404,154 @@ pub async fn run(config: ServerConfig, map: WorkloadState) -> anyhow::Result<()>
 
         App::new()
             .app_data(map.clone())
+            .app_data(feeds.clone())
+            .app_data(log.clone())
+            .app_data(config.clone())
+            .wrap_fn({
+                let config = config.clone();
+                move |req, srv| match config.redirect(req.request()) {
+                    None => {
+                        let fut = srv.call(req);
+                        Either::Left(async move { Ok(fut.await?.map_into_left_body()) })
+                    }
+                    Some(location) => {
+                        let res = match location {
+                            Some(location) => HttpResponse::TemporaryRedirect()
+                                .insert_header((header::LOCATION, location))
+                                .finish(),
+                            None => HttpResponse::ServiceUnavailable().body("Leader unknown"),
+                        };
+                        Either::Right(ready(Ok(req.into_response(res).map_into_right_body())))
+                    }
+                }
+            })
             .wrap(cors)
             .service(get_workload)
+            .service(get_workload_summary)
+            .service(get_export)
             .service(workload_stream)
             .service(workload_stream_ns)
+            .service(put_annotation)
+            .service(delete_annotation)
+            .service(post_retry)
+            .service(get_info)
+            .service(baseline::get_baseline)
+            .service(baseline::put_baseline)
+            .service(baseline::delete_baseline)
+            .service(baseline::get_baseline_diff)
+            .service(admin::get_log_level)
+            .service(admin::put_log_level)
+            .service(admin::get_diagnostics)
         //.service(get_containers_ns)
     })
-    .bind(&config.bind_addr)?
-    .run()
-    .await?;
+    .disable_signals()
+    .bind(bind_addr)?
+    .run())
+}
+
+fn metrics(
+    config: ServerConfig,
+    map: WorkloadState,
+    feeds: Feeds,
+    bind_addr: &str,
+) -> anyhow::Result<Server> {
+    let config = web::Data::new(config);
+    let map = web::Data::new(map);
+    let feeds = web::Data::new(feeds);
+    let metrics = Metrics::new()?;
+    let hook = config.event_metrics.then(|| {
+        let metrics = metrics.clone();
+        Arc::new(map.add_hook(move |evt| metrics.record_event(evt)))
+    });
+    let metrics = web::Data::new(metrics);
+
+    Ok(HttpServer::new(move || {
+        // keeps recording the events as long as the server is around
+        let _ = &hook;
+        App::new()
+            .app_data(config.clone())
+            .app_data(map.clone())
+            .app_data(feeds.clone())
+            .app_data(metrics.clone())
+            .service(metrics::get_metrics)
+            .service(metrics::liveness)
+            .service(metrics::readiness)
+    })
+    .disable_signals()
+    .bind(bind_addr)?
+    .run())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::http::StatusCode;
+    use actix_web::test;
+    use bommer_api::data::ImageBuilder;
+
+    fn config(admin_token: Option<&str>) -> ServerConfig {
+        ServerConfig {
+            bind_api: None,
+            bind_metrics: None,
+            event_metrics: false,
+            admin_token: admin_token.map(ToString::to_string),
+            early_websocket: Default::default(),
+            leader: None,
+            standby: Default::default(),
+            baseline_file: None,
+            record: None,
+            alerts: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn annotations_require_admin_token() {
+        let map = WorkloadState::default();
+        let image = ImageRef("quay.io/app:1".to_string());
+        map.mutate_state(image.clone(), |_| Some(ImageBuilder::default().build()))
+            .await;
+
+        for (admin_token, authorization, expected) in [
+            (None, Some("Bearer secret"), StatusCode::FORBIDDEN),
+            (Some("secret"), None, StatusCode::UNAUTHORIZED),
+            (
+                Some("secret"),
+                Some("Bearer wrong"),
+                StatusCode::UNAUTHORIZED,
+            ),
+            (
+                Some("secret"),
+                Some("Bearer secret"),
+                StatusCode::NO_CONTENT,
+            ),
+        ] {
+            let app = test::init_service(
+                App::new()
+                    .app_data(web::Data::new(map.clone()))
+                    .app_data(web::Data::new(config(admin_token)))
+                    .service(put_annotation)
+                    .service(delete_annotation),
+            )
+            .await;
+
+            for request in [
+                test::TestRequest::put().set_payload("a note"),
+                test::TestRequest::delete(),
+            ] {
+                let mut request = request.uri("/api/v1/annotation?image=quay.io/app:1&key=note");
+                if let Some(authorization) = authorization {
+                    request = request.insert_header((header::AUTHORIZATION, authorization));
+                }
+                let response = test::call_service(&app, request.to_request()).await;
+                assert_eq!(
+                    response.status(),
+                    expected,
+                    "{admin_token:?} {authorization:?}"
+                );
+            }
+        }
 
-    Ok(())
+        // only the authorized requests got through, the last one removing the note again
+        assert!(map.get(&image).await.unwrap().annotations.is_empty());
+    }
 }
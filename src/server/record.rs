@@ -0,0 +1,100 @@
+//! Flight recorder of the events sent to websocket clients, for reproducing client-side bugs with
+//! the `replay` command.
+
+use super::feed::Feeds;
+use crate::workload::WorkloadState;
+use anyhow::Context;
+use std::fs::{self, File};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::{info, warn};
+
+/// Name of the listener recording the events
+const RECORDER: &str = "recorder";
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct RecordOptions {
+    /// file the events get appended to
+    pub file: PathBuf,
+    /// size of the file after which it gets rotated, keeping a single previous one
+    pub max_bytes: u64,
+}
+
+/// A single event of a recording, as written to a line of the file
+#[derive(Clone, Debug, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Recorded<E> {
+    /// sequence number, consecutive across rotations and re-subscriptions of the recorder
+    pub seq: u64,
+    /// milliseconds since the epoch
+    pub timestamp: u64,
+    /// the event, exactly as sent to websocket clients of all namespaces
+    pub event: E,
+}
+
+/// The file rotated out of the way, once the current one is full
+pub fn rotated(file: &Path) -> PathBuf {
+    let mut rotated = file.as_os_str().to_owned();
+    rotated.push(".1");
+    rotated.into()
+}
+
+/// Record the events sent to websocket clients of all namespaces, until the recording fails.
+///
+/// Each file starts with the full state, so that it can be replayed on its own: once a file is
+/// full, it replaces the previous one, and the recorder subscribes again.
+pub async fn run(map: WorkloadState, feeds: Feeds, options: RecordOptions) -> anyhow::Result<()> {
+    // record what clients get to see, which is nothing before the initial sync
+    map.startup().wait().await;
+    info!("Recording events to {}", options.file.display());
+
+    let view = feeds.view(None).await;
+    let mut seq = 0;
+    loop {
+        let (mut writer, mut written) = open(&options.file)?;
+
+        let mut sub = view.subscribe_as(RECORDER).await;
+        while written < options.max_bytes {
+            let Some(event) = sub.recv().await else {
+                // the file continues with the full state
+                warn!("Recorder lost its subscription, subscribing again");
+                sub = view.subscribe_as(RECORDER).await;
+                continue;
+            };
+
+            seq += 1;
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_millis() as u64;
+            let mut line = serde_json::to_vec(&Recorded {
+                seq,
+                timestamp,
+                event,
+            })?;
+            line.push(b'\n');
+
+            writer
+                .write_all(&line)
+                .and_then(|()| writer.flush())
+                .with_context(|| format!("Failed to record to {}", options.file.display()))?;
+            written += line.len() as u64;
+        }
+
+        drop(writer);
+        fs::rename(&options.file, rotated(&options.file))
+            .with_context(|| format!("Failed to rotate {}", options.file.display()))?;
+    }
+}
+
+/// Open the recording for appending, along with its current size.
+fn open(file: &Path) -> anyhow::Result<(BufWriter<File>, u64)> {
+    let writer = File::options()
+        .create(true)
+        .append(true)
+        .open(file)
+        .with_context(|| format!("Failed to open recording {}", file.display()))?;
+    let written = writer.metadata()?.len();
+    Ok((BufWriter::new(writer), written))
+}
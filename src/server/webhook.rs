@@ -0,0 +1,50 @@
+use crate::admission::Admission;
+use actix_web::dev::Server;
+use actix_web::{post, web, App, HttpResponse, HttpServer, Responder};
+use anyhow::Context;
+use k8s_openapi::api::core::v1::Pod;
+use kube::core::admission::AdmissionReview;
+use openssl::ssl::{SslAcceptor, SslAcceptorBuilder, SslFiletype, SslMethod};
+use std::path::{Path, PathBuf};
+
+/// The admission webhook listener
+#[derive(Clone)]
+pub struct Webhook {
+    pub bind_addr: String,
+    /// certificate chain, in PEM format
+    pub tls_cert: PathBuf,
+    /// private key, in PEM format
+    pub tls_key: PathBuf,
+    pub admission: Admission,
+}
+
+#[post("/validate")]
+pub(crate) async fn validate(
+    admission: web::Data<Admission>,
+    review: web::Json<AdmissionReview<Pod>>,
+) -> impl Responder {
+    HttpResponse::Ok().json(admission.review(review.into_inner()).await)
+}
+
+pub fn server(webhook: Webhook) -> anyhow::Result<Server> {
+    let tls = tls(&webhook.tls_cert, &webhook.tls_key)?;
+    let admission = web::Data::new(webhook.admission);
+
+    Ok(
+        HttpServer::new(move || App::new().app_data(admission.clone()).service(validate))
+            .disable_signals()
+            .bind_openssl(&webhook.bind_addr, tls)?
+            .run(),
+    )
+}
+
+fn tls(cert: &Path, key: &Path) -> anyhow::Result<SslAcceptorBuilder> {
+    let mut builder = SslAcceptor::mozilla_intermediate(SslMethod::tls())?;
+    builder
+        .set_private_key_file(key, SslFiletype::PEM)
+        .with_context(|| format!("Failed to load TLS key: {}", key.display()))?;
+    builder
+        .set_certificate_chain_file(cert)
+        .with_context(|| format!("Failed to load TLS certificate: {}", cert.display()))?;
+    Ok(builder)
+}
@@ -1,18 +1,24 @@
-use crate::pubsub::Subscription;
+use crate::pubsub::{Encoded, Subscription};
 use actix_ws::{CloseCode, CloseReason, Message};
 use bommer_api::data::{Event, Image, ImageRef, SbomState};
 use futures::StreamExt;
 use std::time::Duration;
 use tokio::time::{interval, Instant};
+use tracing::debug;
 
 const HEARTBEAT: Duration = Duration::from_secs(5);
 const TIMEOUT: Duration = Duration::from_secs(20);
+/// maximum number of entries sent in a single message of the initial state
+pub const RESTART_CHUNK_SIZE: usize = 500;
 
 pub async fn run(
-    mut subscription: Subscription<ImageRef, Image>,
+    mut subscription: Subscription<ImageRef, Encoded>,
     mut session: actix_ws::Session,
     mut msg_stream: actix_ws::MessageStream,
 ) {
+    // the client may go silent without closing the connection, e.g. behind a proxy
+    subscription.touch();
+
     let close_reason: Option<CloseReason> = {
         let mut last_heartbeat = Instant::now();
         let mut interval = interval(HEARTBEAT);
@@ -39,10 +45,12 @@ pub async fn run(
                         },
                         Some(Ok(Message::Ping(data))) => {
                             last_heartbeat = Instant::now();
+                            subscription.touch();
                             let _ =  session.pong(&data).await;
                         }
                         Some(Ok(Message::Pong(_)))=> {
                             last_heartbeat = Instant::now();
+                            subscription.touch();
                         }
                         Some(Ok(Message::Text(_) | Message::Binary(_))) => {
                             break Some((CloseCode::Protocol, "Must not send data").into());
@@ -53,6 +61,9 @@ pub async fn run(
                 },
                 evt = subscription.recv() => {
                     match evt {
+                        None if subscription.is_reaped() => {
+                            break Some((CloseCode::Policy, "Idle for too long").into());
+                        }
                         None => break Some(CloseCode::Restart.into()),
                         Some(evt) => {
                             if let Err(err) = handle_evt(&mut session, evt).await {
@@ -73,31 +84,30 @@ pub async fn run(
         }
     };
 
+    debug!(?close_reason, "Closing websocket session");
     let _ = session.close(close_reason).await;
 }
 
 async fn handle_evt(
     session: &mut actix_ws::Session,
-    mut evt: Event<ImageRef, Image>,
+    evt: Event<ImageRef, Encoded>,
 ) -> anyhow::Result<()> {
-    match &mut evt {
-        Event::Added(_, state) | Event::Modified(_, state) => {
-            strip_sbom(&mut state.sbom);
-        }
-        Event::Restart(state) => {
-            for state in &mut state.values_mut() {
-                strip_sbom(&mut state.sbom);
-            }
-        }
-        _ => {}
-    }
     session.text(serde_json::to_string(&evt)?).await?;
 
     Ok(())
 }
 
+/// Serialize an image for the clients, which don't need the data of its SBOMs.
+pub fn encode(image: &Image) -> Encoded {
+    let mut image = image.clone();
+    strip_sbom(&mut image.sbom);
+    Encoded::new(&image).expect("Images must serialize")
+}
+
 fn strip_sbom(mut sbom: &mut SbomState) {
-    if let SbomState::Found(sbom) = &mut sbom {
-        sbom.data.clear();
+    if let SbomState::Found(sboms) = &mut sbom {
+        for sbom in sboms {
+            sbom.data = "".into();
+        }
     }
 }
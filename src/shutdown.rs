@@ -0,0 +1,172 @@
+//! Coordinated shutdown of all tasks.
+//!
+//! Once the process is asked to terminate, or one of the tasks ends, all other tasks get cancelled
+//! and are given until the deadline to wrap up. Tasks still running after that are abandoned.
+
+use futures::future::LocalBoxFuture;
+use futures::FutureExt;
+use std::future::Future;
+use std::process::ExitCode;
+use std::time::Duration;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+/// The exit code when tasks had to be abandoned, as they didn't finish before the deadline
+pub const EXIT_DEADLINE_EXCEEDED: u8 = 3;
+
+type Task<'a> = (&'static str, LocalBoxFuture<'a, anyhow::Result<()>>);
+
+pub struct Shutdown<'a> {
+    token: CancellationToken,
+    deadline: Duration,
+    tasks: Vec<Task<'a>>,
+}
+
+impl<'a> Shutdown<'a> {
+    pub fn new(deadline: Duration) -> Self {
+        Self {
+            token: CancellationToken::new(),
+            deadline,
+            tasks: Vec::new(),
+        }
+    }
+
+    /// A token, cancelled once shutting down.
+    pub fn token(&self) -> CancellationToken {
+        self.token.child_token()
+    }
+
+    /// Add a task, which is expected to return soon after its token got cancelled.
+    pub fn add<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + 'a,
+    {
+        self.tasks.push((name, task.boxed_local()));
+    }
+
+    /// Add a task, which simply gets dropped when shutting down.
+    pub fn add_cancellable<F>(&mut self, name: &'static str, task: F)
+    where
+        F: Future<Output = anyhow::Result<()>> + 'a,
+    {
+        let token = self.token();
+        self.add(name, async move {
+            tokio::select! {
+                result = task => result,
+                _ = token.cancelled() => Ok(()),
+            }
+        });
+    }
+
+    /// Run all tasks, until the first one ends or the process gets asked to terminate.
+    ///
+    /// Returns the outcome of the task which ended first, or a distinct exit code if the remaining
+    /// tasks didn't finish before the deadline.
+    pub async fn run(mut self) -> anyhow::Result<ExitCode> {
+        self.add_cancellable("signal handler", signal());
+
+        let tasks = self
+            .tasks
+            .into_iter()
+            .map(|(name, task)| task.map(move |result| (name, result)).boxed_local());
+
+        let ((name, result), _, remaining) = futures::future::select_all(tasks).await;
+        match &result {
+            Ok(()) => info!("Shutting down, {name} ended"),
+            Err(err) => warn!("Shutting down, {name} failed: {err:#}"),
+        }
+
+        self.token.cancel();
+
+        let remaining = futures::future::join_all(remaining.into_iter().map(|task| {
+            task.inspect(|(name, result)| match result {
+                Ok(()) => info!("Stopped {name}"),
+                Err(err) => warn!("Failed to stop {name}: {err:#}"),
+            })
+        }));
+
+        if tokio::time::timeout(self.deadline, remaining)
+            .await
+            .is_err()
+        {
+            warn!(
+                "Tasks didn't stop within {:?}, abandoning them",
+                self.deadline
+            );
+            result?;
+            return Ok(ExitCode::from(EXIT_DEADLINE_EXCEEDED));
+        }
+
+        result?;
+        Ok(ExitCode::SUCCESS)
+    }
+}
+
+/// Wait for the process to be asked to terminate.
+async fn signal() -> anyhow::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut terminate = signal(SignalKind::terminate())?;
+
+    tokio::select! {
+        result = tokio::signal::ctrl_c() => result?,
+        _ = terminate.recv() => {},
+    }
+
+    info!("Received termination signal");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    #[tokio::test(start_paused = true)]
+    async fn first_task_ending_stops_others() {
+        let stopped = &Cell::new(false);
+        let mut shutdown = Shutdown::new(Duration::from_secs(10));
+
+        let token = shutdown.token();
+        shutdown.add("cooperating", async move {
+            token.cancelled().await;
+            // wrapping up takes a while, but less than the deadline
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            stopped.set(true);
+            Ok(())
+        });
+        shutdown.add_cancellable("cancellable", futures::future::pending());
+        shutdown.add("short", async {
+            tokio::time::sleep(Duration::from_secs(5)).await;
+            Ok(())
+        });
+
+        assert_eq!(shutdown.run().await.unwrap(), ExitCode::SUCCESS);
+        assert!(stopped.get());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failing_task() {
+        let mut shutdown = Shutdown::new(Duration::from_secs(10));
+        shutdown.add_cancellable("cancellable", futures::future::pending());
+        shutdown.add("failing", async { anyhow::bail!("failed") });
+
+        let err = shutdown.run().await.unwrap_err();
+        assert_eq!(err.to_string(), "failed");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn deadline_exceeded() {
+        let mut shutdown = Shutdown::new(Duration::from_secs(10));
+        // ignores being cancelled
+        shutdown.add("stuck", futures::future::pending());
+        shutdown.add("short", async { Ok(()) });
+
+        let start = tokio::time::Instant::now();
+        assert_eq!(
+            shutdown.run().await.unwrap(),
+            ExitCode::from(EXIT_DEADLINE_EXCEEDED)
+        );
+        assert_eq!(start.elapsed(), Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,504 @@
+//! A simulated cluster, for running the pipeline under sustained churn without a real cluster.
+
+use crate::batch::Summary;
+use crate::bombastic::{self, SbomSource, SbomSourceRouter, ScanOptions};
+use crate::config::Config;
+use crate::pubsub::{apply_event, DropReason, Subscription, UNNAMED_LISTENER};
+use crate::store::image_store;
+use crate::workload::WorkloadState;
+use anyhow::bail;
+use bommer_api::data::{Event, Image, ImageRef, SbomState, SBOM};
+use futures::future::BoxFuture;
+use futures::{stream, FutureExt, Stream, StreamExt};
+use k8s_openapi::api::core::v1::{ContainerStatus, Pod, PodStatus};
+use k8s_openapi::apimachinery::pkg::apis::meta::v1::ObjectMeta;
+use kube::runtime::watcher;
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{BTreeMap, HashMap};
+use std::hash::{Hash, Hasher};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::info;
+
+/// Interval of applying churn to the simulated cluster
+const TICK: Duration = Duration::from_millis(100);
+/// Number of namespaces the simulated pods are spread across
+const NAMESPACES: usize = 8;
+/// Name the client mirror registers its subscription with
+const MIRROR: &str = "simulation-mirror";
+
+#[derive(Clone, Debug, clap::Args)]
+pub struct SimulateOptions {
+    /// Duration of the churn, in seconds
+    #[arg(long, default_value_t = 60)]
+    pub duration: u64,
+
+    /// Number of pods in the initial listing
+    #[arg(long, default_value_t = 500)]
+    pub initial_pods: usize,
+
+    /// Pods created per second
+    #[arg(long, default_value_t = 20.0)]
+    pub creations: f64,
+
+    /// Pods deleted per second
+    #[arg(long, default_value_t = 20.0)]
+    pub deletions: f64,
+
+    /// Pods rescheduled per second, replacing a pod with a new one running the same image
+    #[arg(long, default_value_t = 10.0)]
+    pub reschedules: f64,
+
+    /// Number of distinct images the pods run
+    #[arg(long, default_value_t = 100)]
+    pub images: usize,
+
+    /// Fraction of images with a mutable tag, which gets pushed again during the simulation
+    #[arg(long, default_value_t = 0.2)]
+    pub mutable_tags: f64,
+
+    /// Pushes of mutable tags per second, new pods of a pushed tag run the new digest
+    #[arg(long, default_value_t = 1.0)]
+    pub tag_pushes: f64,
+
+    /// Fraction of images without an SBOM
+    #[arg(long, default_value_t = 0.1)]
+    pub missing_sboms: f64,
+
+    /// Time each SBOM lookup takes, in milliseconds
+    #[arg(long, default_value_t = 50)]
+    pub lookup_delay_ms: u64,
+
+    /// Maximum time to wait for lookups and the mirror to settle after the churn, in seconds
+    #[arg(long, default_value_t = 30)]
+    pub settle_timeout: u64,
+
+    /// Seed of the churn, for repeating a run
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+/// A pod of the simulated cluster
+#[derive(Clone, Debug)]
+struct SimulatedPod {
+    namespace: String,
+    name: String,
+    image: usize,
+    /// digest of the image when the pod was started, later pushes don't affect it
+    digest: u64,
+}
+
+impl SimulatedPod {
+    fn to_pod(&self) -> Pod {
+        let (image, image_id) = image_names(self.image, self.digest);
+        Pod {
+            metadata: ObjectMeta {
+                namespace: Some(self.namespace.clone()),
+                name: Some(self.name.clone()),
+                ..Default::default()
+            },
+            status: Some(PodStatus {
+                container_statuses: Some(vec![ContainerStatus {
+                    name: "main".to_string(),
+                    image,
+                    image_id,
+                    ready: true,
+                    ..Default::default()
+                }]),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+/// The image (as referenced by the pod) and image ID (as resolved by the runtime) of an image.
+fn image_names(image: usize, digest: u64) -> (String, String) {
+    let repository = format!("registry.example.com/simulated/app-{image}");
+    let digest = format!("{:032x}{digest:032x}", image as u64);
+    (
+        format!("{repository}:latest"),
+        format!("{repository}@sha256:{digest}"),
+    )
+}
+
+/// Number of occurrences within a tick, of something happening at `rate` per second.
+fn occurrences(rng: &mut StdRng, rate: f64) -> usize {
+    let expected = rate.max(0.0) * TICK.as_secs_f64();
+    let mut n = expected.trunc() as usize;
+    if rng.gen_bool(expected.fract()) {
+        n += 1;
+    }
+    n
+}
+
+/// Totals of the churn applied to the simulated cluster
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Churn {
+    pub created: usize,
+    pub deleted: usize,
+    pub rescheduled: usize,
+    pub pushed: usize,
+}
+
+/// A simulated cluster, producing the events of a pod watcher.
+pub struct SimulatedSource {
+    options: SimulateOptions,
+    rng: StdRng,
+    pods: Vec<SimulatedPod>,
+    /// current digest of each image
+    digests: Vec<u64>,
+    next_pod: usize,
+    churn: Churn,
+}
+
+impl SimulatedSource {
+    pub fn new(options: SimulateOptions) -> Self {
+        let rng = match options.seed {
+            Some(seed) => StdRng::seed_from_u64(seed),
+            None => StdRng::from_entropy(),
+        };
+        let digests = vec![0; options.images.max(1)];
+
+        let mut source = Self {
+            options,
+            rng,
+            pods: Vec::new(),
+            digests,
+            next_pod: 0,
+            churn: Churn::default(),
+        };
+        for _ in 0..source.options.initial_pods {
+            let pod = source.new_pod(None);
+            source.pods.push(pod);
+        }
+
+        source
+    }
+
+    /// A new pod, running `image` or a random one.
+    fn new_pod(&mut self, image: Option<usize>) -> SimulatedPod {
+        let image = image.unwrap_or_else(|| self.rng.gen_range(0..self.digests.len()));
+        let namespace = format!("simulated-{}", self.rng.gen_range(0..NAMESPACES));
+        self.next_pod += 1;
+
+        SimulatedPod {
+            namespace,
+            name: format!("pod-{}", self.next_pod),
+            image,
+            digest: self.digests[image],
+        }
+    }
+
+    /// Remove a random pod.
+    fn remove_pod(&mut self) -> Option<SimulatedPod> {
+        if self.pods.is_empty() {
+            return None;
+        }
+        let n = self.rng.gen_range(0..self.pods.len());
+        Some(self.pods.swap_remove(n))
+    }
+
+    /// Apply a tick of churn, returning the resulting events.
+    fn tick(&mut self) -> Vec<watcher::Event<Pod>> {
+        let mut events = Vec::new();
+
+        for _ in 0..occurrences(&mut self.rng, self.options.tag_pushes) {
+            let mutable = (self.digests.len() as f64 * self.options.mutable_tags) as usize;
+            if mutable > 0 {
+                self.digests[self.rng.gen_range(0..mutable)] += 1;
+                self.churn.pushed += 1;
+            }
+        }
+
+        for _ in 0..occurrences(&mut self.rng, self.options.deletions) {
+            if let Some(pod) = self.remove_pod() {
+                events.push(watcher::Event::Deleted(pod.to_pod()));
+                self.churn.deleted += 1;
+            }
+        }
+
+        for _ in 0..occurrences(&mut self.rng, self.options.reschedules) {
+            if let Some(pod) = self.remove_pod() {
+                events.push(watcher::Event::Deleted(pod.to_pod()));
+                let pod = self.new_pod(Some(pod.image));
+                events.push(watcher::Event::Applied(pod.to_pod()));
+                self.pods.push(pod);
+                self.churn.rescheduled += 1;
+            }
+        }
+
+        for _ in 0..occurrences(&mut self.rng, self.options.creations) {
+            let pod = self.new_pod(None);
+            events.push(watcher::Event::Applied(pod.to_pod()));
+            self.pods.push(pod);
+            self.churn.created += 1;
+        }
+
+        events
+    }
+
+    /// Stream the events of the simulated cluster, starting with the initial listing, and ending
+    /// once the configured duration passed.
+    ///
+    /// The totals of the churn are published to `churn` as the simulation goes on.
+    pub fn into_stream(
+        self,
+        churn: Arc<parking_lot::Mutex<Churn>>,
+    ) -> impl Stream<Item = Result<watcher::Event<Pod>, watcher::Error>> {
+        let initial = watcher::Event::Restarted(self.pods.iter().map(|pod| pod.to_pod()).collect());
+        let end = Instant::now() + Duration::from_secs(self.options.duration);
+        let interval = tokio::time::interval(TICK);
+
+        let ticks = stream::unfold((self, interval), move |(mut source, mut interval)| {
+            let churn = churn.clone();
+            async move {
+                interval.tick().await;
+                if Instant::now() >= end {
+                    return None;
+                }
+                let events = source.tick();
+                *churn.lock() = source.churn;
+                Some((events, (source, interval)))
+            }
+        });
+
+        stream::iter([Ok(initial)])
+            .chain(ticks.flat_map(|events| stream::iter(events.into_iter().map(Ok))))
+    }
+}
+
+/// Serves SBOMs of simulated images, after a delay.
+///
+/// Whether an image has an SBOM only depends on the image, so looking it up again yields the
+/// same result.
+pub struct SimulatedSbomSource {
+    pub delay: Duration,
+    /// fraction of images without an SBOM
+    pub missing: f64,
+}
+
+impl SbomSource for SimulatedSbomSource {
+    fn lookup<'a>(&'a self, image: &'a ImageRef) -> BoxFuture<'a, anyhow::Result<Option<SBOM>>> {
+        async move {
+            tokio::time::sleep(self.delay).await;
+
+            let mut hasher = DefaultHasher::new();
+            image.hash(&mut hasher);
+            if (hasher.finish() % 1000) as f64 / 1000.0 < self.missing {
+                return Ok(None);
+            }
+
+            Ok(Some(SBOM::new(format!(
+                r#"{{"bomFormat":"CycloneDX","specVersion":"1.4","metadata":{{"component":{{"name":"{image}"}}}},"components":[]}}"#
+            ))))
+        }
+        .boxed()
+    }
+}
+
+/// A client, mirroring the workload from the events it receives.
+///
+/// Events are encoded and decoded again, as they would be on their way to a remote client.
+fn mirror(
+    mut sub: Subscription<ImageRef, Image>,
+) -> Arc<parking_lot::Mutex<HashMap<ImageRef, Image>>> {
+    let mirror = Arc::new(parking_lot::Mutex::new(HashMap::new()));
+
+    {
+        let mirror = mirror.clone();
+        tokio::spawn(async move {
+            while let Some(evt) = sub.recv().await {
+                let evt = serde_json::to_vec(&evt)
+                    .and_then(|json| serde_json::from_slice::<Event<ImageRef, Image>>(&json))
+                    .expect("events must survive the wire format");
+                apply_event(&mut mirror.lock(), evt);
+            }
+        });
+    }
+
+    mirror
+}
+
+/// Run the `simulate` command, soaking the pipeline with churn from a simulated cluster.
+///
+/// Once the churn is over, and the lookups settled, the exit code is only successful if the
+/// client mirror matches the workload, no listener got evicted as a slow consumer, and the
+/// workload didn't hold on to more memory than the shrink policy allows.
+pub async fn run(options: &SimulateOptions, config: &Config) -> anyhow::Result<ExitCode> {
+    let churn = Arc::new(parking_lot::Mutex::new(Churn::default()));
+    let source = SimulatedSource::new(options.clone());
+    let (store, runner) = image_store(source.into_stream(churn.clone()));
+    store.set_shrink_policy(config.shrink_policy()).await;
+
+    let sboms = SbomSourceRouter::new(SimulatedSbomSource {
+        delay: Duration::from_millis(options.lookup_delay_ms),
+        missing: options.missing_sboms,
+    });
+    let scan = ScanOptions {
+        shrink: config.shrink_policy(),
+        policy: Arc::new(config.policy.clone()),
+        ..Default::default()
+    };
+    let (map, pipeline) = bombastic::store(store, sboms, scan);
+    map.set_shrink_policy(config.shrink_policy()).await;
+    map.set_broadcast_config(config.broadcast_config()).await;
+    map.set_image_limit(config.max_images, config.max_images_strategy)
+        .await;
+    map.lookup_queue()
+        .set_capacity(config.lookup_queue_capacity);
+
+    let mirror = mirror(map.named(MIRROR).subscribe(None).await);
+
+    info!("Simulating churn for {} seconds", options.duration);
+    tokio::pin!(pipeline);
+    tokio::select! {
+        result = runner => result?,
+        result = &mut pipeline => {
+            result?;
+            bail!("SBOM pipeline ended unexpectedly");
+        }
+    }
+
+    info!("Churn complete, waiting for the pipeline to settle");
+    let settled = tokio::select! {
+        result = &mut pipeline => {
+            result?;
+            bail!("SBOM pipeline ended unexpectedly");
+        }
+        settled = tokio::time::timeout(
+            Duration::from_secs(options.settle_timeout),
+            settle(&map, &mirror),
+        ) => settled.is_ok(),
+    };
+
+    let churn = *churn.lock();
+    let state = map.get_state().await;
+    let listener = map
+        .listeners()
+        .await
+        .into_iter()
+        .find(|listener| listener.name.as_deref() == Some(MIRROR));
+    // events to listeners which were gone already are expected, e.g. after a resubscription
+    let mut evicted = BTreeMap::<String, usize>::new();
+    for dropped in map.dropped_events().await {
+        if dropped.reason == DropReason::Timeout {
+            let name = dropped.name.unwrap_or_else(|| UNNAMED_LISTENER.to_string());
+            *evicted.entry(name).or_default() += 1;
+        }
+    }
+    let capacity = map.capacity().await;
+
+    println!(
+        "churn: {} created, {} deleted, {} rescheduled, {} tags pushed",
+        churn.created, churn.deleted, churn.rescheduled, churn.pushed
+    );
+    println!("{}", Summary::new(state.values()));
+    if let Some(listener) = &listener {
+        println!(
+            "mirror: {} events delivered, at most {} of {} queued",
+            listener.delivered, listener.high_water, listener.capacity
+        );
+    }
+    for (name, dropped) in &evicted {
+        println!("evicted: {name}, dropping {dropped} events");
+    }
+    println!("workload capacity: {capacity}");
+
+    let mut failures = Vec::new();
+    if !settled {
+        failures.push("the pipeline didn't settle in time".to_string());
+    }
+    if listener.is_none() {
+        failures.push("the mirror lost its subscription".to_string());
+    }
+    let diverged = diverged(&state, &mirror.lock());
+    if diverged > 0 {
+        failures.push(format!(
+            "{diverged} images of the mirror differ from the workload"
+        ));
+    }
+    for name in evicted.keys() {
+        failures.push(format!(
+            "the {name} listener didn't keep up, and got evicted"
+        ));
+    }
+    if let Some(target) = config
+        .shrink_policy()
+        .and_then(|policy| policy.target(state.len(), capacity))
+    {
+        failures.push(format!(
+            "the workload holds capacity {capacity} for {} images, exceeding the shrink policy (target {target})",
+            state.len()
+        ));
+    }
+
+    for failure in &failures {
+        println!("FAILED: {failure}");
+    }
+
+    Ok(match failures.is_empty() {
+        true => ExitCode::SUCCESS,
+        false => ExitCode::FAILURE,
+    })
+}
+
+/// Wait until no lookups are scheduled anymore, and the mirror caught up with the workload.
+async fn settle(map: &WorkloadState, mirror: &parking_lot::Mutex<HashMap<ImageRef, Image>>) {
+    let mut interval = tokio::time::interval(Duration::from_millis(100));
+
+    loop {
+        interval.tick().await;
+
+        let state = map.get_state().await;
+        let scheduled = state
+            .values()
+            .any(|image| matches!(image.sbom, SbomState::Scheduled));
+        if !scheduled && *mirror.lock() == state {
+            break;
+        }
+    }
+}
+
+/// Number of images which are different, or only present on one side.
+fn diverged(state: &HashMap<ImageRef, Image>, mirror: &HashMap<ImageRef, Image>) -> usize {
+    let different = state
+        .iter()
+        .filter(|(image, value)| mirror.get(image) != Some(value))
+        .count();
+    let extra = mirror
+        .keys()
+        .filter(|image| !state.contains_key(image))
+        .count();
+
+    different + extra
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test(start_paused = true)]
+    async fn soak() {
+        let options = SimulateOptions {
+            duration: 20,
+            initial_pods: 100,
+            creations: 5.0,
+            deletions: 5.0,
+            reschedules: 2.0,
+            images: 30,
+            mutable_tags: 0.2,
+            tag_pushes: 1.0,
+            missing_sboms: 0.1,
+            lookup_delay_ms: 10,
+            settle_timeout: 30,
+            seed: Some(2347),
+        };
+        let exit = run(&options, &Config::default()).await.unwrap();
+        assert_eq!(exit, ExitCode::SUCCESS);
+    }
+}
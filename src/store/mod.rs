@@ -1,6 +1,6 @@
 mod pods;
 
-use crate::pubsub::{State, Subscription};
+use crate::pubsub::{ShrinkPolicy, State, Subscription};
 use std::collections::{HashMap, HashSet};
 use std::fmt::Debug;
 use std::hash::Hash;
@@ -68,6 +68,12 @@ where
 
     /// listeners
     state: State<K, Owned<O, V>>,
+
+    /// if the state was reset from a full list of pods at least once
+    synced: bool,
+
+    /// when to release unused capacity, never if `None`
+    shrink: Option<ShrinkPolicy>,
 }
 
 impl<K, O, V> Default for Inner<K, O, V>
@@ -80,6 +86,8 @@ where
         Self {
             pods: Default::default(),
             state: Default::default(),
+            synced: false,
+            shrink: None,
         }
     }
 }
@@ -136,7 +144,12 @@ where
         A: Fn(&K, V) -> V,
     {
         if let Some(images) = self.pods.remove(pod_ref) {
+            if let Some(policy) = &self.shrink {
+                policy.shrink_map(&mut self.pods);
+            }
+
             // we removed a pod, so let's clean up its images
+            let shrink = self.shrink;
 
             for image in images {
                 self.state
@@ -146,6 +159,9 @@ where
                             if state.owners.is_empty() {
                                 None
                             } else {
+                                if let Some(policy) = &shrink {
+                                    policy.shrink_set(&mut state.owners);
+                                }
                                 state.state = apply(&image, state.state);
                                 Some(state)
                             }
@@ -161,6 +177,7 @@ where
     /// full reset of the state
     async fn reset(&mut self, images: HashMap<K, Owned<O, V>>, pods: HashMap<O, HashSet<K>>) {
         self.pods = pods;
+        self.synced = true;
         self.state.set_state(images).await;
     }
 }
@@ -171,7 +188,6 @@ where
     O: Clone + Debug + Eq + Hash + Send + Sync + 'static,
     V: Clone + Debug + PartialEq + Send + Sync + 'static,
 {
-    #[allow(unused)]
     pub async fn get_state(&self) -> HashMap<K, Owned<O, V>> {
         self.inner.read().await.state.get_state().await
     }
@@ -182,4 +198,30 @@ where
     ) -> Subscription<K, Owned<O, V>> {
         self.inner.read().await.state.subscribe(buffer).await
     }
+
+    /// Subscribe, also reporting if the store was synced at the time of subscribing.
+    ///
+    /// If it was not, the initial [`Event::Restart`](bommer_api::data::Event::Restart) doesn't
+    /// reflect the actual state yet, and the next restart will be the first real one.
+    pub async fn subscribe_synced(
+        &self,
+        buffer: impl Into<Option<usize>>,
+    ) -> (Subscription<K, Owned<O, V>>, bool) {
+        let inner = self.inner.read().await;
+        (inner.state.subscribe(buffer).await, inner.synced)
+    }
+
+    /// Release unused capacity after pods got removed, according to `policy`, never if `None`.
+    ///
+    /// This applies to the state, as well as to the owners of each entry.
+    pub async fn set_shrink_policy(&self, policy: Option<ShrinkPolicy>) {
+        let mut inner = self.inner.write().await;
+        inner.shrink = policy;
+        inner.state.set_shrink_policy(policy).await;
+    }
+
+    /// Number of owners (pods) currently known
+    pub async fn owners(&self) -> usize {
+        self.inner.read().await.pods.len()
+    }
 }
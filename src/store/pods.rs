@@ -6,6 +6,7 @@ use kube::{runtime::watcher, Resource, ResourceExt};
 use std::collections::{HashMap, HashSet};
 use std::future::Future;
 use std::pin::pin;
+use tracing::{debug, info, info_span, Instrument};
 
 pub fn image_store<S>(
     stream: S,
@@ -32,35 +33,119 @@ where
     let mut stream = pin!(stream);
 
     while let Some(evt) = stream.try_next().await? {
-        match evt {
-            watcher::Event::Applied(pod) => {
-                let pod_ref = match to_key(&pod) {
-                    Some(pod_ref) => pod_ref,
-                    None => continue,
-                };
-
-                let images = images_from_pod(pod);
-
-                store
-                    .inner
-                    .write()
-                    .await
-                    .apply(pod_ref, images, |_| (), |_, v| v)
-                    .await;
-            }
-            watcher::Event::Deleted(pod) => {
-                if let Some(pod_ref) = to_key(&pod) {
-                    store.inner.write().await.delete(&pod_ref, |_, v| v).await;
-                }
-            }
-            watcher::Event::Restarted(pods) => {
-                let (images, pods) = to_state(pods);
-                store.inner.write().await.reset(images, pods).await;
+        let kind = match &evt {
+            watcher::Event::Applied(_) => "applied",
+            watcher::Event::Deleted(_) => "deleted",
+            watcher::Event::Restarted(_) => "restarted",
+        };
+        apply(&store, evt)
+            .instrument(info_span!("watch_event", kind))
+            .await;
+    }
+
+    Ok(())
+}
+
+async fn apply(store: &Store<ImageRef, PodRef, ()>, evt: watcher::Event<Pod>) {
+    match evt {
+        watcher::Event::Applied(pod) => {
+            let Some(PodImages { pod, images }) = pod_images(&pod) else {
+                return;
+            };
+
+            store
+                .inner
+                .write()
+                .await
+                .apply(pod, images, |_| (), |_, v| v)
+                .await;
+        }
+        watcher::Event::Deleted(pod) => {
+            if let Ok(pod_ref) = to_key(&pod) {
+                store.inner.write().await.delete(&pod_ref, |_, v| v).await;
             }
         }
+        watcher::Event::Restarted(pods) => {
+            info!("Listed {} pods", pods.len());
+            let (images, pods) = to_state(pods);
+            store.inner.write().await.reset(images, pods).await;
+        }
     }
+}
 
-    Ok(())
+/// A pod, along with the images it runs
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PodImages {
+    pub pod: PodRef,
+    pub images: HashSet<ImageRef>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, thiserror::Error)]
+pub enum PodError {
+    #[error("pod has no name")]
+    MissingName,
+    #[error("pod has no namespace")]
+    MissingNamespace,
+    #[error("pod {} / {} has no status yet", .0.namespace, .0.name)]
+    MissingStatus(PodRef),
+    #[error("pod {} / {} has no containers with an image ID", .0.namespace, .0.name)]
+    NoContainers(PodRef),
+}
+
+impl PodError {
+    /// The pod, if it could be identified at all
+    pub fn pod(&self) -> Option<&PodRef> {
+        match self {
+            Self::MissingName | Self::MissingNamespace => None,
+            Self::MissingStatus(pod) | Self::NoContainers(pod) => Some(pod),
+        }
+    }
+}
+
+impl TryFrom<&Pod> for PodImages {
+    type Error = PodError;
+
+    fn try_from(pod: &Pod) -> Result<Self, Self::Error> {
+        let pod_ref = to_key(pod)?;
+
+        let Some(status) = &pod.status else {
+            return Err(PodError::MissingStatus(pod_ref));
+        };
+
+        let images = status
+            .container_statuses
+            .iter()
+            .chain(&status.init_container_statuses)
+            .chain(&status.ephemeral_container_statuses)
+            .flatten()
+            .filter_map(to_container_id)
+            .collect::<HashSet<_>>();
+
+        if images.is_empty() {
+            return Err(PodError::NoContainers(pod_ref));
+        }
+
+        Ok(Self {
+            pod: pod_ref,
+            images,
+        })
+    }
+}
+
+/// The images of a pod, which can be empty for pods which didn't start yet.
+///
+/// Returns `None` if the pod can't be identified.
+fn pod_images(pod: &Pod) -> Option<PodImages> {
+    match PodImages::try_from(pod) {
+        Ok(pod_images) => Some(pod_images),
+        Err(err) => {
+            debug!("{err}");
+            err.pod().map(|pod| PodImages {
+                pod: pod.clone(),
+                images: Default::default(),
+            })
+        }
+    }
 }
 
 fn to_state(
@@ -72,67 +157,144 @@ fn to_state(
     let mut by_images: HashMap<ImageRef, Owned<PodRef, ()>> = Default::default();
     let mut by_pods = HashMap::new();
 
-    for pod in pods {
-        let pod_ref = match to_key(&pod) {
-            Some(pod_ref) => pod_ref,
-            None => continue,
+    for pod in &pods {
+        let Some(PodImages { pod, images }) = pod_images(pod) else {
+            continue;
         };
 
-        let images = images_from_pod(pod);
         for image in &images {
             by_images
                 .entry(image.clone())
                 .or_default()
                 .owners
-                .insert(pod_ref.clone());
+                .insert(pod.clone());
         }
 
-        by_pods.insert(pod_ref, images);
+        by_pods.insert(pod, images);
     }
 
     (by_images, by_pods)
 }
 
 /// create a key for a pod
-fn to_key(pod: &Pod) -> Option<PodRef> {
-    match (pod.namespace(), pod.meta().name.clone()) {
-        (Some(namespace), Some(name)) => Some(PodRef { namespace, name }),
-        _ => None,
-    }
+fn to_key(pod: &Pod) -> Result<PodRef, PodError> {
+    let name = pod.meta().name.clone().ok_or(PodError::MissingName)?;
+    let namespace = pod.namespace().ok_or(PodError::MissingNamespace)?;
+    Ok(PodRef { namespace, name })
 }
 
-/// collect all container images from a pod
-fn images_from_pod(pod: Pod) -> HashSet<ImageRef> {
-    pod.status
-        .into_iter()
-        .flat_map(|s| {
-            s.container_statuses
-                .into_iter()
-                .flat_map(|c| c.into_iter().flat_map(to_container_id))
-                .chain(
-                    s.init_container_statuses
-                        .into_iter()
-                        .flat_map(|ic| ic.into_iter().flat_map(to_container_id)),
-                )
-                .chain(
-                    s.ephemeral_container_statuses
-                        .into_iter()
-                        .flat_map(|ic| ic.into_iter().flat_map(to_container_id)),
-                )
-        })
-        .collect()
-}
-
-pub fn to_container_id(container: ContainerStatus) -> Option<ImageRef> {
-    if container.image_id.is_empty() {
-        return None;
-    }
+pub fn to_container_id(container: &ContainerStatus) -> Option<ImageRef> {
+    // empty until the image was pulled, and never valid if it contains whitespace
+    let image = container.image_id.parse::<ImageRef>().ok()?;
 
     // FIXME: we need some more magic here, as kubernetes has weird ideas on filling the fields image and imageId.
     // see: docs/image_id.md
 
     // FIXME: this won't work on kind, and maybe others, as they generate broken image ID values
-    Some(ImageRef(container.image_id))
+    Some(image)
 
     // ImageRef(format!("{} / {}", container.image, container.image_id))
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pubsub::ShrinkPolicy;
+    use crate::testutil::pod;
+
+    const NGINX: &str = "docker.io/library/nginx@sha256:1234";
+    const REDIS: &str = "docker.io/library/redis@sha256:5678";
+
+    fn pod_ref() -> PodRef {
+        PodRef {
+            namespace: "default".to_string(),
+            name: "web".to_string(),
+        }
+    }
+
+    #[test]
+    fn images_of_pod() {
+        let images = PodImages::try_from(&pod("default", "web", &[NGINX, REDIS, NGINX]));
+        assert_eq!(
+            images,
+            Ok(PodImages {
+                pod: pod_ref(),
+                images: HashSet::from([NGINX.parse().unwrap(), REDIS.parse().unwrap()]),
+            })
+        );
+    }
+
+    #[test]
+    fn missing_fields() {
+        let parse = |pod: serde_json::Value| {
+            PodImages::try_from(&serde_json::from_value::<Pod>(pod).unwrap())
+        };
+
+        assert_eq!(
+            parse(serde_json::json!({"metadata": {"namespace": "default"}})),
+            Err(PodError::MissingName)
+        );
+        assert_eq!(
+            parse(serde_json::json!({"metadata": {"name": "web"}})),
+            Err(PodError::MissingNamespace)
+        );
+
+        let pending = parse(serde_json::json!({
+            "metadata": {"namespace": "default", "name": "web"},
+        }));
+        assert_eq!(pending, Err(PodError::MissingStatus(pod_ref())));
+
+        // the image wasn't pulled yet
+        let pulling = parse(serde_json::json!({
+            "metadata": {"namespace": "default", "name": "web"},
+            "status": {"containerStatuses": [{
+                "name": "web",
+                "image": NGINX,
+                "imageID": "",
+                "ready": false,
+                "restartCount": 0,
+            }]},
+        }));
+        assert_eq!(pulling, Err(PodError::NoContainers(pod_ref())));
+        assert_eq!(pulling.unwrap_err().pod(), Some(&pod_ref()));
+    }
+
+    #[tokio::test]
+    async fn shrinks_after_pods_got_removed() {
+        let store = Store::default();
+        store
+            .set_shrink_policy(Some(ShrinkPolicy {
+                min_occupancy: 0.25,
+                min_capacity: 64,
+            }))
+            .await;
+
+        let pods = (0..1000)
+            .map(|n| pod("default", &format!("web-{n}"), &[NGINX]))
+            .collect::<Vec<_>>();
+        for pod in &pods {
+            apply(&store, watcher::Event::Applied(pod.clone())).await;
+        }
+        let owners = |store: &Store<ImageRef, PodRef, ()>| {
+            let store = store.clone();
+            async move {
+                store.get_state().await[&NGINX.parse().unwrap()]
+                    .owners
+                    .capacity()
+            }
+        };
+        let peak = (
+            store.inner.read().await.pods.capacity(),
+            owners(&store).await,
+        );
+        assert!(peak.0 >= 1000 && peak.1 >= 1000);
+
+        // scale down by 90%
+        for pod in &pods[..900] {
+            apply(&store, watcher::Event::Deleted(pod.clone())).await;
+        }
+        assert_eq!(store.owners().await, 100);
+        assert!(store.inner.read().await.pods.capacity() < peak.0 / 4);
+        assert!(owners(&store).await < peak.1 / 4);
+    }
+}
@@ -0,0 +1,109 @@
+//! Export of traces via OTLP, if built with the `otel` feature.
+//!
+//! The exporter is configured by the standard `OTEL_` environment variables, and only enabled if
+//! an endpoint is set (`OTEL_EXPORTER_OTLP_ENDPOINT` or `OTEL_EXPORTER_OTLP_TRACES_ENDPOINT`).
+//! Without the feature, none of this carries any dependencies or cost.
+
+use reqwest::header::HeaderMap;
+
+#[cfg(feature = "otel")]
+pub use otel::layer;
+
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::propagation::Injector;
+    use opentelemetry::sdk::propagation::TraceContextPropagator;
+    use opentelemetry::sdk::{trace, Resource};
+    use opentelemetry::trace::TraceError;
+    use opentelemetry::{global, KeyValue};
+    use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+    use tracing::Subscriber;
+    use tracing_opentelemetry::OpenTelemetryLayer;
+    use tracing_subscriber::registry::LookupSpan;
+
+    /// An endpoint is configured, and the SDK isn't disabled.
+    fn enabled() -> bool {
+        let configured = [
+            "OTEL_EXPORTER_OTLP_ENDPOINT",
+            "OTEL_EXPORTER_OTLP_TRACES_ENDPOINT",
+        ]
+        .iter()
+        .any(|var| std::env::var_os(var).is_some());
+        let disabled = std::env::var("OTEL_SDK_DISABLED")
+            .is_ok_and(|disabled| disabled.eq_ignore_ascii_case("true"));
+        configured && !disabled
+    }
+
+    /// The layer exporting the spans, `None` if no endpoint is configured.
+    ///
+    /// Sampling follows `OTEL_TRACES_SAMPLER` and `OTEL_TRACES_SAMPLER_ARG`, sampling everything by
+    /// default.
+    pub fn layer<S>() -> Result<Option<OpenTelemetryLayer<S, trace::Tracer>>, TraceError>
+    where
+        S: Subscriber + for<'a> LookupSpan<'a>,
+    {
+        if !enabled() {
+            return Ok(None);
+        }
+
+        let mut resource = Resource::default();
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            resource = resource.merge(&Resource::new([KeyValue::new("service.name", "bommer")]));
+        }
+
+        global::set_text_map_propagator(TraceContextPropagator::new());
+        let tracer = opentelemetry_otlp::new_pipeline()
+            .tracing()
+            .with_exporter(opentelemetry_otlp::new_exporter().tonic())
+            .with_trace_config(trace::config().with_resource(resource))
+            .install_batch(opentelemetry::runtime::Tokio)?;
+
+        Ok(Some(tracing_opentelemetry::layer().with_tracer(tracer)))
+    }
+
+    pub(super) struct HeaderInjector<'a>(pub &'a mut HeaderMap);
+
+    impl Injector for HeaderInjector<'_> {
+        fn set(&mut self, key: &str, value: String) {
+            // e.g. an empty `tracestate`
+            if value.is_empty() {
+                return;
+            }
+            if let (Ok(name), Ok(value)) = (
+                HeaderName::from_bytes(key.as_bytes()),
+                HeaderValue::from_str(&value),
+            ) {
+                self.0.insert(name, value);
+            }
+        }
+    }
+}
+
+/// Headers propagating the trace of the current span to an HTTP service, e.g. `traceparent`.
+///
+/// Empty, unless traces get exported.
+pub fn trace_headers() -> HeaderMap {
+    #[allow(unused_mut)]
+    let mut headers = HeaderMap::new();
+
+    #[cfg(feature = "otel")]
+    {
+        use tracing_opentelemetry::OpenTelemetrySpanExt;
+
+        let context = tracing::Span::current().context();
+        opentelemetry::global::get_text_map_propagator(|propagator| {
+            propagator.inject_context(&context, &mut otel::HeaderInjector(&mut headers))
+        });
+    }
+
+    headers
+}
+
+/// Export the spans which are still pending, before exiting.
+pub async fn shutdown() {
+    #[cfg(feature = "otel")]
+    {
+        // blocks until the spans are exported
+        let _ = tokio::task::spawn_blocking(opentelemetry::global::shutdown_tracer_provider).await;
+    }
+}
@@ -0,0 +1,79 @@
+//! Helpers for tests, feeding pods into the pipeline without a cluster.
+
+use crate::bombastic::SbomSource;
+use crate::pubsub::State;
+use bommer_api::data::{ImageRef, SBOM};
+use futures::channel::mpsc;
+use futures::future::{self, BoxFuture};
+use futures::{FutureExt, Stream};
+use k8s_openapi::api::core::v1::Pod;
+use kube::runtime::watcher;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::hash::Hash;
+use std::time::Duration;
+
+pub type PodEvent = Result<watcher::Event<Pod>, watcher::Error>;
+
+/// A pod running `images`, which are reported as their image IDs.
+pub fn pod(namespace: &str, name: &str, images: &[&str]) -> Pod {
+    let statuses = images
+        .iter()
+        .enumerate()
+        .map(|(n, image)| {
+            serde_json::json!({
+                "name": format!("container-{n}"),
+                "image": image,
+                "imageID": image,
+                "ready": true,
+                "restartCount": 0,
+            })
+        })
+        .collect::<Vec<_>>();
+
+    serde_json::from_value(serde_json::json!({
+        "metadata": { "namespace": namespace, "name": name },
+        "status": { "containerStatuses": statuses },
+    }))
+    .expect("valid pod")
+}
+
+/// A stream of watch events, as returned by the watcher of the pods, fed by the sender.
+pub fn pod_events() -> (
+    mpsc::UnboundedSender<PodEvent>,
+    impl Stream<Item = PodEvent>,
+) {
+    mpsc::unbounded()
+}
+
+/// A source of SBOMs answering from a map, lookups of images missing from it never complete.
+#[derive(Clone, Debug, Default)]
+pub struct FakeSource(pub HashMap<ImageRef, Option<SBOM>>);
+
+impl SbomSource for FakeSource {
+    fn lookup<'a>(&'a self, image: &'a ImageRef) -> BoxFuture<'a, anyhow::Result<Option<SBOM>>> {
+        match self.0.get(image) {
+            Some(sbom) => future::ready(Ok(sbom.clone())).boxed(),
+            None => future::pending().boxed(),
+        }
+    }
+}
+
+/// Wait until the state satisfies `f`, **panicking** if it doesn't within a few seconds.
+pub async fn wait_for<K, V>(state: &State<K, V>, f: impl Fn(&HashMap<K, V>) -> bool)
+where
+    K: Clone + Debug + Eq + Hash + Send + Sync + 'static,
+    V: Clone + Debug + PartialEq + Send + Sync + 'static,
+{
+    let wait = async {
+        while !f(&state.get_state().await) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    if tokio::time::timeout(Duration::from_secs(5), wait)
+        .await
+        .is_err()
+    {
+        panic!("timed out, state is: {:#?}", state.get_state().await);
+    }
+}
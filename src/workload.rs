@@ -1,12 +1,272 @@
-use crate::pubsub::State;
-use bommer_api::data::{Event, Image, ImageRef};
+use crate::bombastic::LookupQueue;
+use crate::pubsub::{HookHandle, State, Subscription};
+use bommer_api::data::{Event, Image, ImageBuilder, ImageLimitStatus, ImageRef, SbomState};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::File;
 use std::future::Future;
+use std::io::{BufReader, BufWriter, ErrorKind};
 use std::ops::Deref;
-use tracing::log;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tracing::{debug, info, log, warn};
 
 #[derive(Clone, Debug, Default)]
 pub struct WorkloadState {
     state: State<ImageRef, Image>,
+    startup: Startup,
+    limit: ImageLimit,
+    lookups: LookupQueue,
+}
+
+/// Progress of the initial sync
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Progress {
+    /// the initial list of pods was merged into the workload
+    pub synced: bool,
+    pub pods: usize,
+    pub images: usize,
+    /// SBOM lookups queued by the sync
+    pub queued: usize,
+}
+
+/// Tracks the initial sync of the workload, which gates readiness.
+#[derive(Clone, Debug)]
+pub struct Startup {
+    tx: Arc<watch::Sender<Progress>>,
+    rx: watch::Receiver<Progress>,
+}
+
+impl Default for Startup {
+    fn default() -> Self {
+        let (tx, rx) = watch::channel(Progress::default());
+        Self {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+}
+
+impl Startup {
+    pub fn progress(&self) -> Progress {
+        *self.rx.borrow()
+    }
+
+    pub fn is_synced(&self) -> bool {
+        self.rx.borrow().synced
+    }
+
+    /// Record a completed sync, only the first one gets logged.
+    pub fn complete(&self, progress: Progress) {
+        let previous = self.tx.send_replace(progress);
+        if !previous.synced {
+            info!(
+                "Initial sync complete: {} pods, {} images, {} SBOM lookups queued",
+                progress.pods, progress.images, progress.queued
+            );
+        }
+    }
+
+    /// Start over, e.g. after losing leadership, the next sync will be logged again.
+    pub fn reset(&self) {
+        self.tx.send_replace(Progress::default());
+    }
+
+    /// Wait until the initial sync completed.
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while !rx.borrow_and_update().synced {
+            if rx.changed().await.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// What happens to new images, once the maximum number of images is tracked
+#[derive(
+    Clone,
+    Copy,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    clap::ValueEnum,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum LimitStrategy {
+    /// Don't track new images, until there is room again
+    #[default]
+    Refuse,
+    /// Drop the least recently updated image, making room for the new one
+    EvictOldest,
+}
+
+/// What to do with a new image, see [`ImageLimit::admit`]
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum LimitDecision {
+    Admit,
+    Refuse,
+    /// remove this image first
+    Evict(ImageRef),
+}
+
+/// Caps the number of tracked images, as a safety valve against a workload growing without
+/// bounds, e.g. due to a misconfigured selector, or pods using random image references.
+///
+/// Images are ordered by their last update, tracked by a hook on the state, so checking the limit
+/// doesn't need to scan the workload.
+#[derive(Clone, Debug, Default)]
+pub struct ImageLimit {
+    inner: Arc<parking_lot::Mutex<LimitInner>>,
+}
+
+#[derive(Debug, Default)]
+struct LimitInner {
+    /// zero if unlimited
+    max: usize,
+    strategy: LimitStrategy,
+    /// the hook tracking the updates, only installed while the images are limited
+    hook: Option<HookHandle>,
+    /// images, by the sequence number of their last update
+    by_update: BTreeMap<u64, ImageRef>,
+    /// the sequence number of the last update of each image
+    updated: HashMap<ImageRef, u64>,
+    sequence: u64,
+    refused: u64,
+    evicted: u64,
+    /// reached the limit, only logging when this changes
+    at_capacity: bool,
+}
+
+impl LimitInner {
+    fn touch(&mut self, image: &ImageRef) {
+        self.sequence += 1;
+        if let Some(previous) = self.updated.insert(image.clone(), self.sequence) {
+            self.by_update.remove(&previous);
+        }
+        self.by_update.insert(self.sequence, image.clone());
+    }
+
+    fn forget(&mut self, image: &ImageRef) {
+        if let Some(previous) = self.updated.remove(image) {
+            self.by_update.remove(&previous);
+        }
+    }
+
+    fn record(&mut self, evt: &Event<ImageRef, Image>) {
+        match evt {
+            Event::Added(image, _) | Event::Modified(image, _) => self.touch(image),
+            Event::Removed(image) => self.forget(image),
+            Event::Restart(state) => {
+                self.by_update.clear();
+                self.updated.clear();
+                state.keys().for_each(|image| self.touch(image));
+            }
+            Event::RestartChunk { entries, .. } => {
+                entries.keys().for_each(|image| self.touch(image));
+            }
+        }
+
+        let at_capacity = self.max > 0 && self.updated.len() >= self.max;
+        if at_capacity != self.at_capacity {
+            self.at_capacity = at_capacity;
+            match (at_capacity, self.strategy) {
+                (true, LimitStrategy::Refuse) => warn!(
+                    "Tracking the maximum of {} images, refusing new images",
+                    self.max
+                ),
+                (true, LimitStrategy::EvictOldest) => warn!(
+                    "Tracking the maximum of {} images, evicting the oldest ones for new images",
+                    self.max
+                ),
+                (false, _) => info!(
+                    "Tracking less than the maximum of {} images again",
+                    self.max
+                ),
+            }
+        }
+    }
+}
+
+impl ImageLimit {
+    /// Decide on tracking an image, which is only a new image if it isn't tracked already.
+    pub fn admit(&self, image: &ImageRef) -> LimitDecision {
+        let mut inner = self.inner.lock();
+        if inner.max == 0 || inner.updated.len() < inner.max || inner.updated.contains_key(image) {
+            return LimitDecision::Admit;
+        }
+
+        match inner.strategy {
+            LimitStrategy::Refuse => {
+                inner.refused += 1;
+                debug!(
+                    "Refusing {image}, tracking the maximum of {} images",
+                    inner.max
+                );
+                LimitDecision::Refuse
+            }
+            LimitStrategy::EvictOldest => match inner.by_update.values().next().cloned() {
+                Some(oldest) => {
+                    inner.evicted += 1;
+                    debug!(
+                        "Evicting {oldest} for {image}, tracking the maximum of {} images",
+                        inner.max
+                    );
+                    LimitDecision::Evict(oldest)
+                }
+                None => LimitDecision::Admit,
+            },
+        }
+    }
+
+    /// Cut down a complete workload to the maximum number of images.
+    ///
+    /// Images which are tracked already are kept first, starting with the most recently updated
+    /// ones.
+    pub fn trim(&self, state: &mut HashMap<ImageRef, Image>) {
+        let mut inner = self.inner.lock();
+        if inner.max == 0 || state.len() <= inner.max {
+            return;
+        }
+
+        let mut keep = inner
+            .by_update
+            .values()
+            .rev()
+            .filter(|image| state.contains_key(*image))
+            .take(inner.max)
+            .cloned()
+            .collect::<HashSet<_>>();
+        let remaining = inner.max - keep.len();
+        keep.extend(
+            state
+                .keys()
+                .filter(|image| !inner.updated.contains_key(*image))
+                .take(remaining)
+                .cloned(),
+        );
+
+        let dropped = (state.len() - keep.len()) as u64;
+        state.retain(|image, _| keep.contains(image));
+        match inner.strategy {
+            LimitStrategy::Refuse => inner.refused += dropped,
+            LimitStrategy::EvictOldest => inner.evicted += dropped,
+        }
+    }
+
+    pub fn status(&self) -> ImageLimitStatus {
+        let inner = self.inner.lock();
+        ImageLimitStatus {
+            max_images: inner.max,
+            images: inner.updated.len(),
+            refused: inner.refused,
+            evicted: inner.evicted,
+        }
+    }
 }
 
 impl Deref for WorkloadState {
@@ -17,12 +277,162 @@ impl Deref for WorkloadState {
     }
 }
 
+impl WorkloadState {
+    /// A handle to the same workload, registering its listeners under `name`, see
+    /// [`State::named`].
+    pub fn named(&self, name: impl Into<String>) -> Self {
+        Self {
+            state: self.state.named(name),
+            ..self.clone()
+        }
+    }
+
+    pub fn startup(&self) -> &Startup {
+        &self.startup
+    }
+
+    pub fn image_limit(&self) -> &ImageLimit {
+        &self.limit
+    }
+
+    /// The images waiting for an SBOM lookup, see [`LookupQueue`].
+    pub fn lookup_queue(&self) -> &LookupQueue {
+        &self.lookups
+    }
+
+    /// Cap the number of tracked images, zero for no limit, see [`ImageLimit`].
+    pub async fn set_image_limit(&self, max: usize, strategy: LimitStrategy) {
+        // no writes, until the hook is in place and caught up with the current images
+        let state = self.lock_state_for_read().await;
+
+        let mut inner = self.limit.inner.lock();
+        inner.max = max;
+        inner.strategy = strategy;
+        if max == 0 {
+            // detached once the lock is released, as the hook takes it too
+            let _hook = inner.hook.take();
+            drop(inner);
+        } else if inner.hook.is_none() {
+            inner.by_update.clear();
+            inner.updated.clear();
+            state.keys().for_each(|image| inner.touch(image));
+
+            let limit = self.limit.clone();
+            inner.hook = Some(self.add_hook(move |evt| limit.inner.lock().record(evt)));
+        }
+    }
+
+    /// Subscribe to changes of the SBOM of the images only, ignoring changes of their pods.
+    pub async fn subscribe_sbom_only(
+        &self,
+        buffer: impl Into<Option<usize>>,
+    ) -> Subscription<ImageRef, SbomState> {
+        self.subscribe_field(buffer, |image: &Image| image.sbom.clone())
+            .await
+    }
+
+    /// Persist the baseline to a file, removing the file if there is no baseline.
+    pub async fn save_baseline(&self, path: &Path) -> anyhow::Result<()> {
+        // write to a temporary file first, so that we never leave a truncated baseline behind
+        let temp = path.with_extension("tmp");
+        let file = BufWriter::new(File::create(&temp)?);
+
+        match self.save_baseline_ndjson(file).await? {
+            true => std::fs::rename(&temp, path)?,
+            false => {
+                std::fs::remove_file(&temp)?;
+                match std::fs::remove_file(path) {
+                    Err(err) if err.kind() != ErrorKind::NotFound => return Err(err.into()),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Load the baseline from a file, if it exists.
+    pub async fn load_baseline(&self, path: &Path) -> anyhow::Result<()> {
+        let file = match File::open(path) {
+            Ok(file) => file,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err.into()),
+        };
+
+        let report = self.load_baseline_ndjson(BufReader::new(file)).await?;
+        info!(
+            "Loaded baseline of {} images from {}",
+            report.loaded,
+            path.display()
+        );
+        for err in report.errors {
+            warn!("Baseline {}: {err}", path.display());
+        }
+
+        Ok(())
+    }
+
+    /// Set an annotation on an image, returns `false` if the image is unknown.
+    pub async fn set_annotation(
+        &self,
+        image: ImageRef,
+        key: impl Into<String>,
+        value: impl Into<String>,
+    ) -> bool {
+        let (key, value) = (key.into(), value.into());
+        let mut found = false;
+        self.mutate_state(image, |current| {
+            current.map(|mut current| {
+                found = true;
+                current.annotations.insert(key, value);
+                current
+            })
+        })
+        .await;
+        found
+    }
+
+    /// Schedule failed SBOM lookups of the images again, returns the number of images scheduled.
+    ///
+    /// Images which are unknown, or whose lookup didn't fail, are skipped.
+    pub async fn retry(&self, images: impl IntoIterator<Item = ImageRef>) -> usize {
+        let mut scheduled = 0;
+        for image in images {
+            self.mutate_state(image, |current| {
+                current.map(|mut current| {
+                    if let SbomState::Err(_) = current.sbom {
+                        scheduled += 1;
+                        current.sbom = SbomState::Scheduled;
+                    }
+                    current
+                })
+            })
+            .await;
+        }
+        scheduled
+    }
+
+    /// Remove an annotation from an image, returns `false` if the image is unknown.
+    pub async fn remove_annotation(&self, image: ImageRef, key: &str) -> bool {
+        let mut found = false;
+        self.mutate_state(image, |current| {
+            current.map(|mut current| {
+                found = true;
+                current.annotations.remove(key);
+                current
+            })
+        })
+        .await;
+        found
+    }
+}
+
 pub async fn by_ns(
     source: &WorkloadState,
     namespace: impl Into<String>,
 ) -> (WorkloadState, impl Future<Output = anyhow::Result<()>>) {
     let workload = WorkloadState::default();
-    let mut sub = source.subscribe(None).await;
+    let mut sub = source.named("namespace-filter").subscribe(None).await;
 
     let runner = {
         let workload = workload.clone();
@@ -33,14 +443,16 @@ pub async fn by_ns(
                     Event::Added(image_ref, image) => {
                         workload
                             .mutate_state(image_ref, |_current| {
-                                Some(Image {
-                                    sbom: image.sbom,
-                                    pods: image
-                                        .pods
-                                        .into_iter()
-                                        .filter(|pod| pod.namespace == namespace)
-                                        .collect(),
-                                })
+                                // the history of the source covers all namespaces
+                                let mut state = image
+                                    .pods
+                                    .into_iter()
+                                    .filter(|pod| pod.namespace == namespace)
+                                    .collect::<ImageBuilder>()
+                                    .sbom(image.sbom)
+                                    .build();
+                                state.annotations = image.annotations;
+                                Some(state)
                             })
                             .await;
                     }
@@ -51,12 +463,15 @@ pub async fn by_ns(
                         workload
                             .mutate_state(image_ref, |mut current| {
                                 if let Some(state) = &mut current {
-                                    state.pods = image
-                                        .pods
-                                        .into_iter()
-                                        .filter(|pod| pod.namespace == namespace)
-                                        .collect();
+                                    state.set_pods(
+                                        image
+                                            .pods
+                                            .into_iter()
+                                            .filter(|pod| pod.namespace == namespace)
+                                            .collect(),
+                                    );
                                     state.sbom = image.sbom;
+                                    state.annotations = image.annotations;
                                 }
 
                                 current
@@ -66,10 +481,14 @@ pub async fn by_ns(
                     Event::Restart(mut state) => {
                         for s in state.values_mut() {
                             s.pods.retain(|pod| pod.namespace == namespace);
+                            s.pod_history = Default::default();
+                            s.pod_history.record(s.pods.len());
                         }
                         state.retain(|_, v| !v.pods.is_empty());
                         workload.set_state(state).await;
                     }
+                    // only delivered to chunked subscriptions
+                    Event::RestartChunk { .. } => {}
                 }
             }
             log::info!("Lost subscription, re-trying...");
@@ -80,3 +499,287 @@ pub async fn by_ns(
 
     (workload, runner)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::wait_for;
+    use bommer_api::data::PodRef;
+    use std::time::Duration;
+
+    fn image(image: &str) -> ImageRef {
+        ImageRef(image.to_string())
+    }
+
+    /// An image, used by pods given as `(namespace, name)`
+    fn running(pods: &[(&str, &str)]) -> Image {
+        pods.iter()
+            .map(|(namespace, name)| PodRef {
+                namespace: namespace.to_string(),
+                name: name.to_string(),
+            })
+            .collect::<ImageBuilder>()
+            .build()
+    }
+
+    /// Check the consistency of the workload, **panicking** if it is broken, see
+    /// [`State::assert_invariants_with`].
+    ///
+    /// Images without pods must have been removed, and image references must not carry
+    /// surrounding whitespace, which would make them different keys for the same image.
+    async fn assert_invariants(map: &WorkloadState) {
+        map.assert_invariants_with(|image, state| {
+            if image.0.is_empty() || image.0.trim() != image.0 {
+                return Err("image reference is not canonical".to_string());
+            }
+            if state.pods.is_empty() {
+                return Err("image has no pods, but is still present".to_string());
+            }
+            Ok(())
+        })
+        .await
+    }
+
+    #[tokio::test]
+    async fn annotations() {
+        let map = WorkloadState::default();
+        map.set_state(HashMap::from([(
+            image("nginx"),
+            running(&[("default", "a")]),
+        )]))
+        .await;
+        let mut sub = map.subscribe(None).await;
+        assert!(matches!(sub.recv().await, Some(Event::Restart(_))));
+
+        assert!(map.set_annotation(image("nginx"), "owner", "team-a").await);
+        match sub.recv().await {
+            Some(Event::Modified(_, image)) => assert_eq!(
+                image.annotations,
+                BTreeMap::from([("owner".to_string(), "team-a".to_string())])
+            ),
+            other => panic!("expected a modification, got {other:?}"),
+        }
+
+        // annotations can't create images
+        assert!(!map.set_annotation(image("redis"), "owner", "team-a").await);
+        assert!(map.get(&image("redis")).await.is_none());
+
+        assert!(map.remove_annotation(image("nginx"), "owner").await);
+        assert!(map
+            .get(&image("nginx"))
+            .await
+            .unwrap()
+            .annotations
+            .is_empty());
+        assert!(!map.remove_annotation(image("redis"), "owner").await);
+        assert_invariants(&map).await;
+    }
+
+    #[tokio::test]
+    async fn namespace_filter_keeps_annotations() {
+        let map = WorkloadState::default();
+        map.set_state(HashMap::from([(
+            image("nginx"),
+            running(&[("a", "web"), ("b", "web")]),
+        )]))
+        .await;
+        map.set_annotation(image("nginx"), "owner", "team-a").await;
+
+        let (filtered, runner) = by_ns(&map, "a").await;
+        tokio::spawn(runner);
+        wait_for(&filtered, |state| {
+            state
+                .get(&image("nginx"))
+                .is_some_and(|image| image.annotations.contains_key("owner"))
+        })
+        .await;
+        assert_eq!(filtered.get(&image("nginx")).await.unwrap().pods.len(), 1);
+
+        map.set_annotation(image("nginx"), "note", "reviewed").await;
+        wait_for(&filtered, |state| {
+            state
+                .get(&image("nginx"))
+                .is_some_and(|image| image.annotations.len() == 2)
+        })
+        .await;
+    }
+
+    #[tokio::test]
+    async fn baseline_persists() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("baseline.ndjson");
+
+        let map = WorkloadState::default();
+        map.set_state(HashMap::from([(
+            image("nginx"),
+            running(&[("default", "a")]),
+        )]))
+        .await;
+        map.set_baseline().await;
+        map.save_baseline(&path).await.unwrap();
+
+        let restarted = WorkloadState::default();
+        restarted.load_baseline(&path).await.unwrap();
+        assert_eq!(restarted.baseline().await, map.baseline().await);
+        assert!(restarted.baseline().await.is_some());
+
+        // without a baseline, there is no file
+        map.clear_baseline().await;
+        map.save_baseline(&path).await.unwrap();
+        assert!(!path.exists());
+
+        let restarted = WorkloadState::default();
+        restarted.load_baseline(&path).await.unwrap();
+        assert_eq!(restarted.baseline().await, None);
+    }
+
+    #[tokio::test]
+    async fn field_subscriptions() {
+        let map = WorkloadState::default();
+        map.set_state(HashMap::from([(
+            image("nginx"),
+            running(&[("default", "a")]),
+        )]))
+        .await;
+        let mut pods = map
+            .subscribe_field(None, |image: &Image| image.pods.clone())
+            .await;
+        let mut sboms = map.subscribe_sbom_only(None).await;
+        assert!(matches!(pods.recv().await, Some(Event::Restart(_))));
+        assert!(matches!(sboms.recv().await, Some(Event::Restart(_))));
+
+        map.mutate_state(image("nginx"), |image| {
+            image.map(|mut image| {
+                image.sbom = SbomState::Missing;
+                image
+            })
+        })
+        .await;
+        match sboms.recv().await {
+            Some(Event::Modified(_, sbom)) => assert_eq!(sbom, SbomState::Missing),
+            other => panic!("expected a modification, got {other:?}"),
+        }
+
+        map.mutate_state(image("nginx"), |_| {
+            let mut image = running(&[("default", "a"), ("default", "b")]);
+            image.sbom = SbomState::Missing;
+            Some(image)
+        })
+        .await;
+        match pods.recv().await {
+            Some(Event::Modified(_, pods)) => assert_eq!(pods.len(), 2),
+            other => panic!("expected a modification, got {other:?}"),
+        }
+
+        // each only saw the change of its own field
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(pods.try_recv().is_err());
+        assert!(sboms.try_recv().is_err());
+        assert_invariants(&map).await;
+    }
+
+    #[tokio::test]
+    async fn image_limit_refuse() {
+        let map = WorkloadState::default();
+        map.set_image_limit(2, LimitStrategy::Refuse).await;
+        let limit = map.image_limit();
+
+        for name in ["a", "b"] {
+            assert_eq!(limit.admit(&image(name)), LimitDecision::Admit);
+            map.mutate_state(image(name), |_| Some(running(&[("default", name)])))
+                .await;
+        }
+        assert_eq!(limit.admit(&image("c")), LimitDecision::Refuse);
+        // tracked images may still change
+        assert_eq!(limit.admit(&image("a")), LimitDecision::Admit);
+        assert_eq!(limit.status().refused, 1);
+
+        // once there is room again, the refused image gets admitted when its pods change
+        map.remove_state(image("a")).await;
+        assert_eq!(limit.admit(&image("c")), LimitDecision::Admit);
+        assert_eq!(
+            limit.status(),
+            ImageLimitStatus {
+                max_images: 2,
+                images: 1,
+                refused: 1,
+                evicted: 0,
+            }
+        );
+
+        // lifting the limit detaches the hook tracking the updates
+        map.set_image_limit(0, LimitStrategy::Refuse).await;
+        assert!(limit.inner.lock().hook.is_none());
+        assert_eq!(limit.admit(&image("d")), LimitDecision::Admit);
+        assert_invariants(&map).await;
+    }
+
+    #[tokio::test]
+    async fn image_limit_evict_oldest() {
+        let map = WorkloadState::default();
+        map.set_image_limit(2, LimitStrategy::EvictOldest).await;
+        let limit = map.image_limit();
+
+        for name in ["a", "b"] {
+            map.mutate_state(image(name), |_| Some(running(&[("default", name)])))
+                .await;
+        }
+        assert_eq!(limit.admit(&image("c")), LimitDecision::Evict(image("a")));
+
+        // an update makes it the most recent one
+        map.mutate_state(image("a"), |_| Some(running(&[("default", "a2")])))
+            .await;
+        assert_eq!(limit.admit(&image("c")), LimitDecision::Evict(image("b")));
+        assert_eq!(limit.status().evicted, 2);
+        assert_invariants(&map).await;
+    }
+
+    #[tokio::test]
+    async fn image_limit_trim_resync() {
+        let map = WorkloadState::default();
+        map.set_image_limit(2, LimitStrategy::Refuse).await;
+        let limit = map.image_limit();
+
+        for name in ["a", "b"] {
+            map.mutate_state(image(name), |_| Some(running(&[("default", name)])))
+                .await;
+        }
+
+        // tracked images are kept first
+        let mut state = ["a", "b", "c", "d"]
+            .into_iter()
+            .map(|name| (image(name), running(&[("default", name)])))
+            .collect::<HashMap<_, _>>();
+        limit.trim(&mut state);
+        let mut kept = state
+            .keys()
+            .map(|image| image.0.as_str())
+            .collect::<Vec<_>>();
+        kept.sort();
+        assert_eq!(kept, ["a", "b"]);
+        assert_eq!(limit.status().refused, 2);
+
+        // the room of an image gone in the meantime goes to a new one
+        let mut state = ["b", "c", "d"]
+            .into_iter()
+            .map(|name| (image(name), running(&[("default", name)])))
+            .collect::<HashMap<_, _>>();
+        limit.trim(&mut state);
+        assert_eq!(state.len(), 2);
+        assert!(state.contains_key(&image("b")));
+        assert_eq!(limit.status().refused, 3);
+
+        // after the resync, the new image is tracked, and admitted again
+        map.set_state(state).await;
+        let tracked = map
+            .get_state()
+            .await
+            .into_keys()
+            .find(|tracked| *tracked != image("b"))
+            .unwrap();
+        assert_eq!(limit.admit(&tracked), LimitDecision::Admit);
+        assert_eq!(limit.admit(&image("a")), LimitDecision::Refuse);
+        assert_eq!(limit.status().images, 2);
+        assert_invariants(&map).await;
+    }
+}